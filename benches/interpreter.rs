@@ -0,0 +1,24 @@
+use std::hint::black_box;
+
+use basic_rust_vm::{Program, VM};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+// Each benchmark runs a fresh VM, so building it is kept out of the timing.
+fn run(c: &mut Criterion, name: &str, program: Program) {
+    c.bench_function(name, |b| {
+        b.iter_batched(
+            || VM::from_program(program.clone()),
+            |mut vm| black_box(vm.run()),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn interpreter(c: &mut Criterion) {
+    run(c, "fibonacci_iterative_10k", Program::fibonacci(10_000));
+    run(c, "fibonacci_recursive_20", Program::recursive_fibonacci(20));
+    run(c, "memory_churn_1k", Program::memory_churn(1_000, 256));
+}
+
+criterion_group!(benches, interpreter);
+criterion_main!(benches);
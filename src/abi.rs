@@ -0,0 +1,20 @@
+use std::ops::Range;
+
+/// Registers that pass the arguments of a call, first argument in `r0`.
+pub const ARGUMENT_REGISTERS: Range<usize> = 0..4;
+
+/// The register a function returns its result in.
+pub const RETURN_REGISTER: usize = 0;
+
+/// Registers a function must leave as it found them. Everything else, the
+/// argument registers included, may be overwritten by a call.
+pub const CALLEE_SAVED_REGISTERS: Range<usize> = 4..8;
+
+/// How strictly a VM holds functions to the calling convention above.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CallingConvention {
+    #[default]
+    Unchecked, // Functions may use every register freely
+    Verified, // `verify` rejects functions that overwrite a callee-saved register without pushing it first
+    SaveCalleeSaved, // `Call` saves the callee-saved registers and `Return` restores them
+}
@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// The freed blocks below the allocation cursor of a `MemoryStore`. Adjacent free
+/// blocks are always merged, so no two entries touch.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub(crate) struct FreeList {
+    blocks: BTreeMap<usize, usize>, // Free blocks, base address to size
+}
+
+impl FreeList {
+    /// The lowest free block with room for `size` bytes (first fit).
+    pub(crate) fn find(&self, size: usize) -> Option<usize> {
+        self.blocks
+            .iter()
+            .find(|(_, &free)| free >= size)
+            .map(|(&address, _)| address)
+    }
+
+    /// Mark `[address, address + size)` as used, splitting the free block that
+    /// contains it. Ranges that aren't free are left alone and return false.
+    pub(crate) fn reserve(&mut self, address: usize, size: usize) -> bool {
+        let Some((&base, &free)) = self.blocks.range(..=address).next_back() else {
+            return false;
+        };
+        if address + size > base + free {
+            return false;
+        }
+        self.blocks.remove(&base);
+        if address > base {
+            self.blocks.insert(base, address - base);
+        }
+        if base + free > address + size {
+            self.blocks
+                .insert(address + size, base + free - address - size);
+        }
+        true
+    }
+
+    /// Return `[address, address + size)` to the list, merging it with the free
+    /// blocks on either side. Returns the merged block.
+    pub(crate) fn release(&mut self, mut address: usize, mut size: usize) -> (usize, usize) {
+        if let Some((&before, &free)) = self.blocks.range(..address).next_back() {
+            if before + free == address {
+                self.blocks.remove(&before);
+                address = before;
+                size += free;
+            }
+        }
+        if let Some(after) = self.blocks.remove(&(address + size)) {
+            size += after;
+        }
+        self.blocks.insert(address, size);
+        (address, size)
+    }
+
+    pub(crate) fn remove(&mut self, address: usize) {
+        self.blocks.remove(&address);
+    }
+
+    /// The free blocks in address order.
+    pub(crate) fn blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.blocks.iter().map(|(&address, &size)| (address, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_fit_and_split() {
+        let mut list = FreeList::default();
+        list.release(0, 8);
+        list.release(16, 32);
+
+        assert_eq!(list.find(4), Some(0));
+        assert_eq!(list.find(9), Some(16));
+        assert_eq!(list.find(33), None);
+
+        list.reserve(16, 10);
+        assert_eq!(list.blocks().collect::<Vec<_>>(), vec![(0, 8), (26, 22)]);
+        list.reserve(2, 2);
+        assert_eq!(list.blocks().collect::<Vec<_>>(), vec![(0, 2), (4, 4), (26, 22)]);
+    }
+
+    #[test]
+    fn test_coalescing() {
+        let mut list = FreeList::default();
+        assert_eq!(list.release(8, 8), (8, 8));
+        assert_eq!(list.release(24, 8), (24, 8));
+        assert_eq!(list.release(16, 8), (8, 24)); // Merges with both neighbours
+        assert_eq!(list.release(0, 8), (0, 32));
+        assert_eq!(list.blocks().collect::<Vec<_>>(), vec![(0, 32)]);
+    }
+}
@@ -0,0 +1,1597 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::abi::{ARGUMENT_REGISTERS, CALLEE_SAVED_REGISTERS, RETURN_REGISTER};
+use crate::isa::Instruction;
+use crate::program::Program;
+use crate::value::Value;
+
+/// An error found while assembling a program, with the 1-based source line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+/// What went wrong on the line an `AsmError` points at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String), // No instruction with this name
+    OperandCount { expected: usize, found: usize }, // Wrong number of operands
+    InvalidRegister(String), // Operand is not of the form `r<N>`, or `v<N>` for a vector register
+    InvalidNumber(String),   // Operand is not a number in range
+    InvalidLabel(String),    // Label name is empty or malformed
+    DuplicateLabel(String),  // Label defined more than once
+    UndefinedLabel(String),  // Jump or call to a label that is never defined
+    InvalidString(String),   // `.string` operand is not a properly quoted literal
+    WrongSection(String),    // Instruction in the data section, or data outside it
+    InvalidTable(String),    // `jmptbl` targets are not a bracketed list
+    InvalidGlobal(String),   // `.global` declaration is not of the form `name: i32 = 0`
+    InvalidStruct(String),   // `.struct` definition is not of the form `Name { field: 4, ... }`
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmErrorKind::UnknownMnemonic(name) => write!(f, "unknown instruction `{}`", name),
+            AsmErrorKind::OperandCount { expected, found } => {
+                write!(
+                    f,
+                    "wrong number of operands: expected {}, found {}",
+                    expected, found
+                )
+            }
+            AsmErrorKind::InvalidRegister(operand) => {
+                write!(f, "`{}` is not a register", operand)
+            }
+            AsmErrorKind::InvalidNumber(operand) => {
+                write!(f, "`{}` is not a valid number", operand)
+            }
+            AsmErrorKind::InvalidLabel(label) => write!(f, "invalid label `{}`", label),
+            AsmErrorKind::DuplicateLabel(label) => {
+                write!(f, "label `{}` is already defined", label)
+            }
+            AsmErrorKind::UndefinedLabel(label) => write!(f, "undefined label `{}`", label),
+            AsmErrorKind::InvalidString(operand) => {
+                write!(f, "`{}` is not a valid string literal", operand)
+            }
+            AsmErrorKind::WrongSection(name) => {
+                write!(f, "`{}` is not allowed in this section", name)
+            }
+            AsmErrorKind::InvalidTable(operand) => {
+                write!(f, "`{}` is not a bracketed list of jump targets", operand)
+            }
+            AsmErrorKind::InvalidGlobal(declaration) => {
+                write!(f, "`{}` is not a valid global declaration", declaration)
+            }
+            AsmErrorKind::InvalidStruct(definition) => {
+                write!(f, "`{}` is not a valid struct definition", definition)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// A source line holding an instruction, kept between the two passes.
+struct SourceLine<'a> {
+    line: usize,
+    address: usize, // Of its first instruction
+    size: usize,    // Instructions it assembles to, more than one for a field access
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+// The fields of a `.struct`, laid out in order without padding.
+struct StructLayout<'a> {
+    size: usize,
+    fields: Vec<(&'a str, usize)>, // Name and byte offset
+}
+
+// The mnemonics whose offset register may be written as a struct field instead.
+const FIELD_ACCESSES: [&str; 12] = [
+    "storex", "loadx", "st8", "st16", "st32", "ld8s", "ld8u", "ld16s", "ld16u", "ld32", "vload",
+    "vstore",
+];
+
+// Instructions a field access assembles to: saving a temporary register, loading
+// the offset into it, the access itself and restoring the register.
+const FIELD_ACCESS_SIZE: usize = 4;
+
+/// Assemble program text into instructions, dropping any data section. Use
+/// `assemble_program` for programs with data.
+pub fn assemble(source: &str) -> Result<Vec<Instruction>, AsmError> {
+    assemble_program(source).map(|program| program.instructions)
+}
+
+/// Assemble program text into instructions, a data section and a function table.
+///
+/// Each line holds at most one instruction: a mnemonic followed by comma
+/// separated operands, e.g. `add r0, r1, r2`. Anything after `;` is a comment.
+/// A line may start with one or more `name:` labels, which jumps and calls can
+/// use in place of a numeric offset.
+///
+/// Lines after `.data` fill the data section with `.string "text"` and
+/// `.byte 1, 2` directives until `.text` switches back to instructions. A label
+/// in the data section names the memory address of the next byte, which `set`
+/// and `setw` accept as their value.
+///
+/// `.global counter: i32 = 0` declares a global variable, an `i32`, an `f64` or
+/// an `i64` that starts out as zero without the `= value`, or a `nil`. `loadg`
+/// and `storeg` accept its name in place of the global's index.
+///
+/// `.functions f, g` appends the addresses of the code labels `f` and `g` to the
+/// function table, so `callt` with index 0 calls `f`. `set` also accepts a code
+/// label, for callbacks called with `callr`.
+///
+/// `.struct Point { x: 4, y: 4 }` lays out a record in memory, its fields in
+/// order without padding. `set` accepts `Point` as its size and `Point.y` as the
+/// offset of a field, and the loads and stores that take an offset register,
+/// such as `ld32 r0, Point.y, r1`, accept a field in its place: the access
+/// assembles to four instructions that put the offset in a spare register,
+/// saved on the data stack around it.
+///
+/// Code labels at the start of the program, on the targets of `call` and in
+/// the function table become the program's symbols, which name the functions
+/// of a trap's backtrace. Of several labels on one address the first in
+/// alphabetical order is used.
+pub fn assemble_program(source: &str) -> Result<Program, AsmError> {
+    assemble_with_lines(source).map(|(program, _)| program)
+}
+
+/// Assemble program text like `assemble_program`, also returning the 1-based
+/// source line of each instruction, for debuggers that show the source.
+pub fn assemble_with_lines(source: &str) -> Result<(Program, Vec<usize>), AsmError> {
+    // First pass: strip comments, record the address of every label and collect
+    // the data section.
+    let mut labels = HashMap::new();
+    let mut data_labels = HashMap::new();
+    let mut data = Vec::new();
+    let mut in_data = false;
+    let mut lines = Vec::new();
+    let mut table = Vec::new(); // Line and label of each function-table entry
+    let mut global_names = HashMap::new();
+    let mut globals = Vec::new();
+    let mut structs = HashMap::new();
+    let mut address = 0; // Of the next instruction
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let mut text = strip_comment(text).trim();
+        // The `:` of a global declaration doesn't end a label either.
+        if let Some(declaration) = text
+            .strip_prefix(".global")
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+        {
+            let (name, value) =
+                global_declaration(declaration.trim()).map_err(|kind| AsmError { line, kind })?;
+            if labels.contains_key(name)
+                || data_labels.contains_key(name)
+                || structs.contains_key(name)
+                || global_names.insert(name, globals.len()).is_some()
+            {
+                return Err(AsmError {
+                    line,
+                    kind: AsmErrorKind::DuplicateLabel(name.to_string()),
+                });
+            }
+            globals.push(value);
+            continue;
+        }
+        // And neither do those of the fields of a struct.
+        if let Some(definition) = text
+            .strip_prefix(".struct")
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+        {
+            let (name, layout) =
+                struct_definition(definition.trim()).map_err(|kind| AsmError { line, kind })?;
+            if labels.contains_key(name)
+                || data_labels.contains_key(name)
+                || global_names.contains_key(name)
+                || structs.insert(name, layout).is_some()
+            {
+                return Err(AsmError {
+                    line,
+                    kind: AsmErrorKind::DuplicateLabel(name.to_string()),
+                });
+            }
+            continue;
+        }
+        // A `:` inside a string literal doesn't end a label.
+        while let Some((label, rest)) = text
+            .split_once(':')
+            .filter(|(label, _)| !label.contains('"'))
+        {
+            let label = label.trim();
+            if !is_identifier(label) {
+                return Err(AsmError {
+                    line,
+                    kind: AsmErrorKind::InvalidLabel(label.to_string()),
+                });
+            }
+            let duplicate = global_names.contains_key(label)
+                || structs.contains_key(label)
+                || if in_data {
+                    labels.contains_key(label) || data_labels.insert(label, data.len()).is_some()
+                } else {
+                    data_labels.contains_key(label) || labels.insert(label, address).is_some()
+                };
+            if duplicate {
+                return Err(AsmError {
+                    line,
+                    kind: AsmErrorKind::DuplicateLabel(label.to_string()),
+                });
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let (mnemonic, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        if mnemonic == ".functions" {
+            let operands = operands.trim();
+            if operands.is_empty() {
+                return Err(AsmError {
+                    line,
+                    kind: AsmErrorKind::OperandCount {
+                        expected: 1,
+                        found: 0,
+                    },
+                });
+            }
+            for label in operands.split(',').map(str::trim) {
+                if !is_identifier(label) {
+                    return Err(AsmError {
+                        line,
+                        kind: AsmErrorKind::InvalidLabel(label.to_string()),
+                    });
+                }
+                table.push((line, label));
+            }
+            continue;
+        }
+        if mnemonic.starts_with('.') {
+            directive(mnemonic, operands.trim(), &mut in_data, &mut data)
+                .map_err(|kind| AsmError { line, kind })?;
+            continue;
+        }
+        if in_data {
+            return Err(AsmError {
+                line,
+                kind: AsmErrorKind::WrongSection(mnemonic.to_string()),
+            });
+        }
+        let operands = if operands.trim().is_empty() {
+            Vec::new()
+        } else {
+            operands.split(',').map(str::trim).collect()
+        };
+        let size = if is_field_access(mnemonic, &operands) {
+            FIELD_ACCESS_SIZE
+        } else {
+            1
+        };
+        lines.push(SourceLine {
+            line,
+            address,
+            size,
+            mnemonic,
+            operands,
+        });
+        address += size;
+    }
+
+    // Second pass: build the instructions, resolving labels to relative offsets.
+    let mut instructions = Vec::with_capacity(address);
+    for source_line in &lines {
+        let parser = Parser {
+            address: source_line.address,
+            labels: &labels,
+            data_labels: &data_labels,
+            globals: &global_names,
+            structs: &structs,
+        };
+        let error = |kind| AsmError {
+            line: source_line.line,
+            kind,
+        };
+        if source_line.size == FIELD_ACCESS_SIZE {
+            instructions.extend(
+                parser
+                    .field_access(source_line.mnemonic, &source_line.operands)
+                    .map_err(error)?,
+            );
+        } else {
+            instructions.push(
+                parser
+                    .instruction(source_line.mnemonic, &source_line.operands)
+                    .map_err(error)?,
+            );
+        }
+    }
+    let functions = table
+        .iter()
+        .map(|&(line, label)| {
+            labels.get(label).copied().ok_or_else(|| AsmError {
+                line,
+                kind: AsmErrorKind::UndefinedLabel(label.to_string()),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut entries: Vec<usize> = instructions
+        .iter()
+        .filter_map(|instruction| match *instruction {
+            Instruction::Call(target) => Some(target),
+            _ => None,
+        })
+        .chain(functions.iter().copied())
+        .chain([0])
+        .collect();
+    entries.sort_unstable();
+    let mut names: Vec<(&str, usize)> = labels
+        .iter()
+        .map(|(&name, &address)| (name, address))
+        .filter(|(_, address)| entries.binary_search(address).is_ok())
+        .collect();
+    names.sort_unstable();
+    let mut symbols = BTreeMap::new();
+    for (name, address) in names {
+        symbols.entry(address).or_insert_with(|| name.to_string());
+    }
+    let program = Program {
+        instructions,
+        data,
+        functions,
+        globals,
+        symbols,
+    };
+    Ok((
+        program,
+        lines
+            .iter()
+            .flat_map(|source_line| std::iter::repeat_n(source_line.line, source_line.size))
+            .collect(),
+    ))
+}
+
+// Everything before the first `;` that isn't inside a string literal.
+fn strip_comment(text: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &text[..index],
+            _ => {}
+        }
+    }
+    text
+}
+
+fn directive(
+    name: &str,
+    operands: &str,
+    in_data: &mut bool,
+    data: &mut Vec<u8>,
+) -> Result<(), AsmErrorKind> {
+    let found = if operands.is_empty() {
+        0
+    } else {
+        operands.split(',').count()
+    };
+    match name {
+        ".data" | ".text" if found != 0 => Err(AsmErrorKind::OperandCount { expected: 0, found }),
+        ".data" => {
+            *in_data = true;
+            Ok(())
+        }
+        ".text" => {
+            *in_data = false;
+            Ok(())
+        }
+        ".string" | ".byte" if !*in_data => Err(AsmErrorKind::WrongSection(name.to_string())),
+        ".string" => {
+            data.extend(string_literal(operands)?);
+            Ok(())
+        }
+        ".byte" if found == 0 => Err(AsmErrorKind::OperandCount { expected: 1, found }),
+        ".byte" => {
+            for operand in operands.split(',').map(str::trim) {
+                data.push(byte(operand)?);
+            }
+            Ok(())
+        }
+        _ => Err(AsmErrorKind::UnknownMnemonic(name.to_string())),
+    }
+}
+
+// A `.global` declaration after the directive: `name: i32 = 1`, `name: f64`,
+// `name: i64 = -1` or `name: nil`.
+fn global_declaration(declaration: &str) -> Result<(&str, Value), AsmErrorKind> {
+    let invalid = || AsmErrorKind::InvalidGlobal(declaration.to_string());
+    let (name, rest) = declaration.split_once(':').ok_or_else(invalid)?;
+    let name = name.trim();
+    if !is_identifier(name) {
+        return Err(AsmErrorKind::InvalidLabel(name.to_string()));
+    }
+    let (kind, value) = match rest.split_once('=') {
+        Some((kind, value)) => (kind.trim(), Some(value.trim())),
+        None => (rest.trim(), None),
+    };
+    match kind {
+        "i32" => Ok((name, Value::Int(value.map_or(Ok(0), immediate)?))),
+        "f64" => Ok((name, Value::Float(value.map_or(Ok(0.0), float)?))),
+        "i64" => Ok((name, Value::Int64(value.map_or(Ok(0), number)?))),
+        "nil" if value.is_none() => Ok((name, Value::Nil)),
+        _ => Err(invalid()),
+    }
+}
+
+// A `.struct` definition after the directive: `Point { x: 4, y: 4 }`, each field
+// with its size in bytes.
+fn struct_definition(definition: &str) -> Result<(&str, StructLayout<'_>), AsmErrorKind> {
+    let invalid = || AsmErrorKind::InvalidStruct(definition.to_string());
+    let (name, body) = definition.split_once('{').ok_or_else(invalid)?;
+    let name = name.trim();
+    if !is_identifier(name) {
+        return Err(AsmErrorKind::InvalidLabel(name.to_string()));
+    }
+    let body = body.trim().strip_suffix('}').ok_or_else(invalid)?;
+    let mut layout = StructLayout {
+        size: 0,
+        fields: Vec::new(),
+    };
+    for field in body
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+    {
+        let (field, size) = field.split_once(':').ok_or_else(invalid)?;
+        let field = field.trim();
+        if !is_identifier(field) {
+            return Err(AsmErrorKind::InvalidLabel(field.to_string()));
+        }
+        if layout.fields.iter().any(|&(other, _)| other == field) {
+            return Err(AsmErrorKind::DuplicateLabel(format!("{}.{}", name, field)));
+        }
+        layout.fields.push((field, layout.size));
+        layout.size = layout
+            .size
+            .checked_add(number(size.trim())?)
+            .ok_or_else(invalid)?;
+    }
+    Ok((name, layout))
+}
+
+// A struct field written `Point.y`, split into the struct and field names.
+fn field_name(operand: &str) -> Option<(&str, &str)> {
+    operand
+        .split_once('.')
+        .filter(|(name, field)| is_identifier(name) && is_identifier(field))
+}
+
+// Whether a line is a load or store with a struct field in place of its offset
+// register, which assembles to `FIELD_ACCESS_SIZE` instructions.
+fn is_field_access(mnemonic: &str, operands: &[&str]) -> bool {
+    FIELD_ACCESSES.contains(&mnemonic.to_ascii_lowercase().as_str())
+        && operands.len() == 3
+        && field_name(operands[1]).is_some()
+}
+
+// A double-quoted string with the escapes `\n`, `\t`, `\r`, `\0`, `\\` and `\"`.
+fn string_literal(operand: &str) -> Result<Vec<u8>, AsmErrorKind> {
+    let invalid = || AsmErrorKind::InvalidString(operand.to_string());
+    let body = operand
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+    let mut bytes = Vec::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '"')) => c,
+                _ => return Err(invalid()),
+            },
+            '"' => return Err(invalid()), // Unescaped quote before the end
+            c => c,
+        };
+        let mut buffer = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+    }
+    Ok(bytes)
+}
+
+pub(crate) fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+struct Parser<'a> {
+    address: usize,
+    labels: &'a HashMap<&'a str, usize>,
+    data_labels: &'a HashMap<&'a str, usize>, // Memory addresses of data labels
+    globals: &'a HashMap<&'a str, usize>,     // Indices of declared globals
+    structs: &'a HashMap<&'a str, StructLayout<'a>>,
+}
+
+impl Parser<'_> {
+    fn instruction(&self, mnemonic: &str, operands: &[&str]) -> Result<Instruction, AsmErrorKind> {
+        let arity = |expected: usize| {
+            if operands.len() == expected {
+                Ok(())
+            } else {
+                Err(AsmErrorKind::OperandCount {
+                    expected,
+                    found: operands.len(),
+                })
+            }
+        };
+        let reg = |index: usize| register(operands[index]);
+        let num = |index: usize| number::<usize>(operands[index]);
+        let vreg = |index: usize| vector_register(operands[index]);
+
+        let name = mnemonic.to_ascii_lowercase();
+        if let Some(op) = three_register_op(&name) {
+            arity(3)?;
+            return Ok(op(reg(0)?, reg(1)?, reg(2)?));
+        }
+        let instruction = match name.as_str() {
+            "set" => {
+                arity(2)?;
+                Instruction::load_immediate(reg(0)?, self.value(operands[1])?)
+            }
+            "setw" => {
+                arity(2)?;
+                Instruction::SetReg(reg(0)?, self.value(operands[1])?)
+            }
+            "setb" => {
+                arity(2)?;
+                Instruction::SetRegByte(reg(0)?, byte(operands[1])?)
+            }
+            "setsh" => {
+                arity(3)?;
+                Instruction::SetRegShifted(reg(0)?, number(operands[1])?, number(operands[2])?)
+            }
+            "jmp" => {
+                arity(1)?;
+                Instruction::Jump(self.jump_target(operands[0])?)
+            }
+            "jmpz" => {
+                arity(2)?;
+                Instruction::JumpIfZero(reg(0)?, self.jump_target(operands[1])?)
+            }
+            "jmpnz" => {
+                arity(2)?;
+                Instruction::JumpIfNonZero(reg(0)?, self.jump_target(operands[1])?)
+            }
+            "call" => {
+                arity(1)?;
+                Instruction::Call(self.call_target(operands[0])?)
+            }
+            "ret" => {
+                arity(0)?;
+                Instruction::Return
+            }
+            "halt" => {
+                arity(0)?;
+                Instruction::Halt
+            }
+            "print" => {
+                arity(1)?;
+                Instruction::Print(reg(0)?)
+            }
+            "printu" => {
+                arity(1)?;
+                Instruction::PrintU(reg(0)?)
+            }
+            "alloc" => {
+                arity(2)?;
+                Instruction::AllocateMemory(reg(0)?, reg(1)?)
+            }
+            "free" => {
+                arity(1)?;
+                Instruction::FreeMemory(num(0)?)
+            }
+            "store" => {
+                arity(3)?;
+                Instruction::StoreToMemory(num(0)?, reg(1)?, num(2)?)
+            }
+            "load" => {
+                arity(3)?;
+                Instruction::LoadFromMemory(num(0)?, num(1)?, reg(2)?)
+            }
+            "sizeof" => {
+                arity(2)?;
+                Instruction::SizeOf(num(0)?, reg(1)?)
+            }
+            "memcpy" => {
+                arity(3)?;
+                Instruction::MemCopy(num(0)?, num(1)?, num(2)?)
+            }
+            "memfill" => {
+                arity(3)?;
+                Instruction::MemFill(num(0)?, reg(1)?, num(2)?)
+            }
+            "cas" => {
+                arity(5)?;
+                Instruction::Cas(num(0)?, num(1)?, reg(2)?, reg(3)?, reg(4)?)
+            }
+            "itof" => {
+                arity(2)?;
+                Instruction::IntToFloat(reg(0)?, reg(1)?)
+            }
+            "ftoi" => {
+                arity(2)?;
+                Instruction::FloatToInt(reg(0)?, reg(1)?)
+            }
+            "syscall" => {
+                arity(1)?;
+                Instruction::Syscall(num(0)?)
+            }
+            "clregs" => {
+                arity(0)?;
+                Instruction::ClearRegs
+            }
+            "clr" => {
+                arity(1)?;
+                Instruction::ClearReg(reg(0)?)
+            }
+            "asserteq" => {
+                arity(2)?;
+                Instruction::AssertEq(reg(0)?, immediate(operands[1])?)
+            }
+            "not" => {
+                arity(2)?;
+                Instruction::Not(reg(0)?, reg(1)?)
+            }
+            "addi" => {
+                arity(3)?;
+                Instruction::AddImm(reg(0)?, immediate(operands[1])?, reg(2)?)
+            }
+            "subi" => {
+                arity(3)?;
+                Instruction::SubImm(reg(0)?, immediate(operands[1])?, reg(2)?)
+            }
+            "muli" => {
+                arity(3)?;
+                Instruction::MulImm(reg(0)?, immediate(operands[1])?, reg(2)?)
+            }
+            "eqi" => {
+                arity(3)?;
+                Instruction::EqImm(reg(0)?, immediate(operands[1])?, reg(2)?)
+            }
+            "neqi" => {
+                arity(3)?;
+                Instruction::NeqImm(reg(0)?, immediate(operands[1])?, reg(2)?)
+            }
+            "gti" => {
+                arity(3)?;
+                Instruction::GtImm(reg(0)?, immediate(operands[1])?, reg(2)?)
+            }
+            "lti" => {
+                arity(3)?;
+                Instruction::LtImm(reg(0)?, immediate(operands[1])?, reg(2)?)
+            }
+            "gtei" => {
+                arity(3)?;
+                Instruction::GteImm(reg(0)?, immediate(operands[1])?, reg(2)?)
+            }
+            "ltei" => {
+                arity(3)?;
+                Instruction::LteImm(reg(0)?, immediate(operands[1])?, reg(2)?)
+            }
+            "storex" => {
+                arity(3)?;
+                Instruction::StoreIndirect(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "loadx" => {
+                arity(3)?;
+                Instruction::LoadIndirect(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "st8" => {
+                arity(3)?;
+                Instruction::Store8(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "st16" => {
+                arity(3)?;
+                Instruction::Store16(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "st32" => {
+                arity(3)?;
+                Instruction::Store32(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "ld8s" => {
+                arity(3)?;
+                Instruction::Load8S(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "ld8u" => {
+                arity(3)?;
+                Instruction::Load8U(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "ld16s" => {
+                arity(3)?;
+                Instruction::Load16S(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "ld16u" => {
+                arity(3)?;
+                Instruction::Load16U(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "ld32" => {
+                arity(3)?;
+                Instruction::Load32(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "readi" => {
+                arity(1)?;
+                Instruction::ReadInt(reg(0)?)
+            }
+            "readb" => {
+                arity(1)?;
+                Instruction::ReadByte(reg(0)?)
+            }
+            "prints" => {
+                arity(2)?;
+                Instruction::PrintStr(reg(0)?, reg(1)?)
+            }
+            "setf" => {
+                arity(2)?;
+                Instruction::SetFReg(reg(0)?, float(operands[1])?)
+            }
+            "push" => {
+                arity(1)?;
+                Instruction::Push(reg(0)?)
+            }
+            "pop" => {
+                arity(1)?;
+                Instruction::Pop(reg(0)?)
+            }
+            "dup" => {
+                arity(0)?;
+                Instruction::Dup
+            }
+            "swap" => {
+                arity(0)?;
+                Instruction::SwapTop
+            }
+            "enter" => {
+                arity(1)?;
+                Instruction::Enter(num(0)?)
+            }
+            "leave" => {
+                arity(0)?;
+                Instruction::Leave
+            }
+            "ldloc" => {
+                arity(2)?;
+                Instruction::LoadLocal(num(0)?, reg(1)?)
+            }
+            "stloc" => {
+                arity(2)?;
+                Instruction::StoreLocal(reg(0)?, num(1)?)
+            }
+            "callr" => {
+                arity(1)?;
+                Instruction::CallReg(reg(0)?)
+            }
+            "callt" => {
+                arity(1)?;
+                Instruction::CallTable(reg(0)?)
+            }
+            "jmptbl" => {
+                if operands.len() < 3 {
+                    return Err(AsmErrorKind::OperandCount {
+                        expected: 3,
+                        found: operands.len(),
+                    });
+                }
+                let (default, table) = operands[1..].split_last().unwrap();
+                Instruction::JumpTable(reg(0)?, self.jump_table(table)?, self.jump_target(default)?)
+            }
+            "brk" => {
+                arity(1)?;
+                Instruction::Brk(num(0)?)
+            }
+            "nop" => {
+                arity(0)?;
+                Instruction::Nop
+            }
+            "mov" => {
+                arity(2)?;
+                Instruction::Mov(reg(0)?, reg(1)?)
+            }
+            "neg" => {
+                arity(2)?;
+                Instruction::Neg(reg(0)?, reg(1)?)
+            }
+            "abs" => {
+                arity(2)?;
+                Instruction::Abs(reg(0)?, reg(1)?)
+            }
+            "popcnt" => {
+                arity(2)?;
+                Instruction::Popcount(reg(0)?, reg(1)?)
+            }
+            "clz" => {
+                arity(2)?;
+                Instruction::Clz(reg(0)?, reg(1)?)
+            }
+            "ctz" => {
+                arity(2)?;
+                Instruction::Ctz(reg(0)?, reg(1)?)
+            }
+            "cmp" => {
+                arity(2)?;
+                Instruction::Cmp(reg(0)?, reg(1)?)
+            }
+            "cmpi" => {
+                arity(2)?;
+                Instruction::CmpImm(reg(0)?, immediate(operands[1])?)
+            }
+            "jz" => {
+                arity(1)?;
+                Instruction::Jz(self.jump_target(operands[0])?)
+            }
+            "jnz" => {
+                arity(1)?;
+                Instruction::Jnz(self.jump_target(operands[0])?)
+            }
+            "jc" => {
+                arity(1)?;
+                Instruction::Jc(self.jump_target(operands[0])?)
+            }
+            "jo" => {
+                arity(1)?;
+                Instruction::Jo(self.jump_target(operands[0])?)
+            }
+            "jlt" => {
+                arity(1)?;
+                Instruction::Jlt(self.jump_target(operands[0])?)
+            }
+            "jge" => {
+                arity(1)?;
+                Instruction::Jge(self.jump_target(operands[0])?)
+            }
+            "mulw" => {
+                arity(4)?;
+                Instruction::MulWide(reg(0)?, reg(1)?, reg(2)?, reg(3)?)
+            }
+            "sextb" => {
+                arity(2)?;
+                Instruction::SextByte(reg(0)?, reg(1)?)
+            }
+            "sexth" => {
+                arity(2)?;
+                Instruction::SextHalf(reg(0)?, reg(1)?)
+            }
+            "zextb" => {
+                arity(2)?;
+                Instruction::ZextByte(reg(0)?, reg(1)?)
+            }
+            "zexth" => {
+                arity(2)?;
+                Instruction::ZextHalf(reg(0)?, reg(1)?)
+            }
+            "trunc" => {
+                arity(3)?;
+                Instruction::Trunc(reg(0)?, number(operands[1])?, reg(2)?)
+            }
+            "ftrunc" => {
+                arity(2)?;
+                Instruction::FloatTrunc(reg(0)?, reg(1)?)
+            }
+            "fround" => {
+                arity(2)?;
+                Instruction::FloatRound(reg(0)?, reg(1)?)
+            }
+            "ffloor" => {
+                arity(2)?;
+                Instruction::FloatFloor(reg(0)?, reg(1)?)
+            }
+            "fceil" => {
+                arity(2)?;
+                Instruction::FloatCeil(reg(0)?, reg(1)?)
+            }
+            "vload" => {
+                arity(3)?;
+                Instruction::VLoad(reg(0)?, reg(1)?, vreg(2)?)
+            }
+            "vstore" => {
+                arity(3)?;
+                Instruction::VStore(reg(0)?, reg(1)?, vreg(2)?)
+            }
+            "vadd" => {
+                arity(3)?;
+                Instruction::VAdd4x32(vreg(0)?, vreg(1)?, vreg(2)?)
+            }
+            "vmul" => {
+                arity(3)?;
+                Instruction::VMul4x32(vreg(0)?, vreg(1)?, vreg(2)?)
+            }
+            "vextract" => {
+                arity(3)?;
+                Instruction::VExtract(vreg(0)?, number(operands[1])?, reg(2)?)
+            }
+            "vinsert" => {
+                arity(3)?;
+                Instruction::VInsert(reg(0)?, number(operands[1])?, vreg(2)?)
+            }
+            "vsplat" => {
+                arity(2)?;
+                Instruction::VSplat(reg(0)?, vreg(1)?)
+            }
+            "memcpyr" => {
+                arity(3)?;
+                Instruction::MemCopyReg(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "memfillr" => {
+                arity(3)?;
+                Instruction::MemFillReg(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "realloc" => {
+                arity(3)?;
+                Instruction::ReallocMemory(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "protect" => {
+                arity(2)?;
+                Instruction::ProtectMemory(reg(0)?, number(operands[1])?)
+            }
+            "loadg" => {
+                arity(2)?;
+                Instruction::LoadGlobal(self.global(operands[0])?, reg(1)?)
+            }
+            "storeg" => {
+                arity(2)?;
+                Instruction::StoreGlobal(reg(0)?, self.global(operands[1])?)
+            }
+            "pushsp" => {
+                arity(1)?;
+                Instruction::PushSp(reg(0)?)
+            }
+            "popsp" => {
+                arity(1)?;
+                Instruction::PopSp(reg(0)?)
+            }
+            "ldsp" => {
+                arity(2)?;
+                Instruction::LoadSpOffset(num(0)?, reg(1)?)
+            }
+            "stsp" => {
+                arity(2)?;
+                Instruction::StoreSpOffset(reg(0)?, num(1)?)
+            }
+            "newobj" => {
+                arity(2)?;
+                Instruction::NewObject(num(0)?, reg(1)?)
+            }
+            "ldslot" => {
+                arity(3)?;
+                Instruction::LoadSlot(reg(0)?, num(1)?, reg(2)?)
+            }
+            "stslot" => {
+                arity(3)?;
+                Instruction::StoreSlot(reg(0)?, reg(1)?, num(2)?)
+            }
+            "retain" => {
+                arity(1)?;
+                Instruction::Retain(reg(0)?)
+            }
+            "release" => {
+                arity(1)?;
+                Instruction::Release(reg(0)?)
+            }
+            "newarr" => {
+                arity(2)?;
+                Instruction::NewArray(reg(0)?, reg(1)?)
+            }
+            "aget" => {
+                arity(3)?;
+                Instruction::ArrayGet(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "aset" => {
+                arity(3)?;
+                Instruction::ArraySet(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "alen" => {
+                arity(2)?;
+                Instruction::ArrayLen(reg(0)?, reg(1)?)
+            }
+            "newstr" => {
+                arity(3)?;
+                Instruction::NewString(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "strcat" => {
+                arity(3)?;
+                Instruction::StrConcat(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "strlen" => {
+                arity(2)?;
+                Instruction::StrLen(reg(0)?, reg(1)?)
+            }
+            "strbyte" => {
+                arity(3)?;
+                Instruction::StrByte(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "strcmp" => {
+                arity(3)?;
+                Instruction::StrCmp(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "strprint" => {
+                arity(1)?;
+                Instruction::PrintString(reg(0)?)
+            }
+            "mapnew" => {
+                arity(1)?;
+                Instruction::MapNew(reg(0)?)
+            }
+            "mapget" => {
+                arity(3)?;
+                Instruction::MapGet(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "mapset" => {
+                arity(3)?;
+                Instruction::MapSet(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "mapdel" => {
+                arity(2)?;
+                Instruction::MapDel(reg(0)?, reg(1)?)
+            }
+            "maphas" => {
+                arity(3)?;
+                Instruction::MapHas(reg(0)?, reg(1)?, reg(2)?)
+            }
+            "typeof" => {
+                arity(2)?;
+                Instruction::TypeOf(reg(0)?, reg(1)?)
+            }
+            "try" => {
+                arity(1)?;
+                Instruction::TryBegin(self.jump_target(operands[0])?)
+            }
+            "tryend" => {
+                arity(0)?;
+                Instruction::TryEnd
+            }
+            "throw" => {
+                arity(1)?;
+                Instruction::Throw(reg(0)?)
+            }
+            _ => return Err(AsmErrorKind::UnknownMnemonic(mnemonic.to_string())),
+        };
+        Ok(instruction)
+    }
+
+    // A load or store with a struct field in place of its offset register. The
+    // lowest register the access doesn't name holds the offset, pushed onto the
+    // data stack before and popped back after.
+    fn field_access(
+        &self,
+        mnemonic: &str,
+        operands: &[&str],
+    ) -> Result<Vec<Instruction>, AsmErrorKind> {
+        let offset = self.value(operands[1])?;
+        let named = [operands[0], operands[2]].map(|operand| register(operand).ok());
+        let temporary = (0..).find(|index| !named.contains(&Some(*index))).unwrap();
+        let temporary_name = format!("r{}", temporary);
+        let access = self.instruction(mnemonic, &[operands[0], &temporary_name, operands[2]])?;
+        Ok(vec![
+            Instruction::Push(temporary),
+            Instruction::load_immediate(temporary, offset),
+            access,
+            Instruction::Pop(temporary),
+        ])
+    }
+
+    // The value of `set`: an immediate, the memory address of a data label, the
+    // instruction address of a code label, or the size of a struct or offset of
+    // one of its fields.
+    fn value(&self, operand: &str) -> Result<i32, AsmErrorKind> {
+        let undefined = || AsmErrorKind::UndefinedLabel(operand.to_string());
+        let value = if let Some((name, field)) = field_name(operand) {
+            let layout = self.structs.get(name).ok_or_else(undefined)?;
+            layout
+                .fields
+                .iter()
+                .find(|&&(other, _)| other == field)
+                .ok_or_else(undefined)?
+                .1
+        } else if !is_identifier(operand) {
+            return immediate(operand);
+        } else if let Some(layout) = self.structs.get(operand) {
+            layout.size
+        } else {
+            match self.data_labels.get(operand) {
+                Some(&address) => address,
+                None => self.label(operand)?,
+            }
+        };
+        i32::try_from(value).map_err(|_| AsmErrorKind::InvalidNumber(operand.to_string()))
+    }
+
+    // The operand of `loadg` and `storeg`: a declared global, or its index.
+    fn global(&self, operand: &str) -> Result<usize, AsmErrorKind> {
+        if !is_identifier(operand) {
+            return number(operand);
+        }
+        self.globals
+            .get(operand)
+            .copied()
+            .ok_or_else(|| AsmErrorKind::UndefinedLabel(operand.to_string()))
+    }
+
+    // A call target: a label, or a raw absolute address.
+    fn call_target(&self, operand: &str) -> Result<usize, AsmErrorKind> {
+        if !is_identifier(operand) {
+            return number(operand);
+        }
+        self.label(operand)
+    }
+
+    // The targets of `jmptbl`, written `[a, b, c]` and split at the commas
+    // like any other operands.
+    fn jump_table(&self, operands: &[&str]) -> Result<Vec<isize>, AsmErrorKind> {
+        let list = operands.join(", ");
+        let targets = list
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+            .ok_or_else(|| AsmErrorKind::InvalidTable(list.clone()))?;
+        if targets.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+        targets
+            .split(',')
+            .map(|target| self.jump_target(target.trim()))
+            .collect()
+    }
+
+    // A jump target: a label, or a raw offset relative to the next instruction
+    // (which is what the jump operand holds).
+    fn jump_target(&self, operand: &str) -> Result<isize, AsmErrorKind> {
+        if !is_identifier(operand) {
+            return number(operand);
+        }
+        Ok(self.label(operand)? as isize - (self.address + 1) as isize)
+    }
+
+    fn label(&self, name: &str) -> Result<usize, AsmErrorKind> {
+        self.labels
+            .get(name)
+            .copied()
+            .ok_or_else(|| AsmErrorKind::UndefinedLabel(name.to_string()))
+    }
+}
+
+// The instructions of the form `op a, b, target`.
+fn three_register_op(name: &str) -> Option<fn(usize, usize, usize) -> Instruction> {
+    let op = match name {
+        "add" => Instruction::Add,
+        "sub" => Instruction::Sub,
+        "mul" => Instruction::Mul,
+        "div" => Instruction::Div,
+        "mod" => Instruction::Mod,
+        "eq" => Instruction::Eq,
+        "neq" => Instruction::Neq,
+        "gt" => Instruction::Gt,
+        "lt" => Instruction::Lt,
+        "gte" => Instruction::Gte,
+        "lte" => Instruction::Lte,
+        "fadd" => Instruction::FAdd,
+        "fsub" => Instruction::FSub,
+        "fmul" => Instruction::FMul,
+        "fdiv" => Instruction::FDiv,
+        "rol" => Instruction::Rol,
+        "ror" => Instruction::Ror,
+        "and" => Instruction::And,
+        "or" => Instruction::Or,
+        "xor" => Instruction::Xor,
+        "shl" => Instruction::Shl,
+        "shr" => Instruction::Shr,
+        "sar" => Instruction::Sar,
+        "fcmp" => Instruction::FCmp,
+        "min" => Instruction::Min,
+        "max" => Instruction::Max,
+        "adc" => Instruction::Adc,
+        "sbb" => Instruction::Sbb,
+        "divu" => Instruction::DivU,
+        "modu" => Instruction::ModU,
+        "ltu" => Instruction::LtU,
+        "gtu" => Instruction::GtU,
+        "lteu" => Instruction::LteU,
+        "gteu" => Instruction::GteU,
+        _ => return None,
+    };
+    Some(op)
+}
+
+// `r<N>`, or a calling-convention name: `a0`..`a3` for the arguments, `rv` for
+// the return value and `s0`..`s3` for the callee-saved registers.
+fn register(operand: &str) -> Result<usize, AsmErrorKind> {
+    let name = operand.to_ascii_lowercase();
+    let register = if name == "rv" {
+        Some(RETURN_REGISTER)
+    } else if let Some(index) = name.strip_prefix('r') {
+        index.parse().ok()
+    } else if let Some(index) = name.strip_prefix('a') {
+        index
+            .parse()
+            .ok()
+            .and_then(|index| ARGUMENT_REGISTERS.clone().nth(index))
+    } else if let Some(index) = name.strip_prefix('s') {
+        index
+            .parse()
+            .ok()
+            .and_then(|index| CALLEE_SAVED_REGISTERS.clone().nth(index))
+    } else {
+        None
+    };
+    register.ok_or_else(|| AsmErrorKind::InvalidRegister(operand.to_string()))
+}
+
+fn vector_register(operand: &str) -> Result<usize, AsmErrorKind> {
+    operand
+        .to_ascii_lowercase()
+        .strip_prefix('v')
+        .and_then(|index| index.parse().ok())
+        .ok_or_else(|| AsmErrorKind::InvalidRegister(operand.to_string()))
+}
+
+// Decimal, or hexadecimal with a `0x` prefix.
+fn number<T: TryFrom<i64>>(operand: &str) -> Result<T, AsmErrorKind> {
+    let (negative, digits) = match operand.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, operand),
+    };
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse::<i64>(),
+    };
+    value
+        .ok()
+        .map(|value| if negative { -value } else { value })
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or_else(|| AsmErrorKind::InvalidNumber(operand.to_string()))
+}
+
+// An `i32` immediate. Values up to `u32::MAX` are accepted as bit patterns, so
+// `0xFFFFFFFF` is the same as `-1`.
+fn immediate(operand: &str) -> Result<i32, AsmErrorKind> {
+    let value: i64 = number(operand)?;
+    if value > u32::MAX as i64 || value < i32::MIN as i64 {
+        return Err(AsmErrorKind::InvalidNumber(operand.to_string()));
+    }
+    Ok(value as u32 as i32)
+}
+
+// A float literal such as `1.5`, `-2e10`, `inf` or `NaN`.
+fn float(operand: &str) -> Result<f64, AsmErrorKind> {
+    operand
+        .parse()
+        .map_err(|_| AsmErrorKind::InvalidNumber(operand.to_string()))
+}
+
+// A byte operand, written signed (-128..=127) or unsigned (0..=255).
+fn byte(operand: &str) -> Result<u8, AsmErrorKind> {
+    let value = immediate(operand)?;
+    i8::try_from(value)
+        .map(|byte| byte as u8)
+        .or_else(|_| u8::try_from(value))
+        .map_err(|_| AsmErrorKind::InvalidNumber(operand.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_program() {
+        let program = assemble(
+            "
+            ; count down from 3
+                set r0, 3
+                set r1, 1
+            loop:
+                jmpz r0, done      ; leave once r0 hits zero
+                sub r0, r1, r0
+                print r0
+                jmp loop
+            done: halt
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(program, vec![
+            Instruction::SetRegByte(0, 3),
+            Instruction::SetRegByte(1, 1),
+            Instruction::JumpIfZero(0, 3),
+            Instruction::Sub(0, 1, 0),
+            Instruction::Print(0),
+            Instruction::Jump(-4),
+            Instruction::Halt,
+        ]);
+
+        // Call targets are absolute, in either direction
+        let program = assemble("f: ret\ncall f\ncall g\ng: ret").unwrap();
+        assert_eq!(program, vec![
+            Instruction::Return,
+            Instruction::Call(0),
+            Instruction::Call(3),
+            Instruction::Return,
+        ]);
+
+        // Each instruction's source line, skipping labels, directives and data
+        let (program, lines) =
+            assemble_with_lines("\nset r0, 1 ; one\nloop:\n\njmp loop\n.data\n.byte 1\n.text\nhalt")
+                .unwrap();
+        assert_eq!(program.instructions.len(), 3);
+        assert_eq!(lines, vec![2, 5, 9]);
+    }
+
+    #[test]
+    fn test_assemble_operands() {
+        let program = assemble(
+            "set r2, 0x12345678
+             set r3, -1000
+             setb r4, 255
+             cas 0, 4, r1, r2, r3
+             call 0
+             jmp -2
+             addi r0, -1, r0
+             asserteq r1, 0xFFFFFFFF
+             setf r5, -2.5e3
+             setf r6, -inf
+             fcmp r5, r6, r7
+             add a1, s3, rv",
+        )
+        .unwrap();
+
+        assert_eq!(program, vec![
+            Instruction::SetReg(2, 0x12345678),
+            Instruction::SetReg(3, -1000),
+            Instruction::SetRegByte(4, 255),
+            Instruction::Cas(0, 4, 1, 2, 3),
+            Instruction::Call(0),
+            Instruction::Jump(-2),
+            Instruction::AddImm(0, -1, 0),
+            Instruction::AssertEq(1, -1),
+            Instruction::SetFReg(5, -2500.0),
+            Instruction::SetFReg(6, f64::NEG_INFINITY),
+            Instruction::FCmp(5, 6, 7),
+            Instruction::Add(1, 7, 0),
+        ]);
+        // NaN never compares equal to itself, so match on it instead
+        assert!(matches!(assemble("setf r0, NaN").unwrap()[..], [Instruction::SetFReg(0, value)] if value.is_nan()));
+    }
+
+    #[test]
+    fn test_assemble_data_section() {
+        let program = assemble_program(
+            r#"
+            .data
+            greeting: .string "hi; there\n"   ; not a comment inside the quotes
+            bytes: .byte 1, -1, 0x7F
+            .text
+                set r0, greeting
+                set r1, bytes      ; the greeting ends where `bytes` starts
+                prints r0, r1
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(program.data, b"hi; there\n\x01\xFF\x7F");
+        assert_eq!(program.instructions, vec![
+            Instruction::SetRegByte(0, 0),
+            Instruction::SetRegByte(1, 10),
+            Instruction::PrintStr(0, 1),
+        ]);
+
+        let error = |source: &str| assemble_program(source).unwrap_err().kind;
+        assert_eq!(error(".string \"x\""), AsmErrorKind::WrongSection(".string".to_string()));
+        assert_eq!(error(".data\nhalt"), AsmErrorKind::WrongSection("halt".to_string()));
+        assert_eq!(error(".data\n.string \"a\\q\""), AsmErrorKind::InvalidString("\"a\\q\"".to_string()));
+        assert_eq!(error(".data\n.string x"), AsmErrorKind::InvalidString("x".to_string()));
+        assert_eq!(error(".data\na: .byte 1\n.text\na: halt"), AsmErrorKind::DuplicateLabel("a".to_string()));
+        assert_eq!(error("set r0, missing"), AsmErrorKind::UndefinedLabel("missing".to_string()));
+        assert_eq!(error(".rodata"), AsmErrorKind::UnknownMnemonic(".rodata".to_string()));
+    }
+
+    #[test]
+    fn test_assemble_globals() {
+        let program = assemble_program(
+            "
+            .global counter: i32 = -3
+            .global scale: f64 = 0.5
+            .global total: i32     ; starts out as zero
+            .global big: i64 = 0x100000000
+            .global nothing: nil
+                loadg counter, r0
+                storeg r0, total
+                loadg 1, r1
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(program.globals, [
+            Value::Int(-3),
+            Value::Float(0.5),
+            Value::Int(0),
+            Value::Int64(1 << 32),
+            Value::Nil,
+        ]);
+        assert_eq!(program.instructions, [
+            Instruction::LoadGlobal(0, 0),
+            Instruction::StoreGlobal(0, 2),
+            Instruction::LoadGlobal(1, 1),
+        ]);
+
+        let error = |source: &str| assemble_program(source).unwrap_err().kind;
+        assert_eq!(error(".global x: u8"), AsmErrorKind::InvalidGlobal("x: u8".to_string()));
+        assert_eq!(error(".global x = 1"), AsmErrorKind::InvalidGlobal("x = 1".to_string()));
+        assert_eq!(error(".global x: nil = 0"), AsmErrorKind::InvalidGlobal("x: nil = 0".to_string()));
+        assert_eq!(error(".global 1x: i32"), AsmErrorKind::InvalidLabel("1x".to_string()));
+        assert_eq!(error(".global x: i32 = y"), AsmErrorKind::InvalidNumber("y".to_string()));
+        assert_eq!(error(".global x: i32\nx: halt"), AsmErrorKind::DuplicateLabel("x".to_string()));
+        assert_eq!(error(".global x: i32\n.global x: f64"), AsmErrorKind::DuplicateLabel("x".to_string()));
+        assert_eq!(error("loadg y, r0"), AsmErrorKind::UndefinedLabel("y".to_string()));
+    }
+
+    #[test]
+    fn test_assemble_structs() {
+        let (program, lines) = assemble_with_lines(
+            "
+            .struct Point { x: 4, y: 4, tag: 1 }
+                set r0, Point       ; 9 bytes
+                alloc r0, r1
+                set r2, 0x12345678
+                st32 r1, Point.y, r2
+                ld8u r1, Point.tag, r0
+            done: jmp done
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(program.instructions, vec![
+            Instruction::SetRegByte(0, 9),
+            Instruction::AllocateMemory(0, 1),
+            Instruction::SetReg(2, 0x12345678),
+            Instruction::Push(0),           // The lowest register the store doesn't name
+            Instruction::SetRegByte(0, 4),
+            Instruction::Store32(1, 0, 2),
+            Instruction::Pop(0),
+            Instruction::Push(2),
+            Instruction::SetRegByte(2, 8),
+            Instruction::Load8U(1, 2, 0),
+            Instruction::Pop(2),
+            Instruction::Jump(-1),          // Labels count the lowered instructions
+        ]);
+        assert_eq!(lines, vec![3, 4, 5, 6, 6, 6, 6, 7, 7, 7, 7, 8]);
+
+        let error = |source: &str| assemble_program(source).unwrap_err().kind;
+        assert_eq!(error(".struct P"), AsmErrorKind::InvalidStruct("P".to_string()));
+        assert_eq!(error(".struct P { x }"), AsmErrorKind::InvalidStruct("P { x }".to_string()));
+        assert_eq!(error(".struct P { x: y }"), AsmErrorKind::InvalidNumber("y".to_string()));
+        assert_eq!(error(".struct P { x: 1, x: 2 }"), AsmErrorKind::DuplicateLabel("P.x".to_string()));
+        assert_eq!(error(".struct P { }
+P: halt"), AsmErrorKind::DuplicateLabel("P".to_string()));
+        assert_eq!(error(".struct P { x: 1 }
+set r0, P.y"), AsmErrorKind::UndefinedLabel("P.y".to_string()));
+        assert_eq!(error("ld32 r0, Q.x, r1"), AsmErrorKind::UndefinedLabel("Q.x".to_string()));
+    }
+
+    #[test]
+    fn test_assemble_function_table() {
+        let program = assemble_program(
+            "
+            .functions double, square
+                set r0, 1
+                callt r0        ; square
+                set r1, double
+                callr r1
+                halt
+            double: add r0, r0, r0
+                ret
+            square: mul r0, r0, r0
+                ret
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(program.functions, vec![5, 7]);
+        assert_eq!(program.instructions[..4], [
+            Instruction::SetRegByte(0, 1),
+            Instruction::CallTable(0),
+            Instruction::SetRegByte(1, 5),
+            Instruction::CallReg(1),
+        ]);
+
+        let error = |source: &str| assemble_program(source).unwrap_err().kind;
+        assert_eq!(error(".functions f"), AsmErrorKind::UndefinedLabel("f".to_string()));
+        assert_eq!(error(".functions 3"), AsmErrorKind::InvalidLabel("3".to_string()));
+        assert_eq!(error(".functions"), AsmErrorKind::OperandCount { expected: 1, found: 0 });
+    }
+
+    #[test]
+    fn test_assemble_symbols() {
+        let program = assemble_program(
+            "
+            .functions spare
+            main: call outer
+                halt
+            outer: call inner
+                ret
+            inner: inner_entry: div r0, r0, r1
+                ret
+            spare: ret
+            unused: ret
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            program.symbols,
+            BTreeMap::from([
+                (0, "main".to_string()),
+                (2, "outer".to_string()),
+                (4, "inner".to_string()),
+                (6, "spare".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_assemble_jump_table() {
+        let program = assemble(
+            "
+                jmptbl r0, [zero, one, zero], other
+            zero: halt
+            one: halt
+            other: jmptbl r1, [], 3
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(program, vec![
+            Instruction::JumpTable(0, vec![0, 1, 0], 2),
+            Instruction::Halt,
+            Instruction::Halt,
+            Instruction::JumpTable(1, vec![], 3),
+        ]);
+
+        let error = |source: &str| assemble(source).unwrap_err().kind;
+        assert_eq!(error("jmptbl r0, [a]"), AsmErrorKind::OperandCount { expected: 3, found: 2 });
+        assert_eq!(error("jmptbl r0, a, b, c"), AsmErrorKind::InvalidTable("a, b".to_string()));
+        assert_eq!(error("jmptbl r0, [a, b], c"), AsmErrorKind::UndefinedLabel("a".to_string()));
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error = |source: &str| assemble(source).unwrap_err();
+
+        assert_eq!(error("halt\nfrob r0"), AsmError {
+            line: 2,
+            kind: AsmErrorKind::UnknownMnemonic("frob".to_string()),
+        });
+        assert_eq!(error("add r0, r1").kind, AsmErrorKind::OperandCount { expected: 3, found: 2 });
+        assert_eq!(error("print x1").kind, AsmErrorKind::InvalidRegister("x1".to_string()));
+        assert_eq!(error("print a4").kind, AsmErrorKind::InvalidRegister("a4".to_string()));
+        assert_eq!(error("set r0, 1x").kind, AsmErrorKind::InvalidNumber("1x".to_string()));
+        assert_eq!(error("jmp nowhere").kind, AsmErrorKind::UndefinedLabel("nowhere".to_string()));
+        assert_eq!(error("a: halt\na: halt").kind, AsmErrorKind::DuplicateLabel("a".to_string()));
+        assert_eq!(error("halt\n\nprint r0, r1").to_string(), "line 3: wrong number of operands: expected 1, found 2");
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::isa::Instruction;
+
+/// An error found while resolving the labels of a `ProgramBuilder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    DuplicateLabel(String), // Label defined more than once
+    UndefinedLabel(String), // Jump or call to a label that is never defined
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::DuplicateLabel(label) => write!(f, "label `{}` is already defined", label),
+            BuildError::UndefinedLabel(label) => write!(f, "undefined label `{}`", label),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+// A jump or call whose offset is filled in by `build()`.
+enum Target {
+    Jump,
+    JumpIfZero(usize),
+    JumpIfNonZero(usize),
+    Call,
+}
+
+/// Builds a program whose jumps and calls refer to named labels instead of
+/// hand-counted offsets.
+///
+/// ```
+/// use basic_rust_vm::{Instruction, ProgramBuilder};
+///
+/// let mut builder = ProgramBuilder::new();
+/// builder
+///     .push(Instruction::SetReg(0, 0))
+///     .jump_if_zero_to(0, "done")
+///     .push(Instruction::SetReg(1, 1)) // Skipped
+///     .label("done")
+///     .push(Instruction::Halt);
+/// let program = builder.build().unwrap();
+/// assert_eq!(program[1], Instruction::JumpIfZero(0, 1));
+/// ```
+#[derive(Default)]
+pub struct ProgramBuilder {
+    instructions: Vec<Option<Instruction>>, // `None` until a jump or call is resolved
+    fixups: Vec<(usize, Target, String)>,   // Address, kind and label of each jump or call
+    labels: HashMap<String, usize>,
+    duplicate: Option<String>, // The first label defined twice, reported by `build()`
+}
+
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        ProgramBuilder::default()
+    }
+
+    /// Append an instruction.
+    pub fn push(&mut self, instruction: Instruction) -> &mut Self {
+        self.instructions.push(Some(instruction));
+        self
+    }
+
+    /// Name the address of the next instruction.
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        let address = self.instructions.len();
+        if self.labels.insert(name.to_string(), address).is_some() && self.duplicate.is_none() {
+            self.duplicate = Some(name.to_string());
+        }
+        self
+    }
+
+    pub fn jump_to(&mut self, label: &str) -> &mut Self {
+        self.fixup(Target::Jump, label)
+    }
+
+    pub fn jump_if_zero_to(&mut self, register: usize, label: &str) -> &mut Self {
+        self.fixup(Target::JumpIfZero(register), label)
+    }
+
+    pub fn jump_if_non_zero_to(&mut self, register: usize, label: &str) -> &mut Self {
+        self.fixup(Target::JumpIfNonZero(register), label)
+    }
+
+    pub fn call(&mut self, label: &str) -> &mut Self {
+        self.fixup(Target::Call, label)
+    }
+
+    fn fixup(&mut self, target: Target, label: &str) -> &mut Self {
+        self.fixups
+            .push((self.instructions.len(), target, label.to_string()));
+        self.instructions.push(None);
+        self
+    }
+
+    /// Resolve every label: calls get its absolute address, jumps an offset
+    /// relative to the instruction after the jump.
+    pub fn build(&self) -> Result<Vec<Instruction>, BuildError> {
+        if let Some(label) = &self.duplicate {
+            return Err(BuildError::DuplicateLabel(label.clone()));
+        }
+        let mut instructions = self.instructions.clone();
+        for (address, target, label) in &self.fixups {
+            let destination = *self
+                .labels
+                .get(label)
+                .ok_or_else(|| BuildError::UndefinedLabel(label.clone()))?;
+            let offset = destination as isize - (address + 1) as isize;
+            instructions[*address] = Some(match target {
+                Target::Jump => Instruction::Jump(offset),
+                Target::JumpIfZero(register) => Instruction::JumpIfZero(*register, offset),
+                Target::JumpIfNonZero(register) => Instruction::JumpIfNonZero(*register, offset),
+                Target::Call => Instruction::Call(destination),
+            });
+        }
+        Ok(instructions.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels_resolve_to_targets() {
+        let mut builder = ProgramBuilder::new();
+        builder
+            .call("function")                 // 0
+            .jump_if_non_zero_to(4, "end")    // 1
+            .jump_to("end")                   // 2
+            .label("function")
+            .push(Instruction::SetReg(4, 1))   // 3
+            .push(Instruction::Return)         // 4
+            .label("end")
+            .jump_to("end");                  // 5
+
+        assert_eq!(
+            builder.build(),
+            Ok(vec![
+                Instruction::Call(3),
+                Instruction::JumpIfNonZero(4, 3),
+                Instruction::Jump(2),
+                Instruction::SetReg(4, 1),
+                Instruction::Return,
+                Instruction::Jump(-1),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let mut builder = ProgramBuilder::new();
+        builder.jump_to("nowhere");
+        assert_eq!(builder.build(), Err(BuildError::UndefinedLabel("nowhere".to_string())));
+
+        let mut builder = ProgramBuilder::new();
+        builder.label("a").label("a");
+        assert_eq!(builder.build(), Err(BuildError::DuplicateLabel("a".to_string())));
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt;
+
+use crate::error::VmError;
+use crate::value::Value;
+use crate::vm::VM;
+
+/// An error found while parsing a `Condition`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConditionError {
+    UnexpectedCharacter(char), // A character no token starts with
+    UnexpectedToken(String),   // A token where an operand or operator was expected
+    UnexpectedEnd,             // The text ends in the middle of an expression
+    InvalidNumber(String),     // Digits that don't make a number in range
+    UnknownName(String),       // A name that is neither `rN` nor `mem`
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConditionError::UnexpectedCharacter(c) => write!(f, "unexpected character `{}`", c),
+            ConditionError::UnexpectedToken(token) => write!(f, "unexpected `{}`", token),
+            ConditionError::UnexpectedEnd => write!(f, "unexpected end of the condition"),
+            ConditionError::InvalidNumber(text) => write!(f, "`{}` is not a valid number", text),
+            ConditionError::UnknownName(name) => write!(f, "unknown name `{}`", name),
+        }
+    }
+}
+
+impl std::error::Error for ConditionError {}
+
+/// A breakpoint condition such as `r2 == 42 && r0 > 10`, evaluated against the
+/// VM whenever execution reaches the breakpoint.
+///
+/// Operands are integer registers (`r0`), decimal or `0x` hexadecimal numbers
+/// and memory bytes (`mem[r1 + 4]`). The operators are those of Rust with their
+/// precedence: `!` and unary `-`, `*` `/` `%`, `+` `-`, the comparisons, `&&`
+/// and `||`. Arithmetic is done in 64 bits, comparisons give 1 or 0 and any
+/// value other than 0 is true.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    source: String,
+    expression: Expression,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expression {
+    Number(i64),
+    Register(usize),
+    Memory(Box<Expression>), // The byte at the address
+    Not(Box<Expression>),
+    Negate(Box<Expression>),
+    Binary(&'static str, Box<Expression>, Box<Expression>),
+}
+
+// Binary operators by precedence, loosest first.
+const OPERATORS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, ConditionError> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            position: 0,
+        };
+        let expression = parser.expression(0)?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            return Err(ConditionError::UnexpectedToken(token.to_string()));
+        }
+        Ok(Condition {
+            source: text.trim().to_string(),
+            expression,
+        })
+    }
+
+    /// Whether the condition holds in the VM's current state. Reading a float
+    /// register or unallocated memory and dividing by zero are errors.
+    pub fn holds(&self, vm: &VM) -> Result<bool, VmError> {
+        evaluate(&self.expression, vm).map(|value| value != 0)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn evaluate(expression: &Expression, vm: &VM) -> Result<i64, VmError> {
+    Ok(match expression {
+        Expression::Number(value) => *value,
+        Expression::Register(index) => match vm.registers().get(*index) {
+            Some(Value::Int(value)) => i64::from(*value),
+            Some(Value::Int64(value)) => *value,
+            Some(Value::Float(_) | Value::Ref(_) | Value::Nil) => {
+                return Err(VmError::TypeMismatch(*index))
+            }
+            None => return Err(VmError::InvalidRegister(*index)),
+        },
+        Expression::Memory(address) => {
+            // A negative address wraps around to one that is never allocated.
+            let address = evaluate(address, vm)? as usize;
+            i64::from(vm.memory().borrow().bytes(address, 1)?[0])
+        }
+        Expression::Not(operand) => i64::from(evaluate(operand, vm)? == 0),
+        Expression::Negate(operand) => evaluate(operand, vm)?.wrapping_neg(),
+        // `&&` and `||` only evaluate their right side when it matters.
+        Expression::Binary("&&", left, right) => {
+            i64::from(evaluate(left, vm)? != 0 && evaluate(right, vm)? != 0)
+        }
+        Expression::Binary("||", left, right) => {
+            i64::from(evaluate(left, vm)? != 0 || evaluate(right, vm)? != 0)
+        }
+        Expression::Binary(operator, left, right) => {
+            let (a, b) = (evaluate(left, vm)?, evaluate(right, vm)?);
+            match *operator {
+                "==" => i64::from(a == b),
+                "!=" => i64::from(a != b),
+                "<" => i64::from(a < b),
+                "<=" => i64::from(a <= b),
+                ">" => i64::from(a > b),
+                ">=" => i64::from(a >= b),
+                "+" => a.wrapping_add(b),
+                "-" => a.wrapping_sub(b),
+                "*" => a.wrapping_mul(b),
+                "/" | "%" if b == 0 => return Err(VmError::DivisionByZero),
+                "/" => a.wrapping_div(b),
+                _ => a.wrapping_rem(b),
+            }
+        }
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Register(usize),
+    Memory,               // `mem`
+    Symbol(&'static str), // An operator or bracket
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Register(index) => write!(f, "r{}", index),
+            Token::Memory => write!(f, "mem"),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+// Two-character symbols come first, so `<=` isn't read as `<`.
+const SYMBOLS: &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")", "[", "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ConditionError> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            tokens.push(word_token(word)?);
+            rest = &rest[end..];
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else {
+            return Err(ConditionError::UnexpectedCharacter(c));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn word_token(word: &str) -> Result<Token, ConditionError> {
+    if word.starts_with(|c: char| c.is_ascii_digit()) {
+        let value = match word.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None => word.parse(),
+        };
+        return value
+            .map(Token::Number)
+            .map_err(|_| ConditionError::InvalidNumber(word.to_string()));
+    }
+    if word == "mem" {
+        return Ok(Token::Memory);
+    }
+    word.strip_prefix('r')
+        .filter(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|index| index.parse().ok())
+        .map(Token::Register)
+        .ok_or_else(|| ConditionError::UnknownName(word.to_string()))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Result<Token, ConditionError> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or(ConditionError::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), ConditionError> {
+        match self.next()? {
+            Token::Symbol(found) if found == symbol => Ok(()),
+            token => Err(ConditionError::UnexpectedToken(token.to_string())),
+        }
+    }
+
+    // Binary operators at `level` of `OPERATORS` or tighter.
+    fn expression(&mut self, level: usize) -> Result<Expression, ConditionError> {
+        if level == OPERATORS.len() {
+            return self.unary();
+        }
+        let mut left = self.expression(level + 1)?;
+        while let Some(Token::Symbol(symbol)) = self.tokens.get(self.position) {
+            let Some(operator) = OPERATORS[level].iter().find(|operator| *operator == symbol)
+            else {
+                break;
+            };
+            self.position += 1;
+            let right = self.expression(level + 1)?;
+            left = Expression::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, ConditionError> {
+        match self.next()? {
+            Token::Number(value) => Ok(Expression::Number(value)),
+            Token::Register(index) => Ok(Expression::Register(index)),
+            Token::Memory => {
+                self.expect("[")?;
+                let address = self.expression(0)?;
+                self.expect("]")?;
+                Ok(Expression::Memory(Box::new(address)))
+            }
+            Token::Symbol("!") => Ok(Expression::Not(Box::new(self.unary()?))),
+            Token::Symbol("-") => Ok(Expression::Negate(Box::new(self.unary()?))),
+            Token::Symbol("(") => {
+                let expression = self.expression(0)?;
+                self.expect(")")?;
+                Ok(expression)
+            }
+            token => Err(ConditionError::UnexpectedToken(token.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::isa::Instruction;
+
+    use super::*;
+
+    #[test]
+    fn test_conditions() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(1, 4),
+            Instruction::AllocateMemory(1, 1), // Address 0 into r1
+        ]);
+        vm.run().unwrap();
+        vm.registers_mut()[0] = Value::Int(11);
+        vm.registers_mut()[2] = Value::Int(42);
+        vm.registers_mut()[3] = Value::Float(1.5);
+
+        let holds = |text: &str| Condition::parse(text).unwrap().holds(&vm);
+        assert_eq!(holds("r2 == 42 && r0 > 10"), Ok(true));
+        assert_eq!(holds("r2 == 42 && r0 > 11"), Ok(false));
+        assert_eq!(holds("r0 * 2 + 1 == 23 || r9 == 0"), Ok(true)); // Short-circuits
+        assert_eq!(holds("-(r0 - 1) % 3 == -1"), Ok(true));
+        assert_eq!(holds("!(r1) && mem[r1 + 3] == 0 && 0x2a == r2"), Ok(true));
+        assert_eq!(holds("r2 <= 42 != 0"), Ok(true));
+        assert_eq!(holds("r3 == 1"), Err(VmError::TypeMismatch(3)));
+        assert_eq!(holds("r9"), Err(VmError::InvalidRegister(9)));
+        assert_eq!(holds("mem[4]"), Err(VmError::NoMemoryRegion(4)));
+        assert_eq!(holds("r0 / r1"), Err(VmError::DivisionByZero));
+
+        let error = |text: &str| Condition::parse(text).unwrap_err();
+        assert_eq!(error("r0 =="), ConditionError::UnexpectedEnd);
+        assert_eq!(error("r0 = 1"), ConditionError::UnexpectedCharacter('='));
+        assert_eq!(error("(r0"), ConditionError::UnexpectedEnd);
+        assert_eq!(error("r0 r1"), ConditionError::UnexpectedToken("r1".to_string()));
+        assert_eq!(error("x > 1"), ConditionError::UnknownName("x".to_string()));
+        assert_eq!(error("0xfg"), ConditionError::InvalidNumber("0xfg".to_string()));
+        assert_eq!(Condition::parse(" r0 > 1 ").unwrap().to_string(), "r0 > 1");
+    }
+}
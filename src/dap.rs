@@ -0,0 +1,485 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value as Json};
+
+use crate::asm::assemble_with_lines;
+use crate::debugger::{Debugger, SharedOutput, Stop};
+use crate::error::VmError;
+use crate::program::Program;
+use crate::vm::{VmState, VM};
+
+// The only thread a VM has.
+const THREAD_ID: i64 = 1;
+
+// `variablesReference`s of the two scopes every frame shows.
+const REGISTERS_REFERENCE: i64 = 1;
+const MEMORY_REFERENCE: i64 = 2;
+
+// Bytes of a memory region shown in its variable's value.
+const MEMORY_PREVIEW: usize = 64;
+
+/// Serve a Debug Adapter Protocol session, reading requests from `input` and
+/// writing responses and events to `output`, so editors like VS Code can debug
+/// assembly programs. Returns when the client disconnects or `input` ends.
+///
+/// `launch` takes the path of a `.vmasm` file in `program` and optionally
+/// `stopOnEntry`. Breakpoints are set on source lines and land on the first
+/// instruction at or after the line. Frames show the registers and the memory
+/// regions as variables, and the program's output is sent as `output` events.
+/// The program reads no input, as stdin is the protocol's.
+pub fn serve_dap(mut input: impl BufRead, output: impl Write) -> io::Result<()> {
+    let mut client = Client { output, seq: 0 };
+    // Until `launch` there is no program to debug.
+    let launch = loop {
+        let Some(request) = read_message(&mut input)? else {
+            return Ok(());
+        };
+        match command(&request) {
+            "initialize" => client.respond(
+                &request,
+                json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsStepBack": true,
+                }),
+            )?,
+            "launch" => match Launch::new(&request["arguments"]) {
+                Ok(launch) => {
+                    client.respond(&request, json!({}))?;
+                    client.event("initialized", json!({}))?;
+                    break launch;
+                }
+                Err(message) => client.fail(&request, &message)?,
+            },
+            "disconnect" => return client.respond(&request, json!({})),
+            _ => client.fail(&request, "no program has been launched")?,
+        }
+    };
+
+    let mut vm = VM::from_program(launch.program);
+    let program_output = SharedOutput::default();
+    vm.set_output(program_output.clone());
+    vm.set_input(io::empty());
+    let mut session = Session {
+        debugger: Debugger::attach(&mut vm),
+        client,
+        path: launch.path,
+        lines: launch.lines,
+        stop_on_entry: launch.stop_on_entry,
+        program_output,
+    };
+    session.run(input)
+}
+
+// The arguments of `launch`, with the program they name assembled.
+struct Launch {
+    path: String,
+    program: Program,
+    lines: Vec<usize>, // Source line of each instruction
+    stop_on_entry: bool,
+}
+
+impl Launch {
+    fn new(arguments: &Json) -> Result<Self, String> {
+        let path = arguments["program"]
+            .as_str()
+            .ok_or("`launch` needs the path of the program in `program`")?;
+        let source =
+            fs::read_to_string(path).map_err(|error| format!("cannot read {}: {}", path, error))?;
+        let (program, lines) =
+            assemble_with_lines(&source).map_err(|error| format!("{}: {}", path, error))?;
+        Ok(Launch {
+            path: path.to_string(),
+            program,
+            lines,
+            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+        })
+    }
+}
+
+// Writes numbered messages to the client.
+struct Client<W> {
+    output: W,
+    seq: i64,
+}
+
+impl<W: Write> Client<W> {
+    fn send(&mut self, mut message: Json) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+}
+
+struct Session<'a, W> {
+    debugger: Debugger<'a>,
+    client: Client<W>,
+    path: String,
+    lines: Vec<usize>,
+    stop_on_entry: bool,
+    program_output: SharedOutput,
+}
+
+impl<W: Write> Session<'_, W> {
+    fn run(&mut self, mut input: impl BufRead) -> io::Result<()> {
+        while let Some(request) = read_message(&mut input)? {
+            match command(&request) {
+                "setBreakpoints" => {
+                    let breakpoints = self.set_breakpoints(&request["arguments"]["breakpoints"]);
+                    self.client
+                        .respond(&request, json!({ "breakpoints": breakpoints }))?;
+                }
+                "configurationDone" => {
+                    self.client.respond(&request, json!({}))?;
+                    if self.stop_on_entry {
+                        self.stopped("entry", None)?;
+                    } else {
+                        let result = self.debugger.resume();
+                        self.report(result)?;
+                    }
+                }
+                "threads" => self.client.respond(
+                    &request,
+                    json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+                )?,
+                "stackTrace" => {
+                    let frames = self.stack_frames();
+                    let body = json!({ "stackFrames": frames, "totalFrames": frames.len() });
+                    self.client.respond(&request, body)?;
+                }
+                "scopes" => self.client.respond(
+                    &request,
+                    json!({ "scopes": [
+                        { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                        { "name": "Memory", "variablesReference": MEMORY_REFERENCE, "expensive": false },
+                    ] }),
+                )?,
+                "variables" => {
+                    let variables = self.variables(&request["arguments"]);
+                    self.client
+                        .respond(&request, json!({ "variables": variables }))?;
+                }
+                "continue" => {
+                    self.client
+                        .respond(&request, json!({ "allThreadsContinued": true }))?;
+                    let result = self.debugger.resume();
+                    self.report(result)?;
+                }
+                "next" => {
+                    self.client.respond(&request, json!({}))?;
+                    let result = self.debugger.step_over();
+                    self.report(result)?;
+                }
+                "stepIn" => {
+                    self.client.respond(&request, json!({}))?;
+                    let result = self.debugger.step();
+                    self.report(result)?;
+                }
+                "stepBack" => {
+                    if self.debugger.step_back() {
+                        self.client.respond(&request, json!({}))?;
+                        self.stopped("step", None)?;
+                    } else {
+                        self.client
+                            .fail(&request, "no earlier state to go back to")?;
+                    }
+                }
+                // Execution only happens while handling a request, so the
+                // program is already paused.
+                "pause" => self.client.respond(&request, json!({}))?,
+                "disconnect" => return self.client.respond(&request, json!({})),
+                command => self
+                    .client
+                    .fail(&request, &format!("unsupported request `{}`", command))?,
+            }
+        }
+        Ok(())
+    }
+
+    // Replace the breakpoints with ones on the requested lines.
+    fn set_breakpoints(&mut self, requested: &Json) -> Vec<Json> {
+        let old: Vec<usize> = self.debugger.breakpoints().collect();
+        for address in old {
+            self.debugger.remove_breakpoint(address);
+        }
+        let requested = requested.as_array().map(Vec::as_slice).unwrap_or_default();
+        requested
+            .iter()
+            .map(|breakpoint| {
+                let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+                match self
+                    .lines
+                    .iter()
+                    .position(|&source_line| source_line >= line)
+                {
+                    Some(address) => {
+                        self.debugger.add_breakpoint(address);
+                        json!({ "verified": true, "line": self.lines[address] })
+                    }
+                    None => json!({
+                        "verified": false,
+                        "line": line,
+                        "message": "no instruction at or after this line",
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    // The current instruction, then the call of each active function.
+    fn stack_frames(&self) -> Vec<Json> {
+        let vm = self.debugger.vm();
+        let calls = vm
+            .call_stack()
+            .iter()
+            .rev()
+            .map(|&return_address| return_address - 1);
+        std::iter::once(vm.ip())
+            .chain(calls)
+            .enumerate()
+            .map(|(id, address)| {
+                let name = match vm.program().get(address) {
+                    Some(instruction) => format!("{:04}: {}", address, instruction),
+                    None => format!("{:04}", address),
+                };
+                // Past the end of the program, show the last line.
+                let line = self
+                    .lines
+                    .get(address)
+                    .or(self.lines.last())
+                    .copied()
+                    .unwrap_or(1);
+                json!({
+                    "id": id,
+                    "name": name,
+                    "source": { "path": self.path },
+                    "line": line,
+                    "column": 1,
+                })
+            })
+            .collect()
+    }
+
+    fn variables(&self, arguments: &Json) -> Vec<Json> {
+        let vm = self.debugger.vm();
+        match arguments["variablesReference"].as_i64() {
+            Some(REGISTERS_REFERENCE) => vm
+                .registers()
+                .iter()
+                .enumerate()
+                .map(|(index, value)| {
+                    json!({
+                        "name": format!("r{}", index),
+                        "value": value.to_string(),
+                        "variablesReference": 0,
+                    })
+                })
+                .collect(),
+            Some(MEMORY_REFERENCE) => {
+                let memory = vm.memory().borrow();
+                memory
+                    .regions()
+                    .map(|region| {
+                        let shown = region.size.min(MEMORY_PREVIEW);
+                        let bytes = memory.bytes(region.address, shown).unwrap_or_default();
+                        let mut value: Vec<String> =
+                            bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                        if shown < region.size {
+                            value.push("...".to_string());
+                        }
+                        json!({
+                            "name": format!("{:#06x} ({} bytes)", region.address, region.size),
+                            "value": value.join(" "),
+                            "variablesReference": 0,
+                        })
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // Tell the client how an execution request ended, after the output it made.
+    fn report(&mut self, result: Result<Stop, VmError>) -> io::Result<()> {
+        let output = self.program_output.take();
+        if !output.is_empty() {
+            let output = String::from_utf8_lossy(&output);
+            self.client
+                .event("output", json!({ "category": "stdout", "output": output }))?;
+        }
+        match result {
+            Ok(Stop::Step) => self.stopped("step", None),
+            Ok(Stop::Breakpoint(_)) => self.stopped("breakpoint", None),
+            Ok(Stop::Watchpoint(hit)) => {
+                let text = format!(
+                    "{:04} wrote {:#06x}: {:02x?} -> {:02x?}",
+                    hit.ip, hit.address, hit.old, hit.new
+                );
+                self.stopped("data breakpoint", Some(text))
+            }
+            Ok(Stop::Brk { code, .. }) => self.stopped("exception", Some(format!("brk {}", code))),
+            Ok(Stop::Exited(VmState::Halted | VmState::EndOfProgram)) => {
+                self.client.event("exited", json!({ "exitCode": 0 }))?;
+                self.client.event("terminated", json!({}))
+            }
+            Ok(Stop::Exited(state)) => self.stopped("exception", Some(format!("{:?}", state))),
+            Err(error) => self.stopped("exception", Some(error.to_string())),
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["description"] = json!(text);
+            body["text"] = json!(text);
+        }
+        self.client.event("stopped", body)
+    }
+}
+
+fn command(request: &Json) -> &str {
+    request["command"].as_str().unwrap_or_default()
+}
+
+// Read one message: `Content-Length` and other headers, a blank line, then
+// the JSON body. Returns `None` at the end of the input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            let value = value.trim().parse::<usize>().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid Content-Length")
+            })?;
+            length = Some(value);
+        }
+    }
+    let mut body = vec![0; length.unwrap_or_default()];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(seq: i64, command: &str, arguments: Json) -> String {
+        let body = json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+            .to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    #[test]
+    fn test_dap_session() {
+        let path = std::env::temp_dir().join(format!("basic_rust_vm_dap_{}.vmasm", std::process::id()));
+        fs::write(&path, "; doubles r0\n    set r0, 21\n\n    add r0, r0, r0\n    print r0\n    halt\n").unwrap();
+        let path = path.to_str().unwrap().to_string();
+
+        let requests = [
+            message(1, "initialize", json!({ "adapterID": "vmasm" })),
+            message(2, "launch", json!({ "program": path })),
+            message(
+                3,
+                "setBreakpoints",
+                json!({ "source": { "path": path }, "breakpoints": [{ "line": 3 }, { "line": 9 }] }),
+            ),
+            message(4, "configurationDone", json!({})),
+            message(5, "stackTrace", json!({ "threadId": 1 })),
+            message(6, "variables", json!({ "variablesReference": REGISTERS_REFERENCE })),
+            message(7, "stepBack", json!({})),
+            message(8, "next", json!({})),
+            message(9, "continue", json!({})),
+            message(10, "disconnect", json!({})),
+        ]
+        .concat();
+        let mut output = Vec::new();
+        serve_dap(requests.as_bytes(), &mut output).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut output = &output[..];
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        let summary: Vec<String> = messages
+            .iter()
+            .map(|message| match message["type"].as_str().unwrap() {
+                "event" => format!("event {}", message["event"].as_str().unwrap()),
+                _ => format!("response {}", message["command"].as_str().unwrap()),
+            })
+            .collect();
+        assert_eq!(summary, [
+            "response initialize",
+            "response launch",
+            "event initialized",
+            "response setBreakpoints",
+            "response configurationDone",
+            "event stopped",
+            "response stackTrace",
+            "response variables",
+            "response stepBack",
+            "event stopped",
+            "response next",
+            "event stopped",
+            "response continue",
+            "event output",
+            "event exited",
+            "event terminated",
+            "response disconnect",
+        ]);
+        let body = |index: usize| &messages[index]["body"];
+        // Line 3 is blank, so the breakpoint moves to the `add` on line 4
+        assert_eq!(body(3)["breakpoints"][0], json!({ "verified": true, "line": 4 }));
+        assert_eq!(body(3)["breakpoints"][1]["verified"], false);
+        assert_eq!(body(5)["reason"], "breakpoint");
+        assert_eq!(body(6)["stackFrames"][0]["line"], 4);
+        assert_eq!(body(6)["stackFrames"][0]["name"], "0001: add r0, r0, r0");
+        assert_eq!(body(7)["variables"][0]["value"], "21");
+        assert_eq!(body(13)["output"], "Register 0: 42\n");
+    }
+}
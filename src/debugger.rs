@@ -0,0 +1,789 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::condition::Condition;
+use crate::error::VmError;
+use crate::isa::Instruction;
+use crate::undo::Undo;
+use crate::vm::{Effect, VmState, VM};
+
+// Instructions `step_back` can undo unless the debugger is told otherwise.
+const DEFAULT_HISTORY_LIMIT: usize = 1000;
+
+/// Why the debugger handed control back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    Step,                           // A step or step-over completed and the VM can continue
+    Breakpoint(usize),              // Execution reached a breakpoint at the address
+    Watchpoint(WatchHit),           // An instruction wrote into a watched memory range
+    Brk { ip: usize, code: usize }, // Reached a `Brk` instruction, which is stepped over
+    Exited(VmState),                // The VM halted, ran past its end or ran out of fuel
+}
+
+/// A write into a watched memory range, reported by `Stop::Watchpoint`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    pub ip: usize,      // Address of the instruction that wrote
+    pub address: usize, // First watched byte it wrote
+    pub old: Vec<u8>,   // The watched bytes it wrote, before the write
+    pub new: Vec<u8>,   // and after
+}
+
+/// Drives a VM instruction by instruction, stopping at breakpoints. What the
+/// most recent instructions changed is kept, so execution can also be stepped
+/// backwards. A `Brk` instruction hands control to the debugger instead
+/// of trapping the VM, and execution can continue after it.
+pub struct Debugger<'a> {
+    vm: &'a mut VM,
+    breakpoints: BTreeSet<usize>,
+    conditions: BTreeMap<usize, Condition>, // Breakpoints that only stop when their condition holds
+    watchpoints: Vec<Range<usize>>,         // Watched memory, as absolute addresses
+    history: VecDeque<Undo>,                // How to undo each executed instruction, oldest first
+    history_limit: usize,
+}
+
+/// Program output kept for a front end to show instead of being printed,
+/// installed with `VM::set_output(output.clone())`.
+#[derive(Clone, Default)]
+pub(crate) struct SharedOutput(pub(crate) Rc<RefCell<Vec<u8>>>);
+
+impl SharedOutput {
+    /// The output written since the last call.
+    pub(crate) fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.borrow_mut())
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const HELP: &str = "\
+Commands:
+    s, step                 execute one instruction
+    sb, back                undo the last instruction
+    n, next                 step over calls
+    c, continue             run until a breakpoint or the program stops
+    b, break <addr>         set a breakpoint
+    b <addr> if <cond>      stop only when cond holds, e.g. `r2 == 42 && mem[16] > 0`
+    d, delete <addr>        remove a breakpoint
+    w, watch <base> <a..b>  pause when bytes a to b of the region at base are written
+    unwatch <base> <a..b>   remove a watchpoint
+    r, regs                 show the registers and flags
+    stack                   show the call stack
+    mem [addr]              list memory regions, or dump the region at addr
+    l, list                 show the instructions around the current one
+    q, quit                 leave the debugger";
+
+impl<'a> Debugger<'a> {
+    pub fn attach(vm: &'a mut VM) -> Self {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            conditions: BTreeMap::new(),
+            watchpoints: Vec::new(),
+            history: VecDeque::new(),
+            history_limit: DEFAULT_HISTORY_LIMIT,
+        }
+    }
+
+    /// Keep what the last `limit` instructions changed for `step_back`, 1000 by
+    /// default. An entry holds the registers, stacks and locals and the memory
+    /// bytes the instruction overwrote, and a copy of the object heap when the
+    /// instruction changed it or of the memory when it made a syscall.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+
+    /// How many instructions `step_back` can currently undo.
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    pub fn vm(&self) -> &VM {
+        self.vm
+    }
+
+    /// The VM, for front ends that change registers between steps.
+    pub fn vm_mut(&mut self) -> &mut VM {
+        self.vm
+    }
+
+    pub fn add_breakpoint(&mut self, address: usize) {
+        self.breakpoints.insert(address);
+        self.conditions.remove(&address);
+    }
+
+    /// Add a breakpoint that only stops when `condition` holds. A condition
+    /// that can't be evaluated, e.g. because it reads a float register, stops
+    /// as if it held.
+    pub fn add_conditional_breakpoint(&mut self, address: usize, condition: Condition) {
+        self.breakpoints.insert(address);
+        self.conditions.insert(address, condition);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+        self.conditions.remove(&address);
+        self.breakpoints.remove(&address)
+    }
+
+    /// The condition of the breakpoint at `address`, if it has one.
+    pub fn condition(&self, address: usize) -> Option<&Condition> {
+        self.conditions.get(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Pause execution when an instruction writes into the byte `offsets` of
+    /// the memory region at `region`. The region doesn't need to be allocated
+    /// yet.
+    pub fn add_watchpoint(&mut self, region: usize, offsets: Range<usize>) {
+        let range = region + offsets.start..region + offsets.end;
+        if !range.is_empty() && !self.watchpoints.contains(&range) {
+            self.watchpoints.push(range);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, region: usize, offsets: Range<usize>) -> bool {
+        let range = region + offsets.start..region + offsets.end;
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watched| *watched != range);
+        self.watchpoints.len() != count
+    }
+
+    /// The watched memory ranges, as absolute addresses.
+    pub fn watchpoints(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.watchpoints.iter().cloned()
+    }
+
+    /// Execute one instruction.
+    pub fn step(&mut self) -> Result<Stop, VmError> {
+        if let Some(stop) = self.execute()? {
+            return Ok(stop);
+        }
+        Ok(self.stop())
+    }
+
+    /// Restore the machine state from before the last executed instruction.
+    /// Output that was written and input that was read stay as they are.
+    /// Returns false when there is nothing left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let Some(undo) = self.history.pop_back() else {
+            return false;
+        };
+        self.vm.undo(undo);
+        true
+    }
+
+    // Step the VM, remembering how to undo the instruction. Instructions that
+    // fail are undone too, back to the state before the fault.
+    fn execute(&mut self) -> Result<Option<Stop>, VmError> {
+        if self.vm.state() != VmState::Running || self.history_limit == 0 {
+            return self.execute_instruction();
+        }
+        self.vm.begin_undo();
+        let result = self.execute_instruction();
+        if let Some(undo) = self.vm.take_undo() {
+            if self.history.len() == self.history_limit {
+                self.history.pop_front();
+            }
+            self.history.push_back(undo);
+        }
+        result
+    }
+
+    // Step the VM and report a `Brk` or a write into watched memory.
+    fn execute_instruction(&mut self) -> Result<Option<Stop>, VmError> {
+        if let (VmState::Running, Some(&Instruction::Brk(code))) =
+            (self.vm.state(), self.vm.program().get(self.vm.ip()))
+        {
+            let ip = self.vm.ip();
+            self.vm.skip_instruction();
+            return Ok(Some(Stop::Brk { ip, code }));
+        }
+        if self.watchpoints.is_empty() {
+            return self.vm.step().map(|_| None);
+        }
+        let ip = self.vm.ip();
+        // Watched bytes that aren't allocated read as 0.
+        let old: Vec<Vec<u8>> = {
+            let memory = self.vm.memory().borrow();
+            self.watchpoints
+                .iter()
+                .map(|range| {
+                    range
+                        .clone()
+                        .map(|address| memory.bytes(address, 1).map_or(0, |bytes| bytes[0]))
+                        .collect()
+                })
+                .collect()
+        };
+        let (result, effects) = self.vm.step_with_effects();
+        result?;
+        Ok(self.watch_hit(ip, &effects, &old).map(Stop::Watchpoint))
+    }
+
+    // The first write among `effects` into a watched range.
+    fn watch_hit(&self, ip: usize, effects: &[Effect], old: &[Vec<u8>]) -> Option<WatchHit> {
+        let memory = self.vm.memory().borrow();
+        effects.iter().find_map(|effect| {
+            let written = match effect {
+                Effect::MemoryWrite { address, .. } => *address..address + 1,
+                Effect::MemoryWriteBlock { address, bytes } => *address..address + bytes.len(),
+                _ => return None,
+            };
+            self.watchpoints.iter().zip(old).find_map(|(watched, old)| {
+                let start = written.start.max(watched.start);
+                let end = written.end.min(watched.end);
+                (start < end).then(|| WatchHit {
+                    ip,
+                    address: start,
+                    old: old[start - watched.start..end - watched.start].to_vec(),
+                    new: memory
+                        .bytes(start, end - start)
+                        .map(<[u8]>::to_vec)
+                        .unwrap_or_default(),
+                })
+            })
+        })
+    }
+
+    /// Execute one instruction, running a call through to its `Return`.
+    pub fn step_over(&mut self) -> Result<Stop, VmError> {
+        let is_call = matches!(
+            self.vm.program().get(self.vm.ip()),
+            Some(Instruction::Call(_) | Instruction::CallReg(_) | Instruction::CallTable(_))
+        );
+        let depth = self.vm.call_stack().len();
+        if let Some(stop) = self.execute()? {
+            return Ok(stop);
+        }
+        if is_call {
+            while self.stop() == Stop::Step && self.vm.call_stack().len() > depth {
+                if let Some(stop) = self.execute()? {
+                    return Ok(stop);
+                }
+            }
+        }
+        Ok(self.stop())
+    }
+
+    /// Run until a breakpoint is reached or the VM stops. The instruction at the
+    /// current address always executes, so resuming from a breakpoint moves on.
+    pub fn resume(&mut self) -> Result<Stop, VmError> {
+        if let Some(stop) = self.execute()? {
+            return Ok(stop);
+        }
+        while self.stop() == Stop::Step {
+            if let Some(stop) = self.execute()? {
+                return Ok(stop);
+            }
+        }
+        Ok(self.stop())
+    }
+
+    fn stop(&self) -> Stop {
+        match self.vm.state() {
+            VmState::Running if self.breakpoint_hit(self.vm.ip()) => Stop::Breakpoint(self.vm.ip()),
+            VmState::Running => Stop::Step,
+            state => Stop::Exited(state),
+        }
+    }
+
+    fn breakpoint_hit(&self, address: usize) -> bool {
+        self.breakpoints.contains(&address)
+            && self
+                .conditions
+                .get(&address)
+                .is_none_or(|condition| condition.holds(self.vm).unwrap_or(true))
+    }
+
+    /// Read commands from `input` until it ends or `quit` is entered, writing
+    /// prompts and results to `output`.
+    pub fn run_prompt(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        writeln!(output, "Type `help` for a list of commands.")?;
+        self.show_location(&mut output)?;
+        let mut lines = input.lines();
+        loop {
+            write!(output, "(vmdb) ")?;
+            output.flush()?;
+            let Some(line) = lines.next().transpose()? else {
+                return Ok(());
+            };
+            let mut words = line.split_whitespace();
+            let Some(command) = words.next() else {
+                continue;
+            };
+            let argument = words.next().map(|word| word.parse::<usize>());
+            let result = match (command, argument) {
+                ("s" | "step", None) => self.step(),
+                ("sb" | "back", None) => {
+                    if !self.step_back() {
+                        writeln!(output, "No earlier state to go back to")?;
+                    }
+                    self.show_location(&mut output)?;
+                    continue;
+                }
+                ("n" | "next", None) => self.step_over(),
+                ("c" | "continue", None) => self.resume(),
+                ("b" | "break", Some(Ok(address))) => {
+                    match words.next() {
+                        None => {
+                            self.add_breakpoint(address);
+                            writeln!(output, "Breakpoint at {:04}", address)?;
+                        }
+                        Some("if") => {
+                            let text = words.collect::<Vec<_>>().join(" ");
+                            match Condition::parse(&text) {
+                                Ok(condition) => {
+                                    writeln!(
+                                        output,
+                                        "Breakpoint at {:04} if {}",
+                                        address, condition
+                                    )?;
+                                    self.add_conditional_breakpoint(address, condition);
+                                }
+                                Err(error) => writeln!(output, "Invalid condition: {}", error)?,
+                            }
+                        }
+                        Some(_) => writeln!(output, "Usage: {} <addr> [if <condition>]", command)?,
+                    }
+                    continue;
+                }
+                ("d" | "delete", Some(Ok(address))) => {
+                    if !self.remove_breakpoint(address) {
+                        writeln!(output, "No breakpoint at {:04}", address)?;
+                    }
+                    continue;
+                }
+                ("w" | "watch" | "unwatch", Some(Ok(region))) => {
+                    let Some(offsets) = words.next().and_then(parse_range) else {
+                        writeln!(output, "Usage: {} <base> <start>..<end>", command)?;
+                        continue;
+                    };
+                    if command == "unwatch" {
+                        if !self.remove_watchpoint(region, offsets) {
+                            writeln!(output, "No such watchpoint")?;
+                        }
+                    } else {
+                        self.add_watchpoint(region, offsets.clone());
+                        writeln!(
+                            output,
+                            "Watching {:#06x}..{:#06x}",
+                            region + offsets.start,
+                            region + offsets.end
+                        )?;
+                    }
+                    continue;
+                }
+                ("r" | "regs", None) => {
+                    for (index, value) in self.vm.registers().iter().enumerate() {
+                        writeln!(output, "r{} = {}", index, value)?;
+                    }
+                    writeln!(output, "flags = {}", self.vm.flags())?;
+                    continue;
+                }
+                ("stack", None) => {
+                    if self.vm.call_stack().is_empty() {
+                        writeln!(output, "No active calls")?;
+                    }
+                    for (depth, address) in self.vm.call_stack().iter().enumerate().rev() {
+                        writeln!(output, "#{} returns to {:04}", depth, address)?;
+                    }
+                    continue;
+                }
+                ("mem", None) => {
+                    let memory = self.vm.memory().borrow();
+                    if memory.regions().next().is_none() {
+                        writeln!(output, "No memory allocated")?;
+                    }
+                    for region in memory.regions() {
+                        writeln!(output, "{:#06x}: {} bytes", region.address, region.size)?;
+                    }
+                    continue;
+                }
+                ("mem", Some(Ok(address))) => {
+                    let memory = self.vm.memory().borrow();
+                    match memory.region(address) {
+                        Some(region) => {
+                            let data = memory.bytes(address, region.size).unwrap_or_default();
+                            for (row, bytes) in data.chunks(16).enumerate() {
+                                writeln!(output, "{:#06x}: {}", address + row * 16, hex(bytes))?;
+                            }
+                        }
+                        None => writeln!(output, "No memory region at {}", address)?,
+                    }
+                    continue;
+                }
+                ("l" | "list", None) => {
+                    let ip = self.vm.ip();
+                    let program = self.vm.program();
+                    let start = ip.saturating_sub(3);
+                    for (address, instruction) in program.iter().enumerate().skip(start).take(7) {
+                        let marker = if address == ip { "=>" } else { "  " };
+                        writeln!(output, "{} {:04}: {}", marker, address, instruction)?;
+                    }
+                    continue;
+                }
+                ("q" | "quit", None) => return Ok(()),
+                ("help", None) => {
+                    writeln!(output, "{}", HELP)?;
+                    continue;
+                }
+                _ => {
+                    writeln!(
+                        output,
+                        "Unknown command `{}`, type `help` for a list",
+                        line.trim()
+                    )?;
+                    continue;
+                }
+            };
+            match result {
+                Ok(Stop::Breakpoint(address)) => writeln!(output, "Breakpoint at {:04}", address)?,
+                Ok(Stop::Watchpoint(hit)) => writeln!(
+                    output,
+                    "Watchpoint: {:04} wrote {:#06x}, {} -> {}",
+                    hit.ip,
+                    hit.address,
+                    hex(&hit.old),
+                    hex(&hit.new)
+                )?,
+                Ok(Stop::Brk { ip, code }) => writeln!(output, "brk {} at {:04}", code, ip)?,
+                Ok(Stop::Exited(state)) => writeln!(output, "Program stopped: {:?}", state)?,
+                Ok(Stop::Step) => {}
+                Err(error) => writeln!(output, "Error: {}", error)?,
+            }
+            self.show_location(&mut output)?;
+        }
+    }
+
+    fn show_location(&self, output: &mut impl Write) -> io::Result<()> {
+        if matches!(self.vm.state(), VmState::Halted | VmState::Trapped) {
+            return Ok(());
+        }
+        if let Some(instruction) = self.vm.program().get(self.vm.ip()) {
+            writeln!(output, "{:04}: {}", self.vm.ip(), instruction)?;
+        }
+        Ok(())
+    }
+}
+
+// Bytes as space separated hex pairs.
+fn hex(bytes: &[u8]) -> String {
+    let pairs: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    pairs.join(" ")
+}
+
+// A byte range written `start..end`.
+fn parse_range(word: &str) -> Option<Range<usize>> {
+    let (start, end) = word.split_once("..")?;
+    Some(start.parse().ok()?..end.parse().ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{MemorySafety, POISON_BYTE};
+    use crate::value::Value;
+    use crate::vm::VmConfig;
+
+    fn program() -> Vec<Instruction> {
+        vec![
+            Instruction::SetReg(0, 1), // 0
+            Instruction::Call(4),      // 1
+            Instruction::SetReg(1, 2), // 2
+            Instruction::Halt,         // 3
+            Instruction::SetReg(2, 3), // 4
+            Instruction::SetReg(3, 4), // 5
+            Instruction::Return,       // 6
+        ]
+    }
+
+    #[test]
+    fn test_breakpoints_and_resume() {
+        let mut vm = VM::new(program());
+        let mut debugger = Debugger::attach(&mut vm);
+        debugger.add_breakpoint(5);
+        debugger.add_breakpoint(2);
+
+        assert_eq!(debugger.resume(), Ok(Stop::Breakpoint(5)));
+        assert_eq!(debugger.vm().registers()[2], Value::Int(3));
+        assert_eq!(debugger.vm().registers()[3], Value::Int(0));
+        assert_eq!(debugger.vm().call_stack(), &[2]);
+        assert_eq!(debugger.resume(), Ok(Stop::Breakpoint(2)));
+        assert!(debugger.remove_breakpoint(2));
+        assert_eq!(debugger.resume(), Ok(Stop::Exited(VmState::Halted)));
+        assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), vec![5]);
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(1, 5),
+            Instruction::AddImm(0, 1, 0),
+            Instruction::SubImm(1, 1, 1),
+            Instruction::JumpIfNonZero(1, -3),
+            Instruction::Halt,
+        ]);
+        let mut debugger = Debugger::attach(&mut vm);
+        debugger.add_conditional_breakpoint(1, Condition::parse("r0 == 3 && r1 > 0").unwrap());
+        debugger.add_conditional_breakpoint(4, Condition::parse("r0 / r1").unwrap());
+
+        assert_eq!(debugger.resume(), Ok(Stop::Breakpoint(1)));
+        assert_eq!(debugger.vm().registers()[0], Value::Int(3));
+        assert_eq!(debugger.condition(1).unwrap().to_string(), "r0 == 3 && r1 > 0");
+        assert_eq!(debugger.resume(), Ok(Stop::Breakpoint(4))); // Division by zero stops
+        assert_eq!(debugger.resume(), Ok(Stop::Exited(VmState::Halted)));
+
+        debugger.add_breakpoint(1); // Replaces the condition
+        assert_eq!(debugger.condition(1), None);
+        assert!(debugger.remove_breakpoint(4));
+        assert_eq!(debugger.condition(4), None);
+    }
+
+    #[test]
+    fn test_brk() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 1),
+            Instruction::Brk(3),
+            Instruction::SetReg(0, 2),
+        ]);
+        let mut debugger = Debugger::attach(&mut vm);
+
+        assert_eq!(debugger.resume(), Ok(Stop::Brk { ip: 1, code: 3 }));
+        assert_eq!(debugger.vm().ip(), 2);
+        assert!(debugger.step_back());
+        assert_eq!(debugger.step(), Ok(Stop::Brk { ip: 1, code: 3 }));
+        assert_eq!(debugger.resume(), Ok(Stop::Exited(VmState::EndOfProgram)));
+        assert_eq!(debugger.vm().registers()[0], Value::Int(2));
+    }
+
+    #[test]
+    fn test_step_over() {
+        let mut vm = VM::new(program());
+        let mut debugger = Debugger::attach(&mut vm);
+
+        assert_eq!(debugger.step(), Ok(Stop::Step));
+        assert_eq!(debugger.step_over(), Ok(Stop::Step)); // Runs the whole call
+        assert_eq!(debugger.vm().ip(), 2);
+        assert_eq!(debugger.vm().registers()[3], Value::Int(4));
+
+        // A breakpoint inside the call still stops a step-over
+        let mut vm = VM::new(program());
+        let mut debugger = Debugger::attach(&mut vm);
+        debugger.add_breakpoint(5);
+        debugger.step().unwrap();
+        assert_eq!(debugger.step_over(), Ok(Stop::Breakpoint(5)));
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 1),
+            Instruction::AllocateMemory(0, 2),
+            Instruction::SetReg(1, 9),
+            Instruction::StoreToMemory(0, 1, 0),
+            Instruction::AddImm(0, 1, 0),
+            Instruction::Print(9), // Traps
+        ]);
+        vm.set_output(io::sink());
+        let mut debugger = Debugger::attach(&mut vm);
+
+        assert_eq!(debugger.resume(), Err(VmError::InvalidRegister(9)));
+        assert_eq!(debugger.history_len(), 6);
+        assert!(debugger.step_back()); // Back before the fault
+        assert_eq!(debugger.vm().state(), VmState::Running);
+        assert_eq!(debugger.vm().ip(), 5);
+        assert!(debugger.step_back());
+        assert!(debugger.step_back());
+        assert_eq!(debugger.vm().registers()[0], Value::Int(1));
+        assert_eq!(debugger.vm().memory().borrow().bytes(0, 1), Ok(&[0][..]));
+        assert_eq!(debugger.step(), Ok(Stop::Step)); // Redo the store
+        assert_eq!(debugger.vm().memory().borrow().bytes(0, 1), Ok(&[9][..]));
+
+        debugger.set_history_limit(2);
+        assert_eq!(debugger.history_len(), 2);
+        assert!(debugger.step_back());
+        assert!(debugger.step_back());
+        assert!(!debugger.step_back());
+        assert_eq!(debugger.vm().ip(), 2);
+    }
+
+    #[test]
+    fn test_step_back_restores_memory_stats() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 16),
+            Instruction::AllocateMemory(0, 1),
+            Instruction::FreeMemory(0),
+            Instruction::Halt,
+        ]);
+        let mut debugger = Debugger::attach(&mut vm);
+
+        assert_eq!(debugger.resume(), Ok(Stop::Exited(VmState::Halted)));
+        assert_eq!(debugger.vm().memory_stats().frees, 1);
+        assert!(debugger.step_back());
+        assert!(debugger.step_back()); // Before the free
+        let stats = debugger.vm().memory_stats();
+        assert_eq!((stats.allocations, stats.frees, stats.live_bytes), (1, 0, 16));
+        assert!(debugger.step_back()); // Before the allocation
+        let stats = debugger.vm().memory_stats();
+        assert_eq!((stats.allocations, stats.live_bytes), (0, 0));
+        assert_eq!(stats.leaks().count(), 0);
+        assert_eq!(debugger.step(), Ok(Stop::Step));
+        assert_eq!(debugger.vm().memory_stats().live_bytes, 16); // Counted once
+    }
+
+    #[test]
+    fn test_step_back_under_a_large_allocation() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 1 << 20),
+            Instruction::AllocateMemory(0, 1), // 1 MiB at address 0
+            Instruction::SetReg(3, 7),
+            Instruction::SetReg(4, 2000),
+            Instruction::StoreIndirect(1, 2, 3), // 4: mem[r2] = 7
+            Instruction::AddImm(2, 1, 2),
+            Instruction::Lt(2, 4, 5),
+            Instruction::JumpIfNonZero(5, -4),
+            Instruction::Halt,
+        ]);
+        let mut debugger = Debugger::attach(&mut vm);
+
+        assert_eq!(debugger.resume(), Ok(Stop::Exited(VmState::Halted)));
+        assert_eq!(debugger.history_len(), DEFAULT_HISTORY_LIMIT);
+        assert_eq!(debugger.vm().memory().borrow().bytes(1998, 2), Ok(&[7, 7][..]));
+        for _ in 0..5 {
+            assert!(debugger.step_back()); // The halt and the last iteration
+        }
+        assert_eq!(debugger.vm().ip(), 4);
+        assert_eq!(debugger.vm().registers()[2], Value::Int(1999));
+        assert_eq!(debugger.vm().memory().borrow().bytes(1998, 2), Ok(&[7, 0][..]));
+        assert_eq!(debugger.step(), Ok(Stop::Step)); // Redo the store
+        assert_eq!(debugger.vm().memory().borrow().bytes(1999, 1), Ok(&[7][..]));
+
+        // Stepping back over the allocation gives the memory back, and the
+        // heap limit counts it once when it is allocated again
+        let config = VmConfig {
+            max_heap: 1 << 20,
+            ..VmConfig::default()
+        };
+        let mut vm = VM::with_config(
+            vec![
+                Instruction::SetReg(0, 1 << 20),
+                Instruction::AllocateMemory(0, 1),
+                Instruction::SetReg(3, 7),
+                Instruction::StoreIndirect(1, 2, 3),
+                Instruction::Halt,
+            ],
+            config,
+        );
+        vm.set_memory_safety(MemorySafety::Strict);
+        let mut debugger = Debugger::attach(&mut vm);
+        assert_eq!(debugger.resume(), Ok(Stop::Exited(VmState::Halted)));
+        let live = |debugger: &Debugger| debugger.vm().memory_stats().live_bytes;
+        assert!(debugger.step_back());
+        assert!(debugger.step_back());
+        assert_eq!(live(&debugger), 1 << 20);
+        assert_eq!(debugger.vm().memory().borrow().bytes(0, 1), Ok(&[POISON_BYTE][..]));
+        assert!(debugger.vm().memory().borrow().poison(0, 1).is_some()); // Uninitialized again
+        assert!(debugger.step_back());
+        assert_eq!(live(&debugger), 1 << 20);
+        assert!(debugger.step_back());
+        assert_eq!(live(&debugger), 0);
+        assert_eq!(debugger.vm().memory_stats().allocations, 0);
+        assert_eq!(debugger.vm().memory().borrow().regions().count(), 0);
+        assert_eq!(debugger.vm().memory().borrow().next_free_address(), 0);
+        assert_eq!(debugger.step(), Ok(Stop::Step));
+        assert_eq!(debugger.vm().registers()[1], Value::Int(0)); // Allocated at the same address again
+        assert_eq!(live(&debugger), 1 << 20);
+        assert_eq!(debugger.resume(), Ok(Stop::Exited(VmState::Halted)));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(2, 8),
+            Instruction::AllocateMemory(2, 2),
+            Instruction::SetReg(0, 0x201),
+            Instruction::StoreToMemory(0, 0, 1), // Outside the watched bytes
+            Instruction::SetReg(1, 2),
+            Instruction::Store16(2, 1, 0), // Bytes 2 and 3, the second watched
+            Instruction::MemFill(0, 0, 8), // Overwrites all of them
+            Instruction::Halt,
+        ]);
+        let mut debugger = Debugger::attach(&mut vm);
+        debugger.add_watchpoint(0, 3..5);
+        debugger.add_watchpoint(0, 3..5);
+        assert_eq!(debugger.watchpoints().collect::<Vec<_>>(), vec![3..5]);
+
+        let hit = WatchHit {
+            ip: 5,
+            address: 3,
+            old: vec![0],
+            new: vec![2],
+        };
+        assert_eq!(debugger.resume(), Ok(Stop::Watchpoint(hit)));
+        let hit = WatchHit {
+            ip: 6,
+            address: 3,
+            old: vec![2, 0],
+            new: vec![1, 1],
+        };
+        assert_eq!(debugger.step(), Ok(Stop::Watchpoint(hit)));
+        assert!(debugger.remove_watchpoint(0, 3..5));
+        assert!(!debugger.remove_watchpoint(0, 3..5));
+        assert_eq!(debugger.resume(), Ok(Stop::Exited(VmState::Halted)));
+
+        let mut vm = VM::new(program());
+        let mut output = Vec::new();
+        Debugger::attach(&mut vm)
+            .run_prompt("watch 0 0..2\nw 0 zz\nunwatch 0 1..2\n".as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Watching 0x0000..0x0002\n"));
+        assert!(output.contains("Usage: w <base> <start>..<end>\n"));
+        assert!(output.contains("No such watchpoint\n"));
+    }
+
+    #[test]
+    fn test_prompt() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 4),
+            Instruction::AllocateMemory(0, 1),
+            Instruction::SetReg(0, 7),
+            Instruction::Print(9),
+        ]);
+        let mut output = Vec::new();
+        Debugger::attach(&mut vm)
+            .run_prompt(
+                "b 2 if r0 >\nb 2 if r0 == 99\nb 3 if r0 == 7\nc\nmem 0\nr\nfoo\ns\n".as_bytes(),
+                &mut output,
+            )
+            .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("0000: setw r0, 4\n"));
+        assert!(output.contains("Invalid condition: unexpected end of the condition\n"));
+        assert!(output.contains("Breakpoint at 0003 if r0 == 7\n"));
+        assert!(output.contains("Breakpoint at 0003\n0003: print r9\n"));
+        assert!(output.contains("0x0000: 00 00 00 00\n"));
+        assert!(output.contains("r0 = 7\n"));
+        assert!(output.contains("flags = ----\n"));
+        assert!(output.contains("Unknown command `foo`"));
+        assert!(output.contains("Error: invalid register index 9\n"));
+    }
+}
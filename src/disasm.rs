@@ -0,0 +1,579 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+
+use crate::asm::is_identifier;
+use crate::isa::Instruction;
+use crate::program::Program;
+use crate::value::Value;
+
+// Data bytes per `.byte` directive.
+const BYTES_PER_LINE: usize = 16;
+
+/// Turn a program back into assembly text that `assemble` accepts.
+///
+/// Every line ends with the address of its instruction as a comment. Jump and
+/// call targets inside the program get a synthesized `L<address>:` label; targets
+/// outside it are kept as raw offsets.
+pub fn disassemble(program: &[Instruction]) -> String {
+    let mut listing = String::new();
+    write_code(&mut listing, program, &[], &BTreeMap::new());
+    listing
+}
+
+/// Turn a whole program back into assembly text that `assemble_program`
+/// accepts: a `.global` declaration per global, named `g0`, `g1` and so on, a
+/// `.functions` directive for the function table and a `.data` section with a
+/// `D<offset>:` label per directive, followed by the instructions as
+/// `disassemble` lists them. Addresses with a symbol are labelled with its
+/// name instead of `L<address>`, and symbols that aren't valid labels are left
+/// out. Function-table entries past the end of the program get a label at its
+/// end.
+pub fn disassemble_program(program: &Program) -> String {
+    let mut listing = String::new();
+    for (index, value) in program.globals.iter().enumerate() {
+        let declaration = match value {
+            Value::Int(value) => format!("i32 = {}", value),
+            Value::Float(value) => format!("f64 = {:?}", value),
+            Value::Int64(value) => format!("i64 = {}", value),
+            Value::Nil => "nil".to_string(),
+            // A handle means nothing before the program runs.
+            Value::Ref(handle) => format!("i32 ; was a reference to object {}", handle),
+        };
+        writeln!(listing, ".global g{}: {}", index, declaration).unwrap();
+    }
+    if !program.functions.is_empty() {
+        let entries: Vec<_> = program
+            .functions
+            .iter()
+            .map(|&entry| label_name(&program.symbols, entry.min(program.instructions.len())))
+            .collect();
+        writeln!(listing, ".functions {}", entries.join(", ")).unwrap();
+    }
+    if !program.data.is_empty() {
+        listing.push_str(".data\n");
+        write_data(&mut listing, &program.data);
+        listing.push_str(".text\n");
+    }
+    write_code(
+        &mut listing,
+        &program.instructions,
+        &program.functions,
+        &program.symbols,
+    );
+    listing
+}
+
+// The instructions, with a label on every jump and call target inside the
+// program, on every entry of `functions` and on every symbol.
+fn write_code(
+    listing: &mut String,
+    program: &[Instruction],
+    functions: &[usize],
+    symbols: &BTreeMap<usize, String>,
+) {
+    let end = program.len();
+    let label = |address| label_name(symbols, address);
+    let labels: BTreeSet<usize> = (0..program.len())
+        .flat_map(|address| jump_targets(program, address))
+        .flatten()
+        .chain(functions.iter().map(|&entry| entry.min(end)))
+        .chain(symbols.keys().copied().filter(|&address| address <= end))
+        .collect();
+
+    for (address, instruction) in program.iter().enumerate() {
+        if labels.contains(&address) {
+            writeln!(listing, "{}:", label(address)).unwrap();
+        }
+        let targets: Vec<_> = jump_targets(program, address)
+            .into_iter()
+            .map(|target| target.map(label))
+            .collect();
+        writeln!(
+            listing,
+            "    {:<28}; {:04}",
+            format_instruction(instruction, &targets),
+            address
+        )
+        .unwrap();
+    }
+    if labels.contains(&end) {
+        writeln!(listing, "{}:", label(end)).unwrap();
+    }
+}
+
+// The data section as `.string` directives for its runs of text, each ending
+// after a newline, and `.byte` directives for the rest.
+fn write_data(listing: &mut String, data: &[u8]) {
+    let is_text = |byte: &u8| matches!(byte, b' '..=b'~' | b'\n' | b'\t' | b'\r');
+    let mut offset = 0;
+    while offset < data.len() {
+        writeln!(listing, "D{:04}:", offset).unwrap();
+        let rest = &data[offset..];
+        let text = rest.iter().take_while(|byte| is_text(byte)).count();
+        if text > 0 {
+            let len = rest[..text]
+                .iter()
+                .position(|&byte| byte == b'\n')
+                .map_or(text, |newline| newline + 1);
+            let mut literal = String::new();
+            for &byte in &rest[..len] {
+                match byte {
+                    b'\n' => literal.push_str("\\n"),
+                    b'\t' => literal.push_str("\\t"),
+                    b'\r' => literal.push_str("\\r"),
+                    b'\\' => literal.push_str("\\\\"),
+                    b'"' => literal.push_str("\\\""),
+                    byte => literal.push(byte as char),
+                }
+            }
+            writeln!(listing, "    .string \"{}\"", literal).unwrap();
+            offset += len;
+        } else {
+            let len = rest
+                .iter()
+                .take(BYTES_PER_LINE)
+                .take_while(|byte| !is_text(byte))
+                .count();
+            let bytes: Vec<_> = rest[..len]
+                .iter()
+                .map(|byte| format!("0x{:02X}", byte))
+                .collect();
+            writeln!(listing, "    .byte {}", bytes.join(", ")).unwrap();
+            offset += len;
+        }
+    }
+}
+
+// The symbol naming `address`, where there is one, or `L<address>`.
+fn label_name(symbols: &BTreeMap<usize, String>, address: usize) -> String {
+    match symbols.get(&address) {
+        Some(name) if is_identifier(name) => name.clone(),
+        _ => format!("L{:04}", address),
+    }
+}
+
+// The absolute addresses the target operands of the jump or call at `address`
+// go to, in operand order. `None` for a target outside the program.
+fn jump_targets(program: &[Instruction], address: usize) -> Vec<Option<usize>> {
+    let inside = |target: usize| Some(target).filter(|target| *target < program.len());
+    let relative = |offset: isize| (address + 1).checked_add_signed(offset).and_then(inside);
+    match program[address] {
+        Instruction::Jump(offset)
+        | Instruction::JumpIfZero(_, offset)
+        | Instruction::JumpIfNonZero(_, offset)
+        | Instruction::Jz(offset)
+        | Instruction::Jnz(offset)
+        | Instruction::Jc(offset)
+        | Instruction::Jo(offset)
+        | Instruction::Jlt(offset)
+        | Instruction::Jge(offset)
+        | Instruction::TryBegin(offset) => vec![relative(offset)],
+        Instruction::JumpTable(_, ref offsets, default) => offsets
+            .iter()
+            .chain([&default])
+            .map(|&offset| relative(offset))
+            .collect(),
+        Instruction::Call(target) => vec![inside(target)],
+        _ => Vec::new(),
+    }
+}
+
+/// Format a single instruction as an assembly line. The target operands of
+/// jumps and calls use the entries of `targets` in operand order, where given,
+/// in place of their numeric offset or address.
+pub(crate) fn format_instruction(instruction: &Instruction, targets: &[Option<String>]) -> String {
+    let (mnemonic, operands) = instruction_parts(instruction, targets);
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{} {}", mnemonic, operands.join(", "))
+    }
+}
+
+/// The mnemonic and the formatted operands of an instruction, as
+/// `format_instruction` writes them.
+pub(crate) fn instruction_parts(
+    instruction: &Instruction,
+    targets: &[Option<String>],
+) -> (&'static str, Vec<String>) {
+    let r = |register: &usize| format!("r{}", register);
+    let vr = |register: &usize| format!("v{}", register);
+    let next_target = Cell::new(0);
+    let target = |offset: &dyn fmt::Display| {
+        let index = next_target.replace(next_target.get() + 1);
+        targets
+            .get(index)
+            .cloned()
+            .flatten()
+            .unwrap_or_else(|| offset.to_string())
+    };
+    match instruction {
+        Instruction::SetReg(register, value) => ("setw", vec![r(register), value.to_string()]),
+        Instruction::SetRegByte(register, byte) => {
+            ("setb", vec![r(register), (*byte as i8).to_string()])
+        }
+        Instruction::SetRegShifted(register, byte, shift) => (
+            "setsh",
+            vec![r(register), byte.to_string(), shift.to_string()],
+        ),
+        Instruction::Add(a, b, t) => ("add", vec![r(a), r(b), r(t)]),
+        Instruction::Sub(a, b, t) => ("sub", vec![r(a), r(b), r(t)]),
+        Instruction::Mul(a, b, t) => ("mul", vec![r(a), r(b), r(t)]),
+        Instruction::Div(a, b, t) => ("div", vec![r(a), r(b), r(t)]),
+        Instruction::Mod(a, b, t) => ("mod", vec![r(a), r(b), r(t)]),
+        Instruction::Eq(a, b, t) => ("eq", vec![r(a), r(b), r(t)]),
+        Instruction::Neq(a, b, t) => ("neq", vec![r(a), r(b), r(t)]),
+        Instruction::Gt(a, b, t) => ("gt", vec![r(a), r(b), r(t)]),
+        Instruction::Lt(a, b, t) => ("lt", vec![r(a), r(b), r(t)]),
+        Instruction::Gte(a, b, t) => ("gte", vec![r(a), r(b), r(t)]),
+        Instruction::Lte(a, b, t) => ("lte", vec![r(a), r(b), r(t)]),
+        Instruction::FAdd(a, b, t) => ("fadd", vec![r(a), r(b), r(t)]),
+        Instruction::FSub(a, b, t) => ("fsub", vec![r(a), r(b), r(t)]),
+        Instruction::FMul(a, b, t) => ("fmul", vec![r(a), r(b), r(t)]),
+        Instruction::FDiv(a, b, t) => ("fdiv", vec![r(a), r(b), r(t)]),
+        Instruction::Rol(a, b, t) => ("rol", vec![r(a), r(b), r(t)]),
+        Instruction::Ror(a, b, t) => ("ror", vec![r(a), r(b), r(t)]),
+        Instruction::IntToFloat(src, dst) => ("itof", vec![r(src), r(dst)]),
+        Instruction::FloatToInt(src, dst) => ("ftoi", vec![r(src), r(dst)]),
+        Instruction::Jump(offset) => ("jmp", vec![target(offset)]),
+        Instruction::JumpIfZero(register, offset) => ("jmpz", vec![r(register), target(offset)]),
+        Instruction::JumpIfNonZero(register, offset) => {
+            ("jmpnz", vec![r(register), target(offset)])
+        }
+        Instruction::Call(address) => ("call", vec![target(address)]),
+        Instruction::Return => ("ret", vec![]),
+        Instruction::Halt => ("halt", vec![]),
+        Instruction::Print(register) => ("print", vec![r(register)]),
+        Instruction::PrintU(register) => ("printu", vec![r(register)]),
+        Instruction::AllocateMemory(size, t) => ("alloc", vec![r(size), r(t)]),
+        Instruction::FreeMemory(address) => ("free", vec![address.to_string()]),
+        Instruction::StoreToMemory(address, register, offset) => (
+            "store",
+            vec![address.to_string(), r(register), offset.to_string()],
+        ),
+        Instruction::LoadFromMemory(address, offset, register) => (
+            "load",
+            vec![address.to_string(), offset.to_string(), r(register)],
+        ),
+        Instruction::SizeOf(address, register) => {
+            ("sizeof", vec![address.to_string(), r(register)])
+        }
+        Instruction::MemCopy(dst, src, len) => (
+            "memcpy",
+            vec![dst.to_string(), src.to_string(), len.to_string()],
+        ),
+        Instruction::MemFill(address, register, len) => (
+            "memfill",
+            vec![address.to_string(), r(register), len.to_string()],
+        ),
+        Instruction::Cas(address, offset, expected, new, result) => (
+            "cas",
+            vec![
+                address.to_string(),
+                offset.to_string(),
+                r(expected),
+                r(new),
+                r(result),
+            ],
+        ),
+        Instruction::Syscall(number) => ("syscall", vec![number.to_string()]),
+        Instruction::ClearRegs => ("clregs", vec![]),
+        Instruction::ClearReg(register) => ("clr", vec![r(register)]),
+        Instruction::AssertEq(register, expected) => {
+            ("asserteq", vec![r(register), expected.to_string()])
+        }
+        Instruction::And(a, b, t) => ("and", vec![r(a), r(b), r(t)]),
+        Instruction::Or(a, b, t) => ("or", vec![r(a), r(b), r(t)]),
+        Instruction::Xor(a, b, t) => ("xor", vec![r(a), r(b), r(t)]),
+        Instruction::Shl(a, b, t) => ("shl", vec![r(a), r(b), r(t)]),
+        Instruction::Shr(a, b, t) => ("shr", vec![r(a), r(b), r(t)]),
+        Instruction::Sar(a, b, t) => ("sar", vec![r(a), r(b), r(t)]),
+        Instruction::Not(src, dst) => ("not", vec![r(src), r(dst)]),
+        Instruction::AddImm(a, immediate, t) => ("addi", vec![r(a), immediate.to_string(), r(t)]),
+        Instruction::SubImm(a, immediate, t) => ("subi", vec![r(a), immediate.to_string(), r(t)]),
+        Instruction::MulImm(a, immediate, t) => ("muli", vec![r(a), immediate.to_string(), r(t)]),
+        Instruction::EqImm(a, immediate, t) => ("eqi", vec![r(a), immediate.to_string(), r(t)]),
+        Instruction::NeqImm(a, immediate, t) => ("neqi", vec![r(a), immediate.to_string(), r(t)]),
+        Instruction::GtImm(a, immediate, t) => ("gti", vec![r(a), immediate.to_string(), r(t)]),
+        Instruction::LtImm(a, immediate, t) => ("lti", vec![r(a), immediate.to_string(), r(t)]),
+        Instruction::GteImm(a, immediate, t) => ("gtei", vec![r(a), immediate.to_string(), r(t)]),
+        Instruction::LteImm(a, immediate, t) => ("ltei", vec![r(a), immediate.to_string(), r(t)]),
+        Instruction::StoreIndirect(a, o, s) => ("storex", vec![r(a), r(o), r(s)]),
+        Instruction::LoadIndirect(a, o, t) => ("loadx", vec![r(a), r(o), r(t)]),
+        Instruction::Store8(a, o, x) => ("st8", vec![r(a), r(o), r(x)]),
+        Instruction::Store16(a, o, x) => ("st16", vec![r(a), r(o), r(x)]),
+        Instruction::Store32(a, o, x) => ("st32", vec![r(a), r(o), r(x)]),
+        Instruction::Load8S(a, o, x) => ("ld8s", vec![r(a), r(o), r(x)]),
+        Instruction::Load8U(a, o, x) => ("ld8u", vec![r(a), r(o), r(x)]),
+        Instruction::Load16S(a, o, x) => ("ld16s", vec![r(a), r(o), r(x)]),
+        Instruction::Load16U(a, o, x) => ("ld16u", vec![r(a), r(o), r(x)]),
+        Instruction::Load32(a, o, x) => ("ld32", vec![r(a), r(o), r(x)]),
+        Instruction::ReadInt(register) => ("readi", vec![r(register)]),
+        Instruction::ReadByte(register) => ("readb", vec![r(register)]),
+        Instruction::PrintStr(address, len) => ("prints", vec![r(address), r(len)]),
+        Instruction::SetFReg(register, value) => {
+            ("setf", vec![r(register), format!("{:?}", value)])
+        }
+        Instruction::FCmp(a, b, t) => ("fcmp", vec![r(a), r(b), r(t)]),
+        Instruction::Push(register) => ("push", vec![r(register)]),
+        Instruction::Pop(register) => ("pop", vec![r(register)]),
+        Instruction::Dup => ("dup", vec![]),
+        Instruction::SwapTop => ("swap", vec![]),
+        Instruction::Enter(local_count) => ("enter", vec![local_count.to_string()]),
+        Instruction::Leave => ("leave", vec![]),
+        Instruction::LoadLocal(slot, register) => ("ldloc", vec![slot.to_string(), r(register)]),
+        Instruction::StoreLocal(register, slot) => ("stloc", vec![r(register), slot.to_string()]),
+        Instruction::CallReg(register) => ("callr", vec![r(register)]),
+        Instruction::CallTable(register) => ("callt", vec![r(register)]),
+        Instruction::JumpTable(register, offsets, default) => {
+            let table: Vec<_> = offsets.iter().map(|offset| target(offset)).collect();
+            let table = format!("[{}]", table.join(", "));
+            ("jmptbl", vec![r(register), table, target(default)])
+        }
+        Instruction::Brk(code) => ("brk", vec![code.to_string()]),
+        Instruction::Nop => ("nop", vec![]),
+        Instruction::Mov(src, dst) => ("mov", vec![r(src), r(dst)]),
+        Instruction::Neg(src, dst) => ("neg", vec![r(src), r(dst)]),
+        Instruction::Abs(src, dst) => ("abs", vec![r(src), r(dst)]),
+        Instruction::Min(a, b, t) => ("min", vec![r(a), r(b), r(t)]),
+        Instruction::Max(a, b, t) => ("max", vec![r(a), r(b), r(t)]),
+        Instruction::Popcount(src, dst) => ("popcnt", vec![r(src), r(dst)]),
+        Instruction::Clz(src, dst) => ("clz", vec![r(src), r(dst)]),
+        Instruction::Ctz(src, dst) => ("ctz", vec![r(src), r(dst)]),
+        Instruction::Cmp(a, b) => ("cmp", vec![r(a), r(b)]),
+        Instruction::CmpImm(a, immediate) => ("cmpi", vec![r(a), immediate.to_string()]),
+        Instruction::Jz(offset) => ("jz", vec![target(offset)]),
+        Instruction::Jnz(offset) => ("jnz", vec![target(offset)]),
+        Instruction::Jc(offset) => ("jc", vec![target(offset)]),
+        Instruction::Jo(offset) => ("jo", vec![target(offset)]),
+        Instruction::Jlt(offset) => ("jlt", vec![target(offset)]),
+        Instruction::Jge(offset) => ("jge", vec![target(offset)]),
+        Instruction::Adc(a, b, t) => ("adc", vec![r(a), r(b), r(t)]),
+        Instruction::Sbb(a, b, t) => ("sbb", vec![r(a), r(b), r(t)]),
+        Instruction::MulWide(a, b, high, low) => ("mulw", vec![r(a), r(b), r(high), r(low)]),
+        Instruction::DivU(a, b, t) => ("divu", vec![r(a), r(b), r(t)]),
+        Instruction::ModU(a, b, t) => ("modu", vec![r(a), r(b), r(t)]),
+        Instruction::LtU(a, b, t) => ("ltu", vec![r(a), r(b), r(t)]),
+        Instruction::GtU(a, b, t) => ("gtu", vec![r(a), r(b), r(t)]),
+        Instruction::LteU(a, b, t) => ("lteu", vec![r(a), r(b), r(t)]),
+        Instruction::GteU(a, b, t) => ("gteu", vec![r(a), r(b), r(t)]),
+        Instruction::SextByte(src, dst) => ("sextb", vec![r(src), r(dst)]),
+        Instruction::SextHalf(src, dst) => ("sexth", vec![r(src), r(dst)]),
+        Instruction::ZextByte(src, dst) => ("zextb", vec![r(src), r(dst)]),
+        Instruction::ZextHalf(src, dst) => ("zexth", vec![r(src), r(dst)]),
+        Instruction::Trunc(src, bits, dst) => ("trunc", vec![r(src), bits.to_string(), r(dst)]),
+        Instruction::FloatTrunc(src, dst) => ("ftrunc", vec![r(src), r(dst)]),
+        Instruction::FloatRound(src, dst) => ("fround", vec![r(src), r(dst)]),
+        Instruction::FloatFloor(src, dst) => ("ffloor", vec![r(src), r(dst)]),
+        Instruction::FloatCeil(src, dst) => ("fceil", vec![r(src), r(dst)]),
+        Instruction::VLoad(a, o, v) => ("vload", vec![r(a), r(o), vr(v)]),
+        Instruction::VStore(a, o, v) => ("vstore", vec![r(a), r(o), vr(v)]),
+        Instruction::VAdd4x32(a, b, t) => ("vadd", vec![vr(a), vr(b), vr(t)]),
+        Instruction::VMul4x32(a, b, t) => ("vmul", vec![vr(a), vr(b), vr(t)]),
+        Instruction::VExtract(v, lane, dst) => ("vextract", vec![vr(v), lane.to_string(), r(dst)]),
+        Instruction::VInsert(src, lane, v) => ("vinsert", vec![r(src), lane.to_string(), vr(v)]),
+        Instruction::VSplat(src, v) => ("vsplat", vec![r(src), vr(v)]),
+        Instruction::MemCopyReg(dst, src, len) => ("memcpyr", vec![r(dst), r(src), r(len)]),
+        Instruction::MemFillReg(address, value, len) => {
+            ("memfillr", vec![r(address), r(value), r(len)])
+        }
+        Instruction::ReallocMemory(address, size, t) => {
+            ("realloc", vec![r(address), r(size), r(t)])
+        }
+        Instruction::ProtectMemory(address, flags) => {
+            ("protect", vec![r(address), flags.to_string()])
+        }
+        Instruction::LoadGlobal(global, register) => {
+            ("loadg", vec![global.to_string(), r(register)])
+        }
+        Instruction::StoreGlobal(register, global) => {
+            ("storeg", vec![r(register), global.to_string()])
+        }
+        Instruction::PushSp(register) => ("pushsp", vec![r(register)]),
+        Instruction::PopSp(register) => ("popsp", vec![r(register)]),
+        Instruction::LoadSpOffset(offset, register) => {
+            ("ldsp", vec![offset.to_string(), r(register)])
+        }
+        Instruction::StoreSpOffset(register, offset) => {
+            ("stsp", vec![r(register), offset.to_string()])
+        }
+        Instruction::NewObject(size, register) => ("newobj", vec![size.to_string(), r(register)]),
+        Instruction::LoadSlot(object, slot, register) => {
+            ("ldslot", vec![r(object), slot.to_string(), r(register)])
+        }
+        Instruction::StoreSlot(register, object, slot) => {
+            ("stslot", vec![r(register), r(object), slot.to_string()])
+        }
+        Instruction::Retain(register) => ("retain", vec![r(register)]),
+        Instruction::Release(register) => ("release", vec![r(register)]),
+        Instruction::NewArray(length, register) => ("newarr", vec![r(length), r(register)]),
+        Instruction::ArrayGet(array, index, register) => {
+            ("aget", vec![r(array), r(index), r(register)])
+        }
+        Instruction::ArraySet(array, index, register) => {
+            ("aset", vec![r(array), r(index), r(register)])
+        }
+        Instruction::ArrayLen(array, register) => ("alen", vec![r(array), r(register)]),
+        Instruction::NewString(address, len, register) => {
+            ("newstr", vec![r(address), r(len), r(register)])
+        }
+        Instruction::StrConcat(first, second, register) => {
+            ("strcat", vec![r(first), r(second), r(register)])
+        }
+        Instruction::StrLen(string, register) => ("strlen", vec![r(string), r(register)]),
+        Instruction::StrByte(string, index, register) => {
+            ("strbyte", vec![r(string), r(index), r(register)])
+        }
+        Instruction::StrCmp(first, second, register) => {
+            ("strcmp", vec![r(first), r(second), r(register)])
+        }
+        Instruction::PrintString(string) => ("strprint", vec![r(string)]),
+        Instruction::MapNew(register) => ("mapnew", vec![r(register)]),
+        Instruction::MapGet(map, key, register) => ("mapget", vec![r(map), r(key), r(register)]),
+        Instruction::MapSet(map, key, register) => ("mapset", vec![r(map), r(key), r(register)]),
+        Instruction::MapDel(map, key) => ("mapdel", vec![r(map), r(key)]),
+        Instruction::MapHas(map, key, register) => ("maphas", vec![r(map), r(key), r(register)]),
+        Instruction::TypeOf(source, register) => ("typeof", vec![r(source), r(register)]),
+        Instruction::TryBegin(offset) => ("try", vec![target(offset)]),
+        Instruction::TryEnd => ("tryend", vec![]),
+        Instruction::Throw(register) => ("throw", vec![r(register)]),
+    }
+}
+
+/// Instructions display as a line of assembly, with jump and call targets as
+/// raw offsets.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format_instruction(self, &[]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{assemble, assemble_program};
+
+    #[test]
+    fn test_disassemble_listing() {
+        let program = vec![
+            Instruction::SetRegByte(0, 0xFF),
+            Instruction::JumpIfZero(0, 1),
+            Instruction::Print(0),
+            Instruction::Call(5), // Past the end, kept as an address
+            Instruction::Halt,
+        ];
+
+        assert_eq!(
+            disassemble(&program),
+            "    setb r0, -1                 ; 0000\n\
+             \x20   jmpz r0, L0003              ; 0001\n\
+             \x20   print r0                    ; 0002\n\
+             L0003:\n\
+             \x20   call 5                      ; 0003\n\
+             \x20   halt                        ; 0004\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_round_trip() {
+        let program = vec![
+            Instruction::SetReg(0, 100000),
+            Instruction::SetReg(1, 5),
+            Instruction::SetRegShifted(2, 0xAB, 16),
+            Instruction::Cas(0, 4, 1, 2, 3),
+            Instruction::JumpIfNonZero(3, 2),
+            Instruction::MemFill(0, 1, 8),
+            Instruction::AssertEq(1, -5),
+            Instruction::Call(0),
+            Instruction::Jump(-9), // Back to the start
+            Instruction::Return,
+            Instruction::SetFReg(4, 0.1), // Printed with enough digits to read back exactly
+            Instruction::JumpTable(4, vec![-12, 0, 5], -2), // One target past the end
+            Instruction::JumpTable(4, vec![], 0),
+            Instruction::Brk(2),
+            Instruction::Nop,
+            Instruction::Mov(1, 2),
+            Instruction::Neg(1, 1),
+            Instruction::Max(0, 1, 2),
+            Instruction::Popcount(2, 3),
+            Instruction::CmpImm(2, -7),
+            Instruction::Jlt(-3),
+            Instruction::Jc(0),
+            Instruction::MulWide(0, 1, 2, 3),
+            Instruction::VLoad(0, 1, 7),
+            Instruction::VExtract(7, 3, 2),
+        ];
+
+        assert_eq!(assemble(&disassemble(&program)).unwrap(), program);
+    }
+
+    #[test]
+    fn test_disassemble_symbols() {
+        let program = Program {
+            instructions: vec![
+                Instruction::Call(3),
+                Instruction::Jump(0),
+                Instruction::Halt,
+                Instruction::Return,
+            ],
+            functions: vec![3],
+            symbols: BTreeMap::from([
+                (0, "main".to_string()),
+                (2, "not a label".to_string()),
+                (3, "square".to_string()),
+            ]),
+            ..Program::default()
+        };
+
+        assert_eq!(
+            disassemble_program(&program),
+            ".functions square\n\
+             main:\n\
+             \x20   call square                 ; 0000\n\
+             \x20   jmp L0002                   ; 0001\n\
+             L0002:\n\
+             \x20   halt                        ; 0002\n\
+             square:\n\
+             \x20   ret                         ; 0003\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_program_round_trip() {
+        let sources = [
+            include_str!("../programs/countdown.vmasm"),
+            include_str!("../programs/hello.vmasm"),
+            include_str!("../programs/max.vmasm"),
+            include_str!("../programs/strings.vmasm"),
+            "
+            .global counter: i32 = 5
+            .global scale: f64 = 0.1
+            .global big: i64 = -0x100000000
+            .global nothing: nil
+            .functions square, square
+            .data
+            .byte 0, 1, 0xFF
+            .string \"say \\\"hi\\\"\\n\\tand\\\\bye\"
+            .byte 7
+            .text
+                loadg counter, r0
+                callt r0
+                storeg r0, counter
+                halt
+            square: mul r0, r0, r0
+                ret
+            ",
+        ];
+        for source in sources {
+            let program = assemble_program(source).unwrap();
+            let program = Program::from_bytes(&program.to_bytes()).unwrap();
+            let listing = disassemble_program(&program);
+            let reassembled = assemble_program(&listing).unwrap();
+            assert_eq!(reassembled, program, "{}", listing);
+        }
+
+        let listing = disassemble_program(&assemble_program(include_str!("../programs/hello.vmasm")).unwrap());
+        assert!(listing.starts_with(".data\nD0000:\n    .string \"Hello, world!\\n\"\n.text\n"));
+    }
+}
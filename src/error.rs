@@ -0,0 +1,202 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Errors raised while executing a program.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum VmError {
+    TimedOut,                     // The wall-clock time limit was exceeded
+    UnknownSyscall(usize),        // No handler is registered for the syscall number
+    InvalidRegister(usize),       // Register index out of range
+    InvalidVectorRegister(usize), // Vector register index out of range
+    InvalidLane(u8),              // VExtract or VInsert of a lane other than 0 to 3
+    TypeMismatch(usize), // Register holds an integer where a float is expected, or vice versa
+    InvalidShift(u8),    // SetRegShifted would shift set bits out of the register
+    InvalidWidth(u8),    // Trunc to a bit width outside 1 to 32
+    Overflow,            // Integer overflow in ArithmeticMode::Checked
+    DivisionByZero,      // Div or Mod with a zero divisor
+    EndOfInput,          // ReadInt found no more input
+    InputError(String),  // Reading input failed, or ReadInt got something that isn't an integer
+    NoMemoryRegion(usize), // No memory region at (or containing) the address
+    OutOfBounds(usize),  // Access starting at the address runs into unallocated memory
+    ReadOnlyMemory(usize), // Write to or free of the data section at the address
+    UnreadableMemory(usize), // Read from a region protected against reads at the address
+    InvalidProtection(u8), // ProtectMemory flags with bits other than read and write set
+    UseAfterFree(usize), // Access to a quarantined freed region at the address
+    DoubleFree(usize),   // Free of a quarantined region that was already freed
+    PageFault(usize),    // Access to an unmapped page at the address, with paging enabled
+    OutOfMemory(usize), // Allocation of that many bytes that doesn't fit into the heap segment, the heap limit or the 32-bit address space
+    InvalidSize(i32),   // AllocateMemory or ReallocMemory of a negative size
+    UninitializedRead {
+        address: usize, // Base address of the region
+        offset: usize,  // First byte read that was never written
+    },
+    InvalidJump(isize),        // Jump target outside the program
+    InvalidFunctionIndex(i32), // CallTable index outside the function table
+    StackUnderflow,            // Return with no active Call, or PopSp with an empty memory stack
+    StackOverflow, // Call nested deeper than the VM's call-depth limit, or PushSp onto a full memory stack
+    DataStackUnderflow, // Pop, Dup or SwapTop with too few values on the data stack
+    DataStackOverflow, // Push onto a full data stack
+    NoFrame,       // Leave, LoadLocal or StoreLocal outside of any Enter
+    NoHandler,     // TryEnd without a TryBegin in the current call
+    UncaughtException(i32), // Throw with no exception handler installed, with the code thrown
+    InvalidLocal(usize), // Local slot beyond the size of the current frame
+    InvalidGlobal(usize), // Global index beyond the globals the program declares
+    NoMemoryStack, // An SP instruction before `VM::enable_memory_stack`
+    InvalidReference(usize), // Reference to an object handle that isn't live
+    IndexOutOfBounds(usize), // Object slot beyond the object's size
+    MissingKey(String), // MapGet of a key the map doesn't hold, as it displays
+    ReplayMismatch(usize), // Input or syscall at the address that doesn't match the next replayed event
+    Break(usize),          // A Brk instruction with its trap code
+    AssertionFailed {
+        ip: usize,       // Address of the failing AssertEq
+        register: usize, // Register that was checked
+        expected: i32,
+        actual: i32,
+    },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::TimedOut => write!(f, "time limit exceeded"),
+            VmError::Overflow => write!(f, "integer overflow"),
+            VmError::DivisionByZero => write!(f, "division by zero"),
+            VmError::EndOfInput => write!(f, "end of input"),
+            VmError::InputError(message) => write!(f, "cannot read input: {}", message),
+            VmError::UnknownSyscall(number) => write!(f, "unknown syscall {}", number),
+            VmError::InvalidRegister(register) => write!(f, "invalid register index {}", register),
+            VmError::InvalidVectorRegister(register) => {
+                write!(f, "invalid vector register index {}", register)
+            }
+            VmError::InvalidLane(lane) => write!(f, "invalid vector lane {}", lane),
+            VmError::TypeMismatch(register) => {
+                write!(f, "register {} holds a value of the wrong type", register)
+            }
+            VmError::InvalidShift(shift) => write!(f, "invalid shift amount {}", shift),
+            VmError::InvalidWidth(bits) => write!(f, "invalid bit width {}", bits),
+            VmError::NoMemoryRegion(address) => {
+                write!(f, "no memory region found at address {}", address)
+            }
+            VmError::OutOfBounds(address) => {
+                write!(f, "memory access at address {} is out of bounds", address)
+            }
+            VmError::ReadOnlyMemory(address) => {
+                write!(f, "memory at address {} is read-only", address)
+            }
+            VmError::UnreadableMemory(address) => {
+                write!(f, "memory at address {} can't be read", address)
+            }
+            VmError::InvalidProtection(bits) => write!(f, "invalid protection flags {:#04b}", bits),
+            VmError::UseAfterFree(address) => {
+                write!(f, "use of freed memory at address {}", address)
+            }
+            VmError::DoubleFree(address) => {
+                write!(f, "memory at address {} was already freed", address)
+            }
+            VmError::PageFault(address) => write!(f, "page fault at address {}", address),
+            VmError::OutOfMemory(size) => write!(f, "out of memory allocating {} bytes", size),
+            VmError::InvalidSize(size) => write!(f, "invalid allocation size {}", size),
+            VmError::UninitializedRead { address, offset } => write!(
+                f,
+                "read of uninitialized memory at offset {} of the region at address {}",
+                offset, address
+            ),
+            VmError::InvalidJump(target) => write!(f, "invalid jump target {}", target),
+            VmError::InvalidFunctionIndex(index) => {
+                write!(f, "no function at index {} of the function table", index)
+            }
+            VmError::StackUnderflow => write!(
+                f,
+                "return without a matching call, or pop of an empty stack"
+            ),
+            VmError::StackOverflow => write!(f, "stack overflow"),
+            VmError::DataStackUnderflow => write!(f, "not enough values on the data stack"),
+            VmError::DataStackOverflow => write!(f, "data stack overflow"),
+            VmError::NoFrame => write!(f, "no active stack frame"),
+            VmError::NoHandler => write!(f, "no exception handler installed by this call"),
+            VmError::UncaughtException(code) => write!(f, "uncaught exception {}", code),
+            VmError::InvalidLocal(slot) => write!(f, "invalid local slot {}", slot),
+            VmError::InvalidGlobal(global) => write!(f, "invalid global index {}", global),
+            VmError::NoMemoryStack => write!(f, "no memory stack is enabled"),
+            VmError::InvalidReference(handle) => write!(f, "invalid object reference {}", handle),
+            VmError::IndexOutOfBounds(index) => write!(f, "object index {} out of bounds", index),
+            VmError::MissingKey(key) => write!(f, "no key {} in the map", key),
+            VmError::ReplayMismatch(ip) => {
+                write!(f, "instruction {} doesn't match the replayed input log", ip)
+            }
+            VmError::Break(code) => write!(f, "break instruction with code {}", code),
+            VmError::AssertionFailed {
+                ip,
+                register,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "assertion failed at instruction {}: register {} is {}, expected {}",
+                ip, register, actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+// Innermost calls a trap's display lists before summing up the rest.
+const CALL_STACK_DUMP_LIMIT: usize = 16;
+
+/// The error that trapped a VM and the address of the instruction that raised
+/// it, kept by the VM until a snapshot without one is restored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trap {
+    pub ip: usize,
+    pub error: VmError,
+    #[serde(default)]
+    pub backtrace: Vec<Frame>, // The trapping instruction, then the call of each active function
+}
+
+/// An instruction of a backtrace and the function it lies in, the nearest
+/// symbol of the program at or before its address.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Frame {
+    pub address: usize,
+    pub function: Option<String>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}", self.address)?;
+        match &self.function {
+            Some(function) => write!(f, " in {}", function),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "instruction {}", self.ip)?;
+        if let Some(function) = self
+            .backtrace
+            .first()
+            .and_then(|frame| frame.function.as_ref())
+        {
+            write!(f, " in {}", function)?;
+        }
+        write!(f, ": {}", self.error)?;
+        let calls = self.backtrace.get(1..).unwrap_or_default();
+        for frame in calls.iter().take(CALL_STACK_DUMP_LIMIT) {
+            write!(f, "\n  called from {}", frame)?;
+        }
+        if calls.len() > CALL_STACK_DUMP_LIMIT {
+            write!(
+                f,
+                "\n  ... {} more frames",
+                calls.len() - CALL_STACK_DUMP_LIMIT
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Trap {}
@@ -0,0 +1,261 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::VmError;
+use crate::value::Value;
+
+// Bytes of a string that take up as much of the heap as one slot.
+const STRING_BYTES_PER_SLOT: usize = 8;
+
+/// The garbage-collected heap of objects created with `NewObject`, `NewArray`,
+/// `NewString` and `MapNew`, read with `VM::objects()`. An object is a fixed
+/// number of slots, each holding an integer, a float or a reference to another
+/// object; an array is an object whose length is chosen at run time. A string is
+/// an immutable sequence of bytes, taking a slot per started 8 bytes. A map
+/// holds values by key, taking a slot per entry plus the size of its key. Objects are
+/// never freed by the program: when a new one doesn't fit, the VM marks every
+/// object its registers, stacks, locals and globals reach and sweeps the rest.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct ObjectHeap {
+    objects: Vec<Option<Object>>, // By handle, `None` once collected
+    free: Vec<u32>,               // Handles of collected objects, reused first
+    used_slots: usize,            // Slots of all live objects together
+    pub collections: u64,         // Collections run so far
+    pub collected: u64,           // Objects swept by them
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Object {
+    Slots(Vec<Value>),
+    String(Vec<u8>),
+    Map(Vec<(MapKey, Value)>), // In key order, a list so snapshots can be JSON
+}
+
+impl Object {
+    fn size(&self) -> usize {
+        match self {
+            Object::Slots(slots) => slots.len(),
+            Object::String(bytes) => string_size(bytes.len()),
+            Object::Map(entries) => entries.iter().map(|(key, _)| key.entry_size()).sum(),
+        }
+    }
+}
+
+/// A key of a map: an integer, or the bytes of a string, so two strings with
+/// the same contents are the same key.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum MapKey {
+    Int(i32),
+    String(Vec<u8>),
+}
+
+impl MapKey {
+    /// The heap slots an entry with this key takes up, its value included.
+    pub(crate) fn entry_size(&self) -> usize {
+        1 + match self {
+            MapKey::Int(_) => 1,
+            MapKey::String(bytes) => string_size(bytes.len()),
+        }
+    }
+}
+
+impl fmt::Display for MapKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapKey::Int(value) => write!(f, "{}", value),
+            MapKey::String(bytes) => write!(f, "{:?}", String::from_utf8_lossy(bytes)),
+        }
+    }
+}
+
+/// The heap slots a string of `len` bytes takes up.
+pub(crate) fn string_size(len: usize) -> usize {
+    len.div_ceil(STRING_BYTES_PER_SLOT)
+}
+
+impl ObjectHeap {
+    /// The number of live objects.
+    pub fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The slots of all live objects together.
+    pub fn used_slots(&self) -> usize {
+        self.used_slots
+    }
+
+    /// The slots of the object `handle` refers to, if it is a live object or
+    /// array.
+    pub fn get(&self, handle: u32) -> Option<&[Value]> {
+        match self.objects.get(handle as usize)? {
+            Some(Object::Slots(slots)) => Some(slots),
+            _ => None,
+        }
+    }
+
+    /// The bytes of the string `handle` refers to, if it is a live string.
+    pub fn string(&self, handle: u32) -> Option<&[u8]> {
+        match self.objects.get(handle as usize)? {
+            Some(Object::String(bytes)) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// The entries of the map `handle` refers to, in key order, if it is a live
+    /// map.
+    pub fn map(&self, handle: u32) -> Option<&[(MapKey, Value)]> {
+        match self.objects.get(handle as usize)? {
+            Some(Object::Map(entries)) => Some(entries),
+            _ => None,
+        }
+    }
+
+    /// Whether `handle` refers to a live object of any kind.
+    pub fn is_live(&self, handle: u32) -> bool {
+        matches!(self.objects.get(handle as usize), Some(Some(_)))
+    }
+
+    fn insert(&mut self, object: Object) -> Result<u32, VmError> {
+        let size = object.size();
+        let handle = match self.free.pop() {
+            Some(handle) => {
+                self.objects[handle as usize] = Some(object);
+                handle
+            }
+            None => {
+                let handle =
+                    u32::try_from(self.objects.len()).map_err(|_| VmError::OutOfMemory(size))?;
+                self.objects.push(Some(object));
+                handle
+            }
+        };
+        self.used_slots += size;
+        Ok(handle)
+    }
+
+    /// A new object of `size` slots, all integer zero.
+    pub(crate) fn allocate(&mut self, size: usize) -> Result<u32, VmError> {
+        self.insert(Object::Slots(vec![Value::default(); size]))
+    }
+
+    /// A new string holding `bytes`.
+    pub(crate) fn allocate_string(&mut self, bytes: Vec<u8>) -> Result<u32, VmError> {
+        self.insert(Object::String(bytes))
+    }
+
+    /// A new, empty map.
+    pub(crate) fn allocate_map(&mut self) -> Result<u32, VmError> {
+        self.insert(Object::Map(Vec::new()))
+    }
+
+    fn map_mut(&mut self, handle: u32) -> Result<&mut Vec<(MapKey, Value)>, VmError> {
+        match self.objects.get_mut(handle as usize) {
+            Some(Some(Object::Map(entries))) => Ok(entries),
+            _ => Err(VmError::InvalidReference(handle as usize)),
+        }
+    }
+
+    pub(crate) fn map_get(&self, handle: u32, key: &MapKey) -> Result<Option<Value>, VmError> {
+        let entries = self
+            .map(handle)
+            .ok_or(VmError::InvalidReference(handle as usize))?;
+        Ok(entries
+            .binary_search_by(|(other, _)| other.cmp(key))
+            .ok()
+            .map(|index| entries[index].1))
+    }
+
+    pub(crate) fn map_set(
+        &mut self,
+        handle: u32,
+        key: MapKey,
+        value: Value,
+    ) -> Result<(), VmError> {
+        let entries = self.map_mut(handle)?;
+        match entries.binary_search_by(|(other, _)| other.cmp(&key)) {
+            Ok(index) => entries[index].1 = value,
+            Err(index) => {
+                let size = key.entry_size();
+                entries.insert(index, (key, value));
+                self.used_slots += size;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn map_remove(&mut self, handle: u32, key: &MapKey) -> Result<(), VmError> {
+        let entries = self.map_mut(handle)?;
+        if let Ok(index) = entries.binary_search_by(|(other, _)| other.cmp(key)) {
+            entries.remove(index);
+            self.used_slots -= key.entry_size();
+        }
+        Ok(())
+    }
+
+    fn slots_mut(&mut self, handle: u32) -> Result<&mut Vec<Value>, VmError> {
+        match self.objects.get_mut(handle as usize) {
+            Some(Some(Object::Slots(slots))) => Ok(slots),
+            _ => Err(VmError::InvalidReference(handle as usize)),
+        }
+    }
+
+    pub(crate) fn slot(&self, handle: u32, index: usize) -> Result<Value, VmError> {
+        let slots = self
+            .get(handle)
+            .ok_or(VmError::InvalidReference(handle as usize))?;
+        slots
+            .get(index)
+            .copied()
+            .ok_or(VmError::IndexOutOfBounds(index))
+    }
+
+    pub(crate) fn set_slot(
+        &mut self,
+        handle: u32,
+        index: usize,
+        value: Value,
+    ) -> Result<(), VmError> {
+        let slot = self
+            .slots_mut(handle)?
+            .get_mut(index)
+            .ok_or(VmError::IndexOutOfBounds(index))?;
+        *slot = value;
+        Ok(())
+    }
+
+    /// Sweep every object that isn't reachable from `roots`, returning how many
+    /// were collected.
+    pub(crate) fn collect<'a>(&mut self, roots: impl IntoIterator<Item = &'a Value>) -> usize {
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending: Vec<u32> = roots.into_iter().filter_map(Value::reference).collect();
+        while let Some(handle) = pending.pop() {
+            if !self.is_live(handle) || std::mem::replace(&mut marked[handle as usize], true) {
+                continue; // Dangling references keep nothing alive
+            }
+            if let Some(slots) = self.get(handle) {
+                pending.extend(slots.iter().filter_map(Value::reference));
+            }
+            if let Some(entries) = self.map(handle) {
+                pending.extend(entries.iter().filter_map(|(_, value)| value.reference()));
+            }
+        }
+        let mut swept = 0;
+        for (handle, object) in self.objects.iter_mut().enumerate() {
+            if !marked[handle] {
+                if let Some(object) = object.take() {
+                    self.used_slots -= object.size();
+                    self.free.push(handle as u32);
+                    swept += 1;
+                }
+            }
+        }
+        self.collections += 1;
+        self.collected += swept as u64;
+        swept
+    }
+}
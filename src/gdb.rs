@@ -0,0 +1,408 @@
+use std::convert::Infallible;
+use std::io;
+use std::marker::PhantomData;
+use std::net::TcpStream;
+
+use gdbstub::arch::{Arch, Registers};
+use gdbstub::common::Signal;
+use gdbstub::conn::ConnectionExt;
+use gdbstub::stub::run_blocking::{BlockingEventLoop, Event, WaitForStopReasonError};
+use gdbstub::stub::{GdbStub, SingleThreadStopReason};
+use gdbstub::target::ext::base::singlethread::{
+    SingleThreadBase, SingleThreadResume, SingleThreadResumeOps, SingleThreadSingleStep,
+    SingleThreadSingleStepOps,
+};
+use gdbstub::target::ext::base::BaseOps;
+use gdbstub::target::ext::breakpoints::{
+    Breakpoints, BreakpointsOps, SwBreakpoint, SwBreakpointOps,
+};
+use gdbstub::target::ext::target_description_xml_override::{
+    TargetDescriptionXmlOverride, TargetDescriptionXmlOverrideOps,
+};
+use gdbstub::target::{Target, TargetError, TargetResult};
+
+use crate::debugger::{Debugger, Stop};
+use crate::value::Value;
+use crate::vm::{VmState, VM};
+
+// Instructions a `continue` executes between checks for an interrupt (Ctrl-C)
+// from the client.
+const INTERRUPT_CHECK_INTERVAL: usize = 1024;
+
+/// Serve a GDB remote serial protocol session for a VM on a connection, so
+/// existing debugger front ends can drive it: read and write the registers,
+/// read memory, set breakpoints, step and continue. Returns when the client
+/// detaches or kills the VM, or the program ends.
+///
+/// The target has its own architecture: `r0` to `rN` are 64 bits wide, holding
+/// an integer sign-extended or a float's bits, followed by a 32-bit `pc` that is
+/// the instruction index. Memory addresses are the VM's data memory; the program
+/// itself isn't addressable.
+pub fn serve_gdb(vm: &mut VM, connection: TcpStream) -> io::Result<()> {
+    let mut target = GdbTarget::new(vm);
+    GdbStub::new(connection)
+        .run_blocking::<GdbEventLoop>(&mut target)
+        .map(|_| ())
+        .map_err(|error| io::Error::other(error.to_string()))
+}
+
+enum VmArch {}
+
+impl Arch for VmArch {
+    type Usize = u32;
+    type Registers = GdbRegisters;
+    type BreakpointKind = usize;
+    type RegId = ();
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct GdbRegisters {
+    registers: Vec<u64>,
+    pc: u32,
+}
+
+impl Registers for GdbRegisters {
+    type ProgramCounter = u32;
+
+    fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        for register in &self.registers {
+            register
+                .to_le_bytes()
+                .into_iter()
+                .for_each(|byte| write_byte(Some(byte)));
+        }
+        self.pc
+            .to_le_bytes()
+            .into_iter()
+            .for_each(|byte| write_byte(Some(byte)));
+    }
+
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        let (registers, pc) = bytes
+            .split_at_checked(bytes.len().wrapping_sub(4))
+            .ok_or(())?;
+        if registers.len() % 8 != 0 {
+            return Err(());
+        }
+        self.registers = registers
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().expect("chunks are 8 bytes")))
+            .collect();
+        self.pc = u32::from_le_bytes(pc.try_into().map_err(|_| ())?);
+        Ok(())
+    }
+}
+
+// What the client asked for with its last resume packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    Step,
+}
+
+struct GdbTarget<'a> {
+    debugger: Debugger<'a>,
+    resume: Resume,
+    description: String, // Target description XML, listing the VM's registers
+}
+
+impl<'a> GdbTarget<'a> {
+    fn new(vm: &'a mut VM) -> Self {
+        let mut description = String::from(concat!(
+            "<?xml version=\"1.0\"?>\n",
+            "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+            "<target version=\"1.0\">\n",
+            "  <feature name=\"org.basic_rust_vm.core\">\n",
+        ));
+        for index in 0..vm.registers().len() {
+            description += &format!(
+                "    <reg name=\"r{}\" bitsize=\"64\" type=\"int64\"/>\n",
+                index
+            );
+        }
+        description += "    <reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\"/>\n";
+        description += "  </feature>\n</target>\n";
+        GdbTarget {
+            debugger: Debugger::attach(vm),
+            resume: Resume::Continue,
+            description,
+        }
+    }
+
+    // Execute one instruction, returning why execution stopped if it did.
+    fn step(&mut self) -> Option<SingleThreadStopReason<u32>> {
+        match self.debugger.step() {
+            Ok(Stop::Step) => None,
+            Ok(Stop::Breakpoint(_)) => Some(SingleThreadStopReason::SwBreak(())),
+            Ok(Stop::Watchpoint(_) | Stop::Brk { .. }) => {
+                Some(SingleThreadStopReason::Signal(Signal::SIGTRAP))
+            }
+            Ok(Stop::Exited(VmState::Halted | VmState::EndOfProgram)) => {
+                Some(SingleThreadStopReason::Exited(0))
+            }
+            Ok(Stop::Exited(VmState::OutOfFuel)) => {
+                Some(SingleThreadStopReason::Signal(Signal::SIGXCPU))
+            }
+            // A trap leaves the VM inspectable, like a faulting process.
+            Ok(Stop::Exited(_)) | Err(_) => Some(SingleThreadStopReason::Signal(Signal::SIGSEGV)),
+        }
+    }
+}
+
+// Integers are sign-extended so negative values read back as such.
+fn register_bits(value: Value) -> u64 {
+    match value {
+        Value::Int(value) => value as i64 as u64,
+        Value::Float(value) => value.to_bits(),
+        Value::Ref(handle) => u64::from(handle),
+        Value::Int64(value) => value as u64,
+        Value::Nil => 0,
+    }
+}
+
+impl Target for GdbTarget<'_> {
+    type Arch = VmArch;
+    type Error = Infallible;
+
+    fn base_ops(&mut self) -> BaseOps<'_, VmArch, Infallible> {
+        BaseOps::SingleThread(self)
+    }
+
+    fn support_breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_target_description_xml_override(
+        &mut self,
+    ) -> Option<TargetDescriptionXmlOverrideOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadBase for GdbTarget<'_> {
+    fn read_registers(&mut self, registers: &mut GdbRegisters) -> TargetResult<(), Self> {
+        let vm = self.debugger.vm();
+        registers.registers = vm.registers().iter().copied().map(register_bits).collect();
+        registers.pc = vm.ip() as u32;
+        Ok(())
+    }
+
+    // A register keeps its type: integers and references take the low 32 bits
+    // of the new value, 64-bit integers and floats all 64, and nil stays nil.
+    // The pc can't be changed.
+    fn write_registers(&mut self, registers: &GdbRegisters) -> TargetResult<(), Self> {
+        let vm = self.debugger.vm_mut();
+        if registers.pc as usize != vm.ip() || registers.registers.len() != vm.registers().len() {
+            return Err(TargetError::NonFatal);
+        }
+        for (register, &bits) in vm.registers_mut().iter_mut().zip(&registers.registers) {
+            *register = match register {
+                Value::Int(_) => Value::Int(bits as i32),
+                Value::Float(_) => Value::Float(f64::from_bits(bits)),
+                Value::Ref(_) => Value::Ref(bits as u32),
+                Value::Int64(_) => Value::Int64(bits as i64),
+                Value::Nil => Value::Nil,
+            };
+        }
+        Ok(())
+    }
+
+    // Reads stop at the first byte outside allocated memory.
+    fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<usize, Self> {
+        let memory = self.debugger.vm().memory().borrow();
+        let mut read = 0;
+        for (address, byte) in (start_addr as usize..).zip(data.iter_mut()) {
+            match memory.bytes(address, 1) {
+                Ok(bytes) => *byte = bytes[0],
+                Err(_) => break,
+            }
+            read += 1;
+        }
+        if read == 0 {
+            return Err(TargetError::NonFatal);
+        }
+        Ok(read)
+    }
+
+    // Memory is only changed by the program.
+    fn write_addrs(&mut self, _start_addr: u32, _data: &[u8]) -> TargetResult<(), Self> {
+        Err(TargetError::NonFatal)
+    }
+
+    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadResume for GdbTarget<'_> {
+    fn resume(&mut self, _signal: Option<Signal>) -> Result<(), Infallible> {
+        self.resume = Resume::Continue;
+        Ok(())
+    }
+
+    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadSingleStep for GdbTarget<'_> {
+    fn step(&mut self, _signal: Option<Signal>) -> Result<(), Infallible> {
+        self.resume = Resume::Step;
+        Ok(())
+    }
+}
+
+impl Breakpoints for GdbTarget<'_> {
+    fn support_sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SwBreakpoint for GdbTarget<'_> {
+    fn add_sw_breakpoint(&mut self, address: u32, _kind: usize) -> TargetResult<bool, Self> {
+        self.debugger.add_breakpoint(address as usize);
+        Ok(true)
+    }
+
+    fn remove_sw_breakpoint(&mut self, address: u32, _kind: usize) -> TargetResult<bool, Self> {
+        Ok(self.debugger.remove_breakpoint(address as usize))
+    }
+}
+
+impl TargetDescriptionXmlOverride for GdbTarget<'_> {
+    fn target_description_xml(
+        &self,
+        annex: &[u8],
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        if annex != b"target.xml" {
+            return Err(TargetError::NonFatal);
+        }
+        let xml = self.description.as_bytes();
+        let start = usize::try_from(offset).unwrap_or(usize::MAX).min(xml.len());
+        let end = start.saturating_add(length.min(buf.len())).min(xml.len());
+        buf[..end - start].copy_from_slice(&xml[start..end]);
+        Ok(end - start)
+    }
+}
+
+struct GdbEventLoop<'a>(PhantomData<&'a mut VM>);
+
+impl<'a> BlockingEventLoop for GdbEventLoop<'a> {
+    type Target = GdbTarget<'a>;
+    type Connection = TcpStream;
+    type StopReason = SingleThreadStopReason<u32>;
+
+    fn wait_for_stop_reason(
+        target: &mut GdbTarget<'a>,
+        connection: &mut TcpStream,
+    ) -> Result<Event<Self::StopReason>, WaitForStopReasonError<Infallible, io::Error>> {
+        if target.resume == Resume::Step {
+            let stop = target.step().unwrap_or(SingleThreadStopReason::DoneStep);
+            return Ok(Event::TargetStopped(stop));
+        }
+        loop {
+            for _ in 0..INTERRUPT_CHECK_INTERVAL {
+                if let Some(stop) = target.step() {
+                    return Ok(Event::TargetStopped(stop));
+                }
+            }
+            if connection
+                .peek()
+                .map_err(WaitForStopReasonError::Connection)?
+                .is_some()
+            {
+                let byte = connection
+                    .read()
+                    .map_err(WaitForStopReasonError::Connection)?;
+                return Ok(Event::IncomingData(byte));
+            }
+        }
+    }
+
+    fn on_interrupt(_target: &mut GdbTarget<'a>) -> Result<Option<Self::StopReason>, Infallible> {
+        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use crate::isa::Instruction;
+
+    use super::*;
+
+    // Send a packet and return the body of the reply, acknowledging it and
+    // expanding its run-length encoding (`x*n` repeats `x` another `n - 29` times).
+    fn packet(stream: &mut TcpStream, body: &str) -> String {
+        let checksum = body.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(stream, "${}#{:02x}", body, checksum).unwrap();
+        let mut bytes = std::iter::from_fn(|| {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            Some(byte[0])
+        });
+        bytes.by_ref().find(|&byte| byte == b'$'); // Skip the acknowledgement
+        let reply: Vec<u8> = bytes.by_ref().take_while(|&byte| byte != b'#').collect();
+        bytes.by_ref().take(2).for_each(drop); // The reply's checksum
+        stream.write_all(b"+").unwrap();
+        let mut body = Vec::new();
+        let mut reply = reply.into_iter();
+        while let Some(byte) = reply.next() {
+            if byte == b'*' {
+                let last = *body.last().unwrap();
+                let count = reply.next().unwrap() - 29;
+                body.extend((0..count).map(|_| last));
+            } else {
+                body.push(byte);
+            }
+        }
+        String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            [
+                "g", "Z0,4,0", "c", "g", "m0,2", "m10,1", "s", "c",
+            ]
+            .map(|body| packet(&mut stream, body))
+        });
+
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(1, 4),
+            Instruction::AllocateMemory(1, 1), // Address 0 into r1
+            Instruction::SetReg(0, -2),
+            Instruction::StoreToMemory(0, 0, 0),
+            Instruction::Print(0),
+            Instruction::Halt,
+        ]);
+        vm.set_output(io::sink());
+        let (stream, _) = listener.accept().unwrap();
+        serve_gdb(&mut vm, stream).unwrap();
+
+        let replies = client.join().unwrap();
+        assert_eq!(replies[0], "0".repeat(8 * 16 + 8));
+        assert_eq!(replies[1], "OK");
+        assert!(replies[2].starts_with("T05"), "{}", replies[2]);
+        assert!(replies[3].starts_with("feffffffffffffff"));
+        assert!(replies[3].ends_with("04000000"));
+        assert_eq!(replies[4], "fe00");
+        assert!(replies[5].starts_with('E'));
+        assert_eq!(replies[6], "S05");
+        assert_eq!(replies[7], "W00");
+        assert_eq!(vm.state(), VmState::Halted);
+    }
+}
@@ -0,0 +1,819 @@
+/// The operations supported by the VM.
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    SetReg(usize, i32),                     // Store value directly into a register
+    Add(usize, usize, usize), // Add values from two registers and store in a target register
+    Sub(usize, usize, usize), // Subtract values from two registers and store in a target register
+    Mul(usize, usize, usize), // Multiply values from two registers and store in a target register
+    Div(usize, usize, usize), // Divide values from two registers and store in a target register
+    Mod(usize, usize, usize), // Modulo values from two registers and store in a target register
+    Eq(usize, usize, usize), // Check equality of two registers and store result in a target register
+    Neq(usize, usize, usize), // Check inequality of two registers and store result in a target register
+    Gt(usize, usize, usize), // Check greater than between two registers and store result in a target register
+    Lt(usize, usize, usize), // Check less than between two registers and store result in a target register
+    Gte(usize, usize, usize), // Check greater than or equal to between two registers and store result in a target register
+    Lte(usize, usize, usize), // Check less than or equal to between two registers and store result in a target register
+    Jump(isize), // Jump by an offset relative to the next instruction, negative to go back
+    JumpIfZero(usize, isize), // Jump if register value is zero
+    JumpIfNonZero(usize, isize), // Jump if register value is non-zero
+    Print(usize), // Print the value of a register
+    Halt,        // Halt the execution
+    AllocateMemory(usize, usize), // Allocate a memory block of the size held in a register, store its address
+    FreeMemory(usize),            // Free a memory block
+    StoreToMemory(usize, usize, usize), // Store a byte in memory at a specific address
+    LoadFromMemory(usize, usize, usize), // Load a byte from memory at a specific address plus offset
+    Call(usize), // Call a function at the specific instruction pointer offset
+    Return,      // Return from a function
+    FAdd(usize, usize, usize), // Add two float registers and store in a target register
+    FSub(usize, usize, usize), // Subtract two float registers and store in a target register
+    FMul(usize, usize, usize), // Multiply two float registers and store in a target register
+    FDiv(usize, usize, usize), // Divide two float registers and store in a target register
+    IntToFloat(usize, usize), // Convert an integer register to a float in the target register
+    FloatToInt(usize, usize), // Convert a float register to an integer (truncating) in the target register
+    SetRegByte(usize, u8),    // Store a sign-extended byte into a register
+    SetRegShifted(usize, u8, u8), // Store a byte shifted left by the given number of bits into a register
+    SizeOf(usize, usize), // Store the size of the memory block at an address into a register
+    MemCopy(usize, usize, usize), // Copy bytes from a source address to a destination address
+    MemFill(usize, usize, usize), // Fill bytes at an address with the low byte of a register
+    PrintU(usize),        // Print the value of a register as an unsigned integer
+    Syscall(usize),       // Call the host function registered under a number
+    Cas(usize, usize, usize, usize, usize), // Compare-and-swap a 32-bit word in memory, storing 1/0 success
+    ClearRegs,                              // Set every register to zero
+    ClearReg(usize),                        // Set a single register to zero
+    Rol(usize, usize, usize), // Rotate a register left by the bit count in another register, store in a target register
+    Ror(usize, usize, usize), // Rotate a register right by the bit count in another register, store in a target register
+    AssertEq(usize, i32),     // Fail unless the register holds the expected value
+    And(usize, usize, usize), // Bitwise AND of two registers, store in a target register
+    Or(usize, usize, usize),  // Bitwise OR of two registers, store in a target register
+    Xor(usize, usize, usize), // Bitwise XOR of two registers, store in a target register
+    Not(usize, usize),        // Bitwise NOT of a register, store in a target register
+    Shl(usize, usize, usize), // Shift a register left by the bit count in another register, store in a target register
+    Shr(usize, usize, usize), // Logical shift right, filling with zeros
+    Sar(usize, usize, usize), // Arithmetic shift right, filling with the sign bit
+    AddImm(usize, i32, usize), // Add an immediate to a register, store in a target register
+    SubImm(usize, i32, usize), // Subtract an immediate from a register, store in a target register
+    MulImm(usize, i32, usize), // Multiply a register by an immediate, store in a target register
+    EqImm(usize, i32, usize), // Check equality of a register and an immediate, store result in a target register
+    NeqImm(usize, i32, usize), // Check inequality of a register and an immediate, store result in a target register
+    GtImm(usize, i32, usize), // Check greater than between a register and an immediate, store result in a target register
+    LtImm(usize, i32, usize), // Check less than between a register and an immediate, store result in a target register
+    GteImm(usize, i32, usize), // Check greater than or equal between a register and an immediate, store result in a target register
+    LteImm(usize, i32, usize), // Check less than or equal between a register and an immediate, store result in a target register
+    StoreIndirect(usize, usize, usize), // Store a byte at the address plus offset held in two registers
+    LoadIndirect(usize, usize, usize), // Load a byte from the address plus offset held in two registers
+    Store8(usize, usize, usize), // Store the low byte of a register at the address plus offset held in two registers
+    Store16(usize, usize, usize), // Store the low 16 bits of a register, little-endian at the address plus offset held in two registers
+    Store32(usize, usize, usize), // Store a full register as a little-endian 32-bit word at the address plus offset held in two registers
+    Load8S(usize, usize, usize), // Load a byte and sign-extend it at the address plus offset held in two registers
+    Load8U(usize, usize, usize), // Load a byte and zero-extend it at the address plus offset held in two registers
+    Load16S(usize, usize, usize), // Load a little-endian 16-bit value and sign-extend it at the address plus offset held in two registers
+    Load16U(usize, usize, usize), // Load a little-endian 16-bit value and zero-extend it at the address plus offset held in two registers
+    Load32(usize, usize, usize), // Load a little-endian 32-bit word at the address plus offset held in two registers
+    ReadInt(usize),              // Read a line of input and parse it as an integer into a register
+    ReadByte(usize), // Read one byte of input into a register, -1 at the end of the input
+    PrintStr(usize, usize), // Write the bytes at the address held in one register, with the length held in another, to the output
+    SetFReg(usize, f64),    // Store a float constant directly into a register
+    FCmp(usize, usize, usize), // Compare two float registers, store -1, 0 or 1, or 2 if either is NaN
+    Push(usize),               // Push the value of a register onto the data stack
+    Pop(usize),                // Pop the top of the data stack into a register
+    Dup,                       // Push a copy of the top of the data stack
+    SwapTop,                   // Swap the two topmost values of the data stack
+    Enter(usize),              // Open a stack frame with the given number of zeroed local slots
+    Leave,                     // Close the innermost stack frame
+    LoadLocal(usize, usize),   // Load a local slot of the current frame into a register
+    StoreLocal(usize, usize),  // Store a register into a local slot of the current frame
+    CallReg(usize),            // Call the function at the address held in a register
+    CallTable(usize),          // Call the function-table entry whose index is held in a register
+    JumpTable(usize, Vec<isize>, isize), // Jump to the offset indexed by a register, or the default
+    Brk(usize),                // Stop with a trap code, or hand control to an attached debugger
+    Nop,                       // Do nothing
+    Mov(usize, usize),         // Copy a register, integer or float, into a target register
+    Neg(usize, usize),         // Negate a register, store in a target register
+    Abs(usize, usize),         // Absolute value of a register, store in a target register
+    Min(usize, usize, usize),  // The smaller of two registers, store in a target register
+    Max(usize, usize, usize),  // The larger of two registers, store in a target register
+    Popcount(usize, usize),    // Count the set bits of a register, store in a target register
+    Clz(usize, usize), // Count the leading zero bits of a register, store in a target register
+    Ctz(usize, usize), // Count the trailing zero bits of a register, store in a target register
+    Cmp(usize, usize), // Set the flags from the difference of two registers, store nothing
+    CmpImm(usize, i32), // Set the flags from the difference of a register and an immediate
+    Jz(isize),         // Jump if the zero flag is set
+    Jnz(isize),        // Jump if the zero flag is clear
+    Jc(isize),         // Jump if the carry flag is set
+    Jo(isize),         // Jump if the overflow flag is set
+    Jlt(isize),        // Jump if a compare found its first operand less, signed
+    Jge(isize),        // Jump if a compare found its first operand greater or equal, signed
+    Adc(usize, usize, usize), // Add two registers and the carry flag, store in a target register
+    Sbb(usize, usize, usize), // Subtract a register and the carry flag, store in a target register
+    MulWide(usize, usize, usize, usize), // Unsigned 64-bit product of two registers, high and low word
+    DivU(usize, usize, usize), // Unsigned divide of two registers, store in a target register
+    ModU(usize, usize, usize), // Unsigned remainder of two registers, store in a target register
+    LtU(usize, usize, usize), // Unsigned less than of two registers, store 1 or 0 in a target register
+    GtU(usize, usize, usize), // Unsigned greater than of two registers, store 1 or 0 in a target register
+    LteU(usize, usize, usize), // Unsigned less or equal of two registers, store 1 or 0 in a target register
+    GteU(usize, usize, usize), // Unsigned greater or equal of two registers, store 1 or 0 in a target register
+    SextByte(usize, usize),    // Sign-extend the low byte of a register into a target register
+    SextHalf(usize, usize),    // Sign-extend the low 16 bits of a register into a target register
+    ZextByte(usize, usize),    // Zero-extend the low byte of a register into a target register
+    ZextHalf(usize, usize),    // Zero-extend the low 16 bits of a register into a target register
+    Trunc(usize, u8, usize), // Keep the low 1 to 32 bits of a register, zero the rest, store in a target register
+    FloatTrunc(usize, usize), // Round a float register toward zero, store in a target register
+    FloatRound(usize, usize), // Round a float register to nearest, halves away from zero, store in a target register
+    FloatFloor(usize, usize), // Round a float register down, store in a target register
+    FloatCeil(usize, usize),  // Round a float register up, store in a target register
+    VLoad(usize, usize, usize), // Load 16 bytes at the address plus offset held in two registers into a vector register
+    VStore(usize, usize, usize), // Store a vector register as 16 bytes at the address plus offset held in two registers
+    VAdd4x32(usize, usize, usize), // Add two vector registers lane by lane, store in a target vector register
+    VMul4x32(usize, usize, usize), // Multiply two vector registers lane by lane, store in a target vector register
+    VExtract(usize, u8, usize),    // Copy a lane of a vector register into a register
+    VInsert(usize, u8, usize),     // Copy a register into a lane of a vector register
+    VSplat(usize, usize),          // Copy a register into every lane of a vector register
+    MemCopyReg(usize, usize, usize), // Copy bytes between the addresses held in two registers, the length held in a third
+    MemFillReg(usize, usize, usize), // Fill bytes at the address held in a register, the length held in a third
+    ReallocMemory(usize, usize, usize), // Resize the region at the address held in a register, store its new address
+    ProtectMemory(usize, u8), // Set which accesses the region at the address held in a register allows
+    LoadGlobal(usize, usize), // Load a global variable of the program into a register
+    StoreGlobal(usize, usize), // Store a register into a global variable of the program
+    PushSp(usize), // Push a register as a 32-bit word onto the in-memory stack, moving SP down
+    PopSp(usize),  // Pop the 32-bit word at SP into a register, moving SP up
+    LoadSpOffset(usize, usize), // Load the 32-bit word at SP plus an offset into a register
+    StoreSpOffset(usize, usize), // Store a register as a 32-bit word at SP plus an offset
+    NewObject(usize, usize), // Create a garbage-collected object with that many slots, store a reference to it
+    LoadSlot(usize, usize, usize), // Load a slot of the object referenced by a register into a register
+    StoreSlot(usize, usize, usize), // Store a register into a slot of the object referenced by a register
+    Retain(usize),  // Add a reference to the region at the address held in a register
+    Release(usize), // Drop a reference to the region at the address held in a register, freeing it at zero
+    NewArray(usize, usize), // Create a garbage-collected array of the length held in a register, store a reference to it
+    ArrayGet(usize, usize, usize), // Load the element at the index held in a register of the array referenced by a register
+    ArraySet(usize, usize, usize), // Store a register into the element at the index held in a register of an array
+    ArrayLen(usize, usize),        // Store the length of the array referenced by a register
+    NewString(usize, usize, usize), // Create a garbage-collected string from the bytes at an address and length held in two registers
+    StrConcat(usize, usize, usize), // Create the string of two strings referenced by registers, one after the other
+    StrLen(usize, usize), // Store the length in bytes of the string referenced by a register
+    StrByte(usize, usize, usize), // Load the byte at the index held in a register of a string
+    StrCmp(usize, usize, usize), // Compare two strings byte by byte, storing -1, 0 or 1
+    PrintString(usize),   // Write the bytes of the string referenced by a register to the output
+    MapNew(usize),        // Create an empty garbage-collected map, store a reference to it
+    MapGet(usize, usize, usize), // Load the value of the key held in a register from the map referenced by a register
+    MapSet(usize, usize, usize), // Store a register as the value of the key held in a register of a map
+    MapDel(usize, usize),        // Remove the key held in a register from a map
+    MapHas(usize, usize, usize), // Store 1 if a map holds the key held in a register, 0 otherwise
+    TypeOf(usize, usize), // Store the type tag of a register: 0 nil, 1 integer, 2 float, 3 object or array, 4 string, 5 map
+    TryBegin(isize), // Install an exception handler at an offset relative to the next instruction
+    TryEnd,          // Remove the innermost exception handler, installed by the current call
+    Throw(usize),    // Throw the integer code held in a register to the innermost exception handler
+}
+
+// Written out instead of derived so that float immediates compare by their bits,
+// which makes every instruction equal to itself, NaN constants included.
+impl PartialEq for Instruction {
+    fn eq(&self, other: &Self) -> bool {
+        use Instruction::*;
+        match self {
+            SetReg(a0, a1) => matches!(other, SetReg(b0, b1) if (a0, a1) == (b0, b1)),
+            Add(a0, a1, a2) => matches!(other, Add(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Sub(a0, a1, a2) => matches!(other, Sub(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Mul(a0, a1, a2) => matches!(other, Mul(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Div(a0, a1, a2) => matches!(other, Div(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Mod(a0, a1, a2) => matches!(other, Mod(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Eq(a0, a1, a2) => matches!(other, Eq(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Neq(a0, a1, a2) => matches!(other, Neq(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Gt(a0, a1, a2) => matches!(other, Gt(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Lt(a0, a1, a2) => matches!(other, Lt(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Gte(a0, a1, a2) => matches!(other, Gte(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Lte(a0, a1, a2) => matches!(other, Lte(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Jump(a0) => matches!(other, Jump(b0) if a0 == b0),
+            JumpIfZero(a0, a1) => matches!(other, JumpIfZero(b0, b1) if (a0, a1) == (b0, b1)),
+            JumpIfNonZero(a0, a1) => matches!(other, JumpIfNonZero(b0, b1) if (a0, a1) == (b0, b1)),
+            Print(a0) => matches!(other, Print(b0) if a0 == b0),
+            Halt => matches!(other, Halt),
+            AllocateMemory(a0, a1) => {
+                matches!(other, AllocateMemory(b0, b1) if (a0, a1) == (b0, b1))
+            }
+            FreeMemory(a0) => matches!(other, FreeMemory(b0) if a0 == b0),
+            StoreToMemory(a0, a1, a2) => {
+                matches!(other, StoreToMemory(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            LoadFromMemory(a0, a1, a2) => {
+                matches!(other, LoadFromMemory(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            Call(a0) => matches!(other, Call(b0) if a0 == b0),
+            Return => matches!(other, Return),
+            FAdd(a0, a1, a2) => matches!(other, FAdd(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            FSub(a0, a1, a2) => matches!(other, FSub(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            FMul(a0, a1, a2) => matches!(other, FMul(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            FDiv(a0, a1, a2) => matches!(other, FDiv(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            IntToFloat(a0, a1) => matches!(other, IntToFloat(b0, b1) if (a0, a1) == (b0, b1)),
+            FloatToInt(a0, a1) => matches!(other, FloatToInt(b0, b1) if (a0, a1) == (b0, b1)),
+            SetRegByte(a0, a1) => matches!(other, SetRegByte(b0, b1) if (a0, a1) == (b0, b1)),
+            SetRegShifted(a0, a1, a2) => {
+                matches!(other, SetRegShifted(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            SizeOf(a0, a1) => matches!(other, SizeOf(b0, b1) if (a0, a1) == (b0, b1)),
+            MemCopy(a0, a1, a2) => {
+                matches!(other, MemCopy(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            MemFill(a0, a1, a2) => {
+                matches!(other, MemFill(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            PrintU(a0) => matches!(other, PrintU(b0) if a0 == b0),
+            Syscall(a0) => matches!(other, Syscall(b0) if a0 == b0),
+            Cas(a0, a1, a2, a3, a4) => matches!(
+                other,
+                Cas(b0, b1, b2, b3, b4) if (a0, a1, a2, a3, a4) == (b0, b1, b2, b3, b4)
+            ),
+            ClearRegs => matches!(other, ClearRegs),
+            ClearReg(a0) => matches!(other, ClearReg(b0) if a0 == b0),
+            Rol(a0, a1, a2) => matches!(other, Rol(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Ror(a0, a1, a2) => matches!(other, Ror(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            AssertEq(a0, a1) => matches!(other, AssertEq(b0, b1) if (a0, a1) == (b0, b1)),
+            And(a0, a1, a2) => matches!(other, And(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Or(a0, a1, a2) => matches!(other, Or(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Xor(a0, a1, a2) => matches!(other, Xor(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Not(a0, a1) => matches!(other, Not(b0, b1) if (a0, a1) == (b0, b1)),
+            Shl(a0, a1, a2) => matches!(other, Shl(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Shr(a0, a1, a2) => matches!(other, Shr(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Sar(a0, a1, a2) => matches!(other, Sar(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            AddImm(a0, a1, a2) => {
+                matches!(other, AddImm(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            SubImm(a0, a1, a2) => {
+                matches!(other, SubImm(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            MulImm(a0, a1, a2) => {
+                matches!(other, MulImm(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            EqImm(a0, a1, a2) => matches!(other, EqImm(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            NeqImm(a0, a1, a2) => {
+                matches!(other, NeqImm(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            GtImm(a0, a1, a2) => matches!(other, GtImm(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            LtImm(a0, a1, a2) => matches!(other, LtImm(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            GteImm(a0, a1, a2) => {
+                matches!(other, GteImm(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            LteImm(a0, a1, a2) => {
+                matches!(other, LteImm(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            StoreIndirect(a0, a1, a2) => {
+                matches!(other, StoreIndirect(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            LoadIndirect(a0, a1, a2) => {
+                matches!(other, LoadIndirect(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            Store8(a0, a1, a2) => {
+                matches!(other, Store8(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            Store16(a0, a1, a2) => {
+                matches!(other, Store16(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            Store32(a0, a1, a2) => {
+                matches!(other, Store32(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            Load8S(a0, a1, a2) => {
+                matches!(other, Load8S(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            Load8U(a0, a1, a2) => {
+                matches!(other, Load8U(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            Load16S(a0, a1, a2) => {
+                matches!(other, Load16S(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            Load16U(a0, a1, a2) => {
+                matches!(other, Load16U(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            Load32(a0, a1, a2) => {
+                matches!(other, Load32(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            ReadInt(a0) => matches!(other, ReadInt(b0) if a0 == b0),
+            ReadByte(a0) => matches!(other, ReadByte(b0) if a0 == b0),
+            PrintStr(a0, a1) => matches!(other, PrintStr(b0, b1) if (a0, a1) == (b0, b1)),
+            SetFReg(a0, a1) => {
+                matches!(other, SetFReg(b0, b1) if a0 == b0 && a1.to_bits() == b1.to_bits())
+            }
+            FCmp(a0, a1, a2) => matches!(other, FCmp(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Push(a0) => matches!(other, Push(b0) if a0 == b0),
+            Pop(a0) => matches!(other, Pop(b0) if a0 == b0),
+            Dup => matches!(other, Dup),
+            SwapTop => matches!(other, SwapTop),
+            Enter(a0) => matches!(other, Enter(b0) if a0 == b0),
+            Leave => matches!(other, Leave),
+            LoadLocal(a0, a1) => matches!(other, LoadLocal(b0, b1) if (a0, a1) == (b0, b1)),
+            StoreLocal(a0, a1) => matches!(other, StoreLocal(b0, b1) if (a0, a1) == (b0, b1)),
+            CallReg(a0) => matches!(other, CallReg(b0) if a0 == b0),
+            CallTable(a0) => matches!(other, CallTable(b0) if a0 == b0),
+            JumpTable(a0, a1, a2) => {
+                matches!(other, JumpTable(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            Brk(a0) => matches!(other, Brk(b0) if a0 == b0),
+            Nop => matches!(other, Nop),
+            Mov(a0, a1) => matches!(other, Mov(b0, b1) if (a0, a1) == (b0, b1)),
+            Neg(a0, a1) => matches!(other, Neg(b0, b1) if (a0, a1) == (b0, b1)),
+            Abs(a0, a1) => matches!(other, Abs(b0, b1) if (a0, a1) == (b0, b1)),
+            Min(a0, a1, a2) => matches!(other, Min(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Max(a0, a1, a2) => matches!(other, Max(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Popcount(a0, a1) => matches!(other, Popcount(b0, b1) if (a0, a1) == (b0, b1)),
+            Clz(a0, a1) => matches!(other, Clz(b0, b1) if (a0, a1) == (b0, b1)),
+            Ctz(a0, a1) => matches!(other, Ctz(b0, b1) if (a0, a1) == (b0, b1)),
+            Cmp(a0, a1) => matches!(other, Cmp(b0, b1) if (a0, a1) == (b0, b1)),
+            CmpImm(a0, a1) => matches!(other, CmpImm(b0, b1) if (a0, a1) == (b0, b1)),
+            Jz(a0) => matches!(other, Jz(b0) if a0 == b0),
+            Jnz(a0) => matches!(other, Jnz(b0) if a0 == b0),
+            Jc(a0) => matches!(other, Jc(b0) if a0 == b0),
+            Jo(a0) => matches!(other, Jo(b0) if a0 == b0),
+            Jlt(a0) => matches!(other, Jlt(b0) if a0 == b0),
+            Jge(a0) => matches!(other, Jge(b0) if a0 == b0),
+            Adc(a0, a1, a2) => matches!(other, Adc(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            Sbb(a0, a1, a2) => matches!(other, Sbb(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            MulWide(a0, a1, a2, a3) => {
+                matches!(other, MulWide(b0, b1, b2, b3) if (a0, a1, a2, a3) == (b0, b1, b2, b3))
+            }
+            DivU(a0, a1, a2) => matches!(other, DivU(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            ModU(a0, a1, a2) => matches!(other, ModU(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            LtU(a0, a1, a2) => matches!(other, LtU(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            GtU(a0, a1, a2) => matches!(other, GtU(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            LteU(a0, a1, a2) => matches!(other, LteU(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            GteU(a0, a1, a2) => matches!(other, GteU(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            SextByte(a0, a1) => matches!(other, SextByte(b0, b1) if (a0, a1) == (b0, b1)),
+            SextHalf(a0, a1) => matches!(other, SextHalf(b0, b1) if (a0, a1) == (b0, b1)),
+            ZextByte(a0, a1) => matches!(other, ZextByte(b0, b1) if (a0, a1) == (b0, b1)),
+            ZextHalf(a0, a1) => matches!(other, ZextHalf(b0, b1) if (a0, a1) == (b0, b1)),
+            Trunc(a0, a1, a2) => matches!(other, Trunc(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            FloatTrunc(a0, a1) => matches!(other, FloatTrunc(b0, b1) if (a0, a1) == (b0, b1)),
+            FloatRound(a0, a1) => matches!(other, FloatRound(b0, b1) if (a0, a1) == (b0, b1)),
+            FloatFloor(a0, a1) => matches!(other, FloatFloor(b0, b1) if (a0, a1) == (b0, b1)),
+            FloatCeil(a0, a1) => matches!(other, FloatCeil(b0, b1) if (a0, a1) == (b0, b1)),
+            VLoad(a0, a1, a2) => matches!(other, VLoad(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2)),
+            VStore(a0, a1, a2) => {
+                matches!(other, VStore(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            VAdd4x32(a0, a1, a2) => {
+                matches!(other, VAdd4x32(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            VMul4x32(a0, a1, a2) => {
+                matches!(other, VMul4x32(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            VExtract(a0, a1, a2) => {
+                matches!(other, VExtract(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            VInsert(a0, a1, a2) => {
+                matches!(other, VInsert(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            VSplat(a0, a1) => matches!(other, VSplat(b0, b1) if (a0, a1) == (b0, b1)),
+            MemCopyReg(a0, a1, a2) => {
+                matches!(other, MemCopyReg(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            MemFillReg(a0, a1, a2) => {
+                matches!(other, MemFillReg(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            ReallocMemory(a0, a1, a2) => {
+                matches!(other, ReallocMemory(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            ProtectMemory(a0, a1) => matches!(other, ProtectMemory(b0, b1) if (a0, a1) == (b0, b1)),
+            LoadGlobal(a0, a1) => matches!(other, LoadGlobal(b0, b1) if (a0, a1) == (b0, b1)),
+            StoreGlobal(a0, a1) => matches!(other, StoreGlobal(b0, b1) if (a0, a1) == (b0, b1)),
+            PushSp(a0) => matches!(other, PushSp(b0) if a0 == b0),
+            PopSp(a0) => matches!(other, PopSp(b0) if a0 == b0),
+            LoadSpOffset(a0, a1) => matches!(other, LoadSpOffset(b0, b1) if (a0, a1) == (b0, b1)),
+            StoreSpOffset(a0, a1) => matches!(other, StoreSpOffset(b0, b1) if (a0, a1) == (b0, b1)),
+            NewObject(a0, a1) => matches!(other, NewObject(b0, b1) if (a0, a1) == (b0, b1)),
+            LoadSlot(a0, a1, a2) => {
+                matches!(other, LoadSlot(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            StoreSlot(a0, a1, a2) => {
+                matches!(other, StoreSlot(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            Retain(a0) => matches!(other, Retain(b0) if a0 == b0),
+            Release(a0) => matches!(other, Release(b0) if a0 == b0),
+            NewArray(a0, a1) => matches!(other, NewArray(b0, b1) if (a0, a1) == (b0, b1)),
+            ArrayGet(a0, a1, a2) => {
+                matches!(other, ArrayGet(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            ArraySet(a0, a1, a2) => {
+                matches!(other, ArraySet(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            ArrayLen(a0, a1) => matches!(other, ArrayLen(b0, b1) if (a0, a1) == (b0, b1)),
+            NewString(a0, a1, a2) => {
+                matches!(other, NewString(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            StrConcat(a0, a1, a2) => {
+                matches!(other, StrConcat(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            StrLen(a0, a1) => matches!(other, StrLen(b0, b1) if (a0, a1) == (b0, b1)),
+            StrByte(a0, a1, a2) => {
+                matches!(other, StrByte(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            StrCmp(a0, a1, a2) => {
+                matches!(other, StrCmp(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            PrintString(a0) => matches!(other, PrintString(b0) if a0 == b0),
+            MapNew(a0) => matches!(other, MapNew(b0) if a0 == b0),
+            MapGet(a0, a1, a2) => {
+                matches!(other, MapGet(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            MapSet(a0, a1, a2) => {
+                matches!(other, MapSet(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            MapDel(a0, a1) => matches!(other, MapDel(b0, b1) if (a0, a1) == (b0, b1)),
+            MapHas(a0, a1, a2) => {
+                matches!(other, MapHas(b0, b1, b2) if (a0, a1, a2) == (b0, b1, b2))
+            }
+            TypeOf(a0, a1) => matches!(other, TypeOf(b0, b1) if (a0, a1) == (b0, b1)),
+            TryBegin(a0) => matches!(other, TryBegin(b0) if a0 == b0),
+            TryEnd => matches!(other, TryEnd),
+            Throw(a0) => matches!(other, Throw(b0) if a0 == b0),
+        }
+    }
+}
+
+impl Eq for Instruction {}
+
+/// Two programs are equal when they consist of the same instructions with the same operands.
+pub fn programs_equal(a: &[Instruction], b: &[Instruction]) -> bool {
+    a == b
+}
+
+impl Instruction {
+    /// Pick the most compact instruction that loads `value` into `register_index`.
+    pub fn load_immediate(register_index: usize, value: i32) -> Instruction {
+        if let Ok(byte) = i8::try_from(value) {
+            return Instruction::SetRegByte(register_index, byte as u8);
+        }
+        let bits = value as u32;
+        let shift = bits.trailing_zeros();
+        if bits >> shift <= 0xFF {
+            return Instruction::SetRegShifted(register_index, (bits >> shift) as u8, shift as u8);
+        }
+        Instruction::SetReg(register_index, value)
+    }
+
+    /// The register operands of the instruction, in operand order. Addresses,
+    /// sizes and immediates are not included.
+    pub fn registers(&self) -> Vec<usize> {
+        match *self {
+            Instruction::Add(a, b, t)
+            | Instruction::Sub(a, b, t)
+            | Instruction::Mul(a, b, t)
+            | Instruction::Div(a, b, t)
+            | Instruction::Mod(a, b, t)
+            | Instruction::Eq(a, b, t)
+            | Instruction::Neq(a, b, t)
+            | Instruction::Gt(a, b, t)
+            | Instruction::Lt(a, b, t)
+            | Instruction::Gte(a, b, t)
+            | Instruction::Lte(a, b, t)
+            | Instruction::FAdd(a, b, t)
+            | Instruction::FSub(a, b, t)
+            | Instruction::FMul(a, b, t)
+            | Instruction::FDiv(a, b, t)
+            | Instruction::FCmp(a, b, t)
+            | Instruction::Rol(a, b, t)
+            | Instruction::Ror(a, b, t)
+            | Instruction::And(a, b, t)
+            | Instruction::Or(a, b, t)
+            | Instruction::Xor(a, b, t)
+            | Instruction::Shl(a, b, t)
+            | Instruction::Shr(a, b, t)
+            | Instruction::Sar(a, b, t)
+            | Instruction::StoreIndirect(a, b, t)
+            | Instruction::LoadIndirect(a, b, t)
+            | Instruction::Store8(a, b, t)
+            | Instruction::Store16(a, b, t)
+            | Instruction::Store32(a, b, t)
+            | Instruction::Load8S(a, b, t)
+            | Instruction::Load8U(a, b, t)
+            | Instruction::Load16S(a, b, t)
+            | Instruction::Load16U(a, b, t)
+            | Instruction::Load32(a, b, t)
+            | Instruction::Min(a, b, t)
+            | Instruction::Max(a, b, t)
+            | Instruction::Adc(a, b, t)
+            | Instruction::Sbb(a, b, t)
+            | Instruction::DivU(a, b, t)
+            | Instruction::ModU(a, b, t)
+            | Instruction::LtU(a, b, t)
+            | Instruction::GtU(a, b, t)
+            | Instruction::LteU(a, b, t)
+            | Instruction::GteU(a, b, t)
+            | Instruction::MemCopyReg(a, b, t)
+            | Instruction::MemFillReg(a, b, t)
+            | Instruction::ArrayGet(a, b, t)
+            | Instruction::ArraySet(a, b, t)
+            | Instruction::NewString(a, b, t)
+            | Instruction::StrConcat(a, b, t)
+            | Instruction::StrByte(a, b, t)
+            | Instruction::StrCmp(a, b, t)
+            | Instruction::MapGet(a, b, t)
+            | Instruction::MapSet(a, b, t)
+            | Instruction::MapHas(a, b, t)
+            | Instruction::ReallocMemory(a, b, t) => vec![a, b, t],
+            Instruction::MulWide(a, b, high, low) => vec![a, b, high, low],
+            Instruction::Cmp(a, b)
+            | Instruction::AllocateMemory(a, b)
+            | Instruction::VLoad(a, b, _)
+            | Instruction::VStore(a, b, _)
+            | Instruction::LoadSlot(a, _, b)
+            | Instruction::StoreSlot(a, b, _)
+            | Instruction::NewArray(a, b)
+            | Instruction::ArrayLen(a, b)
+            | Instruction::StrLen(a, b)
+            | Instruction::MapDel(a, b)
+            | Instruction::TypeOf(a, b) => vec![a, b],
+            Instruction::AddImm(a, _, t)
+            | Instruction::SubImm(a, _, t)
+            | Instruction::MulImm(a, _, t)
+            | Instruction::EqImm(a, _, t)
+            | Instruction::NeqImm(a, _, t)
+            | Instruction::GtImm(a, _, t)
+            | Instruction::LtImm(a, _, t)
+            | Instruction::GteImm(a, _, t)
+            | Instruction::LteImm(a, _, t)
+            | Instruction::IntToFloat(a, t)
+            | Instruction::FloatToInt(a, t)
+            | Instruction::Not(a, t)
+            | Instruction::Mov(a, t)
+            | Instruction::Neg(a, t)
+            | Instruction::Abs(a, t)
+            | Instruction::Popcount(a, t)
+            | Instruction::Clz(a, t)
+            | Instruction::Ctz(a, t)
+            | Instruction::SextByte(a, t)
+            | Instruction::SextHalf(a, t)
+            | Instruction::ZextByte(a, t)
+            | Instruction::ZextHalf(a, t)
+            | Instruction::Trunc(a, _, t)
+            | Instruction::FloatTrunc(a, t)
+            | Instruction::FloatRound(a, t)
+            | Instruction::FloatFloor(a, t)
+            | Instruction::FloatCeil(a, t)
+            | Instruction::PrintStr(a, t) => vec![a, t],
+            Instruction::SetReg(r, _)
+            | Instruction::SetRegByte(r, _)
+            | Instruction::SetRegShifted(r, _, _)
+            | Instruction::SetFReg(r, _)
+            | Instruction::JumpIfZero(r, _)
+            | Instruction::JumpIfNonZero(r, _)
+            | Instruction::Print(r)
+            | Instruction::PrintU(r)
+            | Instruction::StoreToMemory(_, r, _)
+            | Instruction::LoadFromMemory(_, _, r)
+            | Instruction::SizeOf(_, r)
+            | Instruction::MemFill(_, r, _)
+            | Instruction::ClearReg(r)
+            | Instruction::AssertEq(r, _)
+            | Instruction::ReadInt(r)
+            | Instruction::ReadByte(r)
+            | Instruction::Push(r)
+            | Instruction::Pop(r)
+            | Instruction::LoadLocal(_, r)
+            | Instruction::StoreLocal(r, _)
+            | Instruction::LoadGlobal(_, r)
+            | Instruction::StoreGlobal(r, _)
+            | Instruction::NewObject(_, r)
+            | Instruction::Retain(r)
+            | Instruction::Release(r)
+            | Instruction::PrintString(r)
+            | Instruction::MapNew(r)
+            | Instruction::PushSp(r)
+            | Instruction::PopSp(r)
+            | Instruction::LoadSpOffset(_, r)
+            | Instruction::StoreSpOffset(r, _)
+            | Instruction::CallReg(r)
+            | Instruction::CallTable(r)
+            | Instruction::ProtectMemory(r, _)
+            | Instruction::JumpTable(r, ..)
+            | Instruction::CmpImm(r, _)
+            | Instruction::VExtract(_, _, r)
+            | Instruction::VInsert(r, _, _)
+            | Instruction::VSplat(r, _)
+            | Instruction::Throw(r) => vec![r],
+            Instruction::Cas(_, _, expected, new, result) => vec![expected, new, result],
+            Instruction::Jump(_)
+            | Instruction::TryBegin(_)
+            | Instruction::TryEnd
+            | Instruction::Halt
+            | Instruction::FreeMemory(_)
+            | Instruction::Call(_)
+            | Instruction::Return
+            | Instruction::MemCopy(..)
+            | Instruction::Syscall(_)
+            | Instruction::Brk(_)
+            | Instruction::Nop
+            | Instruction::ClearRegs
+            | Instruction::Dup
+            | Instruction::SwapTop
+            | Instruction::Enter(_)
+            | Instruction::Leave
+            | Instruction::Jz(_)
+            | Instruction::Jnz(_)
+            | Instruction::Jc(_)
+            | Instruction::Jo(_)
+            | Instruction::Jlt(_)
+            | Instruction::Jge(_)
+            | Instruction::VAdd4x32(..)
+            | Instruction::VMul4x32(..) => vec![],
+        }
+    }
+
+    /// The vector register operands of the instruction, in operand order.
+    pub fn vector_registers(&self) -> Vec<usize> {
+        match *self {
+            Instruction::VAdd4x32(a, b, t) | Instruction::VMul4x32(a, b, t) => vec![a, b, t],
+            Instruction::VLoad(_, _, v)
+            | Instruction::VStore(_, _, v)
+            | Instruction::VExtract(v, _, _)
+            | Instruction::VInsert(_, _, v)
+            | Instruction::VSplat(_, v) => vec![v],
+            _ => vec![],
+        }
+    }
+
+    /// The register the instruction writes, if any. `ClearRegs` writes every
+    /// register, `MulWide` two and a `Syscall` whatever its host function
+    /// writes, none of them is reported here.
+    pub fn written_register(&self) -> Option<usize> {
+        match *self {
+            Instruction::Add(_, _, t)
+            | Instruction::Sub(_, _, t)
+            | Instruction::Mul(_, _, t)
+            | Instruction::Div(_, _, t)
+            | Instruction::Mod(_, _, t)
+            | Instruction::Eq(_, _, t)
+            | Instruction::Neq(_, _, t)
+            | Instruction::Gt(_, _, t)
+            | Instruction::Lt(_, _, t)
+            | Instruction::Gte(_, _, t)
+            | Instruction::Lte(_, _, t)
+            | Instruction::FAdd(_, _, t)
+            | Instruction::FSub(_, _, t)
+            | Instruction::FMul(_, _, t)
+            | Instruction::FDiv(_, _, t)
+            | Instruction::FCmp(_, _, t)
+            | Instruction::Rol(_, _, t)
+            | Instruction::Ror(_, _, t)
+            | Instruction::And(_, _, t)
+            | Instruction::Or(_, _, t)
+            | Instruction::Xor(_, _, t)
+            | Instruction::Shl(_, _, t)
+            | Instruction::Shr(_, _, t)
+            | Instruction::Sar(_, _, t)
+            | Instruction::LoadIndirect(_, _, t)
+            | Instruction::Load8S(_, _, t)
+            | Instruction::Load8U(_, _, t)
+            | Instruction::Load16S(_, _, t)
+            | Instruction::Load16U(_, _, t)
+            | Instruction::Load32(_, _, t)
+            | Instruction::Min(_, _, t)
+            | Instruction::Max(_, _, t)
+            | Instruction::Adc(_, _, t)
+            | Instruction::Sbb(_, _, t)
+            | Instruction::DivU(_, _, t)
+            | Instruction::ModU(_, _, t)
+            | Instruction::LtU(_, _, t)
+            | Instruction::GtU(_, _, t)
+            | Instruction::LteU(_, _, t)
+            | Instruction::GteU(_, _, t)
+            | Instruction::AddImm(_, _, t)
+            | Instruction::SubImm(_, _, t)
+            | Instruction::MulImm(_, _, t)
+            | Instruction::EqImm(_, _, t)
+            | Instruction::NeqImm(_, _, t)
+            | Instruction::GtImm(_, _, t)
+            | Instruction::LtImm(_, _, t)
+            | Instruction::GteImm(_, _, t)
+            | Instruction::LteImm(_, _, t)
+            | Instruction::IntToFloat(_, t)
+            | Instruction::FloatToInt(_, t)
+            | Instruction::Not(_, t)
+            | Instruction::Mov(_, t)
+            | Instruction::Neg(_, t)
+            | Instruction::Abs(_, t)
+            | Instruction::Popcount(_, t)
+            | Instruction::Clz(_, t)
+            | Instruction::Ctz(_, t)
+            | Instruction::SextByte(_, t)
+            | Instruction::SextHalf(_, t)
+            | Instruction::ZextByte(_, t)
+            | Instruction::ZextHalf(_, t)
+            | Instruction::Trunc(_, _, t)
+            | Instruction::FloatTrunc(_, t)
+            | Instruction::FloatRound(_, t)
+            | Instruction::FloatFloor(_, t)
+            | Instruction::FloatCeil(_, t)
+            | Instruction::SetReg(t, _)
+            | Instruction::SetRegByte(t, _)
+            | Instruction::SetRegShifted(t, _, _)
+            | Instruction::SetFReg(t, _)
+            | Instruction::LoadFromMemory(_, _, t)
+            | Instruction::SizeOf(_, t)
+            | Instruction::ClearReg(t)
+            | Instruction::ReadInt(t)
+            | Instruction::ReadByte(t)
+            | Instruction::Pop(t)
+            | Instruction::LoadLocal(_, t)
+            | Instruction::LoadGlobal(_, t)
+            | Instruction::PopSp(t)
+            | Instruction::LoadSpOffset(_, t)
+            | Instruction::NewObject(_, t)
+            | Instruction::LoadSlot(_, _, t)
+            | Instruction::NewArray(_, t)
+            | Instruction::ArrayGet(_, _, t)
+            | Instruction::ArrayLen(_, t)
+            | Instruction::NewString(_, _, t)
+            | Instruction::StrConcat(_, _, t)
+            | Instruction::StrLen(_, t)
+            | Instruction::StrByte(_, _, t)
+            | Instruction::StrCmp(_, _, t)
+            | Instruction::MapNew(t)
+            | Instruction::MapGet(_, _, t)
+            | Instruction::MapHas(_, _, t)
+            | Instruction::TypeOf(_, t)
+            | Instruction::VExtract(_, _, t)
+            | Instruction::AllocateMemory(_, t)
+            | Instruction::ReallocMemory(_, _, t)
+            | Instruction::Cas(_, _, _, _, t) => Some(t),
+            _ => None,
+        }
+    }
+
+    /// The fuel a metered VM spends to execute this instruction. Bulk memory
+    /// operations also pay one unit per started 32 bytes, `Enter` one per
+    /// started 32 local slots. `MemCopyReg` and `MemFillReg` have a flat cost,
+    /// their length isn't known before they run.
+    pub fn cost(&self) -> u64 {
+        match self {
+            Instruction::Mul(..)
+            | Instruction::MulImm(..)
+            | Instruction::MulWide(..)
+            | Instruction::VMul4x32(..)
+            | Instruction::FMul(..)
+            | Instruction::Call(_)
+            | Instruction::CallReg(_)
+            | Instruction::CallTable(_)
+            | Instruction::Return => 2,
+            Instruction::Div(..)
+            | Instruction::Mod(..)
+            | Instruction::DivU(..)
+            | Instruction::ModU(..)
+            | Instruction::FDiv(..)
+            | Instruction::Cas(..)
+            | Instruction::MemCopyReg(..)
+            | Instruction::MemFillReg(..) => 3,
+            Instruction::AllocateMemory(..)
+            | Instruction::FreeMemory(_)
+            | Instruction::ReallocMemory(..)
+            | Instruction::ProtectMemory(..)
+            | Instruction::NewObject(..)
+            | Instruction::NewArray(..)
+            | Instruction::NewString(..)
+            | Instruction::StrConcat(..)
+            | Instruction::MapNew(_)
+            | Instruction::Release(_) => 5,
+            Instruction::MemCopy(_, _, len)
+            | Instruction::MemFill(_, _, len)
+            | Instruction::Enter(len) => 1 + len.div_ceil(32) as u64,
+            Instruction::Syscall(_) => 10,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_programs_equal() {
+        let a = vec![
+            Instruction::SetReg(0, 42),
+            Instruction::Add(0, 1, 2),
+            Instruction::Halt,
+        ];
+        let b = vec![
+            Instruction::SetReg(0, 42),
+            Instruction::Add(0, 1, 2),
+            Instruction::Halt,
+        ];
+        let c = vec![
+            Instruction::SetReg(0, 42),
+            Instruction::Add(0, 1, 3), // Differs in the target register
+            Instruction::Halt,
+        ];
+
+        assert!(programs_equal(&a, &b));
+        assert!(!programs_equal(&a, &c));
+        assert!(!programs_equal(&a, &a[..2]));
+    }
+
+    #[test]
+    fn test_float_immediates_compare_by_bits() {
+        fn assert_eq_trait<T: Eq>() {}
+        assert_eq_trait::<Instruction>();
+
+        let nan = vec![Instruction::SetFReg(0, f64::NAN), Instruction::Halt];
+        assert!(programs_equal(&nan, &nan));
+        assert_ne!(Instruction::SetFReg(0, 0.0), Instruction::SetFReg(0, -0.0));
+        assert_ne!(Instruction::SetFReg(0, 1.5), Instruction::SetFReg(1, 1.5));
+        assert_ne!(Instruction::SetFReg(0, 1.5), Instruction::SetReg(0, 1));
+    }
+}
@@ -0,0 +1,92 @@
+//! A basic virtual machine: a register machine with a handful of arithmetic,
+//! memory, control flow and I/O instructions.
+//!
+//! ```
+//! use basic_rust_vm::{ExitStatus, Instruction, Value, VM};
+//!
+//! let mut vm = VM::new(vec![
+//!     Instruction::SetReg(0, 40),
+//!     Instruction::SetReg(1, 2),
+//!     Instruction::Add(0, 1, 2),
+//! ]);
+//! assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+//! assert_eq!(vm.registers()[2], Value::Int(42));
+//! ```
+
+// Diagnostic trace output goes through the `log` crate when the `log` feature is
+// enabled and compiles away to nothing otherwise. Program output (`Print`) is not
+// a diagnostic and always goes to stdout.
+#[cfg(feature = "log")]
+macro_rules! vm_log {
+    ($level:ident, $($arg:tt)*) => { log::$level!($($arg)*) };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! vm_log {
+    ($level:ident, $($arg:tt)*) => {{
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+mod abi;
+mod alloc;
+mod asm;
+mod builder;
+mod condition;
+mod dap;
+mod debugger;
+mod disasm;
+mod error;
+mod gc;
+#[cfg(feature = "gdb")]
+mod gdb;
+mod isa;
+mod memory;
+mod memstats;
+mod mmio;
+mod paging;
+mod profile;
+mod program;
+#[cfg(feature = "repl")]
+mod repl;
+mod replay;
+mod samples;
+mod snapshot;
+mod trace;
+#[cfg(feature = "tui")]
+mod tui;
+mod undo;
+mod value;
+mod verify;
+mod vm;
+
+pub use abi::{CallingConvention, ARGUMENT_REGISTERS, CALLEE_SAVED_REGISTERS, RETURN_REGISTER};
+pub use asm::{assemble, assemble_program, assemble_with_lines, AsmError, AsmErrorKind};
+pub use builder::{BuildError, ProgramBuilder};
+pub use condition::{Condition, ConditionError};
+pub use dap::serve_dap;
+pub use debugger::{Debugger, Stop, WatchHit};
+pub use disasm::{disassemble, disassemble_program};
+pub use error::{Frame, Trap, VmError};
+pub use gc::{MapKey, ObjectHeap};
+#[cfg(feature = "gdb")]
+pub use gdb::serve_gdb;
+pub use isa::{programs_equal, Instruction};
+pub use memory::{MemoryRegion, MemorySafety, MemoryStore, Protection, POISON_BYTE};
+pub use memstats::{Leak, MemoryStats};
+pub use mmio::MmioDevice;
+pub use paging::{PagingConfig, Segment, SegmentKind};
+pub use profile::{ProfileStats, Profiler};
+pub use program::{BytecodeError, Program};
+#[cfg(feature = "repl")]
+pub use repl::run_repl;
+pub use replay::{InputEvent, InputLog};
+pub use snapshot::VmSnapshot;
+#[cfg(feature = "tui")]
+pub use tui::run_tui;
+pub use value::Value;
+pub use verify::{verify, verify_program, VerifyError, VerifyErrorKind};
+pub use vm::{
+    ArithmeticMode, Effect, ExitStatus, Flags, HostFn, RegisterMode, SyscallHandler, Tracer,
+    VmConfig, VmContext, VmState, VECTOR_REGISTER_COUNT, VM,
+};
@@ -1,496 +1,417 @@
-use std::collections::HashMap;
-
-#[derive(Debug, Clone)]
-struct MemoryRegion {
-    size: usize,
-    data: Vec<u8>, // Data as raw bytes
-}
-
-#[derive(Debug)]
-enum Instruction {
-    SetReg(usize, i32),                 // Store value directly into a register
-    Add(usize, usize, usize), // Add values from two registers and store in a target register
-    Sub(usize, usize, usize), // Subtract values from two registers and store in a target register
-    Mul(usize, usize, usize), // Multiply values from two registers and store in a target register
-    Div(usize, usize, usize), // Divide values from two registers and store in a target register
-    Mod(usize, usize, usize), // Modulo values from two registers and store in a target register
-    Eq(usize, usize, usize), // Check equality of two registers and store result in a target register
-    Neq(usize, usize, usize), // Check inequality of two registers and store result in a target register
-    Gt(usize, usize, usize), // Check greater than between two registers and store result in a target register
-    Lt(usize, usize, usize), // Check less than between two registers and store result in a target register
-    Gte(usize, usize, usize), // Check greater than or equal to between two registers and store result in a target register
-    Lte(usize, usize, usize), // Check less than or equal to between two registers and store result in a target register
-    Jump(usize),              // Jump to a specific instruction offset
-    JumpIfZero(usize, usize), // Jump if register value is zero
-    JumpIfNonZero(usize, usize), // Jump if register value is non-zero
-    Print(usize),             // Print the value of a register
-    Halt,                     // Halt the execution
-    AllocateMemory(usize),    // Allocate a memory block of a specific size
-    FreeMemory(usize),        // Free a memory block
-    StoreToMemory(usize, usize, usize), // Store a byte in memory at a specific address
-    LoadFromMemory(usize, usize), // Load a byte from memory at a specific address
-    Call(usize),              // Call a function at the specific instruction pointer offset
-    Return,                   // Return from a function
+use std::env;
+use std::fs;
+use std::io;
+use std::process::ExitCode;
+
+use basic_rust_vm::{
+    assemble_program, disassemble_program, serve_dap, Debugger, InputLog, Instruction, Program,
+    ProgramBuilder, VmConfig, VM,
+};
+
+// The commands this binary was built with; those behind a feature are left out
+// when it isn't compiled in.
+fn usage() -> String {
+    let mut usage = String::from(
+        "\
+Usage:
+    basic_rust_vm                               run the built-in example program
+    basic_rust_vm run <file> [--trace] [--json-trace <trace.jsonl>] [--dump-registers]
+                              [--profile] [--leak-report] [--record-inputs <log.json>]
+                              [--replay-inputs <log.json>]
+    basic_rust_vm asm <file.vmasm> -o <file.bc>
+    basic_rust_vm disasm <file>
+    basic_rust_vm debug <file>
+",
+    );
+    #[cfg(feature = "tui")]
+    usage.push_str("    basic_rust_vm debug-tui <file>\n");
+    #[cfg(feature = "gdb")]
+    usage.push_str("    basic_rust_vm gdb <file> <host:port>\n");
+    usage.push_str("    basic_rust_vm dap                           serve the Debug Adapter Protocol on stdio\n");
+    #[cfg(feature = "repl")]
+    usage.push_str(
+        "    basic_rust_vm repl                          run instructions as they are typed\n",
+    );
+    usage.push_str("\n<file> is either bytecode or assembly text.");
+    usage
 }
 
-struct VM {
-    ip: usize,                            // Instruction pointer
-    program: Vec<Instruction>,            // The program instructions
-    registers: Vec<i32>,                  // 8 registers
-    memory: HashMap<usize, MemoryRegion>, // Memory regions
-    next_free_address: usize,             // Tracks the next free address for allocation
-    stack: Vec<usize>,                    // Stack for function call management (return addresses)
-}
+fn main() -> ExitCode {
+    #[cfg(feature = "log")]
+    env_logger::init();
 
-impl VM {
-    fn new(program: Vec<Instruction>) -> Self {
-        VM {
-            ip: 0,
-            program,
-            registers: vec![0; 8], // 8 registers initialized to zero
-            memory: HashMap::new(),
-            next_free_address: 0, // Initial free address is 0
-            stack: Vec::new(),    // Stack for function calls
+    let args: Vec<String> = env::args().skip(1).collect();
+    match parse_args(&args).and_then(run_command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("Error: {}", message);
+            ExitCode::FAILURE
         }
     }
+}
 
-    fn run(&mut self) {
-        loop {
-            if self.ip >= self.program.len() {
-                break;
-            }
-
-            let instruction = &self.program[self.ip];
-            self.ip += 1;
+// What the command line asks for.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Example, // No arguments
+    Run {
+        file: String,
+        options: RunOptions,
+    },
+    Asm {
+        input: String,
+        output: String,
+    },
+    Disasm(String),
+    Debug(String),
+    #[cfg(feature = "tui")]
+    DebugTui(String),
+    #[cfg(feature = "gdb")]
+    Gdb {
+        file: String,
+        address: String,
+    },
+    Dap,
+    #[cfg(feature = "repl")]
+    Repl,
+    Help,
+}
 
-            match instruction {
-                Instruction::SetReg(register_index, value) => {
-                    self.set_reg(*register_index, *value);
-                }
-                Instruction::Add(register_a, register_b, target_register) => {
-                    self.add(*register_a, *register_b, *target_register);
-                }
-                Instruction::Sub(register_a, register_b, target_register) => {
-                    self.sub(*register_a, *register_b, *target_register);
-                }
-                Instruction::Mul(register_a, register_b, target_register) => {
-                    self.mul(*register_a, *register_b, *target_register);
-                }
-                Instruction::Div(register_a, register_b, target_register) => {
-                    self.div(*register_a, *register_b, *target_register);
-                }
-                Instruction::Mod(register_a, register_b, target_register) => {
-                    self.mod_op(*register_a, *register_b, *target_register);
-                }
-                Instruction::Eq(register_a, register_b, target_register) => {
-                    self.eq(*register_a, *register_b, *target_register);
-                }
-                Instruction::Neq(register_a, register_b, target_register) => {
-                    self.neq(*register_a, *register_b, *target_register);
-                }
-                Instruction::Gt(register_a, register_b, target_register) => {
-                    self.gt(*register_a, *register_b, *target_register);
-                }
-                Instruction::Lt(register_a, register_b, target_register) => {
-                    self.lt(*register_a, *register_b, *target_register);
-                }
-                Instruction::Gte(register_a, register_b, target_register) => {
-                    self.gte(*register_a, *register_b, *target_register);
-                }
-                Instruction::Lte(register_a, register_b, target_register) => {
-                    self.lte(*register_a, *register_b, *target_register);
-                }
-                Instruction::Jump(ip_offset) => {
-                    self.jump(*ip_offset);
-                }
-                Instruction::JumpIfZero(register_index, ip_offset) => {
-                    self.jump_if_zero(*register_index, *ip_offset);
-                }
-                Instruction::JumpIfNonZero(register_index, ip_offset) => {
-                    self.jump_if_non_zero(*register_index, *ip_offset);
-                }
-                Instruction::Print(register_index) => {
-                    self.print(*register_index);
-                }
-                Instruction::Halt => break,
-                Instruction::AllocateMemory(size) => {
-                    self.allocate_memory(*size);
-                }
-                Instruction::FreeMemory(address) => {
-                    self.free_memory(*address);
-                }
-                Instruction::StoreToMemory(address, register_index, offset) => {
-                    self.store_to_memory(*address, *register_index, *offset);
-                }
-                Instruction::LoadFromMemory(address, register_index) => {
-                    self.load_from_memory(*address, *register_index);
-                }
-                Instruction::Call(ip_offset) => {
-                    self.call(*ip_offset);
-                }
-                Instruction::Return => {
-                    self.return_from_function();
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let Some((command, rest)) = args.split_first() else {
+        return Ok(Command::Example);
+    };
+    let unexpected = |arg: &String| format!("unexpected argument `{}`\n\n{}", arg, usage());
+    match command.as_str() {
+        "run" => {
+            let mut file = None;
+            let mut options = RunOptions::default();
+            let mut args = rest.iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--trace" => options.trace = true,
+                    "--json-trace" | "--record-inputs" | "--replay-inputs" => {
+                        let path = args
+                            .next()
+                            .ok_or_else(|| format!("{} needs a file\n\n{}", arg, usage()))?;
+                        let option = match arg.as_str() {
+                            "--json-trace" => &mut options.json_trace,
+                            "--record-inputs" => &mut options.record_inputs,
+                            _ => &mut options.replay_inputs,
+                        };
+                        *option = Some(path.clone());
+                    }
+                    "--dump-registers" => options.dump_registers = true,
+                    "--profile" => options.profile = true,
+                    "--leak-report" => options.leak_report = true,
+                    _ if file.is_none() && !arg.starts_with("--") => file = Some(arg.clone()),
+                    _ => return Err(unexpected(arg)),
                 }
             }
+            let file = file.ok_or_else(|| format!("missing program file\n\n{}", usage()))?;
+            Ok(Command::Run { file, options })
         }
-    }
-
-    fn set_reg(&mut self, register_index: usize, value: i32) {
-        if register_index < self.registers.len() {
-            self.registers[register_index] = value;
-            println!("Set register {} to value {}", register_index, value);
-        } else {
-            println!("Error: Invalid register index.");
+        "asm" => {
+            let [input, flag, output] = rest else {
+                return Err(usage());
+            };
+            if flag != "-o" {
+                return Err(unexpected(flag));
+            }
+            Ok(Command::Asm {
+                input: input.clone(),
+                output: output.clone(),
+            })
         }
-    }
-
-    fn add(&mut self, reg_a: usize, reg_b: usize, target_register: usize) {
-        self.binary_op(reg_a, reg_b, target_register, |a, b| a + b, "Add");
-    }
-
-    fn sub(&mut self, reg_a: usize, reg_b: usize, target_register: usize) {
-        self.binary_op(reg_a, reg_b, target_register, |a, b| a - b, "Sub");
-    }
-
-    fn mul(&mut self, reg_a: usize, reg_b: usize, target_register: usize) {
-        self.binary_op(reg_a, reg_b, target_register, |a, b| a * b, "Mul");
-    }
-
-    fn div(&mut self, reg_a: usize, reg_b: usize, target_register: usize) {
-        self.binary_op(reg_a, reg_b, target_register, |a, b| a / b, "Div");
-    }
-
-    fn mod_op(&mut self, reg_a: usize, reg_b: usize, target_register: usize) {
-        self.binary_op(reg_a, reg_b, target_register, |a, b| a % b, "Mod");
-    }
-
-    fn binary_op<F>(
-        &mut self,
-        reg_a: usize,
-        reg_b: usize,
-        target_register: usize,
-        op: F,
-        op_name: &str,
-    ) where
-        F: Fn(i32, i32) -> i32,
-    {
-        if reg_a < self.registers.len()
-            && reg_b < self.registers.len()
-            && target_register < self.registers.len()
-        {
-            let result = op(self.registers[reg_a], self.registers[reg_b]);
-            self.registers[target_register] = result;
-            println!(
-                "{}: {} and {} -> {} (stored in register {})",
-                op_name, self.registers[reg_a], self.registers[reg_b], result, target_register
-            );
-        } else {
-            println!("Error: Invalid register index.");
+        "disasm" => {
+            let [input] = rest else {
+                return Err(usage());
+            };
+            Ok(Command::Disasm(input.clone()))
         }
-    }
-
-    fn eq(&mut self, reg_a: usize, reg_b: usize, target_register: usize) {
-        self.compare_op(reg_a, reg_b, target_register, |a, b| a == b, "Eq");
-    }
-
-    fn neq(&mut self, reg_a: usize, reg_b: usize, target_register: usize) {
-        self.compare_op(reg_a, reg_b, target_register, |a, b| a != b, "Neq");
-    }
-
-    fn gt(&mut self, reg_a: usize, reg_b: usize, target_register: usize) {
-        self.compare_op(reg_a, reg_b, target_register, |a, b| a > b, "Gt");
-    }
-
-    fn lt(&mut self, reg_a: usize, reg_b: usize, target_register: usize) {
-        self.compare_op(reg_a, reg_b, target_register, |a, b| a < b, "Lt");
-    }
-
-    fn gte(&mut self, reg_a: usize, reg_b: usize, target_register: usize) {
-        self.compare_op(reg_a, reg_b, target_register, |a, b| a >= b, "Gte");
-    }
-
-    fn lte(&mut self, reg_a: usize, reg_b: usize, target_register: usize) {
-        self.compare_op(reg_a, reg_b, target_register, |a, b| a <= b, "Lte");
-    }
-
-    fn compare_op<F>(
-        &mut self,
-        reg_a: usize,
-        reg_b: usize,
-        target_register: usize,
-        op: F,
-        op_name: &str,
-    ) where
-        F: Fn(i32, i32) -> bool,
-    {
-        if reg_a < self.registers.len()
-            && reg_b < self.registers.len()
-            && target_register < self.registers.len()
-        {
-            let result = op(self.registers[reg_a], self.registers[reg_b]);
-            self.registers[target_register] = if result { 1 } else { 0 };
-            println!(
-                "{}: {} and {} -> {} (stored in register {})",
-                op_name,
-                self.registers[reg_a],
-                self.registers[reg_b],
-                if result { 1 } else { 0 },
-                target_register
-            );
-        } else {
-            println!("Error: Invalid register index.");
+        "debug" => {
+            let [input] = rest else {
+                return Err(usage());
+            };
+            Ok(Command::Debug(input.clone()))
         }
-    }
-
-    fn allocate_memory(&mut self, size: usize) {
-        let address = self.next_free_address;
-        self.memory.insert(
-            address,
-            MemoryRegion {
-                size,
-                data: vec![0; size],
-            },
-        );
-        self.next_free_address += size;
-        println!("Allocated {} bytes of memory at address {}", size, address);
-    }
-
-    fn free_memory(&mut self, address: usize) {
-        if self.memory.remove(&address).is_some() {
-            println!("Freed memory at address {}", address);
-        } else {
-            println!("Error: No memory block found at address {}", address);
+        #[cfg(feature = "tui")]
+        "debug-tui" => {
+            let [input] = rest else {
+                return Err(usage());
+            };
+            Ok(Command::DebugTui(input.clone()))
         }
-    }
-
-    fn store_to_memory(&mut self, address: usize, register_index: usize, offset: usize) {
-        if let Some(region) = self.memory.get_mut(&address) {
-            if offset < region.size {
-                region.data[offset] = self.registers[register_index] as u8;
-                println!(
-                    "Stored value {} from register {} at memory address {} and offset {}",
-                    self.registers[register_index], register_index, address, offset
-                );
-            } else {
-                println!("Error: Memory offset out of bounds.");
-            }
-        } else {
-            println!("Error: No memory region found at address {}", address);
+        #[cfg(feature = "gdb")]
+        "gdb" => {
+            let [file, address] = rest else {
+                return Err(usage());
+            };
+            Ok(Command::Gdb {
+                file: file.clone(),
+                address: address.clone(),
+            })
         }
+        "dap" => match rest.first() {
+            Some(arg) => Err(unexpected(arg)),
+            None => Ok(Command::Dap),
+        },
+        #[cfg(feature = "repl")]
+        "repl" => match rest.first() {
+            Some(arg) => Err(unexpected(arg)),
+            None => Ok(Command::Repl),
+        },
+        "help" | "--help" | "-h" => Ok(Command::Help),
+        _ => Err(format!("unknown command `{}`\n\n{}", command, usage())),
     }
+}
 
-    fn load_from_memory(&mut self, address: usize, register_index: usize) {
-        if let Some(region) = self.memory.get(&address) {
-            let value = region.data[0] as i32; // For simplicity, just loading the first byte.
-            self.registers[register_index] = value;
-            println!(
-                "Loaded value {} from memory address {} into register {}",
-                value, address, register_index
-            );
-        } else {
-            println!("Error: No memory region found at address {}", address);
+fn run_command(command: Command) -> Result<(), String> {
+    match command {
+        Command::Example => run_program(Program::from(example_program()), &RunOptions::default()),
+        Command::Run { file, options } => run_program(load_program(&file)?, &options),
+        Command::Asm { input, output } => {
+            let program = load_program(&input)?;
+            fs::write(&output, program.to_bytes())
+                .map_err(|error| format!("cannot write {}: {}", output, error))
         }
-    }
-
-    fn jump(&mut self, ip_offset: usize) {
-        if self.ip + ip_offset < self.program.len() {
-            self.ip += ip_offset;
-            println!("Jumping to instruction {}", self.ip);
-        } else {
-            println!("Error: Invalid jump target.");
+        Command::Disasm(input) => {
+            print!("{}", disassemble_program(&load_program(&input)?));
+            Ok(())
         }
-    }
-
-    fn jump_if_zero(&mut self, register_index: usize, ip_offset: usize) {
-        if self.registers[register_index] == 0 {
-            self.jump(ip_offset);
+        Command::Debug(input) => {
+            let mut vm = VM::from_program(load_program(&input)?);
+            Debugger::attach(&mut vm)
+                .run_prompt(io::stdin().lock(), io::stdout())
+                .map_err(|error| error.to_string())
         }
-    }
-
-    fn jump_if_non_zero(&mut self, register_index: usize, ip_offset: usize) {
-        if self.registers[register_index] != 0 {
-            self.jump(ip_offset);
+        #[cfg(feature = "tui")]
+        Command::DebugTui(input) => {
+            let mut vm = VM::from_program(load_program(&input)?);
+            basic_rust_vm::run_tui(&mut vm).map_err(|error| error.to_string())
         }
-    }
-
-    fn print(&self, register_index: usize) {
-        if register_index < self.registers.len() {
-            println!(
-                "Register {}: {}",
-                register_index, self.registers[register_index]
-            );
-        } else {
-            println!("Error: Invalid register index.");
+        #[cfg(feature = "gdb")]
+        Command::Gdb { file, address } => {
+            let mut vm = VM::from_program(load_program(&file)?);
+            let listener = std::net::TcpListener::bind(&address)
+                .map_err(|error| format!("cannot listen on {}: {}", address, error))?;
+            eprintln!("Waiting for a debugger to connect on {}", address);
+            let (connection, _) = listener.accept().map_err(|error| error.to_string())?;
+            basic_rust_vm::serve_gdb(&mut vm, connection).map_err(|error| error.to_string())
+        }
+        Command::Dap => {
+            serve_dap(io::stdin().lock(), io::stdout()).map_err(|error| error.to_string())
+        }
+        #[cfg(feature = "repl")]
+        Command::Repl => basic_rust_vm::run_repl().map_err(|error| error.to_string()),
+        Command::Help => {
+            println!("{}", usage());
+            Ok(())
         }
     }
+}
 
-    fn call(&mut self, target_pc: usize) {
-        // Push the return address to the stack
-        self.stack.push(self.ip);
-        // Jump to the function address offset
-        self.ip += target_pc;
-        println!("Calling function at {}", self.ip);
+// Bytecode is recognized by its magic bytes, anything else is assembled as text.
+fn load_program(path: &str) -> Result<Program, String> {
+    let bytes = fs::read(path).map_err(|error| format!("cannot read {}: {}", path, error))?;
+    if bytes.starts_with(b"BRVM") {
+        return Program::from_bytes(&bytes).map_err(|error| format!("{}: {}", path, error));
     }
+    let source = String::from_utf8(bytes).map_err(|_| format!("{}: not valid UTF-8", path))?;
+    assemble_program(&source).map_err(|error| format!("{}: {}", path, error))
+}
 
-    fn return_from_function(&mut self) {
-        // Pop the return address from the stack and continue
-        if let Some(return_address) = self.stack.pop() {
-            self.ip = return_address;
+// The flags of `run`.
+#[derive(Debug, Default, PartialEq)]
+struct RunOptions {
+    trace: bool,
+    json_trace: Option<String>, // File the JSON Lines trace is written to
+    record_inputs: Option<String>, // File the input log is written to
+    replay_inputs: Option<String>, // File an input log is replayed from
+    dump_registers: bool,
+    profile: bool,
+    leak_report: bool,
+}
+
+fn run_program(program: Program, options: &RunOptions) -> Result<(), String> {
+    program
+        .verify(&VmConfig::default())
+        .map_err(|error| error.to_string())?;
+    let mut vm = VM::from_program(program);
+    if let Some(path) = &options.json_trace {
+        let file =
+            fs::File::create(path).map_err(|error| format!("cannot write {}: {}", path, error))?;
+        vm.set_json_trace(Some(Box::new(io::BufWriter::new(file))));
+    }
+    vm.set_profiling(options.profile);
+    vm.set_leak_report(options.leak_report);
+    if let Some(path) = &options.replay_inputs {
+        let log =
+            InputLog::load(path).map_err(|error| format!("cannot read {}: {}", path, error))?;
+        vm.replay_inputs(log);
+    }
+    if options.record_inputs.is_some() {
+        vm.record_inputs();
+    }
+    if options.trace {
+        vm.set_tracer(Some(Box::new(|ip, instruction, _| {
+            eprintln!("{:04}: {}", ip, instruction);
+        })));
+    }
+    let result = vm.run();
+    if let Some(path) = &options.record_inputs {
+        vm.take_input_log()
+            .save(path)
+            .map_err(|error| format!("cannot write {}: {}", path, error))?;
+    }
+    if let Some(profile) = vm.profile() {
+        eprint!("{}", profile);
+    }
+    if options.dump_registers {
+        for (index, value) in vm.registers().iter().enumerate() {
+            eprintln!("r{} = {}", index, value);
         }
     }
+    // A trap names the faulting instruction, other errors like a timeout don't
+    // have one.
+    result.map(|_| ()).map_err(|error| match vm.trap() {
+        Some(trap) => trap.to_string(),
+        None => error.to_string(),
+    })
 }
 
 // Our example program
-fn main() {
-    let program = vec![
-        Instruction::AllocateMemory(100),    // Allocate 100 bytes
-        Instruction::SetReg(0, 42),          // Set reg0 to 42
-        Instruction::StoreToMemory(0, 0, 0), // Store value in memory at address 0 and offset 0
-        Instruction::LoadFromMemory(0, 1),   // Load value from memory at address 0 into reg1 1
-        Instruction::Print(1),               // Print reg1
-        Instruction::FreeMemory(0),          // Free memory at address 0
-        Instruction::SetReg(0, 2),           // reg0 = 2
-        Instruction::SetReg(1, 3),           // reg1 = 3
-        Instruction::Call(5),                // Call function 1 (offset 6)
-        Instruction::Print(0),               // Print reg0
-        Instruction::JumpIfZero(4, 1),       // if reg4 is is 0, jump on instruction
-        Instruction::Print(4),               // Print reg4
-        Instruction::Print(2),               // Print reg2
-        Instruction::Halt,                   // Halt the VM
-        // function 1
-        Instruction::SetReg(2, 11), // Store 11 in reg2
-        Instruction::SetReg(3, 22), // Store 22 in reg3
-        Instruction::Add(2, 3, 4),  // Add reg2 and reg3, result in reg4
-        Instruction::Print(4),      // Print reg4
-        Instruction::Return,        // Return from function
-    ];
-
-    let mut vm = VM::new(program);
-    vm.run();
+fn example_program() -> Vec<Instruction> {
+    let mut builder = ProgramBuilder::new();
+    builder
+        .push(Instruction::SetReg(0, 100)) // reg0 = 100
+        .push(Instruction::AllocateMemory(0, 2)) // Allocate 100 bytes, its address (0) goes to reg2
+        .push(Instruction::SetReg(0, 42)) // Set reg0 to 42
+        .push(Instruction::StoreToMemory(0, 0, 0)) // Store value in memory at address 0 and offset 0
+        .push(Instruction::LoadFromMemory(0, 0, 1)) // Load value from memory at address 0 into reg1
+        .push(Instruction::Print(1)) // Print reg1
+        .push(Instruction::FreeMemory(0)) // Free memory at address 0
+        .push(Instruction::SetReg(0, 2)) // reg0 = 2
+        .push(Instruction::SetReg(1, 3)) // reg1 = 3
+        .call("function") // Call the function below
+        .push(Instruction::Print(0)) // Print reg0
+        .jump_if_zero_to(4, "skip") // If reg4 is 0, skip printing it
+        .push(Instruction::Print(4)) // Print reg4
+        .label("skip")
+        .push(Instruction::Print(2)) // Print reg2
+        .push(Instruction::Halt) // Halt the VM
+        .label("function")
+        .push(Instruction::SetReg(2, 11)) // Store 11 in reg2
+        .push(Instruction::SetReg(3, 22)) // Store 22 in reg3
+        .push(Instruction::Add(2, 3, 4)) // Add reg2 and reg3, result in reg4
+        .push(Instruction::Print(4)) // Print reg4
+        .push(Instruction::Return); // Return from function
+    builder
+        .build()
+        .expect("the example program's labels are defined")
 }
 
-
 #[cfg(test)]
 mod tests {
-    use super::*; // Bring the entire VM module into the test scope
-
-    #[test]
-    fn test_set_register() {
-        let mut vm = VM::new(vec![
-            Instruction::SetReg(0, 42), // Set reg0 to 42
-            Instruction::SetReg(1, 100), // Set reg1 to 100
-        ]);
-
-        vm.run();
-
-        // Assert that the registers were set correctly
-        assert_eq!(vm.registers[0], 42);
-        assert_eq!(vm.registers[1], 100);
-    }
-
-    #[test]
-    fn test_addition() {
-        let mut vm = VM::new(vec![
-            Instruction::SetReg(0, 42),        // Set reg0 to 42
-            Instruction::SetReg(1, 58),        // Set reg1 to 58
-            Instruction::Add(0, 1, 2),         // Add reg0 and reg1, store in reg2
-        ]);
-
-        vm.run();
-
-        // Assert that the addition was performed correctly
-        assert_eq!(vm.registers[2], 100);
-    }
-
-    #[test]
-    fn test_subtraction() {
-        let mut vm = VM::new(vec![
-            Instruction::SetReg(0, 42),        // Set reg0 to 42
-            Instruction::SetReg(1, 58),        // Set reg1 to 58
-            Instruction::Sub(1, 0, 2),         // Subtract reg0 from reg1, store in reg2
-        ]);
-
-        vm.run();
+    use super::*;
 
-        // Assert that the subtraction was performed correctly
-        assert_eq!(vm.registers[2], 16); // 58 - 42 = 16
+    fn parse(line: &str) -> Result<Command, String> {
+        let args: Vec<String> = line.split_whitespace().map(String::from).collect();
+        parse_args(&args)
     }
 
     #[test]
-    fn test_allocate_and_free_memory() {
-        let mut vm = VM::new(vec![
-            Instruction::AllocateMemory(100),   // Allocate 100 bytes
-            Instruction::AllocateMemory(200),   // Allocate another 200 bytes
-            Instruction::FreeMemory(0),         // Free memory at address 0
-        ]);
-
-        vm.run();
-
-        // Assert that the memory was allocated correctly and that the memory at address 0 was freed
-        assert!(vm.memory.contains_key(&100)); // Memory at address 100 (next available address)
-        assert!(!vm.memory.contains_key(&0));  // Memory at address 0 should have been freed
+    fn test_parse_run_flags() {
+        assert_eq!(parse(""), Ok(Command::Example));
+        assert_eq!(
+            parse("run --trace a.vmasm --profile --json-trace t.jsonl --replay-inputs in.json"),
+            Ok(Command::Run {
+                file: "a.vmasm".to_string(),
+                options: RunOptions {
+                    trace: true,
+                    json_trace: Some("t.jsonl".to_string()),
+                    replay_inputs: Some("in.json".to_string()),
+                    profile: true,
+                    ..RunOptions::default()
+                },
+            })
+        );
+        assert_eq!(
+            parse("run prog.bc --dump-registers --leak-report --record-inputs out.json"),
+            Ok(Command::Run {
+                file: "prog.bc".to_string(),
+                options: RunOptions {
+                    record_inputs: Some("out.json".to_string()),
+                    dump_registers: true,
+                    leak_report: true,
+                    ..RunOptions::default()
+                },
+            })
+        );
     }
 
     #[test]
-    fn test_memory_store_and_load() {
-        let mut vm = VM::new(vec![
-            Instruction::AllocateMemory(100),    // Allocate 100 bytes
-            Instruction::SetReg(0, 42),          // Set reg0 to 42
-            Instruction::StoreToMemory(0, 0, 0), // Store reg0 value into memory at address 0
-            Instruction::SetReg(1, 0),           // Set reg1 to 0 (for testing load)
-            Instruction::LoadFromMemory(0, 1),   // Load memory at address 0 into reg1
-        ]);
-
-        vm.run();
-
-        // Assert that the value was stored and loaded correctly
-        assert_eq!(vm.registers[1], 42); // reg1 should contain the value 42 loaded from memory
+    fn test_parse_asm() {
+        assert_eq!(
+            parse("asm prog.vmasm -o prog.bc"),
+            Ok(Command::Asm {
+                input: "prog.vmasm".to_string(),
+                output: "prog.bc".to_string(),
+            })
+        );
+        assert!(parse("asm prog.vmasm -x prog.bc")
+            .unwrap_err()
+            .starts_with("unexpected argument `-x`"));
+        assert_eq!(parse("asm prog.vmasm"), Err(usage()));
     }
 
     #[test]
-    fn test_jump_if_zero() {
-        let mut vm = VM::new(vec![
-            Instruction::SetReg(0, 0),           // Set reg0 to 0
-            Instruction::JumpIfZero(0, 2),       // Jump 2 instructions ahead if reg0 is 0
-            Instruction::SetReg(1, 100),         // This will be skipped due to the jump
-            Instruction::SetReg(2, 200),         // This will be executed after the jump
-        ]);
-
-        vm.run();
-
-        // Assert that reg2 was set (since reg0 was 0, we jumped over the previous instructions)
-        assert_eq!(vm.registers[2], 200);
+    fn test_parse_errors() {
+        let error = |line| parse(line).unwrap_err().lines().next().unwrap().to_string();
+        assert_eq!(error("run --trace"), "missing program file");
+        assert_eq!(error("run a.vmasm b.vmasm"), "unexpected argument `b.vmasm`");
+        assert_eq!(error("run a.vmasm --fast"), "unexpected argument `--fast`");
+        assert_eq!(error("run a.vmasm --json-trace"), "--json-trace needs a file");
+        assert_eq!(error("dap --port"), "unexpected argument `--port`");
+        assert_eq!(error("compile a.vmasm"), "unknown command `compile`");
+        assert!(parse("run").unwrap_err().ends_with(&usage()));
     }
 
     #[test]
-    fn test_halt_execution() {
-        let mut vm = VM::new(vec![
-            Instruction::SetReg(0, 42), // Set reg0 to 42
-            Instruction::Halt,           // Halt the program
-            Instruction::SetReg(1, 100), // This should not be executed
-        ]);
-
-        vm.run();
-
-        // Assert that the program halts and the second instruction does not execute
-        assert_eq!(vm.registers[0], 42);
-        assert_eq!(vm.registers[1], 0); // reg1 should still be 0 since the second instruction was never executed
+    fn test_usage_lists_compiled_in_commands() {
+        let usage = usage();
+        assert!(usage.contains("basic_rust_vm disasm <file>"));
+        assert_eq!(usage.contains("debug-tui"), cfg!(feature = "tui"));
+        assert_eq!(usage.contains("basic_rust_vm gdb"), cfg!(feature = "gdb"));
+        assert_eq!(usage.contains("basic_rust_vm repl"), cfg!(feature = "repl"));
+        assert_eq!(parse("repl").is_ok(), cfg!(feature = "repl"));
     }
 
     #[test]
-    fn test_function_call_and_return() {
-        let mut vm = VM::new(vec![
-            Instruction::SetReg(0, 42),    // Set reg0 to 42
-            Instruction::Call(4),           // Call function at offset 4
-            Instruction::Print(0),          // Print reg0 after return (should be 42)
-            // Function body starts here (offset 4)
-            Instruction::SetReg(0, 99),     // Set reg0 to 99 inside function
-            Instruction::Return,            // Return from function
-        ]);
-
-        vm.run();
+    fn test_load_program() {
+        let path = |extension: &str| {
+            let name = format!("basic_rust_vm_cli_{}.{}", std::process::id(), extension);
+            std::env::temp_dir().join(name)
+        };
+        let (text, bytecode) = (path("vmasm"), path("bc"));
+        fs::write(&text, "set r0, 7\nprint r0\nhalt\n").unwrap();
+        let program = load_program(text.to_str().unwrap()).unwrap();
+        assert_eq!(
+            program.instructions,
+            [Instruction::SetRegByte(0, 7), Instruction::Print(0), Instruction::Halt]
+        );
 
-        // Assert that the function call worked correctly and returned to the correct point
-        assert_eq!(vm.registers[0], 42); // reg0 should still be 42 after returning from the function
+        // Bytecode is told apart by its magic bytes, not the file name
+        fs::write(&bytecode, program.to_bytes()).unwrap();
+        assert_eq!(load_program(bytecode.to_str().unwrap()), Ok(program.clone()));
+        fs::write(&text, program.to_bytes()).unwrap();
+        assert_eq!(load_program(text.to_str().unwrap()), Ok(program));
+
+        let text = text.to_str().unwrap();
+        fs::write(text, [0xFF, 0xFE]).unwrap();
+        assert_eq!(load_program(text), Err(format!("{}: not valid UTF-8", text)));
+        fs::remove_file(text).unwrap();
+        fs::remove_file(&bytecode).unwrap();
+        assert!(load_program(text).unwrap_err().starts_with(&format!("cannot read {}", text)));
     }
 }
@@ -0,0 +1,560 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::alloc::FreeList;
+use crate::error::VmError;
+use crate::paging::{PageTable, PagingConfig, Segment};
+
+/// A block of memory handed out by the allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub address: usize,
+    pub size: usize,
+    pub protection: Protection,
+}
+
+/// The accesses the program may make to a region, set with `ProtectMemory`.
+/// New regions can be read and written, the data section only read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Protection {
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Protection {
+    pub const READ_WRITE: Protection = Protection {
+        read: true,
+        write: true,
+    };
+    pub const READ_ONLY: Protection = Protection {
+        read: true,
+        write: false,
+    };
+
+    /// The flags operand of `ProtectMemory`: bit 0 allows reads, bit 1 writes.
+    /// `None` if any other bit is set.
+    pub fn from_bits(bits: u8) -> Option<Protection> {
+        (bits <= 0b11).then_some(Protection {
+            read: bits & 0b01 != 0,
+            write: bits & 0b10 != 0,
+        })
+    }
+
+    pub fn bits(self) -> u8 {
+        self.read as u8 | (self.write as u8) << 1
+    }
+}
+
+impl Default for Protection {
+    fn default() -> Self {
+        Protection::READ_WRITE
+    }
+}
+
+/// How carefully a memory store checks the program's use of it, set with
+/// `VM::set_memory_safety`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MemorySafety {
+    #[default]
+    Off, // Freed memory is reused, accesses to it report `NoMemoryRegion`
+    Quarantine, // Freed regions are never reused, accesses trap with `UseAfterFree`
+    Strict,     // Quarantine, and reads of bytes never written trap with `UninitializedRead`
+}
+
+// Registers hold addresses as unsigned 32-bit values, so no region may end
+// past this.
+const ADDRESS_SPACE: usize = 1 << 32;
+
+/// The byte a region allocated in `MemorySafety::Strict` mode is filled with
+/// until the program writes it.
+pub const POISON_BYTE: u8 = 0xCD;
+
+/// The linear address space of a VM. All bytes live in one flat buffer, the
+/// regions only record which parts of it are currently allocated. Once paging
+/// is enabled, accesses are checked against the mapped pages instead. It lives
+/// behind an `Rc<RefCell<_>>` so several VMs can share one store and see each
+/// other's allocations and writes.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct MemoryStore {
+    pub(crate) bytes: Vec<u8>,                  // The whole address space
+    pub(crate) regions: BTreeMap<usize, usize>, // Allocated regions, base address to size
+    pub(crate) next_free_address: usize,        // End of the highest allocated region
+    pub(crate) free_list: FreeList,             // Freed blocks below `next_free_address`
+    pub(crate) read_only_end: usize,            // Bytes below this address can't be written
+    #[serde(default)]
+    pub(crate) safety: MemorySafety,
+    #[serde(default)]
+    pub(crate) quarantine: BTreeMap<usize, usize>, // Regions freed in Quarantine mode, base address to size
+    #[serde(default)]
+    pub(crate) protections: BTreeMap<usize, Protection>, // Regions that aren't read-write, base address to protection
+    #[serde(default)]
+    pub(crate) poisoned: Vec<bool>, // Per byte, allocated in Strict mode and not written since
+    #[serde(default)]
+    pub(crate) pages: Option<PageTable>, // Mapped pages and segments, when paging is enabled
+    #[serde(default)]
+    pub(crate) reserved: BTreeMap<usize, usize>, // Ranges kept from the allocator for devices, base address to size
+    #[serde(default)]
+    pub(crate) refcounts: BTreeMap<usize, usize>, // Regions the program retained or released, base address to count
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    /// The region allocated at exactly `address`, if any.
+    pub fn region(&self, address: usize) -> Option<MemoryRegion> {
+        self.regions
+            .get(&address)
+            .map(|&size| self.described(address, size))
+    }
+
+    fn described(&self, address: usize, size: usize) -> MemoryRegion {
+        MemoryRegion {
+            address,
+            size,
+            protection: self.protection(address),
+        }
+    }
+
+    /// The reference count of the region allocated at exactly `address`, 1 for
+    /// a region the program never retained.
+    pub fn refcount(&self, address: usize) -> Option<usize> {
+        self.regions
+            .contains_key(&address)
+            .then(|| self.refcounts.get(&address).copied().unwrap_or(1))
+    }
+
+    /// Groups of reference-counted regions that keep each other alive: each one
+    /// holds the base address of the next as a little-endian 32-bit word at an
+    /// offset that is a multiple of four. Releasing every outside reference
+    /// never frees them. Each group is in address order.
+    pub fn reference_cycles(&self) -> Vec<Vec<usize>> {
+        let counted: Vec<usize> = self.refcounts.keys().copied().collect();
+        let targets = |address: usize| -> Vec<usize> {
+            self.bytes[address..address + self.regions[&address]]
+                .chunks_exact(4)
+                .map(|word| u32::from_le_bytes(word.try_into().unwrap()) as usize)
+                .filter(|target| self.refcounts.contains_key(target))
+                .collect()
+        };
+        // The counted regions each one reaches in one or more steps
+        let reachable: Vec<BTreeSet<usize>> = counted
+            .iter()
+            .map(|&start| {
+                let mut reached = BTreeSet::new();
+                let mut pending = targets(start);
+                while let Some(address) = pending.pop() {
+                    if reached.insert(address) {
+                        pending.extend(targets(address));
+                    }
+                }
+                reached
+            })
+            .collect();
+        let mut cycles: Vec<Vec<usize>> = Vec::new();
+        for (index, &address) in counted.iter().enumerate() {
+            if reachable[index].contains(&address)
+                && !cycles.iter().flatten().any(|&a| a == address)
+            {
+                cycles.push(
+                    counted
+                        .iter()
+                        .zip(&reachable)
+                        .filter(|&(other, reached)| {
+                            reached.contains(&address) && reachable[index].contains(other)
+                        })
+                        .map(|(&other, _)| other)
+                        .collect(),
+                );
+            }
+        }
+        cycles
+    }
+
+    // The protection of the region allocated at `address`.
+    fn protection(&self, address: usize) -> Protection {
+        self.protections.get(&address).copied().unwrap_or_default()
+    }
+
+    /// The end of the highest allocated region. Allocations that don't fit into a
+    /// freed block are placed here.
+    pub fn next_free_address(&self) -> usize {
+        self.next_free_address
+    }
+
+    /// All allocated regions, in address order.
+    pub fn regions(&self) -> impl Iterator<Item = MemoryRegion> + '_ {
+        self.regions
+            .iter()
+            .map(|(&address, &size)| self.described(address, size))
+    }
+
+    /// The freed blocks available for reuse, in address order.
+    pub fn free_blocks(&self) -> impl Iterator<Item = MemoryRegion> + '_ {
+        self.free_list.blocks().map(|(address, size)| MemoryRegion {
+            address,
+            size,
+            protection: Protection::default(),
+        })
+    }
+
+    /// The freed region containing `address`, while it is quarantined.
+    pub fn freed_region(&self, address: usize) -> Option<MemoryRegion> {
+        self.quarantine
+            .range(..=address)
+            .next_back()
+            .filter(|&(&base, &size)| address - base < size)
+            .map(|(&address, &size)| MemoryRegion {
+                address,
+                size,
+                protection: Protection::default(),
+            })
+    }
+
+    /// The end of the read-only data section at the start of the address space,
+    /// 0 when there is none.
+    pub fn read_only_end(&self) -> usize {
+        self.read_only_end
+    }
+
+    /// Allocate a region at the start of the empty address space holding `data`,
+    /// which can be read but not written or freed. Returns false, and leaves the
+    /// store alone, when memory has already been allocated.
+    pub fn load_data(&mut self, data: &[u8]) -> bool {
+        if self.next_free_address != 0 {
+            return false;
+        }
+        if !data.is_empty() {
+            self.allocate_at(0, data.len());
+            self.write(0, data);
+            self.protections.insert(0, Protection::READ_ONLY);
+        }
+        self.read_only_end = data.len();
+        true
+    }
+
+    /// Switch to a paged address space laid out by `config`: the data section
+    /// and the stack are mapped, and the heap starts at the first page after the
+    /// data section. Returns false, and leaves the store alone, when paging is
+    /// already enabled, memory beyond the data section has been allocated or the
+    /// layout doesn't fit.
+    pub fn enable_paging(&mut self, config: PagingConfig) -> bool {
+        if self.pages.is_some() || self.next_free_address != self.read_only_end {
+            return false;
+        }
+        let Some(pages) = PageTable::new(config, self.read_only_end) else {
+            return false;
+        };
+        self.bytes.resize(pages.memory_size(), 0);
+        self.next_free_address = pages.heap_base();
+        self.pages = Some(pages);
+        true
+    }
+
+    /// The layout of the address space, when paging is enabled.
+    pub fn paging(&self) -> Option<PagingConfig> {
+        self.pages.as_ref().map(PageTable::config)
+    }
+
+    /// The data, heap and stack segments, empty when paging isn't enabled.
+    pub fn segments(&self) -> Vec<Segment> {
+        self.pages
+            .as_ref()
+            .map(PageTable::segments)
+            .unwrap_or_default()
+    }
+
+    /// The base addresses of the mapped pages, in order. Empty when paging isn't
+    /// enabled.
+    pub fn mapped_pages(&self) -> Vec<usize> {
+        self.pages
+            .iter()
+            .flat_map(PageTable::mapped_pages)
+            .collect()
+    }
+
+    /// The `len` bytes starting at `address`. Every byte must be allocated, but
+    /// the range may span adjacent regions.
+    pub fn bytes(&self, address: usize, len: usize) -> Result<&[u8], VmError> {
+        self.check_range(address, len)?;
+        Ok(&self.bytes[address..address + len])
+    }
+
+    /// Fails unless every byte of `[address, address + len)` is allocated. A range
+    /// starting outside allocated memory reports `NoMemoryRegion`, one that runs
+    /// into unallocated memory reports `OutOfBounds`. With paging, every byte
+    /// must be in a mapped page instead, or the access reports `PageFault` with
+    /// the first unmapped byte. Any of them reports `UseAfterFree` with the first
+    /// freed byte when that byte is quarantined.
+    pub(crate) fn check_range(&self, address: usize, len: usize) -> Result<(), VmError> {
+        if let Some(pages) = &self.pages {
+            address
+                .checked_add(len)
+                .ok_or(VmError::OutOfBounds(address))?;
+            return match pages.first_unmapped(address, len) {
+                Some(byte) => Err(self.unallocated(byte, VmError::PageFault(byte))),
+                None => Ok(()),
+            };
+        }
+        let (base, size) = self
+            .regions
+            .range(..=address)
+            .next_back()
+            .filter(|&(&base, &size)| address - base < size)
+            .ok_or_else(|| self.unallocated(address, VmError::NoMemoryRegion(address)))?;
+        let end = address
+            .checked_add(len)
+            .ok_or(VmError::OutOfBounds(address))?;
+        let mut covered = base + size;
+        for (&next, &size) in self.regions.range(covered..) {
+            if covered >= end || next != covered {
+                break;
+            }
+            covered += size;
+        }
+        if covered < end {
+            return Err(self.unallocated(covered, VmError::OutOfBounds(address)));
+        }
+        Ok(())
+    }
+
+    /// Like `bytes`, for a read by the program: every region it touches must
+    /// be readable, and in `MemorySafety::Strict` mode every byte must also
+    /// have been written since it was allocated.
+    pub(crate) fn read(&self, address: usize, len: usize) -> Result<&[u8], VmError> {
+        self.check_readable(address, len)?;
+        let bytes = &self.bytes[address..address + len];
+        let poisoned = self.poisoned.get(address..).unwrap_or_default();
+        if let Some(index) = poisoned.iter().take(len).position(|&poisoned| poisoned) {
+            let byte = address + index;
+            let (&base, _) = self.regions.range(..=byte).next_back().unwrap();
+            return Err(VmError::UninitializedRead {
+                address: base,
+                offset: byte - base,
+            });
+        }
+        Ok(bytes)
+    }
+
+    // Which of `len` bytes at `address` were never written, taken before they
+    // are copied and handed to `repoison` afterwards, so copying memory
+    // doesn't make it count as initialized. `None` when none of them is.
+    pub(crate) fn poison(&self, address: usize, len: usize) -> Option<Vec<bool>> {
+        let poisoned = self.poisoned.get(address..address + len)?;
+        poisoned.contains(&true).then(|| poisoned.to_vec())
+    }
+
+    pub(crate) fn repoison(&mut self, address: usize, poison: &[bool]) {
+        if self.poisoned.len() < address + poison.len() {
+            self.poisoned.resize(address + poison.len(), false);
+        }
+        self.poisoned[address..address + poison.len()].copy_from_slice(poison);
+    }
+
+    // The error for an access that reaches the unallocated byte at `address`.
+    fn unallocated(&self, address: usize, error: VmError) -> VmError {
+        match self.freed_region(address) {
+            Some(_) => VmError::UseAfterFree(address),
+            None => error,
+        }
+    }
+
+    pub(crate) fn set_safety(&mut self, safety: MemorySafety) {
+        self.safety = safety;
+    }
+
+    /// Like `check_range`, but also fails with `UnreadableMemory` for a range
+    /// that touches a region protected against reads.
+    pub(crate) fn check_readable(&self, address: usize, len: usize) -> Result<(), VmError> {
+        self.check_range(address, len)?;
+        if self.protected(address, len, |protection| !protection.read) {
+            return Err(VmError::UnreadableMemory(address));
+        }
+        Ok(())
+    }
+
+    /// Like `check_range`, but also fails with `ReadOnlyMemory` for a range
+    /// that touches the data section or another region that isn't writable.
+    pub(crate) fn check_writable(&self, address: usize, len: usize) -> Result<(), VmError> {
+        self.check_range(address, len)?;
+        if address < self.read_only_end
+            || self.protected(address, len, |protection| !protection.write)
+        {
+            return Err(VmError::ReadOnlyMemory(address));
+        }
+        Ok(())
+    }
+
+    // Whether a region overlapping the allocated range `[address, address +
+    // len)` has a protection that `denies` the access.
+    fn protected(&self, address: usize, len: usize, denies: fn(Protection) -> bool) -> bool {
+        self.protections
+            .range(..address + len)
+            .filter(|&(&base, _)| base + self.regions[&base] > address)
+            .any(|(_, &protection)| denies(protection))
+    }
+
+    pub(crate) fn set_refcount(&mut self, address: usize, count: usize) {
+        self.refcounts.insert(address, count);
+    }
+
+    pub(crate) fn set_protection(&mut self, address: usize, protection: Protection) {
+        if protection == Protection::default() {
+            self.protections.remove(&address);
+        } else {
+            self.protections.insert(address, protection);
+        }
+    }
+
+    // Where an allocation of `size` bytes will be placed: the lowest freed block
+    // it fits into, or the end of the allocated memory.
+    pub(crate) fn find_free(&self, size: usize) -> usize {
+        first_fit(&self.free_list, self.next_free_address, size)
+    }
+
+    // Where `find_free(size)` will place an allocation once the region at
+    // `address` has been freed.
+    pub(crate) fn find_free_replacing(&self, address: usize, size: usize) -> usize {
+        let Some(&old_size) = self.regions.get(&address) else {
+            return self.find_free(size);
+        };
+        if self.safety != MemorySafety::Off {
+            return self.find_free(size);
+        }
+        let mut free_list = self.free_list.clone();
+        let mut next_free_address = self.next_free_address;
+        release(
+            &mut free_list,
+            &mut next_free_address,
+            address,
+            footprint(old_size),
+        );
+        first_fit(&free_list, next_free_address, size)
+    }
+
+    /// Fails with `OutOfMemory` when a region of `size` bytes at `address`
+    /// would reach past the 32-bit addresses registers can hold, or, with
+    /// paging enabled, past the heap into the guard pages.
+    pub(crate) fn check_allocation(&self, address: usize, size: usize) -> Result<(), VmError> {
+        let end = address.saturating_add(footprint(size));
+        match &self.pages {
+            _ if end > ADDRESS_SPACE => Err(VmError::OutOfMemory(size)),
+            Some(pages) if end > pages.heap_end() => Err(VmError::OutOfMemory(size)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Keep `[address, address + size)` from the allocator, for a memory-mapped
+    /// device. The range must be neither allocated nor reserved. Memory skipped
+    /// below a range past the allocated memory goes on the free list.
+    pub(crate) fn reserve(&mut self, address: usize, size: usize) -> bool {
+        let Some(end) = address.checked_add(size) else {
+            return false;
+        };
+        if address >= self.next_free_address {
+            if address > self.next_free_address {
+                self.free_list
+                    .release(self.next_free_address, address - self.next_free_address);
+            }
+            self.next_free_address = end;
+        } else if !self.free_list.reserve(address, size) {
+            return false;
+        }
+        self.reserved.insert(address, size);
+        true
+    }
+
+    /// The ranges reserved for memory-mapped devices, as base address and size.
+    pub fn reserved(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.reserved
+            .iter()
+            .map(|(&address, &size)| (address, size))
+    }
+
+    pub(crate) fn allocate_at(&mut self, address: usize, size: usize) {
+        let end = address + size;
+        self.free_list.reserve(address, footprint(size));
+        self.next_free_address = self.next_free_address.max(address + footprint(size));
+        if self.bytes.len() < end {
+            self.bytes.resize(end, 0);
+        }
+        if self.safety == MemorySafety::Strict {
+            self.bytes[address..end].fill(POISON_BYTE);
+            if self.poisoned.len() < end {
+                self.poisoned.resize(end, false);
+            }
+            self.poisoned[address..end].fill(true);
+        } else {
+            self.bytes[address..end].fill(0);
+            if let Some(poisoned) = self.poisoned.get_mut(address..end) {
+                poisoned.fill(false);
+            }
+        }
+        self.regions.insert(address, size);
+        if let Some(pages) = &mut self.pages {
+            pages.map(address, size);
+        }
+    }
+
+    // Freed blocks at the end of the allocated memory move the cursor back
+    // instead of staying on the free list. Quarantined blocks are never reused.
+    pub(crate) fn free(&mut self, address: usize) -> bool {
+        let Some(size) = self.regions.remove(&address) else {
+            return false;
+        };
+        self.protections.remove(&address);
+        self.refcounts.remove(&address);
+        if let Some(mut pages) = self.pages.take() {
+            pages.unmap(address, size, |page, len| {
+                self.regions
+                    .range(..page + len)
+                    .any(|(&base, &size)| base + size > page)
+            });
+            self.pages = Some(pages);
+        }
+        if self.safety != MemorySafety::Off {
+            if size > 0 {
+                self.quarantine.insert(address, size);
+            }
+            return true;
+        }
+        release(
+            &mut self.free_list,
+            &mut self.next_free_address,
+            address,
+            footprint(size),
+        );
+        true
+    }
+
+    // Callers check the range first, writes never grow the address space.
+    pub(crate) fn write(&mut self, address: usize, bytes: &[u8]) {
+        if let Some(target) = self.bytes.get_mut(address..address + bytes.len()) {
+            target.copy_from_slice(bytes);
+        }
+        let end = self.poisoned.len().min(address + bytes.len());
+        if let Some(poisoned) = self.poisoned.get_mut(address..end) {
+            poisoned.fill(false);
+        }
+    }
+}
+
+// The bytes of the address space a region of `size` bytes takes up. An empty
+// region still takes one, so that it has an address of its own.
+fn footprint(size: usize) -> usize {
+    size.max(1)
+}
+
+fn first_fit(free_list: &FreeList, next_free_address: usize, size: usize) -> usize {
+    free_list.find(footprint(size)).unwrap_or(next_free_address)
+}
+
+fn release(free_list: &mut FreeList, next_free_address: &mut usize, address: usize, size: usize) {
+    let (start, size) = free_list.release(address, size);
+    if start + size == *next_free_address {
+        free_list.remove(start);
+        *next_free_address = start;
+    }
+}
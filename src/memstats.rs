@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::memory::{MemoryRegion, Protection};
+
+/// Counters for the memory a program allocated and freed, read with
+/// `VM::memory_stats()`. Resizing a region counts as a free and an allocation.
+/// The read-only data section isn't an allocation and isn't counted, and neither
+/// are regions of a shared store that another VM allocated. Displays as
+/// a leak report listing the regions that are still allocated.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct MemoryStats {
+    pub allocations: u64,                  // Regions allocated
+    pub frees: u64,                        // Regions freed
+    pub live_bytes: usize,                 // Bytes allocated and not freed
+    pub peak_bytes: usize,                 // The most bytes that were live at once
+    live: BTreeMap<usize, (usize, usize)>, // Base address to size and allocating ip
+}
+
+/// A region that is still allocated, and the address of the instruction that
+/// allocated it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Leak {
+    pub region: MemoryRegion,
+    pub ip: usize,
+}
+
+impl MemoryStats {
+    pub(crate) fn allocated(&mut self, address: usize, size: usize, ip: usize) {
+        self.allocations += 1;
+        self.live_bytes += size;
+        self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+        self.live.insert(address, (size, ip));
+    }
+
+    pub(crate) fn freed(&mut self, address: usize) {
+        if let Some((size, _)) = self.live.remove(&address) {
+            self.frees += 1;
+            self.live_bytes -= size;
+        }
+    }
+
+    // The size of the region at `address` if it was counted as allocated.
+    pub(crate) fn live_size(&self, address: usize) -> usize {
+        self.live.get(&address).map_or(0, |&(size, _)| size)
+    }
+
+    /// The number of regions allocated and not freed.
+    pub fn live_allocations(&self) -> usize {
+        self.live.len()
+    }
+
+    /// The regions allocated and not freed, in address order.
+    pub fn leaks(&self) -> impl Iterator<Item = Leak> + '_ {
+        self.live.iter().map(|(&address, &(size, ip))| Leak {
+            region: MemoryRegion {
+                address,
+                size,
+                protection: Protection::default(),
+            },
+            ip,
+        })
+    }
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Allocations: {}, frees: {}, peak usage: {} bytes",
+            self.allocations, self.frees, self.peak_bytes
+        )?;
+        if self.live.is_empty() {
+            return writeln!(f, "No leaks");
+        }
+        writeln!(
+            f,
+            "Leaked {} bytes in {} region{}:",
+            self.live_bytes,
+            self.live.len(),
+            if self.live.len() == 1 { "" } else { "s" }
+        )?;
+        for leak in self.leaks() {
+            writeln!(
+                f,
+                "  0x{:04x}: {} bytes allocated at {:04}",
+                leak.region.address, leak.region.size, leak.ip
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use std::ops::Range;
+
+use crate::error::VmError;
+
+/// A host device mapped into the address space with `VM::map_device`. The
+/// program's loads and stores to its range reach it one byte at a time, in
+/// address order, with the offset from the start of the range.
+pub trait MmioDevice {
+    fn read(&mut self, offset: usize) -> u8;
+    fn write(&mut self, offset: usize, value: u8);
+}
+
+/// The devices mapped into a VM's address space, in address order.
+#[derive(Default)]
+pub(crate) struct DeviceMap {
+    devices: Vec<(Range<usize>, Box<dyn MmioDevice>)>,
+}
+
+impl DeviceMap {
+    pub(crate) fn overlaps(&self, range: &Range<usize>) -> bool {
+        self.devices
+            .iter()
+            .any(|(mapped, _)| mapped.start < range.end && range.start < mapped.end)
+    }
+
+    pub(crate) fn insert(&mut self, range: Range<usize>, device: Box<dyn MmioDevice>) {
+        let index = self
+            .devices
+            .partition_point(|(mapped, _)| mapped.start < range.start);
+        self.devices.insert(index, (range, device));
+    }
+
+    /// The device whose range holds all of `[address, address + len)`, with the
+    /// offset of `address` in it. `None` when the access doesn't touch a device,
+    /// `OutOfBounds` when it only partly lies in one.
+    pub(crate) fn find(
+        &mut self,
+        address: usize,
+        len: usize,
+    ) -> Option<Result<(&mut dyn MmioDevice, usize), VmError>> {
+        let end = address.saturating_add(len.max(1));
+        let (range, device) = self
+            .devices
+            .iter_mut()
+            .find(|(range, _)| range.start < end && address < range.end)?;
+        if address < range.start || end > range.end {
+            return Some(Err(VmError::OutOfBounds(address)));
+        }
+        Some(Ok((device.as_mut(), address - range.start)))
+    }
+
+    /// Read `len` bytes from a device, `None` when the access doesn't touch one.
+    pub(crate) fn read(&mut self, address: usize, len: usize) -> Option<Result<Vec<u8>, VmError>> {
+        Some(
+            self.find(address, len)?
+                .map(|(device, offset)| (offset..offset + len).map(|at| device.read(at)).collect()),
+        )
+    }
+
+    /// Write `bytes` to a device, returning false when the access doesn't touch one.
+    pub(crate) fn write(&mut self, address: usize, bytes: &[u8]) -> bool {
+        let Some(Ok((device, offset))) = self.find(address, bytes.len()) else {
+            return false;
+        };
+        for (at, &value) in (offset..).zip(bytes) {
+            device.write(at, value);
+        }
+        true
+    }
+}
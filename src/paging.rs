@@ -0,0 +1,171 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::memory::Protection;
+
+/// The layout of a paged address space, chosen with `VM::enable_paging`. Sizes
+/// are rounded up to whole pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PagingConfig {
+    pub page_size: usize,   // Bytes per page, a power of two, 256 by default
+    pub memory_size: usize, // End of the address space and top of the stack, 64 KiB by default
+    pub stack_size: usize,  // Bytes of stack mapped below `memory_size`, 4 KiB by default
+    pub guard_pages: usize, // Unmapped pages between the heap and the stack, 1 by default
+}
+
+impl Default for PagingConfig {
+    fn default() -> Self {
+        PagingConfig {
+            page_size: 256,
+            memory_size: 64 * 1024,
+            stack_size: 4 * 1024,
+            guard_pages: 1,
+        }
+    }
+}
+
+/// What a part of a paged address space holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SegmentKind {
+    Code,  // The instructions, addressed by instruction index rather than byte
+    Data,  // The read-only data section at address 0
+    Heap,  // Where `AllocateMemory` places regions, mapped as they are allocated
+    Stack, // Mapped up front at the top of the address space, for the program's own use
+}
+
+/// A segment descriptor, returned by `VM::segments()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub base: usize,
+    pub size: usize,
+    pub protection: Protection,
+}
+
+/// Which pages of a paged `MemoryStore` are mapped, and where its segments lie.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PageTable {
+    page_size: usize,
+    data_end: usize,         // End of the data section
+    heap_base: usize,        // First page after the data section
+    heap_end: usize,         // First guard page
+    stack_base: usize,       // First stack page
+    memory_size: usize,      // Top of the stack
+    mapped: BTreeSet<usize>, // Mapped page numbers
+}
+
+impl PageTable {
+    /// A page table for a store whose data section ends at `data_end`, with the
+    /// data and stack pages mapped. `None` when the page size isn't a power of
+    /// two or the data section, guard pages and stack don't fit.
+    pub(crate) fn new(config: PagingConfig, data_end: usize) -> Option<PageTable> {
+        let page_size = config.page_size;
+        if !page_size.is_power_of_two() {
+            return None;
+        }
+        let round = |size: usize| size.checked_next_multiple_of(page_size);
+        let memory_size = round(config.memory_size)?;
+        let stack_base = memory_size.checked_sub(round(config.stack_size)?)?;
+        let heap_end = stack_base.checked_sub(config.guard_pages.checked_mul(page_size)?)?;
+        let heap_base = round(data_end)?;
+        if heap_base > heap_end {
+            return None;
+        }
+        let mut table = PageTable {
+            page_size,
+            data_end,
+            heap_base,
+            heap_end,
+            stack_base,
+            memory_size,
+            mapped: BTreeSet::new(),
+        };
+        table.map(0, data_end);
+        table.map(stack_base, memory_size - stack_base);
+        Some(table)
+    }
+
+    pub(crate) fn config(&self) -> PagingConfig {
+        PagingConfig {
+            page_size: self.page_size,
+            memory_size: self.memory_size,
+            stack_size: self.memory_size - self.stack_base,
+            guard_pages: (self.stack_base - self.heap_end) / self.page_size,
+        }
+    }
+
+    pub(crate) fn heap_base(&self) -> usize {
+        self.heap_base
+    }
+
+    pub(crate) fn heap_end(&self) -> usize {
+        self.heap_end
+    }
+
+    pub(crate) fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+
+    // The page numbers of `[address, address + len)`.
+    fn pages(&self, address: usize, len: usize) -> std::ops::Range<usize> {
+        if len == 0 {
+            return 0..0;
+        }
+        address / self.page_size..(address + len - 1) / self.page_size + 1
+    }
+
+    pub(crate) fn map(&mut self, address: usize, len: usize) {
+        self.mapped.extend(self.pages(address, len));
+    }
+
+    /// Unmap the pages of `[address, address + len)` for which `in_use` is false.
+    pub(crate) fn unmap(
+        &mut self,
+        address: usize,
+        len: usize,
+        in_use: impl Fn(usize, usize) -> bool,
+    ) {
+        for page in self.pages(address, len) {
+            if !in_use(page * self.page_size, self.page_size) {
+                self.mapped.remove(&page);
+            }
+        }
+    }
+
+    /// The first byte of `[address, address + len)` in an unmapped page, checking
+    /// at least the byte at `address`.
+    pub(crate) fn first_unmapped(&self, address: usize, len: usize) -> Option<usize> {
+        self.pages(address, len.max(1))
+            .find(|page| !self.mapped.contains(page))
+            .map(|page| (page * self.page_size).max(address))
+    }
+
+    /// The base addresses of the mapped pages, in order.
+    pub(crate) fn mapped_pages(&self) -> impl Iterator<Item = usize> + '_ {
+        self.mapped.iter().map(|page| page * self.page_size)
+    }
+
+    pub(crate) fn segments(&self) -> Vec<Segment> {
+        vec![
+            Segment {
+                kind: SegmentKind::Data,
+                base: 0,
+                size: self.data_end,
+                protection: Protection::READ_ONLY,
+            },
+            Segment {
+                kind: SegmentKind::Heap,
+                base: self.heap_base,
+                size: self.heap_end - self.heap_base,
+                protection: Protection::READ_WRITE,
+            },
+            Segment {
+                kind: SegmentKind::Stack,
+                base: self.stack_base,
+                size: self.memory_size - self.stack_base,
+                protection: Protection::READ_WRITE,
+            },
+        ]
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::disasm::instruction_parts;
+use crate::isa::Instruction;
+
+/// How often an instruction, or all instructions with one opcode, ran and how
+/// long they took together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProfileStats {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl ProfileStats {
+    fn add(&mut self, other: ProfileStats) {
+        self.count += other.count;
+        self.time += other.time;
+    }
+}
+
+/// Execution counts and times per instruction address and per opcode, collected
+/// while `VM::set_profiling` is on and read with `VM::profile()`. Displays as a
+/// report sorted by time, the hottest entries first.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    addresses: Vec<Option<(&'static str, ProfileStats)>>, // Indexed by address, `None` until executed
+}
+
+impl Profiler {
+    pub(crate) fn new(program_len: usize) -> Self {
+        Profiler {
+            addresses: vec![None; program_len],
+        }
+    }
+
+    // Make room for instructions appended to the program.
+    pub(crate) fn resize(&mut self, program_len: usize) {
+        self.addresses.resize(program_len, None);
+    }
+
+    pub(crate) fn record(&mut self, ip: usize, instruction: &Instruction, time: Duration) {
+        let Some(entry) = self.addresses.get_mut(ip) else {
+            return;
+        };
+        // The mnemonic is the opcode's name, looked up once per address.
+        let (_, stats) = entry.get_or_insert_with(|| {
+            (
+                instruction_parts(instruction, &[]).0,
+                ProfileStats::default(),
+            )
+        });
+        stats.add(ProfileStats { count: 1, time });
+    }
+
+    /// The executed addresses with their mnemonic, the most time first.
+    pub fn by_address(&self) -> Vec<(usize, &'static str, ProfileStats)> {
+        let mut entries: Vec<_> = self
+            .addresses
+            .iter()
+            .enumerate()
+            .filter_map(|(address, entry)| entry.map(|(opcode, stats)| (address, opcode, stats)))
+            .collect();
+        entries.sort_by(|a, b| b.2.time.cmp(&a.2.time).then(a.0.cmp(&b.0)));
+        entries
+    }
+
+    /// The executed opcodes, the most time first.
+    pub fn by_opcode(&self) -> Vec<(&'static str, ProfileStats)> {
+        let mut opcodes: HashMap<&'static str, ProfileStats> = HashMap::new();
+        for (opcode, stats) in self.addresses.iter().flatten() {
+            opcodes.entry(opcode).or_default().add(*stats);
+        }
+        let mut entries: Vec<_> = opcodes.into_iter().collect();
+        entries.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+        entries
+    }
+}
+
+impl fmt::Display for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<10} {:>12} {:>14}", "opcode", "count", "time")?;
+        for (opcode, stats) in self.by_opcode() {
+            writeln!(f, "{:<10} {:>12} {:>14?}", opcode, stats.count, stats.time)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:<10} {:>12} {:>14}", "address", "count", "time")?;
+        for (address, opcode, stats) in self.by_address() {
+            let address = format!("{:04} {}", address, opcode);
+            writeln!(f, "{:<10} {:>12} {:>14?}", address, stats.count, stats.time)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1450 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::isa::Instruction;
+use crate::value::Value;
+use crate::verify::{verify_program, VerifyError};
+use crate::vm::VmConfig;
+
+// Bytecode layout: the magic bytes, a little-endian `u16` format version, the
+// instruction count, one record per instruction, a one-byte opcode followed by
+// its operands, the length of the data section and its bytes, and finally the
+// length of the function table and its entries, then the number of globals and
+// their initial values, a type byte each followed by its value, and finally
+// the number of symbols, an address and a length-prefixed UTF-8 name each.
+// Register indices, addresses, sizes and offsets are unsigned LEB128 varints,
+// `i32` immediates are four little-endian bytes, floats eight and `u8`
+// operands a single byte.
+const MAGIC: &[u8; 4] = b"BRVM";
+const VERSION: u16 = 8;
+
+/// A sequence of instructions and the read-only data they work on, which can be
+/// saved to and loaded from bytecode.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub data: Vec<u8>,         // Loaded at address 0 by `VM::from_program`
+    pub functions: Vec<usize>, // Function table for `CallTable`, addresses of instructions
+    pub globals: Vec<Value>,   // Initial values of the globals for `LoadGlobal` and `StoreGlobal`
+    pub symbols: BTreeMap<usize, String>, // Names of functions by entry address, for backtraces
+}
+
+/// Errors raised while decoding bytecode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BytecodeError {
+    BadMagic,                                    // The data does not start with the magic bytes
+    UnsupportedVersion(u16),                     // Written by an incompatible version of the format
+    UnexpectedEnd, // The data ends in the middle of the header or an instruction
+    UnknownOpcode { offset: usize, opcode: u8 }, // No instruction has this opcode
+    OperandOverflow(usize), // An operand at the byte offset does not fit a `usize`
+    TrailingBytes(usize), // Data left over after the last instruction, at the byte offset
+    InvalidGlobal(usize), // A global whose type byte, at the byte offset, is unknown
+    InvalidSymbol(usize), // A symbol at the byte offset whose name isn't UTF-8
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BytecodeError::BadMagic => write!(f, "not a bytecode file"),
+            BytecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported bytecode version {}", version)
+            }
+            BytecodeError::UnexpectedEnd => write!(f, "unexpected end of bytecode"),
+            BytecodeError::UnknownOpcode { offset, opcode } => {
+                write!(f, "unknown opcode {:#04x} at byte {}", opcode, offset)
+            }
+            BytecodeError::OperandOverflow(offset) => {
+                write!(f, "operand at byte {} is too large", offset)
+            }
+            BytecodeError::TrailingBytes(offset) => {
+                write!(
+                    f,
+                    "unexpected data after the last instruction at byte {}",
+                    offset
+                )
+            }
+            BytecodeError::InvalidGlobal(offset) => {
+                write!(f, "invalid global type at byte {}", offset)
+            }
+            BytecodeError::InvalidSymbol(offset) => {
+                write!(f, "invalid symbol name at byte {}", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+impl From<Vec<Instruction>> for Program {
+    fn from(instructions: Vec<Instruction>) -> Self {
+        Program {
+            instructions,
+            data: Vec::new(),
+            functions: Vec::new(),
+            globals: Vec::new(),
+            symbols: BTreeMap::new(),
+        }
+    }
+}
+
+impl Program {
+    /// Check the program before running it with `config`, see `verify_program`.
+    pub fn verify(&self, config: &VmConfig) -> Result<(), VerifyError> {
+        verify_program(self, config)
+    }
+
+    /// Encode the program as bytecode.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer {
+            bytes: MAGIC.to_vec(),
+        };
+        writer.bytes.extend_from_slice(&VERSION.to_le_bytes());
+        writer.varint(self.instructions.len());
+        for instruction in &self.instructions {
+            writer.instruction(instruction);
+        }
+        writer.varint(self.data.len());
+        writer.bytes.extend_from_slice(&self.data);
+        writer.varint(self.functions.len());
+        writer.varints(&self.functions);
+        writer.varint(self.globals.len());
+        for global in &self.globals {
+            match *global {
+                Value::Int(value) => {
+                    writer.byte(0);
+                    writer.immediate(value);
+                }
+                Value::Float(value) => {
+                    writer.byte(1);
+                    writer.float(value);
+                }
+                Value::Ref(handle) => {
+                    writer.byte(2);
+                    writer.varint(handle as usize);
+                }
+                Value::Int64(value) => {
+                    writer.byte(3);
+                    writer.int64(value);
+                }
+                Value::Nil => writer.byte(4),
+            }
+        }
+        writer.varint(self.symbols.len());
+        for (&address, name) in &self.symbols {
+            writer.varint(address);
+            writer.varint(name.len());
+            writer.bytes.extend_from_slice(name.as_bytes());
+        }
+        writer.bytes
+    }
+
+    /// Decode bytecode produced by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, BytecodeError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(if MAGIC.starts_with(bytes) {
+                BytecodeError::UnexpectedEnd
+            } else {
+                BytecodeError::BadMagic
+            });
+        }
+        let mut reader = Reader {
+            bytes,
+            position: MAGIC.len(),
+        };
+        let version = u16::from_le_bytes([reader.byte()?, reader.byte()?]);
+        if version != VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version));
+        }
+
+        let count = reader.varint()?;
+        // Every instruction takes at least one byte, which bounds the allocation.
+        let mut instructions = Vec::with_capacity(count.min(bytes.len()));
+        for _ in 0..count {
+            instructions.push(reader.instruction()?);
+        }
+        let len = reader.varint()?;
+        let data = reader
+            .position
+            .checked_add(len)
+            .and_then(|end| bytes.get(reader.position..end))
+            .ok_or(BytecodeError::UnexpectedEnd)?
+            .to_vec();
+        reader.position += len;
+        let count = reader.varint()?;
+        let mut functions = Vec::with_capacity(count.min(bytes.len()));
+        for _ in 0..count {
+            functions.push(reader.varint()?);
+        }
+        let count = reader.varint()?;
+        let mut globals = Vec::with_capacity(count.min(bytes.len()));
+        for _ in 0..count {
+            let offset = reader.position;
+            globals.push(match reader.byte()? {
+                0 => Value::Int(reader.immediate()?),
+                1 => Value::Float(reader.float()?),
+                2 => match u32::try_from(reader.varint()?) {
+                    Ok(handle) => Value::Ref(handle),
+                    Err(_) => return Err(BytecodeError::InvalidGlobal(offset)),
+                },
+                3 => Value::Int64(reader.int64()?),
+                4 => Value::Nil,
+                _ => return Err(BytecodeError::InvalidGlobal(offset)),
+            });
+        }
+        let count = reader.varint()?;
+        let mut symbols = BTreeMap::new();
+        for _ in 0..count {
+            let offset = reader.position;
+            let address = reader.varint()?;
+            let len = reader.varint()?;
+            let name = reader
+                .position
+                .checked_add(len)
+                .and_then(|end| bytes.get(reader.position..end))
+                .ok_or(BytecodeError::UnexpectedEnd)?;
+            reader.position += len;
+            let name = String::from_utf8(name.to_vec())
+                .map_err(|_| BytecodeError::InvalidSymbol(offset))?;
+            symbols.insert(address, name);
+        }
+        if reader.position != bytes.len() {
+            return Err(BytecodeError::TrailingBytes(reader.position));
+        }
+        Ok(Program {
+            instructions,
+            data,
+            functions,
+            globals,
+            symbols,
+        })
+    }
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn byte(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn varint(&mut self, mut value: usize) {
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    fn varints(&mut self, values: &[usize]) {
+        for value in values {
+            self.varint(*value);
+        }
+    }
+
+    fn immediate(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn float(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn int64(&mut self, value: i64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Signed offsets are zigzag encoded so small negative values stay short.
+    fn signed(&mut self, value: isize) {
+        self.varint(((value << 1) ^ (value >> (isize::BITS - 1))) as usize);
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        match *instruction {
+            Instruction::SetReg(register, value) => {
+                self.byte(0x00);
+                self.varint(register);
+                self.immediate(value);
+            }
+            Instruction::Add(a, b, target) => {
+                self.byte(0x01);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Sub(a, b, target) => {
+                self.byte(0x02);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Mul(a, b, target) => {
+                self.byte(0x03);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Div(a, b, target) => {
+                self.byte(0x04);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Mod(a, b, target) => {
+                self.byte(0x05);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Eq(a, b, target) => {
+                self.byte(0x06);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Neq(a, b, target) => {
+                self.byte(0x07);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Gt(a, b, target) => {
+                self.byte(0x08);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Lt(a, b, target) => {
+                self.byte(0x09);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Gte(a, b, target) => {
+                self.byte(0x0A);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Lte(a, b, target) => {
+                self.byte(0x0B);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Jump(offset) => {
+                self.byte(0x0C);
+                self.signed(offset);
+            }
+            Instruction::JumpIfZero(register, offset) => {
+                self.byte(0x0D);
+                self.varint(register);
+                self.signed(offset);
+            }
+            Instruction::JumpIfNonZero(register, offset) => {
+                self.byte(0x0E);
+                self.varint(register);
+                self.signed(offset);
+            }
+            Instruction::Print(register) => {
+                self.byte(0x0F);
+                self.varint(register);
+            }
+            Instruction::Halt => self.byte(0x10),
+            Instruction::AllocateMemory(size, target) => {
+                self.byte(0x11);
+                self.varints(&[size, target]);
+            }
+            Instruction::FreeMemory(address) => {
+                self.byte(0x12);
+                self.varint(address);
+            }
+            Instruction::StoreToMemory(address, register, offset) => {
+                self.byte(0x13);
+                self.varints(&[address, register, offset]);
+            }
+            Instruction::LoadFromMemory(address, offset, register) => {
+                self.byte(0x14);
+                self.varints(&[address, offset, register]);
+            }
+            Instruction::Call(address) => {
+                self.byte(0x15);
+                self.varint(address);
+            }
+            Instruction::Return => self.byte(0x16),
+            Instruction::FAdd(a, b, target) => {
+                self.byte(0x17);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::FSub(a, b, target) => {
+                self.byte(0x18);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::FMul(a, b, target) => {
+                self.byte(0x19);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::FDiv(a, b, target) => {
+                self.byte(0x1A);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::IntToFloat(src, target) => {
+                self.byte(0x1B);
+                self.varints(&[src, target]);
+            }
+            Instruction::FloatToInt(src, target) => {
+                self.byte(0x1C);
+                self.varints(&[src, target]);
+            }
+            Instruction::SetRegByte(register, byte) => {
+                self.byte(0x1D);
+                self.varint(register);
+                self.byte(byte);
+            }
+            Instruction::SetRegShifted(register, byte, shift) => {
+                self.byte(0x1E);
+                self.varint(register);
+                self.byte(byte);
+                self.byte(shift);
+            }
+            Instruction::SizeOf(address, register) => {
+                self.byte(0x1F);
+                self.varints(&[address, register]);
+            }
+            Instruction::MemCopy(dst, src, len) => {
+                self.byte(0x20);
+                self.varints(&[dst, src, len]);
+            }
+            Instruction::MemFill(address, register, len) => {
+                self.byte(0x21);
+                self.varints(&[address, register, len]);
+            }
+            Instruction::PrintU(register) => {
+                self.byte(0x22);
+                self.varint(register);
+            }
+            Instruction::Syscall(number) => {
+                self.byte(0x23);
+                self.varint(number);
+            }
+            Instruction::Cas(address, offset, expected, new, result) => {
+                self.byte(0x24);
+                self.varints(&[address, offset, expected, new, result]);
+            }
+            Instruction::ClearRegs => self.byte(0x25),
+            Instruction::ClearReg(register) => {
+                self.byte(0x26);
+                self.varint(register);
+            }
+            Instruction::Rol(a, b, target) => {
+                self.byte(0x27);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Ror(a, b, target) => {
+                self.byte(0x28);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::AssertEq(register, expected) => {
+                self.byte(0x29);
+                self.varint(register);
+                self.immediate(expected);
+            }
+            Instruction::And(a, b, target) => {
+                self.byte(0x2A);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Or(a, b, target) => {
+                self.byte(0x2B);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Xor(a, b, target) => {
+                self.byte(0x2C);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Not(src, target) => {
+                self.byte(0x2D);
+                self.varints(&[src, target]);
+            }
+            Instruction::Shl(a, b, target) => {
+                self.byte(0x2E);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Shr(a, b, target) => {
+                self.byte(0x2F);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Sar(a, b, target) => {
+                self.byte(0x30);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::AddImm(a, immediate, target) => {
+                self.byte(0x31);
+                self.varint(a);
+                self.immediate(immediate);
+                self.varint(target);
+            }
+            Instruction::SubImm(a, immediate, target) => {
+                self.byte(0x32);
+                self.varint(a);
+                self.immediate(immediate);
+                self.varint(target);
+            }
+            Instruction::MulImm(a, immediate, target) => {
+                self.byte(0x33);
+                self.varint(a);
+                self.immediate(immediate);
+                self.varint(target);
+            }
+            Instruction::EqImm(a, immediate, target) => {
+                self.byte(0x34);
+                self.varint(a);
+                self.immediate(immediate);
+                self.varint(target);
+            }
+            Instruction::NeqImm(a, immediate, target) => {
+                self.byte(0x35);
+                self.varint(a);
+                self.immediate(immediate);
+                self.varint(target);
+            }
+            Instruction::GtImm(a, immediate, target) => {
+                self.byte(0x36);
+                self.varint(a);
+                self.immediate(immediate);
+                self.varint(target);
+            }
+            Instruction::LtImm(a, immediate, target) => {
+                self.byte(0x37);
+                self.varint(a);
+                self.immediate(immediate);
+                self.varint(target);
+            }
+            Instruction::GteImm(a, immediate, target) => {
+                self.byte(0x38);
+                self.varint(a);
+                self.immediate(immediate);
+                self.varint(target);
+            }
+            Instruction::LteImm(a, immediate, target) => {
+                self.byte(0x39);
+                self.varint(a);
+                self.immediate(immediate);
+                self.varint(target);
+            }
+            Instruction::StoreIndirect(address, offset, register) => {
+                self.byte(0x3A);
+                self.varints(&[address, offset, register]);
+            }
+            Instruction::LoadIndirect(address, offset, register) => {
+                self.byte(0x3B);
+                self.varints(&[address, offset, register]);
+            }
+            Instruction::Store8(address, offset, register) => {
+                self.byte(0x3C);
+                self.varints(&[address, offset, register]);
+            }
+            Instruction::Store16(address, offset, register) => {
+                self.byte(0x3D);
+                self.varints(&[address, offset, register]);
+            }
+            Instruction::Store32(address, offset, register) => {
+                self.byte(0x3E);
+                self.varints(&[address, offset, register]);
+            }
+            Instruction::Load8S(address, offset, register) => {
+                self.byte(0x3F);
+                self.varints(&[address, offset, register]);
+            }
+            Instruction::Load8U(address, offset, register) => {
+                self.byte(0x40);
+                self.varints(&[address, offset, register]);
+            }
+            Instruction::Load16S(address, offset, register) => {
+                self.byte(0x41);
+                self.varints(&[address, offset, register]);
+            }
+            Instruction::Load16U(address, offset, register) => {
+                self.byte(0x42);
+                self.varints(&[address, offset, register]);
+            }
+            Instruction::Load32(address, offset, register) => {
+                self.byte(0x43);
+                self.varints(&[address, offset, register]);
+            }
+            Instruction::ReadInt(register) => {
+                self.byte(0x44);
+                self.varint(register);
+            }
+            Instruction::ReadByte(register) => {
+                self.byte(0x45);
+                self.varint(register);
+            }
+            Instruction::PrintStr(address, len) => {
+                self.byte(0x46);
+                self.varints(&[address, len]);
+            }
+            Instruction::SetFReg(register, value) => {
+                self.byte(0x47);
+                self.varint(register);
+                self.float(value);
+            }
+            Instruction::FCmp(a, b, target) => {
+                self.byte(0x48);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Push(register) => {
+                self.byte(0x49);
+                self.varint(register);
+            }
+            Instruction::Pop(register) => {
+                self.byte(0x4A);
+                self.varint(register);
+            }
+            Instruction::Dup => self.byte(0x4B),
+            Instruction::SwapTop => self.byte(0x4C),
+            Instruction::Enter(local_count) => {
+                self.byte(0x4D);
+                self.varint(local_count);
+            }
+            Instruction::Leave => self.byte(0x4E),
+            Instruction::LoadLocal(slot, register) => {
+                self.byte(0x4F);
+                self.varints(&[slot, register]);
+            }
+            Instruction::StoreLocal(register, slot) => {
+                self.byte(0x50);
+                self.varints(&[register, slot]);
+            }
+            Instruction::CallReg(register) => {
+                self.byte(0x51);
+                self.varint(register);
+            }
+            Instruction::CallTable(register) => {
+                self.byte(0x52);
+                self.varint(register);
+            }
+            Instruction::JumpTable(register, ref offsets, default) => {
+                self.byte(0x53);
+                self.varint(register);
+                self.varint(offsets.len());
+                for &offset in offsets {
+                    self.signed(offset);
+                }
+                self.signed(default);
+            }
+            Instruction::Brk(code) => {
+                self.byte(0x54);
+                self.varint(code);
+            }
+            Instruction::Nop => self.byte(0x55),
+            Instruction::Mov(src, target) => {
+                self.byte(0x56);
+                self.varints(&[src, target]);
+            }
+            Instruction::Neg(src, target) => {
+                self.byte(0x57);
+                self.varints(&[src, target]);
+            }
+            Instruction::Abs(src, target) => {
+                self.byte(0x58);
+                self.varints(&[src, target]);
+            }
+            Instruction::Min(a, b, target) => {
+                self.byte(0x59);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Max(a, b, target) => {
+                self.byte(0x5A);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Popcount(src, target) => {
+                self.byte(0x5B);
+                self.varints(&[src, target]);
+            }
+            Instruction::Clz(src, target) => {
+                self.byte(0x5C);
+                self.varints(&[src, target]);
+            }
+            Instruction::Ctz(src, target) => {
+                self.byte(0x5D);
+                self.varints(&[src, target]);
+            }
+            Instruction::Cmp(a, b) => {
+                self.byte(0x5E);
+                self.varints(&[a, b]);
+            }
+            Instruction::CmpImm(a, immediate) => {
+                self.byte(0x5F);
+                self.varint(a);
+                self.immediate(immediate);
+            }
+            Instruction::Jz(offset) => {
+                self.byte(0x60);
+                self.signed(offset);
+            }
+            Instruction::Jnz(offset) => {
+                self.byte(0x61);
+                self.signed(offset);
+            }
+            Instruction::Jc(offset) => {
+                self.byte(0x62);
+                self.signed(offset);
+            }
+            Instruction::Jo(offset) => {
+                self.byte(0x63);
+                self.signed(offset);
+            }
+            Instruction::Jlt(offset) => {
+                self.byte(0x64);
+                self.signed(offset);
+            }
+            Instruction::Jge(offset) => {
+                self.byte(0x65);
+                self.signed(offset);
+            }
+            Instruction::Adc(a, b, target) => {
+                self.byte(0x66);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::Sbb(a, b, target) => {
+                self.byte(0x67);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::MulWide(a, b, high, low) => {
+                self.byte(0x68);
+                self.varints(&[a, b, high, low]);
+            }
+            Instruction::DivU(a, b, target) => {
+                self.byte(0x69);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::ModU(a, b, target) => {
+                self.byte(0x6A);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::LtU(a, b, target) => {
+                self.byte(0x6B);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::GtU(a, b, target) => {
+                self.byte(0x6C);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::LteU(a, b, target) => {
+                self.byte(0x6D);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::GteU(a, b, target) => {
+                self.byte(0x6E);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::SextByte(src, target) => {
+                self.byte(0x6F);
+                self.varints(&[src, target]);
+            }
+            Instruction::SextHalf(src, target) => {
+                self.byte(0x70);
+                self.varints(&[src, target]);
+            }
+            Instruction::ZextByte(src, target) => {
+                self.byte(0x71);
+                self.varints(&[src, target]);
+            }
+            Instruction::ZextHalf(src, target) => {
+                self.byte(0x72);
+                self.varints(&[src, target]);
+            }
+            Instruction::Trunc(src, bits, target) => {
+                self.byte(0x73);
+                self.varint(src);
+                self.byte(bits);
+                self.varint(target);
+            }
+            Instruction::FloatTrunc(src, target) => {
+                self.byte(0x74);
+                self.varints(&[src, target]);
+            }
+            Instruction::FloatRound(src, target) => {
+                self.byte(0x75);
+                self.varints(&[src, target]);
+            }
+            Instruction::FloatFloor(src, target) => {
+                self.byte(0x76);
+                self.varints(&[src, target]);
+            }
+            Instruction::FloatCeil(src, target) => {
+                self.byte(0x77);
+                self.varints(&[src, target]);
+            }
+            Instruction::VLoad(address, offset, vector) => {
+                self.byte(0x78);
+                self.varints(&[address, offset, vector]);
+            }
+            Instruction::VStore(address, offset, vector) => {
+                self.byte(0x79);
+                self.varints(&[address, offset, vector]);
+            }
+            Instruction::VAdd4x32(a, b, target) => {
+                self.byte(0x7A);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::VMul4x32(a, b, target) => {
+                self.byte(0x7B);
+                self.varints(&[a, b, target]);
+            }
+            Instruction::VExtract(vector, lane, target) => {
+                self.byte(0x7C);
+                self.varint(vector);
+                self.byte(lane);
+                self.varint(target);
+            }
+            Instruction::VInsert(src, lane, vector) => {
+                self.byte(0x7D);
+                self.varint(src);
+                self.byte(lane);
+                self.varint(vector);
+            }
+            Instruction::VSplat(src, vector) => {
+                self.byte(0x7E);
+                self.varints(&[src, vector]);
+            }
+            Instruction::MemCopyReg(dst, src, len) => {
+                self.byte(0x7F);
+                self.varints(&[dst, src, len]);
+            }
+            Instruction::MemFillReg(address, value, len) => {
+                self.byte(0x80);
+                self.varints(&[address, value, len]);
+            }
+            Instruction::ReallocMemory(address, size, target) => {
+                self.byte(0x81);
+                self.varints(&[address, size, target]);
+            }
+            Instruction::ProtectMemory(address, flags) => {
+                self.byte(0x82);
+                self.varint(address);
+                self.byte(flags);
+            }
+            Instruction::LoadGlobal(global, register) => {
+                self.byte(0x83);
+                self.varints(&[global, register]);
+            }
+            Instruction::StoreGlobal(register, global) => {
+                self.byte(0x84);
+                self.varints(&[register, global]);
+            }
+            Instruction::PushSp(register) => {
+                self.byte(0x85);
+                self.varint(register);
+            }
+            Instruction::PopSp(register) => {
+                self.byte(0x86);
+                self.varint(register);
+            }
+            Instruction::LoadSpOffset(offset, register) => {
+                self.byte(0x87);
+                self.varints(&[offset, register]);
+            }
+            Instruction::StoreSpOffset(register, offset) => {
+                self.byte(0x88);
+                self.varints(&[register, offset]);
+            }
+            Instruction::NewObject(size, register) => {
+                self.byte(0x89);
+                self.varints(&[size, register]);
+            }
+            Instruction::LoadSlot(object, slot, register) => {
+                self.byte(0x8A);
+                self.varints(&[object, slot, register]);
+            }
+            Instruction::StoreSlot(register, object, slot) => {
+                self.byte(0x8B);
+                self.varints(&[register, object, slot]);
+            }
+            Instruction::Retain(register) => {
+                self.byte(0x8C);
+                self.varint(register);
+            }
+            Instruction::Release(register) => {
+                self.byte(0x8D);
+                self.varint(register);
+            }
+            Instruction::NewArray(length, register) => {
+                self.byte(0x8E);
+                self.varints(&[length, register]);
+            }
+            Instruction::ArrayGet(array, index, register) => {
+                self.byte(0x8F);
+                self.varints(&[array, index, register]);
+            }
+            Instruction::ArraySet(array, index, register) => {
+                self.byte(0x90);
+                self.varints(&[array, index, register]);
+            }
+            Instruction::ArrayLen(array, register) => {
+                self.byte(0x91);
+                self.varints(&[array, register]);
+            }
+            Instruction::NewString(address, len, register) => {
+                self.byte(0x92);
+                self.varints(&[address, len, register]);
+            }
+            Instruction::StrConcat(first, second, register) => {
+                self.byte(0x93);
+                self.varints(&[first, second, register]);
+            }
+            Instruction::StrLen(string, register) => {
+                self.byte(0x94);
+                self.varints(&[string, register]);
+            }
+            Instruction::StrByte(string, index, register) => {
+                self.byte(0x95);
+                self.varints(&[string, index, register]);
+            }
+            Instruction::StrCmp(first, second, register) => {
+                self.byte(0x96);
+                self.varints(&[first, second, register]);
+            }
+            Instruction::PrintString(string) => {
+                self.byte(0x97);
+                self.varint(string);
+            }
+            Instruction::MapNew(register) => {
+                self.byte(0x98);
+                self.varint(register);
+            }
+            Instruction::MapGet(map, key, register) => {
+                self.byte(0x99);
+                self.varints(&[map, key, register]);
+            }
+            Instruction::MapSet(map, key, register) => {
+                self.byte(0x9A);
+                self.varints(&[map, key, register]);
+            }
+            Instruction::MapDel(map, key) => {
+                self.byte(0x9B);
+                self.varints(&[map, key]);
+            }
+            Instruction::MapHas(map, key, register) => {
+                self.byte(0x9C);
+                self.varints(&[map, key, register]);
+            }
+            Instruction::TypeOf(source, register) => {
+                self.byte(0x9D);
+                self.varints(&[source, register]);
+            }
+            Instruction::TryBegin(offset) => {
+                self.byte(0x9E);
+                self.signed(offset);
+            }
+            Instruction::TryEnd => self.byte(0x9F),
+            Instruction::Throw(register) => {
+                self.byte(0xA0);
+                self.varint(register);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, BytecodeError> {
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or(BytecodeError::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<usize, BytecodeError> {
+        let start = self.position;
+        let mut value: usize = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7F) as usize;
+            if shift >= usize::BITS || (bits << shift) >> shift != bits {
+                return Err(BytecodeError::OperandOverflow(start));
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn immediate(&mut self) -> Result<i32, BytecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + 4)
+            .ok_or(BytecodeError::UnexpectedEnd)?;
+        self.position += 4;
+        Ok(i32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn float(&mut self) -> Result<f64, BytecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + 8)
+            .ok_or(BytecodeError::UnexpectedEnd)?;
+        self.position += 8;
+        Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn int64(&mut self) -> Result<i64, BytecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + 8)
+            .ok_or(BytecodeError::UnexpectedEnd)?;
+        self.position += 8;
+        Ok(i64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn signed(&mut self) -> Result<isize, BytecodeError> {
+        let value = self.varint()?;
+        Ok((value >> 1) as isize ^ -((value & 1) as isize))
+    }
+
+    fn three(&mut self) -> Result<(usize, usize, usize), BytecodeError> {
+        Ok((self.varint()?, self.varint()?, self.varint()?))
+    }
+
+    fn two(&mut self) -> Result<(usize, usize), BytecodeError> {
+        Ok((self.varint()?, self.varint()?))
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, BytecodeError> {
+        let offset = self.position;
+        let instruction = match self.byte()? {
+            0x00 => Instruction::SetReg(self.varint()?, self.immediate()?),
+            0x01 => self.three().map(|(a, b, t)| Instruction::Add(a, b, t))?,
+            0x02 => self.three().map(|(a, b, t)| Instruction::Sub(a, b, t))?,
+            0x03 => self.three().map(|(a, b, t)| Instruction::Mul(a, b, t))?,
+            0x04 => self.three().map(|(a, b, t)| Instruction::Div(a, b, t))?,
+            0x05 => self.three().map(|(a, b, t)| Instruction::Mod(a, b, t))?,
+            0x06 => self.three().map(|(a, b, t)| Instruction::Eq(a, b, t))?,
+            0x07 => self.three().map(|(a, b, t)| Instruction::Neq(a, b, t))?,
+            0x08 => self.three().map(|(a, b, t)| Instruction::Gt(a, b, t))?,
+            0x09 => self.three().map(|(a, b, t)| Instruction::Lt(a, b, t))?,
+            0x0A => self.three().map(|(a, b, t)| Instruction::Gte(a, b, t))?,
+            0x0B => self.three().map(|(a, b, t)| Instruction::Lte(a, b, t))?,
+            0x0C => Instruction::Jump(self.signed()?),
+            0x0D => Instruction::JumpIfZero(self.varint()?, self.signed()?),
+            0x0E => Instruction::JumpIfNonZero(self.varint()?, self.signed()?),
+            0x0F => Instruction::Print(self.varint()?),
+            0x10 => Instruction::Halt,
+            0x11 => self.two().map(|(s, t)| Instruction::AllocateMemory(s, t))?,
+            0x12 => Instruction::FreeMemory(self.varint()?),
+            0x13 => self
+                .three()
+                .map(|(a, r, o)| Instruction::StoreToMemory(a, r, o))?,
+            0x14 => self
+                .three()
+                .map(|(a, o, r)| Instruction::LoadFromMemory(a, o, r))?,
+            0x15 => Instruction::Call(self.varint()?),
+            0x16 => Instruction::Return,
+            0x17 => self.three().map(|(a, b, t)| Instruction::FAdd(a, b, t))?,
+            0x18 => self.three().map(|(a, b, t)| Instruction::FSub(a, b, t))?,
+            0x19 => self.three().map(|(a, b, t)| Instruction::FMul(a, b, t))?,
+            0x1A => self.three().map(|(a, b, t)| Instruction::FDiv(a, b, t))?,
+            0x1B => self.two().map(|(s, t)| Instruction::IntToFloat(s, t))?,
+            0x1C => self.two().map(|(s, t)| Instruction::FloatToInt(s, t))?,
+            0x1D => Instruction::SetRegByte(self.varint()?, self.byte()?),
+            0x1E => Instruction::SetRegShifted(self.varint()?, self.byte()?, self.byte()?),
+            0x1F => self.two().map(|(a, r)| Instruction::SizeOf(a, r))?,
+            0x20 => self
+                .three()
+                .map(|(d, s, l)| Instruction::MemCopy(d, s, l))?,
+            0x21 => self
+                .three()
+                .map(|(a, r, l)| Instruction::MemFill(a, r, l))?,
+            0x22 => Instruction::PrintU(self.varint()?),
+            0x23 => Instruction::Syscall(self.varint()?),
+            0x24 => {
+                let (address, offset, expected) = self.three()?;
+                let (new, result) = self.two()?;
+                Instruction::Cas(address, offset, expected, new, result)
+            }
+            0x25 => Instruction::ClearRegs,
+            0x26 => Instruction::ClearReg(self.varint()?),
+            0x27 => self.three().map(|(a, b, t)| Instruction::Rol(a, b, t))?,
+            0x28 => self.three().map(|(a, b, t)| Instruction::Ror(a, b, t))?,
+            0x29 => Instruction::AssertEq(self.varint()?, self.immediate()?),
+            0x2A => self.three().map(|(a, b, t)| Instruction::And(a, b, t))?,
+            0x2B => self.three().map(|(a, b, t)| Instruction::Or(a, b, t))?,
+            0x2C => self.three().map(|(a, b, t)| Instruction::Xor(a, b, t))?,
+            0x2D => self.two().map(|(s, t)| Instruction::Not(s, t))?,
+            0x2E => self.three().map(|(a, b, t)| Instruction::Shl(a, b, t))?,
+            0x2F => self.three().map(|(a, b, t)| Instruction::Shr(a, b, t))?,
+            0x30 => self.three().map(|(a, b, t)| Instruction::Sar(a, b, t))?,
+            0x31 => Instruction::AddImm(self.varint()?, self.immediate()?, self.varint()?),
+            0x32 => Instruction::SubImm(self.varint()?, self.immediate()?, self.varint()?),
+            0x33 => Instruction::MulImm(self.varint()?, self.immediate()?, self.varint()?),
+            0x34 => Instruction::EqImm(self.varint()?, self.immediate()?, self.varint()?),
+            0x35 => Instruction::NeqImm(self.varint()?, self.immediate()?, self.varint()?),
+            0x36 => Instruction::GtImm(self.varint()?, self.immediate()?, self.varint()?),
+            0x37 => Instruction::LtImm(self.varint()?, self.immediate()?, self.varint()?),
+            0x38 => Instruction::GteImm(self.varint()?, self.immediate()?, self.varint()?),
+            0x39 => Instruction::LteImm(self.varint()?, self.immediate()?, self.varint()?),
+            0x3A => self
+                .three()
+                .map(|(a, o, r)| Instruction::StoreIndirect(a, o, r))?,
+            0x3B => self
+                .three()
+                .map(|(a, o, r)| Instruction::LoadIndirect(a, o, r))?,
+            0x3C => self.three().map(|(a, o, r)| Instruction::Store8(a, o, r))?,
+            0x3D => self
+                .three()
+                .map(|(a, o, r)| Instruction::Store16(a, o, r))?,
+            0x3E => self
+                .three()
+                .map(|(a, o, r)| Instruction::Store32(a, o, r))?,
+            0x3F => self.three().map(|(a, o, r)| Instruction::Load8S(a, o, r))?,
+            0x40 => self.three().map(|(a, o, r)| Instruction::Load8U(a, o, r))?,
+            0x41 => self
+                .three()
+                .map(|(a, o, r)| Instruction::Load16S(a, o, r))?,
+            0x42 => self
+                .three()
+                .map(|(a, o, r)| Instruction::Load16U(a, o, r))?,
+            0x43 => self.three().map(|(a, o, r)| Instruction::Load32(a, o, r))?,
+            0x44 => Instruction::ReadInt(self.varint()?),
+            0x45 => Instruction::ReadByte(self.varint()?),
+            0x46 => Instruction::PrintStr(self.varint()?, self.varint()?),
+            0x47 => Instruction::SetFReg(self.varint()?, self.float()?),
+            0x48 => self.three().map(|(a, b, t)| Instruction::FCmp(a, b, t))?,
+            0x49 => Instruction::Push(self.varint()?),
+            0x4A => Instruction::Pop(self.varint()?),
+            0x4B => Instruction::Dup,
+            0x4C => Instruction::SwapTop,
+            0x4D => Instruction::Enter(self.varint()?),
+            0x4E => Instruction::Leave,
+            0x4F => self.two().map(|(s, r)| Instruction::LoadLocal(s, r))?,
+            0x50 => self.two().map(|(r, s)| Instruction::StoreLocal(r, s))?,
+            0x51 => Instruction::CallReg(self.varint()?),
+            0x52 => Instruction::CallTable(self.varint()?),
+            0x53 => {
+                let register = self.varint()?;
+                let count = self.varint()?;
+                let mut offsets = Vec::with_capacity(count.min(self.bytes.len()));
+                for _ in 0..count {
+                    offsets.push(self.signed()?);
+                }
+                Instruction::JumpTable(register, offsets, self.signed()?)
+            }
+            0x54 => Instruction::Brk(self.varint()?),
+            0x55 => Instruction::Nop,
+            0x56 => self.two().map(|(s, t)| Instruction::Mov(s, t))?,
+            0x57 => self.two().map(|(s, t)| Instruction::Neg(s, t))?,
+            0x58 => self.two().map(|(s, t)| Instruction::Abs(s, t))?,
+            0x59 => self.three().map(|(a, b, t)| Instruction::Min(a, b, t))?,
+            0x5A => self.three().map(|(a, b, t)| Instruction::Max(a, b, t))?,
+            0x5B => self.two().map(|(s, t)| Instruction::Popcount(s, t))?,
+            0x5C => self.two().map(|(s, t)| Instruction::Clz(s, t))?,
+            0x5D => self.two().map(|(s, t)| Instruction::Ctz(s, t))?,
+            0x5E => self.two().map(|(a, b)| Instruction::Cmp(a, b))?,
+            0x5F => Instruction::CmpImm(self.varint()?, self.immediate()?),
+            0x60 => Instruction::Jz(self.signed()?),
+            0x61 => Instruction::Jnz(self.signed()?),
+            0x62 => Instruction::Jc(self.signed()?),
+            0x63 => Instruction::Jo(self.signed()?),
+            0x64 => Instruction::Jlt(self.signed()?),
+            0x65 => Instruction::Jge(self.signed()?),
+            0x66 => self.three().map(|(a, b, t)| Instruction::Adc(a, b, t))?,
+            0x67 => self.three().map(|(a, b, t)| Instruction::Sbb(a, b, t))?,
+            0x68 => Instruction::MulWide(
+                self.varint()?,
+                self.varint()?,
+                self.varint()?,
+                self.varint()?,
+            ),
+            0x69 => self.three().map(|(a, b, t)| Instruction::DivU(a, b, t))?,
+            0x6A => self.three().map(|(a, b, t)| Instruction::ModU(a, b, t))?,
+            0x6B => self.three().map(|(a, b, t)| Instruction::LtU(a, b, t))?,
+            0x6C => self.three().map(|(a, b, t)| Instruction::GtU(a, b, t))?,
+            0x6D => self.three().map(|(a, b, t)| Instruction::LteU(a, b, t))?,
+            0x6E => self.three().map(|(a, b, t)| Instruction::GteU(a, b, t))?,
+            0x6F => self.two().map(|(s, t)| Instruction::SextByte(s, t))?,
+            0x70 => self.two().map(|(s, t)| Instruction::SextHalf(s, t))?,
+            0x71 => self.two().map(|(s, t)| Instruction::ZextByte(s, t))?,
+            0x72 => self.two().map(|(s, t)| Instruction::ZextHalf(s, t))?,
+            0x73 => Instruction::Trunc(self.varint()?, self.byte()?, self.varint()?),
+            0x74 => self.two().map(|(s, t)| Instruction::FloatTrunc(s, t))?,
+            0x75 => self.two().map(|(s, t)| Instruction::FloatRound(s, t))?,
+            0x76 => self.two().map(|(s, t)| Instruction::FloatFloor(s, t))?,
+            0x77 => self.two().map(|(s, t)| Instruction::FloatCeil(s, t))?,
+            0x78 => self.three().map(|(a, o, v)| Instruction::VLoad(a, o, v))?,
+            0x79 => self.three().map(|(a, o, v)| Instruction::VStore(a, o, v))?,
+            0x7A => self
+                .three()
+                .map(|(a, b, t)| Instruction::VAdd4x32(a, b, t))?,
+            0x7B => self
+                .three()
+                .map(|(a, b, t)| Instruction::VMul4x32(a, b, t))?,
+            0x7C => Instruction::VExtract(self.varint()?, self.byte()?, self.varint()?),
+            0x7D => Instruction::VInsert(self.varint()?, self.byte()?, self.varint()?),
+            0x7E => self.two().map(|(s, v)| Instruction::VSplat(s, v))?,
+            0x7F => self
+                .three()
+                .map(|(d, s, l)| Instruction::MemCopyReg(d, s, l))?,
+            0x80 => self
+                .three()
+                .map(|(a, v, l)| Instruction::MemFillReg(a, v, l))?,
+            0x81 => self
+                .three()
+                .map(|(a, s, t)| Instruction::ReallocMemory(a, s, t))?,
+            0x82 => Instruction::ProtectMemory(self.varint()?, self.byte()?),
+            0x83 => self.two().map(|(g, r)| Instruction::LoadGlobal(g, r))?,
+            0x84 => self.two().map(|(r, g)| Instruction::StoreGlobal(r, g))?,
+            0x85 => Instruction::PushSp(self.varint()?),
+            0x86 => Instruction::PopSp(self.varint()?),
+            0x87 => self.two().map(|(o, r)| Instruction::LoadSpOffset(o, r))?,
+            0x88 => self.two().map(|(r, o)| Instruction::StoreSpOffset(r, o))?,
+            0x89 => self.two().map(|(s, r)| Instruction::NewObject(s, r))?,
+            0x8A => self
+                .three()
+                .map(|(o, s, r)| Instruction::LoadSlot(o, s, r))?,
+            0x8B => self
+                .three()
+                .map(|(r, o, s)| Instruction::StoreSlot(r, o, s))?,
+            0x8C => Instruction::Retain(self.varint()?),
+            0x8D => Instruction::Release(self.varint()?),
+            0x8E => self.two().map(|(l, r)| Instruction::NewArray(l, r))?,
+            0x8F => self
+                .three()
+                .map(|(a, i, r)| Instruction::ArrayGet(a, i, r))?,
+            0x90 => self
+                .three()
+                .map(|(a, i, r)| Instruction::ArraySet(a, i, r))?,
+            0x91 => self.two().map(|(a, r)| Instruction::ArrayLen(a, r))?,
+            0x92 => self
+                .three()
+                .map(|(a, l, r)| Instruction::NewString(a, l, r))?,
+            0x93 => self
+                .three()
+                .map(|(a, b, r)| Instruction::StrConcat(a, b, r))?,
+            0x94 => self.two().map(|(s, r)| Instruction::StrLen(s, r))?,
+            0x95 => self
+                .three()
+                .map(|(s, i, r)| Instruction::StrByte(s, i, r))?,
+            0x96 => self.three().map(|(a, b, r)| Instruction::StrCmp(a, b, r))?,
+            0x97 => Instruction::PrintString(self.varint()?),
+            0x98 => Instruction::MapNew(self.varint()?),
+            0x99 => self.three().map(|(m, k, r)| Instruction::MapGet(m, k, r))?,
+            0x9A => self.three().map(|(m, k, r)| Instruction::MapSet(m, k, r))?,
+            0x9B => self.two().map(|(m, k)| Instruction::MapDel(m, k))?,
+            0x9C => self.three().map(|(m, k, r)| Instruction::MapHas(m, k, r))?,
+            0x9D => self.two().map(|(s, r)| Instruction::TypeOf(s, r))?,
+            0x9E => Instruction::TryBegin(self.signed()?),
+            0x9F => Instruction::TryEnd,
+            0xA0 => Instruction::Throw(self.varint()?),
+            opcode => return Err(BytecodeError::UnknownOpcode { offset, opcode }),
+        };
+        Ok(instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_instruction() -> Vec<Instruction> {
+        vec![
+            Instruction::SetReg(0, -123456),
+            Instruction::Add(0, 1, 2),
+            Instruction::Sub(1, 2, 3),
+            Instruction::Mul(2, 3, 4),
+            Instruction::Div(3, 4, 5),
+            Instruction::Mod(4, 5, 6),
+            Instruction::Eq(5, 6, 7),
+            Instruction::Neq(6, 7, 0),
+            Instruction::Gt(7, 0, 1),
+            Instruction::Lt(0, 1, 2),
+            Instruction::Gte(1, 2, 3),
+            Instruction::Lte(2, 3, 4),
+            Instruction::Jump(300),
+            Instruction::Jump(-1),
+            Instruction::Jump(isize::MIN),
+            Instruction::JumpIfZero(1, 2),
+            Instruction::JumpIfNonZero(3, -4),
+            Instruction::Print(5),
+            Instruction::Halt,
+            Instruction::AllocateMemory(1, 2),
+            Instruction::FreeMemory(128),
+            Instruction::StoreToMemory(100, 1, 7),
+            Instruction::LoadFromMemory(100, 3, 2),
+            Instruction::Call(5),
+            Instruction::Return,
+            Instruction::FAdd(0, 1, 2),
+            Instruction::FSub(0, 1, 2),
+            Instruction::FMul(0, 1, 2),
+            Instruction::FDiv(0, 1, 2),
+            Instruction::IntToFloat(3, 4),
+            Instruction::FloatToInt(4, 3),
+            Instruction::SetRegByte(6, 0xFE),
+            Instruction::SetRegShifted(7, 0xAB, 24),
+            Instruction::SizeOf(0, 1),
+            Instruction::MemCopy(10, 20, 30),
+            Instruction::MemFill(10, 2, 30),
+            Instruction::PrintU(3),
+            Instruction::Syscall(usize::MAX),
+            Instruction::Cas(0, 4, 1, 2, 3),
+            Instruction::ClearRegs,
+            Instruction::ClearReg(4),
+            Instruction::Rol(0, 1, 2),
+            Instruction::Ror(2, 1, 0),
+            Instruction::AssertEq(5, i32::MIN),
+            Instruction::And(0, 1, 2),
+            Instruction::Or(0, 1, 2),
+            Instruction::Xor(0, 1, 2),
+            Instruction::Not(1, 2),
+            Instruction::Shl(0, 1, 2),
+            Instruction::Shr(0, 1, 2),
+            Instruction::Sar(0, 1, 2),
+            Instruction::AddImm(1, -49, 2),
+            Instruction::SubImm(1, -50, 2),
+            Instruction::MulImm(1, -51, 2),
+            Instruction::EqImm(1, -52, 2),
+            Instruction::NeqImm(1, -53, 2),
+            Instruction::GtImm(1, -54, 2),
+            Instruction::LtImm(1, -55, 2),
+            Instruction::GteImm(1, -56, 2),
+            Instruction::LteImm(1, -57, 2),
+            Instruction::StoreIndirect(1, 2, 3),
+            Instruction::LoadIndirect(3, 2, 1),
+            Instruction::Store8(1, 2, 3),
+            Instruction::Store16(1, 2, 3),
+            Instruction::Store32(1, 2, 3),
+            Instruction::Load8S(1, 2, 3),
+            Instruction::Load8U(1, 2, 3),
+            Instruction::Load16S(1, 2, 3),
+            Instruction::Load16U(1, 2, 3),
+            Instruction::Load32(1, 2, 3),
+            Instruction::ReadInt(4),
+            Instruction::ReadByte(5),
+            Instruction::PrintStr(1, 2),
+            Instruction::SetFReg(6, -0.25),
+            Instruction::FCmp(1, 2, 3),
+            Instruction::Push(6),
+            Instruction::Pop(7),
+            Instruction::Dup,
+            Instruction::SwapTop,
+            Instruction::Enter(3),
+            Instruction::Leave,
+            Instruction::LoadLocal(2, 1),
+            Instruction::StoreLocal(1, 2),
+            Instruction::CallReg(3),
+            Instruction::CallTable(4),
+            Instruction::JumpTable(5, vec![0, -3, 300], -1),
+            Instruction::JumpTable(6, vec![], 2),
+            Instruction::Brk(usize::MAX),
+            Instruction::Nop,
+            Instruction::Mov(3, 4),
+            Instruction::Neg(1, 2),
+            Instruction::Abs(2, 1),
+            Instruction::Min(0, 1, 2),
+            Instruction::Max(2, 1, 0),
+            Instruction::Popcount(1, 2),
+            Instruction::Clz(2, 3),
+            Instruction::Ctz(3, 4),
+            Instruction::Cmp(1, 2),
+            Instruction::CmpImm(3, i32::MIN),
+            Instruction::Jz(-3),
+            Instruction::Jnz(2),
+            Instruction::Jc(0),
+            Instruction::Jo(7),
+            Instruction::Jlt(-1),
+            Instruction::Jge(4),
+            Instruction::Adc(0, 1, 2),
+            Instruction::Sbb(3, 4, 5),
+            Instruction::MulWide(6, 7, 0, 1),
+            Instruction::DivU(0, 1, 2),
+            Instruction::ModU(1, 2, 3),
+            Instruction::LtU(2, 3, 4),
+            Instruction::GtU(3, 4, 5),
+            Instruction::LteU(4, 5, 6),
+            Instruction::GteU(5, 6, 7),
+            Instruction::SextByte(0, 7),
+            Instruction::SextHalf(1, 6),
+            Instruction::ZextByte(2, 5),
+            Instruction::ZextHalf(3, 4),
+            Instruction::Trunc(1, 12, 2),
+            Instruction::FloatTrunc(0, 1),
+            Instruction::FloatRound(2, 3),
+            Instruction::FloatFloor(4, 5),
+            Instruction::FloatCeil(6, 7),
+            Instruction::VLoad(0, 1, 2),
+            Instruction::VStore(3, 4, 5),
+            Instruction::VAdd4x32(0, 1, 2),
+            Instruction::VMul4x32(3, 4, 5),
+            Instruction::VExtract(6, 3, 7),
+            Instruction::VInsert(1, 2, 0),
+            Instruction::VSplat(4, 7),
+            Instruction::MemCopyReg(2, 1, 0),
+            Instruction::MemFillReg(0, 1, 2),
+            Instruction::ReallocMemory(3, 4, 5),
+            Instruction::ProtectMemory(6, 0b01),
+            Instruction::LoadGlobal(3, 1),
+            Instruction::StoreGlobal(1, 3),
+            Instruction::PushSp(2),
+            Instruction::PopSp(3),
+            Instruction::LoadSpOffset(8, 4),
+            Instruction::StoreSpOffset(4, 12),
+            Instruction::NewObject(3, 1),
+            Instruction::LoadSlot(1, 2, 4),
+            Instruction::StoreSlot(4, 1, 0),
+            Instruction::Retain(3),
+            Instruction::Release(3),
+            Instruction::NewArray(2, 1),
+            Instruction::ArrayGet(1, 3, 4),
+            Instruction::ArraySet(1, 3, 4),
+            Instruction::ArrayLen(1, 5),
+            Instruction::NewString(0, 1, 2),
+            Instruction::StrConcat(2, 3, 4),
+            Instruction::StrLen(4, 5),
+            Instruction::StrByte(4, 5, 6),
+            Instruction::StrCmp(2, 4, 7),
+            Instruction::PrintString(4),
+            Instruction::MapNew(1),
+            Instruction::MapGet(1, 2, 3),
+            Instruction::MapSet(1, 2, 3),
+            Instruction::MapDel(1, 2),
+            Instruction::MapHas(1, 2, 4),
+            Instruction::TypeOf(2, 3),
+            Instruction::TryBegin(-3),
+            Instruction::TryEnd,
+            Instruction::Throw(1),
+        ]
+    }
+
+    #[test]
+    fn test_bytecode_round_trip() {
+        let program = Program {
+            instructions: every_instruction(),
+            data: b"hello\n".to_vec(),
+            functions: vec![0, 300],
+            globals: vec![
+                Value::Int(-1),
+                Value::Float(0.5),
+                Value::Int64(-1 << 40),
+                Value::Nil,
+            ],
+            symbols: BTreeMap::from([(0, "main".to_string()), (300, "fib".to_string())]),
+        };
+        let bytes = program.to_bytes();
+
+        assert_eq!(&bytes[..6], b"BRVM\x08\x00");
+        assert_eq!(Program::from_bytes(&bytes), Ok(program));
+    }
+
+    #[test]
+    fn test_bytecode_is_compact() {
+        let program = Program::from(vec![Instruction::Add(0, 1, 2), Instruction::Halt]);
+
+        assert_eq!(program.to_bytes(), b"BRVM\x08\x00\x02\x01\x00\x01\x02\x10\x00\x00\x00\x00");
+    }
+
+    #[test]
+    fn test_bytecode_errors() {
+        let bytes = Program::from(vec![Instruction::SetReg(0, 1)]).to_bytes();
+
+        assert_eq!(Program::from_bytes(b"ELF\x7f\x01\x00\x00"), Err(BytecodeError::BadMagic));
+        assert_eq!(Program::from_bytes(b"BRV"), Err(BytecodeError::UnexpectedEnd));
+        assert_eq!(Program::from_bytes(b"BRVM\x07\x00\x00"), Err(BytecodeError::UnsupportedVersion(7)));
+        assert_eq!(Program::from_bytes(&bytes[..bytes.len() - 1]), Err(BytecodeError::UnexpectedEnd));
+        assert_eq!(Program::from_bytes(b"BRVM\x08\x00\x01\xFF"), Err(BytecodeError::UnknownOpcode { offset: 7, opcode: 0xFF }));
+        assert_eq!(Program::from_bytes(b"BRVM\x08\x00\x00\x00\x00\x00\x00\x10"), Err(BytecodeError::TrailingBytes(11)));
+        assert_eq!(Program::from_bytes(b"BRVM\x08\x00\x00\x00\x00\x01\x05"), Err(BytecodeError::InvalidGlobal(10)));
+        assert_eq!(Program::from_bytes(b"BRVM\x08\x00\x00\x00\x00\x00\x01\x00\x01\xFF"), Err(BytecodeError::InvalidSymbol(11)));
+        assert_eq!(Program::from_bytes(b"BRVM\x08\x00\x00\x05abc"), Err(BytecodeError::UnexpectedEnd)); // Short data section
+        assert_eq!(
+            Program::from_bytes(b"BRVM\x08\x00\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\x01"),
+            Err(BytecodeError::OperandOverflow(6))
+        );
+    }
+}
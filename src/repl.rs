@@ -0,0 +1,177 @@
+use std::io::{self, Write};
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use crate::asm::assemble;
+use crate::vm::{Effect, ExitStatus, VmState, VM};
+
+const HELP: &str = "\
+Type an instruction, e.g. `add r0, r1, r2`, to run it. Commands:
+    :regs               show the registers
+    :mem <addr> <len>   dump memory
+    :reset              start over with a fresh VM
+    :help               show this text
+    :quit               leave (also Ctrl-D)";
+
+/// Read assembly lines from the terminal and execute each one immediately
+/// against a VM that persists between lines, printing the registers it wrote.
+/// Returns at `:quit` or the end of the input.
+pub fn run_repl() -> rustyline::Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let mut repl = Repl::new();
+    println!("Type assembly to run it, or `:help` for a list of commands.");
+    loop {
+        match editor.readline("vm> ") {
+            Ok(line) => {
+                editor.add_history_entry(line.as_str())?;
+                if !repl.eval(&line, &mut io::stdout())? {
+                    return Ok(());
+                }
+            }
+            Err(ReadlineError::Interrupted) => {} // Ctrl-C discards the line
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+struct Repl {
+    vm: VM,
+}
+
+impl Repl {
+    fn new() -> Self {
+        Repl {
+            vm: VM::new(Vec::new()),
+        }
+    }
+
+    // Handle one line, returning false when the user quits.
+    fn eval(&mut self, line: &str, output: &mut impl Write) -> io::Result<bool> {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None => {}
+            Some(":q" | ":quit") => return Ok(false),
+            Some(":help") => writeln!(output, "{}", HELP)?,
+            Some(":regs") => {
+                for (index, value) in self.vm.registers().iter().enumerate() {
+                    writeln!(output, "r{} = {}", index, value)?;
+                }
+            }
+            Some(":mem") => {
+                let numbers: Option<Vec<usize>> = words.map(number).collect();
+                match numbers.as_deref() {
+                    Some(&[address, len]) => self.dump_memory(address, len, output)?,
+                    _ => writeln!(output, "Usage: :mem <addr> <len>")?,
+                }
+            }
+            Some(":reset") => *self = Repl::new(),
+            Some(command) if command.starts_with(':') => writeln!(
+                output,
+                "Unknown command `{}`, type `:help` for a list",
+                command
+            )?,
+            Some(_) => self.execute(line, output)?,
+        }
+        Ok(true)
+    }
+
+    // Append the line's instructions to the program and run them. A line that
+    // traps is undone, so a mistake doesn't end the session.
+    fn execute(&mut self, line: &str, output: &mut impl Write) -> io::Result<()> {
+        let instructions = match assemble(line) {
+            Ok(instructions) => instructions,
+            Err(error) => return writeln!(output, "Error: {}", error.kind),
+        };
+        let before = self.vm.snapshot();
+        self.vm.extend_program(instructions);
+        self.vm.record_effects();
+        let result = self.vm.run();
+        let effects = self.vm.take_effects();
+        // The next line continues after this one, also after a `halt`.
+        let mut state = match result {
+            Ok(status) => {
+                for effect in effects {
+                    if let Effect::RegisterWrite { register, value } = effect {
+                        writeln!(output, "r{} = {}", register, value)?;
+                    }
+                }
+                if status != ExitStatus::EndOfProgram {
+                    writeln!(output, "{:?}", status)?;
+                }
+                self.vm.snapshot()
+            }
+            Err(error) => {
+                writeln!(output, "Error: {}, the line was undone", error)?;
+                before
+            }
+        };
+        state.ip = self.vm.program().len();
+        state.state = VmState::Running;
+        state.trap = None;
+        self.vm.restore(&state);
+        Ok(())
+    }
+
+    fn dump_memory(&self, address: usize, len: usize, output: &mut impl Write) -> io::Result<()> {
+        let memory = self.vm.memory().borrow();
+        match memory.bytes(address, len) {
+            Ok(bytes) => {
+                for (row, bytes) in bytes.chunks(16).enumerate() {
+                    let hex: Vec<String> =
+                        bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                    writeln!(output, "{:#06x}: {}", address + row * 16, hex.join(" "))?;
+                }
+                Ok(())
+            }
+            Err(error) => writeln!(output, "Error: {}", error),
+        }
+    }
+}
+
+// A decimal or `0x` hexadecimal number.
+fn number(word: &str) -> Option<usize> {
+    match word.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => word.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(repl: &mut Repl, line: &str) -> String {
+        let mut output = Vec::new();
+        assert!(repl.eval(line, &mut output).unwrap());
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut repl = Repl::new();
+        repl.vm.set_output(io::sink());
+
+        assert_eq!(eval(&mut repl, "set r0, 40"), "r0 = 40\n");
+        assert_eq!(eval(&mut repl, "addi r0, 2, r1"), "r1 = 42\n");
+        assert_eq!(eval(&mut repl, "set r3, 32"), "r3 = 32\n");
+        assert_eq!(eval(&mut repl, "alloc r3, r3"), "r3 = 0\n");
+        assert_eq!(eval(&mut repl, "store 0, r1, 1"), "");
+        assert_eq!(eval(&mut repl, ":mem 0 2"), "0x0000: 00 2a\n");
+        assert_eq!(eval(&mut repl, ":mem 0x1f 2"), "Error: memory access at address 31 is out of bounds\n");
+        assert_eq!(eval(&mut repl, ":mem 0"), "Usage: :mem <addr> <len>\n");
+
+        // Errors don't end the session
+        assert_eq!(eval(&mut repl, "frob r0"), "Error: unknown instruction `frob`\n");
+        assert_eq!(eval(&mut repl, "print r9"), "Error: invalid register index 9, the line was undone\n");
+        assert_eq!(eval(&mut repl, "halt"), "Halted\n");
+        assert_eq!(eval(&mut repl, "subi r1, 2, r1"), "r1 = 40\n");
+        assert!(eval(&mut repl, ":regs").starts_with("r0 = 40\nr1 = 40\nr2 = 0\n"));
+
+        assert_eq!(eval(&mut repl, ":reset"), "");
+        assert!(eval(&mut repl, ":regs").starts_with("r0 = 0\nr1 = 0\n"));
+        assert!(eval(&mut repl, ":bogus").starts_with("Unknown command `:bogus`"));
+        assert!(!repl.eval(":quit", &mut Vec::new()).unwrap());
+    }
+}
@@ -0,0 +1,44 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::VmError;
+use crate::value::Value;
+use crate::vm::Effect;
+
+/// One nondeterministic input a VM consumed: what a read instruction got from
+/// the input, or what a host function did.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum InputEvent {
+    ReadInt(Result<i32, VmError>), // The integer `ReadInt` read, or why it failed
+    ReadByte(Result<i32, VmError>), // The byte `ReadByte` read (-1 at the end), or why it failed
+    Syscall {
+        number: usize,
+        registers: Vec<Value>, // The register file after the host function returned
+        memory: Vec<Effect>,   // Allocations, frees and memory writes it made
+        result: Result<(), VmError>,
+    },
+}
+
+/// The inputs of a run in the order they were consumed, recorded with
+/// `VM::record_inputs` and fed back with `VM::replay_inputs`, so a failing
+/// execution can be reproduced exactly. `save` and `load` keep a log in a JSON
+/// file.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct InputLog {
+    pub events: Vec<InputEvent>,
+}
+
+impl InputLog {
+    /// Write the log to a file as JSON.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec(self)?)
+    }
+
+    /// Read a log written by `save`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<InputLog> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
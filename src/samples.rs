@@ -0,0 +1,110 @@
+use crate::builder::ProgramBuilder;
+use crate::isa::Instruction;
+use crate::program::Program;
+
+// Representative programs for benchmarks and tests, each stressing one part of
+// the interpreter. Their labels are fixed, so building them can't fail.
+impl Program {
+    /// Compute the `n`th Fibonacci number iteratively, leaving it in `r0`.
+    /// Exercises plain dispatch: register arithmetic and a backward jump.
+    pub fn fibonacci(n: i32) -> Program {
+        let mut builder = ProgramBuilder::new();
+        builder
+            .push(Instruction::SetReg(0, 0)) // fib(i)
+            .push(Instruction::SetReg(1, 1)) // fib(i + 1)
+            .push(Instruction::SetReg(2, n)) // Iterations left
+            .label("loop")
+            .jump_if_zero_to(2, "done")
+            .push(Instruction::Add(0, 1, 3))
+            .push(Instruction::AddImm(1, 0, 0))
+            .push(Instruction::AddImm(3, 0, 1))
+            .push(Instruction::SubImm(2, 1, 2))
+            .jump_to("loop")
+            .label("done")
+            .push(Instruction::Halt);
+        Program::from(builder.build().expect("labels are defined"))
+    }
+
+    /// Compute the `n`th Fibonacci number with the naive recursion, leaving it
+    /// in `r0`. Exercises calls, returns and the data stack.
+    pub fn recursive_fibonacci(n: i32) -> Program {
+        let mut builder = ProgramBuilder::new();
+        builder
+            .push(Instruction::SetReg(0, n))
+            .call("fib")
+            .push(Instruction::Halt)
+            .label("fib") // r0 = fib(r0)
+            .push(Instruction::LtImm(0, 2, 1))
+            .jump_if_non_zero_to(1, "return")
+            .push(Instruction::Push(0)) // Save n
+            .push(Instruction::SubImm(0, 1, 0))
+            .call("fib")
+            .push(Instruction::Pop(1))
+            .push(Instruction::Push(0)) // Save fib(n - 1)
+            .push(Instruction::SubImm(1, 2, 0))
+            .call("fib")
+            .push(Instruction::Pop(1))
+            .push(Instruction::Add(0, 1, 0))
+            .label("return")
+            .push(Instruction::Return);
+        Program::from(builder.build().expect("labels are defined"))
+    }
+
+    /// Allocate, fill and free blocks of `block_size` bytes `iterations` times.
+    /// Each round splits and coalesces a freed block, so it exercises the
+    /// allocator and the free list as well as bulk writes. Memory is empty again
+    /// when the program ends.
+    pub fn memory_churn(iterations: i32, block_size: usize) -> Program {
+        let mut builder = ProgramBuilder::new();
+        builder
+            .push(Instruction::SetReg(0, iterations))
+            .push(Instruction::SetReg(1, 0xAB)) // Fill byte
+            .push(Instruction::SetReg(2, block_size as i32))
+            .push(Instruction::SetReg(3, (block_size / 2) as i32))
+            .label("loop")
+            .jump_if_zero_to(0, "done")
+            .push(Instruction::AllocateMemory(2, 4)) // At 0
+            .push(Instruction::AllocateMemory(2, 4)) // After the first block
+            .push(Instruction::MemFill(block_size, 1, block_size))
+            .push(Instruction::FreeMemory(0))
+            .push(Instruction::AllocateMemory(3, 4)) // Splits the freed block
+            .push(Instruction::MemFill(0, 1, block_size / 2))
+            .push(Instruction::FreeMemory(0))
+            .push(Instruction::FreeMemory(block_size))
+            .push(Instruction::SubImm(0, 1, 0))
+            .jump_to("loop")
+            .label("done")
+            .push(Instruction::Halt);
+        Program::from(builder.build().expect("labels are defined"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::value::Value;
+    use crate::vm::{ExitStatus, VM};
+
+    use super::*;
+
+    #[test]
+    fn test_fibonacci_programs() {
+        for program in [Program::fibonacci(20), Program::recursive_fibonacci(20)] {
+            let mut vm = VM::from_program(program);
+            assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+            assert_eq!(vm.registers()[0], Value::Int(6765));
+            assert!(vm.data_stack().is_empty());
+        }
+        let mut vm = VM::from_program(Program::fibonacci(0));
+        vm.run().unwrap();
+        assert_eq!(vm.registers()[0], Value::Int(0));
+    }
+
+    #[test]
+    fn test_memory_churn() {
+        let mut vm = VM::from_program(Program::memory_churn(10, 64));
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        let memory = vm.memory();
+        assert_eq!(memory.borrow().regions().count(), 0);
+        assert_eq!(memory.borrow().next_free_address(), 0);
+    }
+}
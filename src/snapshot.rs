@@ -0,0 +1,74 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Trap;
+use crate::gc::ObjectHeap;
+use crate::memory::MemoryStore;
+use crate::memstats::MemoryStats;
+use crate::value::Value;
+use crate::vm::{Flags, Handler, VmState, VECTOR_REGISTER_COUNT};
+
+/// The complete execution state of a VM, taken with `VM::snapshot()` and put
+/// back with `VM::restore()`. The program, the host configuration (output,
+/// input, syscalls, limits) and hooks like the tracer are not part of it, so a
+/// snapshot is restored into a VM created for the same program.
+///
+/// Snapshots implement `Serialize` and `Deserialize`; `save` and `load` store
+/// them as JSON files to resume a computation after a restart.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VmSnapshot {
+    pub(crate) ip: usize,
+    pub(crate) registers: Vec<Value>,
+    #[serde(default = "zeroed_vectors")]
+    // Missing from snapshots saved before the VM had vector registers
+    pub(crate) vectors: Vec<[i32; 4]>,
+    pub(crate) stack: Vec<usize>,                // Return addresses
+    pub(crate) saved_registers: Vec<Vec<Value>>, // Callee-saved registers of each active call
+    pub(crate) data_stack: Vec<Value>,
+    pub(crate) frames: Vec<usize>,
+    #[serde(default)] // Missing from snapshots saved before the VM had exception handlers
+    pub(crate) handlers: Vec<Handler>,
+    pub(crate) locals: Vec<Value>,
+    #[serde(default)] // Missing from snapshots saved before programs had globals
+    pub(crate) globals: Vec<Value>,
+    #[serde(default)] // Missing from snapshots saved before the VM had a memory stack
+    pub(crate) sp: usize,
+    #[serde(default)] // Missing from snapshots saved before the VM had objects
+    pub(crate) objects: ObjectHeap,
+    pub(crate) memory: MemoryStore,
+    #[serde(default)] // Missing from snapshots saved before the VM counted allocations
+    pub(crate) memory_stats: MemoryStats,
+    pub(crate) state: VmState,
+    pub(crate) trap: Option<Trap>,
+    #[serde(default)] // Missing from snapshots saved before the VM had flags
+    pub(crate) flags: Flags,
+    pub(crate) fuel: Option<u64>,
+}
+
+fn zeroed_vectors() -> Vec<[i32; 4]> {
+    vec![[0; 4]; VECTOR_REGISTER_COUNT]
+}
+
+impl VmSnapshot {
+    /// The address of the next instruction to execute.
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn registers(&self) -> &[Value] {
+        &self.registers
+    }
+
+    /// Write the snapshot to a file as JSON.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec(self)?)
+    }
+
+    /// Read a snapshot written by `save`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<VmSnapshot> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::Write;
+
+use serde_json::json;
+
+use crate::disasm::instruction_parts;
+use crate::error::VmError;
+use crate::isa::Instruction;
+use crate::value::Value;
+use crate::vm::Effect;
+
+/// Writes one JSON object per executed instruction to a writer (JSON Lines),
+/// for diffing runs and feeding external tools. Installed with
+/// `VM::set_json_trace`.
+pub(crate) struct JsonTrace {
+    writer: Box<dyn Write>,
+    effects: Vec<Effect>, // Effects of the instruction being executed
+}
+
+impl JsonTrace {
+    pub(crate) fn new(writer: Box<dyn Write>) -> Self {
+        JsonTrace {
+            writer,
+            effects: Vec::new(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, effect: &Effect) {
+        self.effects.push(effect.clone());
+    }
+
+    /// Write the line for the instruction at `ip` with the effects recorded
+    /// since the last line. Register writes and memory writes are listed in the
+    /// order they happened, a faulting instruction also gets an `error`.
+    pub(crate) fn write_line(
+        &mut self,
+        ip: usize,
+        instruction: &Instruction,
+        error: Option<&VmError>,
+    ) {
+        let (opcode, operands) = instruction_parts(instruction, &[]);
+        let mut registers = Vec::new();
+        let mut memory = Vec::new();
+        for effect in self.effects.drain(..) {
+            match effect {
+                Effect::RegisterWrite { register, value } => {
+                    registers.push(json!({ "register": register, "value": json_value(value) }));
+                }
+                Effect::MemoryWrite { address, value } => {
+                    memory.push(json!({ "address": address, "bytes": [value] }));
+                }
+                Effect::MemoryWriteBlock { address, bytes } => {
+                    memory.push(json!({ "address": address, "bytes": bytes }));
+                }
+                _ => {}
+            }
+        }
+        let mut line = json!({
+            "ip": ip,
+            "opcode": opcode,
+            "operands": operands,
+            "registers": registers,
+            "memory": memory,
+        });
+        if let Some(error) = error {
+            line["error"] = json!(error.to_string());
+        }
+        if let Err(error) = writeln!(self.writer, "{}", line) {
+            vm_log!(error, "Failed to write JSON trace: {}", error);
+        }
+    }
+}
+
+// Integers stay integers and floats are written with a fraction, so the two
+// can be told apart. NaN and the infinities have no JSON form and become null.
+// References are objects, `{"ref": handle}`, and nil is null too.
+fn json_value(value: Value) -> serde_json::Value {
+    match value {
+        Value::Int(value) => json!(value),
+        Value::Float(value) => json!(value),
+        Value::Ref(handle) => json!({ "ref": handle }),
+        Value::Int64(value) => json!(value),
+        Value::Nil => serde_json::Value::Null,
+    }
+}
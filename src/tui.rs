@@ -0,0 +1,355 @@
+use std::io;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::debugger::{Debugger, SharedOutput, Stop};
+use crate::error::VmError;
+use crate::vm::VM;
+
+const KEYS: &str =
+    "s step  n next  c continue  u back  b breakpoint  ↑/↓ select  m next region  q quit";
+
+/// Debug a VM in a full-screen terminal UI: the disassembly with the current
+/// instruction highlighted, the registers, the call stack, a hexdump of one
+/// memory region and the program's output, which is captured into a panel
+/// instead of being printed. Returns when the user quits.
+pub fn run_tui(vm: &mut VM) -> io::Result<()> {
+    let output = SharedOutput::default();
+    vm.set_output(output.clone());
+    let mut tui = Tui::new(Debugger::attach(vm), output);
+    let mut terminal = ratatui::try_init()?;
+    let result = tui.run(&mut terminal);
+    let restored = ratatui::try_restore();
+    result.and(restored)
+}
+
+struct Tui<'a> {
+    debugger: Debugger<'a>,
+    output: SharedOutput,
+    cursor: usize, // Selected address in the disassembly, follows the ip after each command
+    region: usize, // Index of the memory region in the hexdump
+    message: String,
+    quit: bool,
+}
+
+impl<'a> Tui<'a> {
+    fn new(debugger: Debugger<'a>, output: SharedOutput) -> Self {
+        Tui {
+            cursor: debugger.vm().ip(),
+            debugger,
+            output,
+            region: 0,
+            message: String::new(),
+            quit: false,
+        }
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    self.handle_key(key.code);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::Char('s') => {
+                let result = self.debugger.step();
+                self.report(result);
+            }
+            KeyCode::Char('n') => {
+                let result = self.debugger.step_over();
+                self.report(result);
+            }
+            KeyCode::Char('c') => {
+                let result = self.debugger.resume();
+                self.report(result);
+            }
+            KeyCode::Char('u') => {
+                self.message = if self.debugger.step_back() {
+                    String::new()
+                } else {
+                    "No earlier state to go back to".to_string()
+                };
+                self.cursor = self.debugger.vm().ip();
+            }
+            KeyCode::Char('b') => {
+                if self.debugger.remove_breakpoint(self.cursor) {
+                    self.message = format!("Removed breakpoint at {:04}", self.cursor);
+                } else {
+                    self.debugger.add_breakpoint(self.cursor);
+                    self.message = format!("Breakpoint at {:04}", self.cursor);
+                }
+            }
+            KeyCode::Up | KeyCode::Char('k') => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                let last = self.debugger.vm().program().len().saturating_sub(1);
+                self.cursor = (self.cursor + 1).min(last);
+            }
+            KeyCode::Char('m') => self.region += 1,
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            _ => {}
+        }
+    }
+
+    fn report(&mut self, result: Result<Stop, VmError>) {
+        self.message = match result {
+            Ok(Stop::Step) => String::new(),
+            Ok(Stop::Breakpoint(address)) => format!("Breakpoint at {:04}", address),
+            Ok(Stop::Watchpoint(hit)) => format!(
+                "Watchpoint: {:04} wrote {:#06x}, {:02x?} -> {:02x?}",
+                hit.ip, hit.address, hit.old, hit.new
+            ),
+            Ok(Stop::Brk { ip, code }) => format!("brk {} at {:04}", code, ip),
+            Ok(Stop::Exited(state)) => format!("Program stopped: {:?}", state),
+            Err(error) => format!("Error: {}", error),
+        };
+        self.cursor = self.debugger.vm().ip();
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, memory, status] = Layout::vertical([
+            Constraint::Min(8),
+            Constraint::Length(10),
+            Constraint::Length(2),
+        ])
+        .areas(frame.area());
+        let [code, side] =
+            Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)])
+                .areas(main);
+        let register_rows = self.debugger.vm().registers().len() as u16 + 2;
+        let [registers, stack, output] = Layout::vertical([
+            Constraint::Length(register_rows),
+            Constraint::Min(3),
+            Constraint::Min(3),
+        ])
+        .areas(side);
+
+        self.draw_code(frame, code);
+        self.draw_registers(frame, registers);
+        self.draw_stack(frame, stack);
+        self.draw_output(frame, output);
+        self.draw_memory(frame, memory);
+        let status_lines = vec![
+            Line::from(self.message.as_str()),
+            Line::styled(KEYS, Style::new().fg(Color::DarkGray)),
+        ];
+        frame.render_widget(Paragraph::new(status_lines), status);
+    }
+
+    // The instructions around the selection. The gutter shows breakpoints (`*`)
+    // and the selection (`>`), the next instruction to execute is highlighted.
+    fn draw_code(&self, frame: &mut Frame, area: Rect) {
+        let vm = self.debugger.vm();
+        let height = area.height.saturating_sub(2) as usize;
+        let start = self.cursor.saturating_sub(height / 2);
+        let lines: Vec<Line> = vm
+            .program()
+            .iter()
+            .enumerate()
+            .skip(start)
+            .take(height)
+            .map(|(address, instruction)| {
+                let breakpoint = self.debugger.breakpoints().any(|b| b == address);
+                let text = format!(
+                    "{}{} {:04}: {}",
+                    if breakpoint { '*' } else { ' ' },
+                    if address == self.cursor { '>' } else { ' ' },
+                    address,
+                    instruction
+                );
+                if address == vm.ip() {
+                    Line::styled(
+                        text,
+                        Style::new()
+                            .bg(Color::Yellow)
+                            .fg(Color::Black)
+                            .add_modifier(Modifier::BOLD),
+                    )
+                } else {
+                    Line::from(text)
+                }
+            })
+            .collect();
+        let title = format!(" Code (ip {:04}, {:?}) ", vm.ip(), vm.state());
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    fn draw_registers(&self, frame: &mut Frame, area: Rect) {
+        let lines: Vec<Line> = self
+            .debugger
+            .vm()
+            .registers()
+            .iter()
+            .enumerate()
+            .map(|(index, value)| Line::from(format!("r{:<2} = {}", index, value)))
+            .collect();
+        let block = Block::bordered().title(" Registers ");
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn draw_stack(&self, frame: &mut Frame, area: Rect) {
+        let stack = self.debugger.vm().call_stack();
+        let lines: Vec<Line> = if stack.is_empty() {
+            vec![Line::from("No active calls")]
+        } else {
+            stack
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(depth, address)| Line::from(format!("#{} returns to {:04}", depth, address)))
+                .collect()
+        };
+        let block = Block::bordered().title(" Call stack ");
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    // The last lines the program printed.
+    fn draw_output(&self, frame: &mut Frame, area: Rect) {
+        let output = self.output.0.borrow();
+        let text = String::from_utf8_lossy(&output);
+        let lines: Vec<&str> = text.lines().collect();
+        let height = area.height.saturating_sub(2) as usize;
+        let shown: Vec<Line> = lines[lines.len().saturating_sub(height)..]
+            .iter()
+            .map(|line| Line::from(*line))
+            .collect();
+        let block = Block::bordered().title(" Output ");
+        frame.render_widget(Paragraph::new(shown).block(block), area);
+    }
+
+    // A hexdump of the selected region, 16 bytes per row with their ASCII.
+    fn draw_memory(&self, frame: &mut Frame, area: Rect) {
+        let memory = self.debugger.vm().memory().borrow();
+        let regions: Vec<_> = memory.regions().collect();
+        let (title, lines) = match regions.get(self.region % regions.len().max(1)) {
+            Some(region) => {
+                let data = memory
+                    .bytes(region.address, region.size)
+                    .unwrap_or_default();
+                let lines = data
+                    .chunks(16)
+                    .enumerate()
+                    .map(|(row, bytes)| {
+                        let hex: Vec<String> =
+                            bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                        let ascii: String = bytes
+                            .iter()
+                            .map(|&byte| {
+                                if byte.is_ascii_graphic() || byte == b' ' {
+                                    byte as char
+                                } else {
+                                    '.'
+                                }
+                            })
+                            .collect();
+                        Line::from(format!(
+                            "{:#06x}: {:<47}  {}",
+                            region.address + row * 16,
+                            hex.join(" "),
+                            ascii
+                        ))
+                    })
+                    .collect();
+                let title = format!(
+                    " Memory {:#06x}, {} bytes (region {} of {}) ",
+                    region.address,
+                    region.size,
+                    self.region % regions.len() + 1,
+                    regions.len()
+                );
+                (title, lines)
+            }
+            None => (
+                " Memory ".to_string(),
+                vec![Line::from("No memory allocated")],
+            ),
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    use crate::isa::Instruction;
+
+    use super::*;
+
+    fn render(tui: &Tui) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(100, 30)).unwrap();
+        terminal.draw(|frame| tui.draw(frame)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let mut text = String::new();
+        for y in 0..buffer.area.height {
+            for x in 0..buffer.area.width {
+                text.push_str(buffer[(x, y)].symbol());
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    #[test]
+    fn test_tui_keys_and_panels() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(1, 4),
+            Instruction::AllocateMemory(1, 1),
+            Instruction::SetReg(0, 65),
+            Instruction::StoreToMemory(0, 0, 0),
+            Instruction::Print(0),
+            Instruction::Halt,
+        ]);
+        let output = SharedOutput::default();
+        vm.set_output(output.clone());
+        let mut tui = Tui::new(Debugger::attach(&mut vm), output);
+
+        let screen = render(&tui);
+        assert!(screen.contains(" > 0000: setw r1, 4"));
+        assert!(screen.contains("0001: alloc r1, r1"));
+        assert!(screen.contains("No memory allocated"));
+
+        tui.handle_key(KeyCode::Down);
+        tui.handle_key(KeyCode::Down);
+        tui.handle_key(KeyCode::Down);
+        tui.handle_key(KeyCode::Down);
+        tui.handle_key(KeyCode::Char('b'));
+        assert_eq!(tui.message, "Breakpoint at 0004");
+        tui.handle_key(KeyCode::Char('c'));
+        assert_eq!(tui.message, "Breakpoint at 0004");
+        assert_eq!(tui.cursor, 4);
+
+        let screen = render(&tui);
+        assert!(screen.contains("*> 0004: print r0"));
+        assert!(screen.contains("r0  = 65"));
+        assert!(screen.contains("0x0000: 41 00 00 00"));
+        assert!(screen.contains("A..."));
+
+        tui.handle_key(KeyCode::Char('s'));
+        assert!(render(&tui).contains("│Register 0: 65"));
+        tui.handle_key(KeyCode::Char('u'));
+        assert_eq!(tui.debugger.vm().ip(), 4);
+        tui.handle_key(KeyCode::Char('c'));
+        assert_eq!(tui.message, "Program stopped: Halted");
+        tui.handle_key(KeyCode::Char('q'));
+        assert!(tui.quit);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::gc::ObjectHeap;
+use crate::memory::MemoryStore;
+use crate::snapshot::VmSnapshot;
+
+/// What undoing one instruction takes, recorded between `VM::begin_undo` and
+/// `VM::take_undo`: the machine state before it apart from memory and objects,
+/// and of those only what the instruction changed, so stepping back doesn't
+/// cost a copy of memory per instruction.
+pub(crate) struct Undo {
+    pub(crate) state: VmSnapshot,           // With empty memory and objects
+    pub(crate) memory: Vec<MemoryUndo>,     // In the order the changes were made
+    pub(crate) objects: Option<ObjectHeap>, // The heap before the first change to it
+}
+
+/// What one change to a memory store overwrote.
+pub(crate) enum MemoryUndo {
+    // Bytes and their poison flags from `address` on, as far as the store reached
+    Bytes {
+        address: usize,
+        bytes: Vec<u8>,
+        poisoned: Vec<bool>,
+    },
+    // The store without its bytes and poison flags, and how many of those it had
+    Layout {
+        store: MemoryStore,
+        bytes_len: usize,
+        poisoned_len: usize,
+    },
+    // The whole store, before a syscall that may change anything in it
+    Store(MemoryStore),
+}
+
+impl MemoryUndo {
+    /// The bytes of `[address, address + len)` that a write is about to change.
+    pub(crate) fn bytes(memory: &MemoryStore, address: usize, len: usize) -> MemoryUndo {
+        let range = |total: usize| address.min(total)..address.saturating_add(len).min(total);
+        MemoryUndo::Bytes {
+            address,
+            bytes: memory.bytes[range(memory.bytes.len())].to_vec(),
+            poisoned: memory.poisoned[range(memory.poisoned.len())].to_vec(),
+        }
+    }
+
+    /// The regions, free list and the rest of the bookkeeping that allocating,
+    /// freeing or protecting memory is about to change.
+    pub(crate) fn layout(memory: &mut MemoryStore) -> MemoryUndo {
+        let bytes = std::mem::take(&mut memory.bytes);
+        let poisoned = std::mem::take(&mut memory.poisoned);
+        let undo = MemoryUndo::Layout {
+            store: memory.clone(),
+            bytes_len: bytes.len(),
+            poisoned_len: poisoned.len(),
+        };
+        memory.bytes = bytes;
+        memory.poisoned = poisoned;
+        undo
+    }
+
+    pub(crate) fn undo(self, memory: &mut MemoryStore) {
+        match self {
+            MemoryUndo::Bytes {
+                address,
+                bytes,
+                poisoned,
+            } => {
+                if let Some(target) = memory.bytes.get_mut(address..address + bytes.len()) {
+                    target.copy_from_slice(&bytes);
+                }
+                if let Some(target) = memory.poisoned.get_mut(address..address + poisoned.len()) {
+                    target.copy_from_slice(&poisoned);
+                }
+            }
+            MemoryUndo::Layout {
+                mut store,
+                bytes_len,
+                poisoned_len,
+            } => {
+                store.bytes = std::mem::take(&mut memory.bytes);
+                store.bytes.truncate(bytes_len);
+                store.poisoned = std::mem::take(&mut memory.poisoned);
+                store.poisoned.truncate(poisoned_len);
+                *memory = store;
+            }
+            MemoryUndo::Store(store) => *memory = store,
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// A register cell. Integer instructions only accept `Int` cells and float
+/// instructions only accept `Float` cells; there is no implicit coercion, values
+/// are converted explicitly with `IntToFloat` / `FloatToInt`. A `Ref` is only
+/// made by `NewObject` and only accepted by the object instructions, so the
+/// program can't forge one with arithmetic. `Int64` and `Nil` are what
+/// integers and empty registers are held as in `RegisterMode::Tagged`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    Int(i32),
+    Float(#[serde(with = "float_bits")] f64),
+    Ref(u32),   // Handle of an object on the garbage-collected heap
+    Int64(i64), // An integer in tagged mode
+    Nil,        // An empty register in tagged mode
+}
+
+// Floats are serialized as their bit pattern, so NaN, the infinities and -0.0
+// survive formats like JSON that can't represent them.
+mod float_bits {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(value.to_bits())
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        u64::deserialize(deserializer).map(f64::from_bits)
+    }
+}
+
+impl Value {
+    /// The object handle, for a reference.
+    pub fn reference(&self) -> Option<u32> {
+        match self {
+            Value::Ref(handle) => Some(*handle),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Int(0)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{}", value),
+            Value::Ref(handle) => write!(f, "<object {}>", handle),
+            Value::Int64(value) => write!(f, "{}", value),
+            Value::Nil => write!(f, "nil"),
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use std::fmt;
+
+use crate::abi::{CallingConvention, CALLEE_SAVED_REGISTERS};
+use crate::isa::Instruction;
+use crate::program::Program;
+use crate::vm::{VmConfig, VECTOR_REGISTER_COUNT};
+
+/// A problem `verify` found in a program, with the address of the instruction,
+/// or the index of the function-table entry for `InvalidFunctionEntry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub address: usize,
+    pub kind: VerifyErrorKind,
+}
+
+/// What is wrong with the instruction a `VerifyError` points at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    InvalidRegister(usize), // Register operand outside the configured register file
+    InvalidVectorRegister(usize), // Vector register operand past the last vector register
+    ClobbersCalleeSaved(usize), // A function overwrites a callee-saved register it didn't save
+    InvalidFunctionEntry(usize), // Function-table entry pointing outside the program
+    InvalidJump(isize),     // Jump or call target outside the program
+    InvalidGlobal(usize),   // LoadGlobal or StoreGlobal of a global the program doesn't declare
+    ReturnOutsideFunction,  // A `Return` the program entry reaches without a call
+    StackUnderflow,         // Pops more values than the data stack holds on every path to it
+    UnbalancedStack(isize), // A function returns with the data stack grown or shrunk by this much
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let VerifyErrorKind::InvalidFunctionEntry(target) = self.kind {
+            return write!(
+                f,
+                "function table entry {}: target {} is outside the program",
+                self.address, target
+            );
+        }
+        write!(f, "instruction {}: ", self.address)?;
+        match &self.kind {
+            VerifyErrorKind::InvalidRegister(register) => {
+                write!(f, "invalid register index {}", register)
+            }
+            VerifyErrorKind::InvalidVectorRegister(register) => {
+                write!(f, "invalid vector register index {}", register)
+            }
+            VerifyErrorKind::ClobbersCalleeSaved(register) => write!(
+                f,
+                "callee-saved register r{} is overwritten without being saved",
+                register
+            ),
+            VerifyErrorKind::InvalidJump(target) => {
+                write!(f, "target {} is outside the program", target)
+            }
+            VerifyErrorKind::InvalidGlobal(global) => write!(f, "invalid global index {}", global),
+            VerifyErrorKind::ReturnOutsideFunction => {
+                write!(f, "`ret` is reachable without a call")
+            }
+            VerifyErrorKind::StackUnderflow => write!(f, "the data stack is always empty here"),
+            VerifyErrorKind::UnbalancedStack(change) => write!(
+                f,
+                "the function returns with the data stack changed by {:+}",
+                change
+            ),
+            VerifyErrorKind::InvalidFunctionEntry(_) => unreachable!(),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Check a program against the VM configuration it will run with before running
+/// it, so problems are reported up front instead of when the instruction is
+/// reached. Reports the first problem found. With
+/// `CallingConvention::Verified` it also checks that functions save the
+/// callee-saved registers they overwrite.
+pub fn verify(program: &[Instruction], config: &VmConfig) -> Result<(), VerifyError> {
+    for (address, instruction) in program.iter().enumerate() {
+        if let Some(register) = instruction
+            .registers()
+            .into_iter()
+            .find(|register| *register >= config.registers())
+        {
+            return Err(VerifyError {
+                address,
+                kind: VerifyErrorKind::InvalidRegister(register),
+            });
+        }
+        if let Some(register) = instruction
+            .vector_registers()
+            .into_iter()
+            .find(|register| *register >= VECTOR_REGISTER_COUNT)
+        {
+            return Err(VerifyError {
+                address,
+                kind: VerifyErrorKind::InvalidVectorRegister(register),
+            });
+        }
+    }
+    if config.calling_convention == CallingConvention::Verified {
+        verify_calling_convention(program, &[])?;
+    }
+    Ok(())
+}
+
+/// Like `verify`, but checks the control flow of the whole program too: every
+/// jump, call and function-table entry targets an instruction, every global
+/// accessed is declared, a `Return` is only reachable inside a function, and,
+/// where all paths agree on how deep the data stack is, that nothing pops an
+/// empty stack and that functions leave it as deep as they found it. Calls
+/// through a register aren't followed. With `CallingConvention::Verified` the
+/// table entries are checked as functions too.
+pub fn verify_program(program: &Program, config: &VmConfig) -> Result<(), VerifyError> {
+    if let Some((index, &target)) = program
+        .functions
+        .iter()
+        .enumerate()
+        .find(|(_, &target)| target >= program.instructions.len())
+    {
+        return Err(VerifyError {
+            address: index,
+            kind: VerifyErrorKind::InvalidFunctionEntry(target),
+        });
+    }
+    verify(
+        &program.instructions,
+        &VmConfig {
+            calling_convention: CallingConvention::Unchecked,
+            ..*config
+        },
+    )?;
+    if let Some((address, global)) = program.instructions.iter().enumerate().find_map(
+        |(address, instruction)| match *instruction {
+            Instruction::LoadGlobal(global, _) | Instruction::StoreGlobal(_, global) => {
+                Some((address, global)).filter(|_| global >= program.globals.len())
+            }
+            _ => None,
+        },
+    ) {
+        return Err(VerifyError {
+            address,
+            kind: VerifyErrorKind::InvalidGlobal(global),
+        });
+    }
+    verify_control_flow(&program.instructions, &program.functions)?;
+    if config.calling_convention == CallingConvention::Verified {
+        verify_calling_convention(&program.instructions, &program.functions)?;
+    }
+    Ok(())
+}
+
+// The data stack depth at an instruction, relative to the entry of the code
+// being walked, once it was reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Depth {
+    Known(isize),
+    Unknown, // Paths to the instruction disagree
+}
+
+fn verify_control_flow(program: &[Instruction], table: &[usize]) -> Result<(), VerifyError> {
+    for (address, instruction) in program.iter().enumerate() {
+        if let Some(target) = targets(address, instruction)
+            .into_iter()
+            .find(|target| usize::try_from(*target).map_or(true, |target| target >= program.len()))
+        {
+            return Err(VerifyError {
+                address,
+                kind: VerifyErrorKind::InvalidJump(target),
+            });
+        }
+    }
+    if !program.is_empty() {
+        verify_flow(program, 0, false)?;
+    }
+    let mut entries: Vec<usize> = program
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Call(target) => Some(*target),
+            _ => None,
+        })
+        .chain(table.iter().copied())
+        .collect();
+    entries.sort_unstable();
+    entries.dedup();
+    for entry in entries {
+        verify_flow(program, entry, true)?;
+    }
+    Ok(())
+}
+
+// Follows every path from `entry`, stepping over calls, up to a `Halt`, a
+// `Return` or the end of the program. The data stack starts empty at the
+// program entry, so only there is popping too much provable.
+fn verify_flow(program: &[Instruction], entry: usize, function: bool) -> Result<(), VerifyError> {
+    let mut depths: Vec<Option<Depth>> = vec![None; program.len()];
+    let mut pending = vec![(entry, Depth::Known(0))];
+    while let Some((address, depth)) = pending.pop() {
+        let depth = match depths[address] {
+            None => depth,
+            Some(seen) if seen == depth => continue,
+            Some(_) => Depth::Unknown,
+        };
+        if depths[address] == Some(depth) {
+            continue;
+        }
+        depths[address] = Some(depth);
+        let instruction = &program[address];
+        let error = |kind| Err(VerifyError { address, kind });
+        let (needed, change) = match instruction {
+            Instruction::Push(_) => (0, 1),
+            Instruction::Pop(_) => (1, -1),
+            Instruction::Dup => (1, 1),
+            Instruction::SwapTop => (2, 0),
+            _ => (0, 0),
+        };
+        let after = match depth {
+            _ if *instruction == Instruction::Return && !function => {
+                return error(VerifyErrorKind::ReturnOutsideFunction)
+            }
+            Depth::Known(depth) if *instruction == Instruction::Return && depth != 0 => {
+                return error(VerifyErrorKind::UnbalancedStack(depth))
+            }
+            Depth::Known(depth) if depth < needed && !function => {
+                return error(VerifyErrorKind::StackUnderflow)
+            }
+            Depth::Known(depth) => Depth::Known(depth + change),
+            Depth::Unknown => Depth::Unknown,
+        };
+        pending.extend(
+            successors(address, instruction)
+                .into_iter()
+                .filter(|next| *next < program.len())
+                .map(|next| (next, after)),
+        );
+    }
+    Ok(())
+}
+
+// The addresses an instruction can jump or call to.
+fn targets(address: usize, instruction: &Instruction) -> Vec<isize> {
+    let relative = |offset: &isize| (address as isize + 1).saturating_add(*offset);
+    match instruction {
+        Instruction::Jump(offset)
+        | Instruction::JumpIfZero(_, offset)
+        | Instruction::JumpIfNonZero(_, offset)
+        | Instruction::Jz(offset)
+        | Instruction::Jnz(offset)
+        | Instruction::Jc(offset)
+        | Instruction::Jo(offset)
+        | Instruction::Jlt(offset)
+        | Instruction::Jge(offset)
+        | Instruction::TryBegin(offset) => vec![relative(offset)],
+        Instruction::JumpTable(_, offsets, default) => {
+            offsets.iter().chain([default]).map(relative).collect()
+        }
+        Instruction::Call(target) => vec![*target as isize],
+        _ => Vec::new(),
+    }
+}
+
+// Where execution continues after an instruction, assuming calls return. The
+// targets must have been checked to be in the program.
+fn successors(address: usize, instruction: &Instruction) -> Vec<usize> {
+    let mut next = match instruction {
+        Instruction::Halt
+        | Instruction::Return
+        | Instruction::Throw(_)
+        | Instruction::Jump(_)
+        | Instruction::JumpTable(..) => Vec::new(),
+        _ => vec![address + 1],
+    };
+    if !matches!(instruction, Instruction::Call(_)) {
+        next.extend(
+            targets(address, instruction)
+                .into_iter()
+                .map(|target| target as usize),
+        );
+    }
+    next
+}
+
+// Walks each function from its entry up to the first `Return`. A callee-saved
+// register counts as saved once it was pushed or stored in a local slot. Jumps
+// are not followed, so this catches a function that forgets to save a register,
+// not every path through it.
+fn verify_calling_convention(program: &[Instruction], table: &[usize]) -> Result<(), VerifyError> {
+    let mut entries: Vec<usize> = program
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Call(target) => Some(*target),
+            _ => None,
+        })
+        .chain(table.iter().copied())
+        .collect();
+    entries.sort_unstable();
+    entries.dedup();
+    for entry in entries {
+        let mut saved = Vec::new();
+        for (address, instruction) in program.iter().enumerate().skip(entry) {
+            let unsaved = |register: &usize| {
+                CALLEE_SAVED_REGISTERS.contains(register) && !saved.contains(register)
+            };
+            let clobbered = match instruction {
+                Instruction::Return => break,
+                Instruction::Push(register)
+                | Instruction::PushSp(register)
+                | Instruction::StoreLocal(register, _) => {
+                    saved.push(*register);
+                    None
+                }
+                Instruction::ClearRegs => CALLEE_SAVED_REGISTERS.clone().find(unsaved),
+                Instruction::MulWide(_, _, high, low) => [*high, *low].into_iter().find(unsaved),
+                _ => instruction.written_register().filter(unsaved),
+            };
+            if let Some(register) = clobbered {
+                return Err(VerifyError {
+                    address,
+                    kind: VerifyErrorKind::ClobbersCalleeSaved(register),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn test_verify_registers() {
+        let program = vec![
+            Instruction::SetReg(0, 1),
+            Instruction::Cas(100, 4, 1, 2, 9), // Addresses aren't registers
+            Instruction::Halt,
+        ];
+
+        assert_eq!(verify(&program, &VmConfig::default()), Err(VerifyError {
+            address: 1,
+            kind: VerifyErrorKind::InvalidRegister(9),
+        }));
+        let config = VmConfig {
+            register_count: 16,
+            ..VmConfig::default()
+        };
+        assert_eq!(verify(&program, &config), Ok(()));
+        assert_eq!(
+            verify(&[Instruction::AddImm(3, 1, 8)], &VmConfig::default()).unwrap_err().to_string(),
+            "instruction 0: invalid register index 8"
+        );
+        assert_eq!(verify(&[Instruction::Nop, Instruction::Mov(0, 8)], &VmConfig::default()), Err(VerifyError {
+            address: 1,
+            kind: VerifyErrorKind::InvalidRegister(8),
+        }));
+        assert_eq!(
+            verify(&[Instruction::VAdd4x32(0, 8, 1)], &config).unwrap_err().to_string(),
+            "instruction 0: invalid vector register index 8"
+        );
+
+        // A VM always has r0
+        let config = VmConfig {
+            register_count: 0,
+            ..VmConfig::default()
+        };
+        assert_eq!(verify(&[Instruction::Mov(0, 0)], &config), Ok(()));
+        assert_eq!(verify(&[Instruction::Mov(0, 1)], &config), Err(VerifyError {
+            address: 0,
+            kind: VerifyErrorKind::InvalidRegister(1),
+        }));
+    }
+
+    #[test]
+    fn test_verify_calling_convention() {
+        let config = VmConfig {
+            calling_convention: CallingConvention::Verified,
+            ..VmConfig::default()
+        };
+        let program = vec![
+            Instruction::Call(3),
+            Instruction::Call(8),
+            Instruction::Halt,
+            Instruction::Push(4), // 3: saves r4 before using it
+            Instruction::AddImm(0, 1, 4),
+            Instruction::Add(4, 1, 0), // Argument and return registers are free
+            Instruction::Pop(4),
+            Instruction::Return,
+            Instruction::SetReg(5, 1), // 8: overwrites r5 without saving it
+            Instruction::Return,
+        ];
+
+        assert_eq!(verify(&program, &config), Err(VerifyError {
+            address: 8,
+            kind: VerifyErrorKind::ClobbersCalleeSaved(5),
+        }));
+        assert_eq!(verify(&program[..8], &config), Ok(()));
+        assert_eq!(verify(&program, &VmConfig::default()), Ok(())); // Not checked by default
+    }
+
+    #[test]
+    fn test_verify_function_table() {
+        let mut program = Program {
+            instructions: vec![
+                Instruction::CallTable(0),
+                Instruction::Halt,
+                Instruction::SetReg(4, 1), // 2: overwrites r4 without saving it
+                Instruction::Return,
+            ],
+            functions: vec![2, 4],
+            ..Program::default()
+        };
+
+        let error = verify_program(&program, &VmConfig::default()).unwrap_err();
+        assert_eq!(error, VerifyError {
+            address: 1,
+            kind: VerifyErrorKind::InvalidFunctionEntry(4),
+        });
+        assert_eq!(error.to_string(), "function table entry 1: target 4 is outside the program");
+
+        program.functions.pop();
+        assert_eq!(verify_program(&program, &VmConfig::default()), Ok(()));
+
+        let config = VmConfig {
+            calling_convention: CallingConvention::Verified,
+            ..VmConfig::default()
+        };
+        assert_eq!(verify_program(&program, &config), Err(VerifyError {
+            address: 2,
+            kind: VerifyErrorKind::ClobbersCalleeSaved(4),
+        }));
+    }
+
+    #[test]
+    fn test_verify_control_flow() {
+        let verify = |instructions: Vec<Instruction>| {
+            Program::from(instructions).verify(&VmConfig::default())
+        };
+        let error = |address, kind| Err(VerifyError { address, kind });
+
+        assert_eq!(
+            verify(vec![Instruction::JumpIfZero(0, 1), Instruction::Halt]),
+            error(0, VerifyErrorKind::InvalidJump(2))
+        );
+        assert_eq!(
+            verify(vec![Instruction::JumpTable(0, vec![-1, -2], -1)]),
+            error(0, VerifyErrorKind::InvalidJump(-1))
+        );
+        assert_eq!(
+            verify(vec![Instruction::Call(0), Instruction::Call(2)]).unwrap_err().to_string(),
+            "instruction 1: target 2 is outside the program"
+        );
+        // The entry runs into the function's `Return`
+        assert_eq!(
+            verify(vec![Instruction::Call(2), Instruction::SetReg(0, 1), Instruction::Return]),
+            error(2, VerifyErrorKind::ReturnOutsideFunction)
+        );
+        assert_eq!(
+            verify(vec![
+                Instruction::Push(0),
+                Instruction::JumpIfZero(0, 1),
+                Instruction::SetReg(1, 1),
+                Instruction::Pop(0), // Either path has one value
+                Instruction::Pop(0),
+            ]),
+            error(4, VerifyErrorKind::StackUnderflow)
+        );
+        assert_eq!(
+            verify(vec![
+                Instruction::Call(3),
+                Instruction::Halt,
+                Instruction::Halt,
+                Instruction::Push(0), // 3
+                Instruction::JumpIfZero(0, 1),
+                Instruction::Pop(1),
+                Instruction::Return, // Leaves r0 on the stack when it is zero
+            ]),
+            error(6, VerifyErrorKind::UnbalancedStack(1))
+        );
+
+        // A loop that pushes a value each round has no known depth after it
+        assert_eq!(
+            verify(vec![
+                Instruction::Push(0),
+                Instruction::SubImm(0, 1, 0),
+                Instruction::JumpIfNonZero(0, -3),
+                Instruction::Pop(0),
+                Instruction::Pop(0),
+            ]),
+            Ok(())
+        );
+        assert_eq!(Program::recursive_fibonacci(10).verify(&VmConfig::default()), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_globals() {
+        let mut program = Program {
+            instructions: vec![Instruction::LoadGlobal(0, 1), Instruction::StoreGlobal(1, 1)],
+            globals: vec![Value::Int(7)],
+            ..Program::default()
+        };
+
+        let error = verify_program(&program, &VmConfig::default()).unwrap_err();
+        assert_eq!(error, VerifyError {
+            address: 1,
+            kind: VerifyErrorKind::InvalidGlobal(1),
+        });
+        assert_eq!(error.to_string(), "instruction 1: invalid global index 1");
+        program.globals.push(Value::Float(0.0));
+        assert_eq!(verify_program(&program, &VmConfig::default()), Ok(()));
+    }
+}
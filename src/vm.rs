@@ -0,0 +1,7710 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::abi::{CallingConvention, CALLEE_SAVED_REGISTERS, RETURN_REGISTER};
+use crate::error::{Frame, Trap, VmError};
+use crate::gc::{string_size, MapKey, ObjectHeap};
+use crate::isa::Instruction;
+use crate::memory::{MemorySafety, MemoryStore, Protection};
+use crate::memstats::MemoryStats;
+use crate::mmio::{DeviceMap, MmioDevice};
+use crate::paging::{PagingConfig, Segment, SegmentKind};
+use crate::profile::Profiler;
+use crate::program::Program;
+use crate::replay::{InputEvent, InputLog};
+use crate::snapshot::VmSnapshot;
+use crate::trace::JsonTrace;
+use crate::undo::{MemoryUndo, Undo};
+use crate::value::Value;
+
+// How many instructions run between two checks of the wall-clock time limit.
+const TIME_CHECK_INTERVAL: u64 = 1024;
+
+// Nesting limit for calls unless the config or `set_max_call_depth` chooses
+// another one.
+const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+
+// Values the data stack may hold unless the config chooses another limit.
+const DEFAULT_MAX_DATA_STACK: usize = 4096;
+
+// Local slots all active frames together may hold unless the config chooses
+// another limit.
+const DEFAULT_MAX_LOCALS: usize = 65536;
+
+// Bytes a memory stack may take unless the config or `set_max_memory_stack`
+// chooses another limit.
+const DEFAULT_MAX_MEMORY_STACK: usize = 64 * 1024;
+
+// Slots all live objects together may hold unless the config chooses another
+// limit.
+const DEFAULT_MAX_OBJECT_SLOTS: usize = 65536;
+
+/// Vector registers every VM has, each four 32-bit integer lanes wide.
+pub const VECTOR_REGISTER_COUNT: usize = 8;
+
+/// The shape of a VM, chosen when it is created with `VM::with_config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmConfig {
+    pub register_count: usize, // Size of the register file, 8 by default and at least 1
+    pub max_call_depth: usize, // Return addresses the call stack may hold, 1024 by default
+    pub max_data_stack: usize, // Values the data stack may hold, 4096 by default
+    pub max_locals: usize,     // Local slots of all active frames together, 65536 by default
+    pub max_memory_stack: usize, // Bytes `enable_memory_stack` may give the program, 64 KiB by default
+    pub max_heap: usize, // Bytes the program may have allocated at once, unlimited by default
+    pub max_object_slots: usize, // Slots of all live objects together, 65536 by default
+    pub calling_convention: CallingConvention, // Unchecked by default
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            register_count: 8,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_data_stack: DEFAULT_MAX_DATA_STACK,
+            max_locals: DEFAULT_MAX_LOCALS,
+            max_memory_stack: DEFAULT_MAX_MEMORY_STACK,
+            max_heap: usize::MAX,
+            max_object_slots: DEFAULT_MAX_OBJECT_SLOTS,
+            calling_convention: CallingConvention::default(),
+        }
+    }
+}
+
+impl VmConfig {
+    // The size of the register file: `register_count`, but never without the
+    // `r0` that calls, throws and host functions return their values in.
+    pub(crate) fn registers(&self) -> usize {
+        self.register_count.max(1)
+    }
+}
+
+/// How a successful call to `run()` ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Halted,       // A Halt instruction was executed
+    EndOfProgram, // Execution ran past the last instruction
+    OutOfFuel,    // Stopped before an instruction the remaining fuel can't pay for
+}
+
+/// The execution state of a VM, as reported by `step()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VmState {
+    Running,      // There are more instructions to execute
+    Halted,       // A Halt instruction was executed
+    EndOfProgram, // Execution ran past the last instruction
+    Trapped,      // Stopped by an error
+    OutOfFuel,    // Not enough fuel left for the next instruction
+}
+
+/// What integer `Add`, `Sub` and `Mul` and their immediate forms, `Neg`,
+/// `Abs` and `FloatToInt` do when the result doesn't fit in an `i32`. The
+/// behaviour is the same in debug and release builds. `FloatToInt` always
+/// saturates outside of Checked mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArithmeticMode {
+    #[default]
+    Wrapping, // Wrap around in two's complement, `i32::MAX + 1 == i32::MIN`
+    Saturating, // Clamp to `i32::MIN` or `i32::MAX`
+    Checked,    // Stop with `VmError::Overflow`
+}
+
+impl ArithmeticMode {
+    fn apply<T>(
+        self,
+        a: T,
+        b: T,
+        wrapping: fn(T, T) -> T,
+        saturating: fn(T, T) -> T,
+        checked: fn(T, T) -> Option<T>,
+    ) -> Result<T, VmError> {
+        match self {
+            ArithmeticMode::Wrapping => Ok(wrapping(a, b)),
+            ArithmeticMode::Saturating => Ok(saturating(a, b)),
+            ArithmeticMode::Checked => checked(a, b).ok_or(VmError::Overflow),
+        }
+    }
+}
+
+/// What the registers hold, chosen with `VM::set_register_mode`. In tagged mode
+/// every integer written to a register is held as a `Value::Int64` and cleared
+/// registers are `Value::Nil`. `Add`, `Sub`, `Mul`, `Div` and `Mod` then work on
+/// two 64-bit integers or two floats, and the comparisons `Eq` to `Lte` on any
+/// such pair, the result taking the operands' type; anything else, such as
+/// adding an integer to a float or to nil, is a `TypeMismatch`. `Eq` and `Neq`
+/// also compare nil and references, values of different types being unequal.
+/// These leave the flags alone. The other instructions work as in raw mode,
+/// reading a 64-bit integer that doesn't fit in an `i32` as an `Overflow`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RegisterMode {
+    #[default]
+    Raw, // Integers are `Value::Int`, and registers start out as zero
+    Tagged, // Integers are `Value::Int64`, and registers start out as nil
+}
+
+// An exception handler installed by `TryBegin`: where a `Throw` continues, and
+// the depth of each stack to unwind to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Handler {
+    target: usize,
+    calls: usize,      // Return addresses on the call stack
+    frames: usize,     // Active frames
+    data_stack: usize, // Values on the data stack
+    sp: usize,         // Stack pointer of the memory stack
+}
+
+/// The condition flags, set by integer `Add`, `Sub` and `Mul`, their immediate
+/// forms, `Adc`, `Sbb`, `Cmp` and `CmpImm`, and tested by the flag branches `Jz` to `Jge`.
+/// They describe the wrapping result whatever the arithmetic mode, so a
+/// `Jlt` after a subtract or compare is right for any operands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Flags {
+    pub zero: bool,     // The result is 0
+    pub negative: bool, // The sign bit of the result is set
+    pub carry: bool,    // Carry out of an unsigned add, borrow of an unsigned subtract
+    pub overflow: bool, // The signed result doesn't fit in an `i32`
+}
+
+impl Flags {
+    fn of(result: i32, carry: bool, overflow: bool) -> Flags {
+        Flags {
+            zero: result == 0,
+            negative: result < 0,
+            carry,
+            overflow,
+        }
+    }
+
+    fn add(a: i32, b: i32) -> Flags {
+        let (result, overflow) = a.overflowing_add(b);
+        Flags::of(result, (a as u32).overflowing_add(b as u32).1, overflow)
+    }
+
+    fn sub(a: i32, b: i32) -> Flags {
+        let (result, overflow) = a.overflowing_sub(b);
+        Flags::of(result, (a as u32) < (b as u32), overflow)
+    }
+
+    // A multiply carries and overflows together, when the product needs more
+    // than 32 bits.
+    fn mul(a: i32, b: i32) -> Flags {
+        let (result, overflow) = a.overflowing_mul(b);
+        Flags::of(result, overflow, overflow)
+    }
+
+    // `Adc` and `Sbb`, which also return the wrapping result.
+    fn add_with_carry(a: i32, b: i32, carry: bool) -> (i32, Flags) {
+        let unsigned = a as u32 as u64 + b as u32 as u64 + carry as u64;
+        let exact = a as i64 + b as i64 + carry as i64;
+        let result = unsigned as i32;
+        let flags = Flags::of(result, unsigned > u32::MAX as u64, exact != result as i64);
+        (result, flags)
+    }
+
+    fn sub_with_borrow(a: i32, b: i32, borrow: bool) -> (i32, Flags) {
+        let exact = a as i64 - b as i64 - borrow as i64;
+        let result = exact as i32;
+        let borrowed = (a as u32 as u64) < b as u32 as u64 + borrow as u64;
+        (result, Flags::of(result, borrowed, exact != result as i64))
+    }
+
+    fn less(self) -> bool {
+        self.negative != self.overflow
+    }
+}
+
+/// Flags display as `ZNCV`, with a `-` for each clear flag.
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (set, letter) in [
+            (self.zero, 'Z'),
+            (self.negative, 'N'),
+            (self.carry, 'C'),
+            (self.overflow, 'V'),
+        ] {
+            write!(f, "{}", if set { letter } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+/// An observable side effect of executing an instruction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Effect {
+    RegisterWrite {
+        register: usize,
+        value: Value,
+    },
+    VectorWrite {
+        register: usize,
+        lanes: [i32; 4],
+    },
+    MemoryWrite {
+        address: usize,
+        value: u8,
+    },
+    MemoryWriteBlock {
+        address: usize,
+        bytes: Vec<u8>,
+    },
+    Print {
+        register: usize,
+        value: Value,
+    },
+    PrintUnsigned {
+        register: usize,
+        value: u32,
+    },
+    PrintStr {
+        address: usize,
+        bytes: Vec<u8>,
+    },
+    PrintString {
+        register: usize,
+        bytes: Vec<u8>,
+    },
+    Allocate {
+        address: usize,
+        size: usize,
+    },
+    Free {
+        address: usize,
+    },
+    Protect {
+        address: usize,
+        protection: Protection,
+    },
+    Refcount {
+        address: usize,
+        count: usize,
+    },
+    Syscall {
+        number: usize,
+    },
+}
+
+/// A host function invoked by `Syscall`. By convention it reads its arguments from
+/// and writes its results to the registers, but it has full access to the VM.
+pub type SyscallHandler = Box<dyn FnMut(&mut VM)>;
+
+/// A host function registered with `VM::register_host_fn`. An error stops the
+/// `Syscall` like any other instruction error.
+pub type HostFn = Box<dyn FnMut(&mut VmContext<'_>) -> Result<(), VmError>>;
+
+/// The view of the VM a host function gets. Arguments are passed in the
+/// registers from `r0` upwards and the result is returned in `r0`.
+pub struct VmContext<'a> {
+    vm: &'a mut VM,
+}
+
+impl VmContext<'_> {
+    /// The integer argument in register `index`.
+    pub fn arg(&self, index: usize) -> Result<i32, VmError> {
+        self.vm.read_int(index)
+    }
+
+    /// The float argument in register `index`.
+    pub fn float_arg(&self, index: usize) -> Result<f64, VmError> {
+        self.vm.read_float(index)
+    }
+
+    /// Store the result in `r0`.
+    pub fn set_return(&mut self, value: Value) {
+        self.set_register(0, value).expect("r0 always exists");
+    }
+
+    /// Store a value in any register, for host functions with several results.
+    pub fn set_register(&mut self, register: usize, value: Value) -> Result<(), VmError> {
+        self.vm.check_register(register)?;
+        self.vm.apply(Effect::RegisterWrite { register, value });
+        Ok(())
+    }
+
+    /// Read `len` bytes of VM memory, which must all be allocated.
+    pub fn read_memory(&self, address: usize, len: usize) -> Result<Vec<u8>, VmError> {
+        Ok(self.vm.memory.borrow().bytes(address, len)?.to_vec())
+    }
+
+    /// Write bytes to VM memory, which must all be allocated.
+    pub fn write_memory(&mut self, address: usize, bytes: &[u8]) -> Result<(), VmError> {
+        self.vm.check_writable(address, bytes.len())?;
+        self.vm.apply(Effect::MemoryWriteBlock {
+            address,
+            bytes: bytes.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Full access to the VM, for anything the helpers above don't cover.
+    pub fn vm(&mut self) -> &mut VM {
+        self.vm
+    }
+}
+
+/// Observer called before each instruction executes with its address, the
+/// instruction and the register file as it is before the instruction runs.
+pub type Tracer = Box<dyn FnMut(usize, &Instruction, &[Value])>;
+
+/// The virtual machine: program, registers, memory and execution state.
+pub struct VM {
+    ip: usize,                             // Instruction pointer
+    program: Vec<Instruction>,             // The program instructions
+    registers: Vec<Value>,                 // 8 registers unless configured otherwise
+    vectors: Vec<[i32; 4]>,                // Vector registers, 128 bits each
+    memory: Rc<RefCell<MemoryStore>>,      // Memory regions, possibly shared with other VMs
+    stack: Vec<usize>,                     // Stack for function call management (return addresses)
+    functions: Vec<usize>,                 // Function table for CallTable
+    symbols: BTreeMap<usize, String>,      // Names of functions by entry address, for backtraces
+    saved_registers: Vec<Vec<Value>>,      // Callee-saved registers of each active call, when saved
+    data_stack: Vec<Value>,                // Operand stack for Push, Pop, Dup and SwapTop
+    frames: Vec<usize>,                    // Start of each active frame's slots in `locals`
+    handlers: Vec<Handler>, // Exception handlers installed by TryBegin, innermost last
+    locals: Vec<Value>,     // Local slots of all active frames, innermost last
+    globals: Vec<Value>,    // Global variables for LoadGlobal and StoreGlobal
+    objects: ObjectHeap,    // Objects created with NewObject, collected when unreachable
+    memory_stack: Option<Range<usize>>, // The memory region SP moves in, once enabled
+    sp: usize,              // Stack pointer, the address of the top word of the memory stack
+    effects: Option<Vec<Effect>>, // Recorded side effects, when recording is enabled
+    undo: Option<Undo>,     // What undoing the current instruction takes, while recording
+    dry_run: bool,          // Suppress output, used when running a shadow copy
+    time_limit: Option<Duration>, // Wall-clock limit for a single call to run()
+    output: Box<dyn Write>, // Sink for program output (Print instructions)
+    input: Box<dyn BufRead>, // Source for ReadInt and ReadByte
+    input_log: Option<Vec<InputEvent>>, // Recorded inputs, when recording
+    replay: Option<VecDeque<InputEvent>>, // Inputs still to be replayed, when replaying
+    syscalls: HashMap<usize, HostFn>, // Host functions callable via Syscall
+    strict: bool,           // Stop on the first error instead of skipping the instruction
+    tracer: Option<Tracer>, // Called before each instruction executes
+    json_trace: Option<JsonTrace>, // Writes a JSON line per executed instruction
+    profiler: Option<Profiler>, // Execution counts and times, when profiling
+    memory_stats: MemoryStats, // Allocations and frees made by the program
+    leak_report: bool,      // Print the regions never freed when the program ends
+    state: VmState,         // Running, Halted or Trapped; running past the end is derived from ip
+    trap: Option<Trap>,     // The error that trapped the VM and where
+    flags: Flags,           // Set by arithmetic and compares, tested by the flag branches
+    fuel: Option<u64>,      // Remaining fuel, when execution is metered
+    arithmetic_mode: ArithmeticMode, // Overflow behaviour of integer arithmetic
+    register_mode: RegisterMode, // Raw `i32` registers, or tagged values
+    max_call_depth: usize,  // Return addresses the call stack may hold
+    max_data_stack: usize,  // Values the data stack may hold
+    max_locals: usize,      // Local slots all active frames may hold together
+    max_memory_stack: usize, // Bytes a memory stack may take
+    max_heap: usize,        // Bytes the program may have allocated at once
+    max_object_slots: usize, // Slots all live objects may hold together
+    calling_convention: CallingConvention, // Whether Call and Return save registers
+    devices: DeviceMap,     // Host devices that loads and stores to their ranges reach
+}
+
+impl VM {
+    /// Create a VM with its own, empty memory.
+    pub fn new(program: Vec<Instruction>) -> Self {
+        VM::new_shared(program, Rc::new(RefCell::new(MemoryStore::new())))
+    }
+
+    /// Create a VM that operates on an existing, possibly shared, memory store.
+    pub fn new_shared(program: Vec<Instruction>, memory: Rc<RefCell<MemoryStore>>) -> Self {
+        VM {
+            ip: 0,
+            program,
+            registers: vec![Value::default(); 8], // 8 registers initialized to integer zero
+            vectors: vec![[0; 4]; VECTOR_REGISTER_COUNT],
+            memory,
+            stack: Vec::new(), // Stack for function calls
+            functions: Vec::new(),
+            symbols: BTreeMap::new(),
+            saved_registers: Vec::new(),
+            data_stack: Vec::new(),
+            frames: Vec::new(),
+            handlers: Vec::new(),
+            locals: Vec::new(),
+            globals: Vec::new(),
+            objects: ObjectHeap::default(),
+            memory_stack: None,
+            sp: 0,
+            effects: None,
+            undo: None,
+            dry_run: false,
+            time_limit: None,
+            output: Box::new(io::stdout()),
+            input: Box::new(BufReader::new(io::stdin())),
+            input_log: None,
+            replay: None,
+            syscalls: HashMap::new(),
+            strict: true,
+            tracer: None,
+            json_trace: None,
+            profiler: None,
+            memory_stats: MemoryStats::default(),
+            leak_report: false,
+            state: VmState::Running,
+            trap: None,
+            flags: Flags::default(),
+            fuel: None,
+            arithmetic_mode: ArithmeticMode::default(),
+            register_mode: RegisterMode::default(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_data_stack: DEFAULT_MAX_DATA_STACK,
+            max_locals: DEFAULT_MAX_LOCALS,
+            max_memory_stack: DEFAULT_MAX_MEMORY_STACK,
+            max_heap: usize::MAX,
+            max_object_slots: DEFAULT_MAX_OBJECT_SLOTS,
+            calling_convention: CallingConvention::default(),
+            devices: DeviceMap::default(),
+        }
+    }
+
+    /// Create a VM with a register file and limits other than the defaults. Use
+    /// `verify` to check up front that a program fits the configuration.
+    pub fn with_config(program: Vec<Instruction>, config: VmConfig) -> Self {
+        let mut vm = VM::new(program);
+        vm.registers = vec![Value::default(); config.registers()];
+        vm.max_call_depth = config.max_call_depth;
+        vm.max_data_stack = config.max_data_stack;
+        vm.max_locals = config.max_locals;
+        vm.max_memory_stack = config.max_memory_stack;
+        vm.max_heap = config.max_heap;
+        vm.max_object_slots = config.max_object_slots;
+        vm.calling_convention = config.calling_convention;
+        vm
+    }
+
+    /// Create a VM for a program with a data section and a function table. The
+    /// data is loaded into a read-only region at address 0 before anything runs,
+    /// so addresses the assembler hands out for data labels are valid memory
+    /// addresses.
+    pub fn from_program(program: Program) -> Self {
+        let mut vm = VM::new(program.instructions);
+        vm.memory.borrow_mut().load_data(&program.data);
+        vm.functions = program.functions;
+        vm.globals = program.globals;
+        vm.symbols = program.symbols;
+        vm
+    }
+
+    /// Replace the function table `CallTable` indexes into.
+    pub fn set_function_table(&mut self, functions: Vec<usize>) {
+        self.functions = functions;
+    }
+
+    /// Replace the names of functions by entry address that backtraces use.
+    pub fn set_symbols(&mut self, symbols: BTreeMap<usize, String>) {
+        self.symbols = symbols;
+    }
+
+    /// The function an instruction lies in: the nearest symbol at or before
+    /// its address.
+    pub fn function_at(&self, address: usize) -> Option<&str> {
+        self.symbols
+            .range(..=address)
+            .next_back()
+            .map(|(_, name)| name.as_str())
+    }
+
+    // The instruction at `ip`, then the call of each active function, innermost
+    // first, with the functions they lie in.
+    fn backtrace(&self, ip: usize) -> Vec<Frame> {
+        let calls = self.stack.iter().rev().map(|address| address - 1);
+        std::iter::once(ip)
+            .chain(calls)
+            .map(|address| Frame {
+                address,
+                function: self.function_at(address).map(str::to_string),
+            })
+            .collect()
+    }
+
+    /// Create a VM whose execution is metered: every instruction spends its
+    /// `Instruction::cost()` and the VM stops once `fuel` runs out.
+    pub fn with_fuel(program: Vec<Instruction>, fuel: u64) -> Self {
+        let mut vm = VM::new(program);
+        vm.fuel = Some(fuel);
+        vm
+    }
+
+    /// Feed `ReadInt` and `ReadByte` from `input` instead of stdin.
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        self.input = Box::new(input);
+    }
+
+    /// Create a VM that writes its program output to `output` instead of stdout.
+    pub fn with_output(program: Vec<Instruction>, output: impl Write + 'static) -> Self {
+        let mut vm = VM::new(program);
+        vm.set_output(output);
+        vm
+    }
+
+    /// The fuel left, or `None` if execution is not metered.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Add fuel to a metered VM, so a VM that ran out can resume.
+    pub fn refuel(&mut self, amount: u64) {
+        if let Some(fuel) = &mut self.fuel {
+            *fuel = fuel.saturating_add(amount);
+        }
+    }
+
+    /// The register file.
+    pub fn registers(&self) -> &[Value] {
+        &self.registers
+    }
+
+    /// Mutable access to the register file, e.g. for syscall handlers.
+    pub fn registers_mut(&mut self) -> &mut [Value] {
+        &mut self.registers
+    }
+
+    /// The address of the next instruction to execute.
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// The program being executed.
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+
+    /// Append instructions to the program. A VM that ran past its last
+    /// instruction continues with the new ones on the next `run()` or `step()`.
+    pub fn extend_program(&mut self, instructions: impl IntoIterator<Item = Instruction>) {
+        self.program.extend(instructions);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.resize(self.program.len());
+        }
+    }
+
+    /// The return addresses of the active calls, innermost last.
+    pub fn call_stack(&self) -> &[usize] {
+        &self.stack
+    }
+
+    /// The values on the data stack, top last.
+    pub fn data_stack(&self) -> &[Value] {
+        &self.data_stack
+    }
+
+    /// The local slots of the innermost frame, empty outside of any frame.
+    pub fn locals(&self) -> &[Value] {
+        let base = self.frames.last().copied().unwrap_or(self.locals.len());
+        &self.locals[base..]
+    }
+
+    /// The global variables, with the values the program last stored in them.
+    pub fn globals(&self) -> &[Value] {
+        &self.globals
+    }
+
+    /// The garbage-collected objects the program created with `NewObject`.
+    pub fn objects(&self) -> &ObjectHeap {
+        &self.objects
+    }
+
+    /// Collect the objects that no register, data stack value, local, global or
+    /// callee-saved register reaches, directly or through other objects, and
+    /// return how many there were. `NewObject` does this itself when the
+    /// object heap is full.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.record_objects();
+        let roots = self
+            .registers
+            .iter()
+            .chain(&self.data_stack)
+            .chain(&self.locals)
+            .chain(&self.globals)
+            .chain(self.saved_registers.iter().flatten());
+        let collected = self.objects.collect(roots);
+        vm_log!(debug, "Collected {} objects", collected);
+        collected
+    }
+
+    /// The memory store, which may be shared with other VMs.
+    pub fn memory(&self) -> &Rc<RefCell<MemoryStore>> {
+        &self.memory
+    }
+
+    /// Redirect program output, e.g. into a buffer for tests.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// Register (or replace) the host function invoked by `Syscall(number)`.
+    pub fn register_syscall(&mut self, number: usize, mut handler: SyscallHandler) {
+        self.register_host_fn(number, move |context| {
+            handler(context.vm());
+            Ok(())
+        });
+    }
+
+    /// Register (or replace) a fallible host function invoked by `Syscall(id)`.
+    /// It reads its arguments from `r0` upwards and returns its result in `r0`
+    /// through the `VmContext`.
+    pub fn register_host_fn(
+        &mut self,
+        id: usize,
+        function: impl FnMut(&mut VmContext<'_>) -> Result<(), VmError> + 'static,
+    ) {
+        self.syscalls.insert(id, Box::new(function));
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Write a JSON object per executed instruction to `writer`, one per line,
+    /// with its address, mnemonic, operands and the registers and memory it
+    /// wrote. `None` stops tracing.
+    pub fn set_json_trace(&mut self, writer: Option<Box<dyn Write>>) {
+        self.json_trace = writer.map(JsonTrace::new);
+    }
+
+    /// Count executions and measure the time of each instruction from now on.
+    /// Turning profiling on again starts a fresh profile, turning it off drops the
+    /// profile.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiler = enabled.then(|| Profiler::new(self.program.len()));
+    }
+
+    /// The profile collected since profiling was turned on.
+    pub fn profile(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Allocation counts, peak usage and the regions still allocated, with the
+    /// instruction that allocated each.
+    pub fn memory_stats(&self) -> &MemoryStats {
+        &self.memory_stats
+    }
+
+    /// Write the memory statistics and the regions never freed to the program
+    /// output when the program halts or runs past its last instruction.
+    pub fn set_leak_report(&mut self, enabled: bool) {
+        self.leak_report = enabled;
+    }
+
+    /// Capture the execution state: instruction pointer, registers, vector
+    /// registers, flags, the call and data stacks, local frames, exception
+    /// handlers, memory, and whether the VM halted, trapped or ran out of fuel.
+    pub fn snapshot(&self) -> VmSnapshot {
+        self.snapshot_with(self.objects.clone(), self.memory.borrow().clone())
+    }
+
+    // A snapshot of the state apart from the objects and memory, which it
+    // takes as given.
+    fn snapshot_with(&self, objects: ObjectHeap, memory: MemoryStore) -> VmSnapshot {
+        VmSnapshot {
+            ip: self.ip,
+            registers: self.registers.clone(),
+            vectors: self.vectors.clone(),
+            stack: self.stack.clone(),
+            saved_registers: self.saved_registers.clone(),
+            data_stack: self.data_stack.clone(),
+            frames: self.frames.clone(),
+            handlers: self.handlers.clone(),
+            locals: self.locals.clone(),
+            globals: self.globals.clone(),
+            sp: self.sp,
+            objects,
+            memory,
+            memory_stats: self.memory_stats.clone(),
+            state: self.state,
+            trap: self.trap.clone(),
+            flags: self.flags,
+            fuel: self.fuel,
+        }
+    }
+
+    /// Continue from a snapshot taken of a VM running the same program. The
+    /// memory store is overwritten in place, so VMs sharing it see the restored
+    /// memory too.
+    pub fn restore(&mut self, snapshot: &VmSnapshot) {
+        self.restore_state(snapshot);
+        self.objects = snapshot.objects.clone();
+        *self.memory.borrow_mut() = snapshot.memory.clone();
+    }
+
+    // Restore everything but the objects and memory.
+    fn restore_state(&mut self, snapshot: &VmSnapshot) {
+        self.ip = snapshot.ip;
+        self.registers = snapshot.registers.clone();
+        self.vectors = snapshot.vectors.clone();
+        self.stack = snapshot.stack.clone();
+        self.saved_registers = snapshot.saved_registers.clone();
+        self.data_stack = snapshot.data_stack.clone();
+        self.frames = snapshot.frames.clone();
+        self.handlers = snapshot.handlers.clone();
+        self.locals = snapshot.locals.clone();
+        self.globals = snapshot.globals.clone();
+        self.sp = snapshot.sp;
+        self.memory_stats = snapshot.memory_stats.clone();
+        self.state = snapshot.state;
+        self.trap = snapshot.trap.clone();
+        self.flags = snapshot.flags;
+        self.fuel = snapshot.fuel;
+    }
+
+    /// Start recording what undoing the next instruction takes, until
+    /// `take_undo`.
+    pub(crate) fn begin_undo(&mut self) {
+        self.undo = Some(Undo {
+            state: self.snapshot_with(ObjectHeap::default(), MemoryStore::default()),
+            memory: Vec::new(),
+            objects: None,
+        });
+    }
+
+    pub(crate) fn take_undo(&mut self) -> Option<Undo> {
+        self.undo.take()
+    }
+
+    /// Put the VM back into the state from before the instruction `undo` was
+    /// recorded for.
+    pub(crate) fn undo(&mut self, undo: Undo) {
+        let mut memory = self.memory.borrow_mut();
+        for change in undo.memory.into_iter().rev() {
+            change.undo(&mut memory);
+        }
+        drop(memory);
+        if let Some(objects) = undo.objects {
+            self.objects = objects;
+        }
+        self.restore_state(&undo.state);
+    }
+
+    // Keep the objects as they are before an instruction changes them, while
+    // recording an undo entry.
+    fn record_objects(&mut self) {
+        if let Some(undo) = &mut self.undo {
+            if undo.objects.is_none() {
+                undo.objects = Some(self.objects.clone());
+            }
+        }
+    }
+
+    // Keep what `effect` is about to overwrite, while recording an undo entry.
+    fn record_memory(&mut self, effect: &Effect) {
+        let Some(undo) = &mut self.undo else {
+            return;
+        };
+        let mut memory = self.memory.borrow_mut();
+        match *effect {
+            Effect::MemoryWrite { address, .. } => {
+                undo.memory.push(MemoryUndo::bytes(&memory, address, 1));
+            }
+            Effect::MemoryWriteBlock { address, ref bytes } => {
+                undo.memory
+                    .push(MemoryUndo::bytes(&memory, address, bytes.len()));
+            }
+            Effect::Allocate { address, size } => {
+                undo.memory.push(MemoryUndo::layout(&mut memory));
+                undo.memory.push(MemoryUndo::bytes(&memory, address, size));
+            }
+            Effect::Free { .. } | Effect::Protect { .. } | Effect::Refcount { .. } => {
+                undo.memory.push(MemoryUndo::layout(&mut memory));
+            }
+            Effect::Syscall { .. } => {
+                undo.memory.push(MemoryUndo::Store(memory.clone()));
+                drop(memory);
+                self.record_objects();
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_time_limit(&mut self, time_limit: Option<Duration>) {
+        self.time_limit = time_limit;
+    }
+
+    /// In strict mode (the default) any error stops `run()`. Otherwise the faulting
+    /// instruction is skipped, the error is logged and execution carries on.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Choose how the memory store checks accesses to freed and uninitialized
+    /// memory. The mode belongs to the store, so it applies to every VM sharing
+    /// it.
+    pub fn set_memory_safety(&mut self, safety: MemorySafety) {
+        self.memory.borrow_mut().set_safety(safety);
+    }
+
+    /// Route the program's loads and stores to `range` to a host device instead of
+    /// memory. The range is kept from the allocator; it must not overlap allocated
+    /// memory or another device. Returns false, and maps nothing, otherwise.
+    pub fn map_device(&mut self, range: Range<usize>, device: impl MmioDevice + 'static) -> bool {
+        if range.is_empty()
+            || self.devices.overlaps(&range)
+            || !self.memory.borrow_mut().reserve(range.start, range.len())
+        {
+            return false;
+        }
+        self.devices.insert(range, Box::new(device));
+        true
+    }
+
+    /// Give the program a stack of `size` bytes in memory for `PushSp`, `PopSp`,
+    /// `LoadSpOffset` and `StoreSpOffset`, with SP starting at its top. With
+    /// paging it takes the top of the stack segment, otherwise it is a region
+    /// placed where `AllocateMemory` would put it, which the program can't free.
+    /// Returns false when a memory stack is already enabled, is larger than the
+    /// limit `set_max_memory_stack` chose or doesn't fit.
+    pub fn enable_memory_stack(&mut self, size: usize) -> bool {
+        if self.memory_stack.is_some() || size == 0 || size > self.max_memory_stack {
+            return false;
+        }
+        let mut memory = self.memory.borrow_mut();
+        let stack = match memory
+            .segments()
+            .into_iter()
+            .find(|segment| segment.kind == SegmentKind::Stack)
+        {
+            Some(segment) if size <= segment.size => {
+                segment.base + segment.size - size..segment.base + segment.size
+            }
+            Some(_) => return false,
+            None => {
+                let address = memory.find_free(size);
+                if memory.check_allocation(address, size).is_err() {
+                    return false;
+                }
+                memory.allocate_at(address, size);
+                address..address + size
+            }
+        };
+        drop(memory);
+        self.sp = stack.end;
+        self.memory_stack = Some(stack);
+        true
+    }
+
+    /// The stack pointer, the address of the word on top of the memory stack.
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    /// The memory region the stack pointer moves in, when a memory stack is
+    /// enabled.
+    pub fn memory_stack(&self) -> Option<Range<usize>> {
+        self.memory_stack.clone()
+    }
+
+    /// Give the memory store a paged address space laid out by `config`, see
+    /// `MemoryStore::enable_paging`. Returns false when it can't be enabled.
+    pub fn enable_paging(&mut self, config: PagingConfig) -> bool {
+        self.memory.borrow_mut().enable_paging(config)
+    }
+
+    /// The segment descriptors of a paged address space: the code, addressed
+    /// by instruction, then the data, heap and stack. Empty without paging.
+    pub fn segments(&self) -> Vec<Segment> {
+        let mut segments = self.memory.borrow().segments();
+        if !segments.is_empty() {
+            segments.insert(
+                0,
+                Segment {
+                    kind: SegmentKind::Code,
+                    base: 0,
+                    size: self.program.len(),
+                    protection: Protection::READ_ONLY,
+                },
+            );
+        }
+        segments
+    }
+
+    /// Choose how integer overflow is handled, wrapping by default.
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arithmetic_mode = mode;
+    }
+
+    /// Choose what the registers hold, raw `i32` registers by default. This
+    /// clears every register, to nil in tagged mode, so it belongs before the
+    /// program runs.
+    pub fn set_register_mode(&mut self, mode: RegisterMode) {
+        self.register_mode = mode;
+        let cleared = self.cleared_value();
+        self.registers.fill(cleared);
+    }
+
+    /// Limit how deeply calls may nest. A `Call` that would push more return
+    /// addresses traps with `StackOverflow`.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Limit how many bytes `enable_memory_stack` may give the program, so a
+    /// runaway recursion that spills to it can't claim most of memory.
+    pub fn set_max_memory_stack(&mut self, size: usize) {
+        self.max_memory_stack = size;
+    }
+
+    /// Limit how many bytes the program may have allocated and not freed. An
+    /// `AllocateMemory` or `ReallocMemory` past the limit traps with
+    /// `OutOfMemory`, so a guest can't make the host allocate without bound.
+    pub fn set_max_heap(&mut self, size: usize) {
+        self.max_heap = size;
+    }
+
+    /// Run the program until it halts, runs past its last instruction or is
+    /// stopped by an error.
+    pub fn run(&mut self) -> Result<ExitStatus, VmError> {
+        let started = Instant::now();
+        let mut executed: u64 = 0;
+        loop {
+            executed += 1;
+            if let Some(time_limit) = self.time_limit {
+                if executed.is_multiple_of(TIME_CHECK_INTERVAL) && started.elapsed() > time_limit {
+                    vm_log!(error, "Time limit of {:?} exceeded.", time_limit);
+                    return Err(VmError::TimedOut);
+                }
+            }
+
+            match self.step()? {
+                VmState::Running => {}
+                VmState::Halted => return Ok(ExitStatus::Halted),
+                VmState::EndOfProgram => return Ok(ExitStatus::EndOfProgram),
+                VmState::OutOfFuel => return Ok(ExitStatus::OutOfFuel),
+                VmState::Trapped => return Err(self.trap.clone().unwrap().error),
+            }
+        }
+    }
+
+    /// Execute a single instruction and report the state the VM is left in. Once
+    /// the VM has halted, finished or trapped, further steps do nothing.
+    pub fn step(&mut self) -> Result<VmState, VmError> {
+        if self.state() != VmState::Running {
+            return Ok(self.state());
+        }
+
+        let ip = self.ip;
+        let instruction = &self.program[ip];
+        if let Some(fuel) = &mut self.fuel {
+            *fuel -= instruction.cost();
+        }
+        vm_log!(trace, "{:04}: {:?}", self.ip, instruction);
+        if let Some(tracer) = &mut self.tracer {
+            tracer(self.ip, instruction, &self.registers);
+        }
+        self.ip += 1;
+
+        let started = self.profiler.is_some().then(Instant::now);
+        let result = match instruction {
+            Instruction::SetReg(register_index, value) => self.set_reg(*register_index, *value),
+            Instruction::Add(register_a, register_b, target_register) => {
+                self.add(*register_a, *register_b, *target_register)
+            }
+            Instruction::Sub(register_a, register_b, target_register) => {
+                self.sub(*register_a, *register_b, *target_register)
+            }
+            Instruction::Mul(register_a, register_b, target_register) => {
+                self.mul(*register_a, *register_b, *target_register)
+            }
+            Instruction::Div(register_a, register_b, target_register) => {
+                self.div(*register_a, *register_b, *target_register)
+            }
+            Instruction::Mod(register_a, register_b, target_register) => {
+                self.mod_op(*register_a, *register_b, *target_register)
+            }
+            Instruction::Eq(register_a, register_b, target_register) => {
+                self.eq(*register_a, *register_b, *target_register)
+            }
+            Instruction::Neq(register_a, register_b, target_register) => {
+                self.neq(*register_a, *register_b, *target_register)
+            }
+            Instruction::Gt(register_a, register_b, target_register) => {
+                self.gt(*register_a, *register_b, *target_register)
+            }
+            Instruction::Lt(register_a, register_b, target_register) => {
+                self.lt(*register_a, *register_b, *target_register)
+            }
+            Instruction::Gte(register_a, register_b, target_register) => {
+                self.gte(*register_a, *register_b, *target_register)
+            }
+            Instruction::Lte(register_a, register_b, target_register) => {
+                self.lte(*register_a, *register_b, *target_register)
+            }
+            Instruction::Jump(ip_offset) => self.jump(*ip_offset),
+            Instruction::JumpIfZero(register_index, ip_offset) => {
+                self.jump_if_zero(*register_index, *ip_offset)
+            }
+            Instruction::JumpIfNonZero(register_index, ip_offset) => {
+                self.jump_if_non_zero(*register_index, *ip_offset)
+            }
+            Instruction::Print(register_index) => self.print(*register_index),
+            Instruction::Halt => {
+                self.state = VmState::Halted;
+                Ok(())
+            }
+            Instruction::AllocateMemory(size_register, target_register) => {
+                self.allocate_memory(*size_register, *target_register)
+            }
+            Instruction::FreeMemory(address) => self.free_memory(*address),
+            Instruction::StoreToMemory(address, register_index, offset) => {
+                self.store_to_memory(*address, *register_index, *offset)
+            }
+            Instruction::LoadFromMemory(address, offset, register_index) => {
+                self.load_from_memory(*address, *offset, *register_index)
+            }
+            Instruction::Call(target) => self.call(*target),
+            Instruction::Return => self.return_from_function(),
+            Instruction::FAdd(register_a, register_b, target_register) => {
+                self.fadd(*register_a, *register_b, *target_register)
+            }
+            Instruction::FSub(register_a, register_b, target_register) => {
+                self.fsub(*register_a, *register_b, *target_register)
+            }
+            Instruction::FMul(register_a, register_b, target_register) => {
+                self.fmul(*register_a, *register_b, *target_register)
+            }
+            Instruction::FDiv(register_a, register_b, target_register) => {
+                self.fdiv(*register_a, *register_b, *target_register)
+            }
+            Instruction::IntToFloat(src_register, target_register) => {
+                self.int_to_float(*src_register, *target_register)
+            }
+            Instruction::FloatToInt(src_register, target_register) => {
+                self.float_to_int(*src_register, *target_register)
+            }
+            Instruction::SetRegByte(register_index, byte) => {
+                self.set_reg(*register_index, *byte as i8 as i32)
+            }
+            Instruction::SetRegShifted(register_index, byte, shift) => {
+                self.set_reg_shifted(*register_index, *byte, *shift)
+            }
+            Instruction::SizeOf(address, register_index) => self.size_of(*address, *register_index),
+            Instruction::MemCopy(dst_address, src_address, len) => {
+                self.mem_copy(*dst_address, *src_address, *len)
+            }
+            Instruction::MemFill(address, value_register, len) => {
+                self.mem_fill(*address, *value_register, *len)
+            }
+            Instruction::PrintU(register_index) => self.print_unsigned(*register_index),
+            Instruction::Syscall(number) => self.syscall(*number),
+            Instruction::Cas(address, offset, expected_register, new_register, result_register) => {
+                self.compare_and_swap(
+                    *address,
+                    *offset,
+                    *expected_register,
+                    *new_register,
+                    *result_register,
+                )
+            }
+            Instruction::ClearRegs => self.clear_regs(),
+            Instruction::ClearReg(register_index) => self.clear_reg(*register_index),
+            Instruction::Rol(register_a, register_b, target_register) => {
+                self.rol(*register_a, *register_b, *target_register)
+            }
+            Instruction::Ror(register_a, register_b, target_register) => {
+                self.ror(*register_a, *register_b, *target_register)
+            }
+            Instruction::AssertEq(register_index, expected) => {
+                self.assert_eq(*register_index, *expected)
+            }
+            Instruction::And(register_a, register_b, target_register) => {
+                self.and(*register_a, *register_b, *target_register)
+            }
+            Instruction::Or(register_a, register_b, target_register) => {
+                self.or(*register_a, *register_b, *target_register)
+            }
+            Instruction::Xor(register_a, register_b, target_register) => {
+                self.xor(*register_a, *register_b, *target_register)
+            }
+            Instruction::Shl(register_a, register_b, target_register) => {
+                self.shl(*register_a, *register_b, *target_register)
+            }
+            Instruction::Shr(register_a, register_b, target_register) => {
+                self.shr(*register_a, *register_b, *target_register)
+            }
+            Instruction::Sar(register_a, register_b, target_register) => {
+                self.sar(*register_a, *register_b, *target_register)
+            }
+            Instruction::Not(src_register, target_register) => {
+                self.not(*src_register, *target_register)
+            }
+            Instruction::AddImm(register_a, immediate, target_register) => {
+                self.add_imm(*register_a, *immediate, *target_register)
+            }
+            Instruction::SubImm(register_a, immediate, target_register) => {
+                self.sub_imm(*register_a, *immediate, *target_register)
+            }
+            Instruction::MulImm(register_a, immediate, target_register) => {
+                self.mul_imm(*register_a, *immediate, *target_register)
+            }
+            Instruction::EqImm(register_a, immediate, target_register) => {
+                self.eq_imm(*register_a, *immediate, *target_register)
+            }
+            Instruction::NeqImm(register_a, immediate, target_register) => {
+                self.neq_imm(*register_a, *immediate, *target_register)
+            }
+            Instruction::GtImm(register_a, immediate, target_register) => {
+                self.gt_imm(*register_a, *immediate, *target_register)
+            }
+            Instruction::LtImm(register_a, immediate, target_register) => {
+                self.lt_imm(*register_a, *immediate, *target_register)
+            }
+            Instruction::GteImm(register_a, immediate, target_register) => {
+                self.gte_imm(*register_a, *immediate, *target_register)
+            }
+            Instruction::LteImm(register_a, immediate, target_register) => {
+                self.lte_imm(*register_a, *immediate, *target_register)
+            }
+            Instruction::StoreIndirect(address_register, offset_register, register_index) => {
+                self.store_indirect(*address_register, *offset_register, *register_index)
+            }
+            Instruction::LoadIndirect(address_register, offset_register, register_index) => {
+                self.load_indirect(*address_register, *offset_register, *register_index)
+            }
+            Instruction::Store8(address_register, offset_register, register_index) => {
+                self.store_sized(*address_register, *offset_register, *register_index, 1)
+            }
+            Instruction::Store16(address_register, offset_register, register_index) => {
+                self.store_sized(*address_register, *offset_register, *register_index, 2)
+            }
+            Instruction::Store32(address_register, offset_register, register_index) => {
+                self.store_sized(*address_register, *offset_register, *register_index, 4)
+            }
+            Instruction::Load8S(address_register, offset_register, register_index) => self
+                .load_sized(
+                    *address_register,
+                    *offset_register,
+                    *register_index,
+                    1,
+                    true,
+                ),
+            Instruction::Load8U(address_register, offset_register, register_index) => self
+                .load_sized(
+                    *address_register,
+                    *offset_register,
+                    *register_index,
+                    1,
+                    false,
+                ),
+            Instruction::Load16S(address_register, offset_register, register_index) => self
+                .load_sized(
+                    *address_register,
+                    *offset_register,
+                    *register_index,
+                    2,
+                    true,
+                ),
+            Instruction::Load16U(address_register, offset_register, register_index) => self
+                .load_sized(
+                    *address_register,
+                    *offset_register,
+                    *register_index,
+                    2,
+                    false,
+                ),
+            Instruction::Load32(address_register, offset_register, register_index) => self
+                .load_sized(
+                    *address_register,
+                    *offset_register,
+                    *register_index,
+                    4,
+                    true,
+                ),
+            Instruction::ReadInt(register_index) => self.read_int_input(*register_index),
+            Instruction::ReadByte(register_index) => self.read_byte_input(*register_index),
+            Instruction::PrintStr(address_register, len_register) => {
+                self.print_str(*address_register, *len_register)
+            }
+            Instruction::SetFReg(register_index, value) => {
+                self.set_float_register(*register_index, *value)
+            }
+            Instruction::FCmp(register_a, register_b, target_register) => {
+                self.float_compare(*register_a, *register_b, *target_register)
+            }
+            Instruction::Push(register_index) => self.push(*register_index),
+            Instruction::Pop(register_index) => self.pop(*register_index),
+            Instruction::Dup => self.dup(),
+            Instruction::SwapTop => self.swap_top(),
+            Instruction::Enter(local_count) => self.enter(*local_count),
+            Instruction::Leave => self.leave(),
+            Instruction::LoadLocal(slot, register_index) => self.load_local(*slot, *register_index),
+            Instruction::StoreLocal(register_index, slot) => {
+                self.store_local(*register_index, *slot)
+            }
+            Instruction::CallReg(register_index) => self.call_register(*register_index),
+            Instruction::CallTable(register_index) => self.call_table(*register_index),
+            Instruction::JumpTable(register_index, ip_offsets, default_offset) => {
+                // An index outside the table takes the default offset.
+                let ip_offset = self.read_int(*register_index).map(|index| {
+                    usize::try_from(index)
+                        .ok()
+                        .and_then(|index| ip_offsets.get(index))
+                        .copied()
+                        .unwrap_or(*default_offset)
+                });
+                ip_offset.and_then(|ip_offset| self.jump(ip_offset))
+            }
+            Instruction::Brk(code) => Err(VmError::Break(*code)),
+            Instruction::Nop => Ok(()),
+            Instruction::Mov(source, target_register) => self.mov(*source, *target_register),
+            Instruction::Neg(src, target_register) => self.neg(*src, *target_register),
+            Instruction::Abs(src, target_register) => self.abs(*src, *target_register),
+            Instruction::Min(register_a, register_b, target_register) => {
+                self.min(*register_a, *register_b, *target_register)
+            }
+            Instruction::Max(register_a, register_b, target_register) => {
+                self.max(*register_a, *register_b, *target_register)
+            }
+            Instruction::Popcount(src, target_register) => self.popcount(*src, *target_register),
+            Instruction::Clz(src, target_register) => self.clz(*src, *target_register),
+            Instruction::Ctz(src, target_register) => self.ctz(*src, *target_register),
+            Instruction::Cmp(register_a, register_b) => self.cmp(*register_a, *register_b),
+            Instruction::CmpImm(register_a, immediate) => self.cmp_imm(*register_a, *immediate),
+            Instruction::Jz(ip_offset) => self.jump_if(self.flags.zero, *ip_offset),
+            Instruction::Jnz(ip_offset) => self.jump_if(!self.flags.zero, *ip_offset),
+            Instruction::Jc(ip_offset) => self.jump_if(self.flags.carry, *ip_offset),
+            Instruction::Jo(ip_offset) => self.jump_if(self.flags.overflow, *ip_offset),
+            Instruction::Jlt(ip_offset) => self.jump_if(self.flags.less(), *ip_offset),
+            Instruction::Jge(ip_offset) => self.jump_if(!self.flags.less(), *ip_offset),
+            Instruction::Adc(register_a, register_b, target_register) => {
+                self.adc(*register_a, *register_b, *target_register)
+            }
+            Instruction::Sbb(register_a, register_b, target_register) => {
+                self.sbb(*register_a, *register_b, *target_register)
+            }
+            Instruction::MulWide(register_a, register_b, high_register, low_register) => {
+                self.mul_wide(*register_a, *register_b, *high_register, *low_register)
+            }
+            Instruction::DivU(register_a, register_b, target_register) => {
+                self.div_u(*register_a, *register_b, *target_register)
+            }
+            Instruction::ModU(register_a, register_b, target_register) => {
+                self.mod_u(*register_a, *register_b, *target_register)
+            }
+            Instruction::LtU(register_a, register_b, target_register) => {
+                self.lt_u(*register_a, *register_b, *target_register)
+            }
+            Instruction::GtU(register_a, register_b, target_register) => {
+                self.gt_u(*register_a, *register_b, *target_register)
+            }
+            Instruction::LteU(register_a, register_b, target_register) => {
+                self.lte_u(*register_a, *register_b, *target_register)
+            }
+            Instruction::GteU(register_a, register_b, target_register) => {
+                self.gte_u(*register_a, *register_b, *target_register)
+            }
+            Instruction::SextByte(src, target_register) => self.sext_byte(*src, *target_register),
+            Instruction::SextHalf(src, target_register) => self.sext_half(*src, *target_register),
+            Instruction::ZextByte(src, target_register) => self.zext_byte(*src, *target_register),
+            Instruction::ZextHalf(src, target_register) => self.zext_half(*src, *target_register),
+            Instruction::Trunc(src, bits, target_register) => {
+                self.trunc(*src, *bits, *target_register)
+            }
+            Instruction::FloatTrunc(src, target_register) => {
+                self.float_trunc(*src, *target_register)
+            }
+            Instruction::FloatRound(src, target_register) => {
+                self.float_round(*src, *target_register)
+            }
+            Instruction::FloatFloor(src, target_register) => {
+                self.float_floor(*src, *target_register)
+            }
+            Instruction::FloatCeil(src, target_register) => self.float_ceil(*src, *target_register),
+            Instruction::VLoad(address_register, offset_register, vector) => {
+                self.vload(*address_register, *offset_register, *vector)
+            }
+            Instruction::VStore(address_register, offset_register, vector) => {
+                self.vstore(*address_register, *offset_register, *vector)
+            }
+            Instruction::VAdd4x32(vector_a, vector_b, target_vector) => self.vector_op(
+                *vector_a,
+                *vector_b,
+                *target_vector,
+                i32::wrapping_add,
+                "VAdd4x32",
+            ),
+            Instruction::VMul4x32(vector_a, vector_b, target_vector) => self.vector_op(
+                *vector_a,
+                *vector_b,
+                *target_vector,
+                i32::wrapping_mul,
+                "VMul4x32",
+            ),
+            Instruction::VExtract(vector, lane, target_register) => {
+                self.vextract(*vector, *lane, *target_register)
+            }
+            Instruction::VInsert(src, lane, vector) => self.vinsert(*src, *lane, *vector),
+            Instruction::VSplat(src, vector) => self.vsplat(*src, *vector),
+            Instruction::MemCopyReg(dst_register, src_register, len_register) => {
+                self.mem_copy_register(*dst_register, *src_register, *len_register)
+            }
+            Instruction::MemFillReg(address_register, value_register, len_register) => {
+                self.mem_fill_register(*address_register, *value_register, *len_register)
+            }
+            Instruction::ReallocMemory(address_register, size_register, target_register) => {
+                self.realloc_memory(*address_register, *size_register, *target_register)
+            }
+            Instruction::ProtectMemory(address_register, flags) => {
+                self.protect_memory(*address_register, *flags)
+            }
+            Instruction::LoadGlobal(global, register_index) => {
+                self.load_global(*global, *register_index)
+            }
+            Instruction::StoreGlobal(register_index, global) => {
+                self.store_global(*register_index, *global)
+            }
+            Instruction::PushSp(register_index) => self.push_sp(*register_index),
+            Instruction::PopSp(register_index) => self.pop_sp(*register_index),
+            Instruction::LoadSpOffset(offset, register_index) => {
+                self.load_sp_offset(*offset, *register_index)
+            }
+            Instruction::StoreSpOffset(register_index, offset) => {
+                self.store_sp_offset(*register_index, *offset)
+            }
+            Instruction::NewObject(size, register_index) => self.new_object(*size, *register_index),
+            Instruction::LoadSlot(object_register, slot, register_index) => {
+                self.load_slot(*object_register, *slot, *register_index)
+            }
+            Instruction::StoreSlot(register_index, object_register, slot) => {
+                self.store_slot(*register_index, *object_register, *slot)
+            }
+            Instruction::Retain(register_index) => self.retain(*register_index),
+            Instruction::Release(register_index) => self.release(*register_index),
+            Instruction::NewArray(length_register, register_index) => {
+                self.new_array(*length_register, *register_index)
+            }
+            Instruction::ArrayGet(array_register, index_register, register_index) => {
+                self.array_get(*array_register, *index_register, *register_index)
+            }
+            Instruction::ArraySet(array_register, index_register, register_index) => {
+                self.array_set(*array_register, *index_register, *register_index)
+            }
+            Instruction::ArrayLen(array_register, register_index) => {
+                self.array_len(*array_register, *register_index)
+            }
+            Instruction::NewString(address_register, len_register, register_index) => {
+                self.new_string(*address_register, *len_register, *register_index)
+            }
+            Instruction::StrConcat(first_register, second_register, register_index) => {
+                self.str_concat(*first_register, *second_register, *register_index)
+            }
+            Instruction::StrLen(string_register, register_index) => {
+                self.str_len(*string_register, *register_index)
+            }
+            Instruction::StrByte(string_register, index_register, register_index) => {
+                self.str_byte(*string_register, *index_register, *register_index)
+            }
+            Instruction::StrCmp(first_register, second_register, register_index) => {
+                self.str_cmp(*first_register, *second_register, *register_index)
+            }
+            Instruction::PrintString(string_register) => self.print_string(*string_register),
+            Instruction::MapNew(register_index) => self.map_new(*register_index),
+            Instruction::MapGet(map_register, key_register, register_index) => {
+                self.map_get(*map_register, *key_register, *register_index)
+            }
+            Instruction::MapSet(map_register, key_register, register_index) => {
+                self.map_set(*map_register, *key_register, *register_index)
+            }
+            Instruction::MapDel(map_register, key_register) => {
+                self.map_del(*map_register, *key_register)
+            }
+            Instruction::MapHas(map_register, key_register, register_index) => {
+                self.map_has(*map_register, *key_register, *register_index)
+            }
+            Instruction::TypeOf(source, register_index) => self.type_of(*source, *register_index),
+            Instruction::TryBegin(ip_offset) => self.try_begin(*ip_offset),
+            Instruction::TryEnd => self.try_end(),
+            Instruction::Throw(register_index) => self.throw(*register_index),
+        };
+
+        if let (Some(profiler), Some(started)) = (&mut self.profiler, started) {
+            profiler.record(ip, &self.program[ip], started.elapsed());
+        }
+        if let Some(trace) = &mut self.json_trace {
+            trace.write_line(ip, &self.program[ip], result.as_ref().err());
+        }
+        if let Err(error) = result {
+            // Without a handler there is nothing sensible to skip to, so an
+            // unknown syscall stops the program even in lenient mode, and a
+            // `Brk` exists to stop it.
+            if self.strict || matches!(error, VmError::UnknownSyscall(_) | VmError::Break(_)) {
+                vm_log!(error, "Trapped at instruction {}: {}", self.ip - 1, error);
+                self.state = VmState::Trapped;
+                self.trap = Some(Trap {
+                    ip,
+                    error: error.clone(),
+                    backtrace: self.backtrace(ip),
+                });
+                return Err(error);
+            }
+            vm_log!(warn, "Skipped instruction {}: {}", self.ip - 1, error);
+        }
+        let state = self.state();
+        if self.leak_report && matches!(state, VmState::Halted | VmState::EndOfProgram) {
+            let mut report = self.memory_stats.to_string();
+            for cycle in self.memory.borrow().reference_cycles() {
+                let regions: Vec<_> = cycle
+                    .iter()
+                    .map(|address| format!("0x{:04x}", address))
+                    .collect();
+                report += &format!("Reference cycle, never freed: {}\n", regions.join(", "));
+            }
+            if let Err(error) = self.output.write_all(report.as_bytes()) {
+                vm_log!(error, "Failed to write the leak report: {}", error);
+            }
+        }
+        Ok(state)
+    }
+
+    /// The state the last step left the VM in.
+    pub fn state(&self) -> VmState {
+        if self.state != VmState::Running {
+            return self.state;
+        }
+        match (self.program.get(self.ip), self.fuel) {
+            (None, _) => VmState::EndOfProgram,
+            (Some(instruction), Some(fuel)) if fuel < instruction.cost() => VmState::OutOfFuel,
+            _ => VmState::Running,
+        }
+    }
+
+    /// The error that trapped the VM and the address of the faulting
+    /// instruction, while the state is `VmState::Trapped`.
+    pub fn trap(&self) -> Option<&Trap> {
+        self.trap.as_ref()
+    }
+
+    /// The vector registers, lane 0 first.
+    pub fn vectors(&self) -> &[[i32; 4]] {
+        &self.vectors
+    }
+
+    /// The condition flags, as the last flag-setting instruction left them.
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    fn set_reg(&mut self, register_index: usize, value: i32) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(value),
+        });
+        vm_log!(trace, "Set register {} to value {}", register_index, value);
+        Ok(())
+    }
+
+    fn clear_regs(&mut self) -> Result<(), VmError> {
+        for register_index in 0..self.registers.len() {
+            self.apply(Effect::RegisterWrite {
+                register: register_index,
+                value: self.cleared_value(),
+            });
+        }
+        vm_log!(trace, "Cleared all registers");
+        Ok(())
+    }
+
+    fn clear_reg(&mut self, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: self.cleared_value(),
+        });
+        vm_log!(trace, "Cleared register {}", register_index);
+        Ok(())
+    }
+
+    // What a cleared register holds: zero, or nil in tagged mode.
+    fn cleared_value(&self) -> Value {
+        match self.register_mode {
+            RegisterMode::Raw => Value::Int(0),
+            RegisterMode::Tagged => Value::Nil,
+        }
+    }
+
+    fn mov(&mut self, source: usize, target_register: usize) -> Result<(), VmError> {
+        self.check_register(source)?;
+        self.check_register(target_register)?;
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: self.registers[source],
+        });
+        vm_log!(
+            trace,
+            "Copied register {} to register {}",
+            source,
+            target_register
+        );
+        Ok(())
+    }
+
+    fn set_reg_shifted(
+        &mut self,
+        register_index: usize,
+        byte: u8,
+        shift: u8,
+    ) -> Result<(), VmError> {
+        match (byte as u32).checked_shl(shift as u32) {
+            Some(bits) if bits >> shift == byte as u32 => self.set_reg(register_index, bits as i32),
+            _ => Err(VmError::InvalidShift(shift)),
+        }
+    }
+
+    fn add(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        let mode = self.arithmetic_mode;
+        if self.register_mode == RegisterMode::Tagged {
+            return self.tagged_binary_op(
+                reg_a,
+                reg_b,
+                target_register,
+                |a, b| {
+                    mode.apply(
+                        a,
+                        b,
+                        i64::wrapping_add,
+                        i64::saturating_add,
+                        i64::checked_add,
+                    )
+                },
+                |a, b| a + b,
+                "Add",
+            );
+        }
+        self.try_binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| {
+                mode.apply(
+                    a,
+                    b,
+                    i32::wrapping_add,
+                    i32::saturating_add,
+                    i32::checked_add,
+                )
+            },
+            Some(Flags::add),
+            "Add",
+        )
+    }
+
+    fn sub(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        let mode = self.arithmetic_mode;
+        if self.register_mode == RegisterMode::Tagged {
+            return self.tagged_binary_op(
+                reg_a,
+                reg_b,
+                target_register,
+                |a, b| {
+                    mode.apply(
+                        a,
+                        b,
+                        i64::wrapping_sub,
+                        i64::saturating_sub,
+                        i64::checked_sub,
+                    )
+                },
+                |a, b| a - b,
+                "Sub",
+            );
+        }
+        self.try_binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| {
+                mode.apply(
+                    a,
+                    b,
+                    i32::wrapping_sub,
+                    i32::saturating_sub,
+                    i32::checked_sub,
+                )
+            },
+            Some(Flags::sub),
+            "Sub",
+        )
+    }
+
+    fn mul(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        let mode = self.arithmetic_mode;
+        if self.register_mode == RegisterMode::Tagged {
+            return self.tagged_binary_op(
+                reg_a,
+                reg_b,
+                target_register,
+                |a, b| {
+                    mode.apply(
+                        a,
+                        b,
+                        i64::wrapping_mul,
+                        i64::saturating_mul,
+                        i64::checked_mul,
+                    )
+                },
+                |a, b| a * b,
+                "Mul",
+            );
+        }
+        self.try_binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| {
+                mode.apply(
+                    a,
+                    b,
+                    i32::wrapping_mul,
+                    i32::saturating_mul,
+                    i32::checked_mul,
+                )
+            },
+            Some(Flags::mul),
+            "Mul",
+        )
+    }
+
+    // Multi-precision arithmetic: the carry flag carries in, and the flags are
+    // set like `Add` and `Sub` set them. Always wrapping, whatever the mode.
+    fn adc(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.carry_op(reg_a, reg_b, target_register, Flags::add_with_carry, "Adc")
+    }
+
+    fn sbb(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.carry_op(reg_a, reg_b, target_register, Flags::sub_with_borrow, "Sbb")
+    }
+
+    fn carry_op(
+        &mut self,
+        reg_a: usize,
+        reg_b: usize,
+        target_register: usize,
+        op: fn(i32, i32, bool) -> (i32, Flags),
+        op_name: &str,
+    ) -> Result<(), VmError> {
+        self.check_register(target_register)?;
+        let a = self.read_int(reg_a)?;
+        let b = self.read_int(reg_b)?;
+        let carry = self.flags.carry;
+        let (result, flags) = op(a, b, carry);
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Int(result),
+        });
+        self.flags = flags;
+        vm_log!(
+            trace,
+            "{}: {} and {} with carry {} -> {} (stored in register {})",
+            op_name,
+            a,
+            b,
+            carry,
+            result,
+            target_register
+        );
+        Ok(())
+    }
+
+    // The registers are multiplied as unsigned, like the limbs of a
+    // multi-precision number. The low word is written last, so it wins when
+    // both targets are the same register.
+    fn mul_wide(
+        &mut self,
+        reg_a: usize,
+        reg_b: usize,
+        high_register: usize,
+        low_register: usize,
+    ) -> Result<(), VmError> {
+        self.check_register(high_register)?;
+        self.check_register(low_register)?;
+        let a = self.read_int(reg_a)?;
+        let b = self.read_int(reg_b)?;
+        let product = a as u32 as u64 * b as u32 as u64;
+        self.apply(Effect::RegisterWrite {
+            register: high_register,
+            value: Value::Int((product >> 32) as i32),
+        });
+        self.apply(Effect::RegisterWrite {
+            register: low_register,
+            value: Value::Int(product as i32),
+        });
+        vm_log!(
+            trace,
+            "MulWide: {} and {} -> {} (stored in registers {} and {})",
+            a as u32,
+            b as u32,
+            product,
+            high_register,
+            low_register
+        );
+        Ok(())
+    }
+
+    fn div(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        let mode = self.arithmetic_mode;
+        if self.register_mode == RegisterMode::Tagged {
+            return self.tagged_binary_op(
+                reg_a,
+                reg_b,
+                target_register,
+                |a, b| match b {
+                    0 => Err(VmError::DivisionByZero),
+                    _ => mode.apply(
+                        a,
+                        b,
+                        i64::wrapping_div,
+                        i64::saturating_div,
+                        i64::checked_div,
+                    ),
+                },
+                |a, b| a / b,
+                "Div",
+            );
+        }
+        self.try_binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| match b {
+                0 => Err(VmError::DivisionByZero),
+                _ => mode.apply(
+                    a,
+                    b,
+                    i32::wrapping_div,
+                    i32::saturating_div,
+                    i32::checked_div,
+                ),
+            },
+            None,
+            "Div",
+        )
+    }
+
+    // The unsigned forms read the registers as `u32`. The quotient and
+    // remainder always fit, so the arithmetic mode doesn't apply.
+    fn div_u(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.try_binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| match b {
+                0 => Err(VmError::DivisionByZero),
+                _ => Ok((a as u32 / b as u32) as i32),
+            },
+            None,
+            "DivU",
+        )
+    }
+
+    fn mod_u(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.try_binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| match b {
+                0 => Err(VmError::DivisionByZero),
+                _ => Ok((a as u32 % b as u32) as i32),
+            },
+            None,
+            "ModU",
+        )
+    }
+
+    fn mod_op(
+        &mut self,
+        reg_a: usize,
+        reg_b: usize,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        // `i32::MIN % -1` is 0 in every mode, the result always fits
+        if self.register_mode == RegisterMode::Tagged {
+            return self.tagged_binary_op(
+                reg_a,
+                reg_b,
+                target_register,
+                |a, b| match b {
+                    0 => Err(VmError::DivisionByZero),
+                    _ => Ok(a.wrapping_rem(b)),
+                },
+                |a, b| a % b,
+                "Mod",
+            );
+        }
+        self.try_binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| match b {
+                0 => Err(VmError::DivisionByZero),
+                _ => Ok(a.wrapping_rem(b)),
+            },
+            None,
+            "Mod",
+        )
+    }
+
+    // Rotations work on the `u32` bit pattern and take the count modulo 32.
+    fn rol(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| (a as u32).rotate_left(b as u32 % 32) as i32,
+            "Rol",
+        )
+    }
+
+    fn ror(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| (a as u32).rotate_right(b as u32 % 32) as i32,
+            "Ror",
+        )
+    }
+
+    fn and(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.binary_op(reg_a, reg_b, target_register, |a, b| a & b, "And")
+    }
+
+    fn or(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.binary_op(reg_a, reg_b, target_register, |a, b| a | b, "Or")
+    }
+
+    fn xor(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.binary_op(reg_a, reg_b, target_register, |a, b| a ^ b, "Xor")
+    }
+
+    fn not(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.try_unary_op(src_register, target_register, |value| Ok(!value), "Not")
+    }
+
+    fn neg(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        let mode = self.arithmetic_mode;
+        self.try_unary_op(
+            src_register,
+            target_register,
+            |value| {
+                mode.apply(
+                    0,
+                    value,
+                    i32::wrapping_sub,
+                    i32::saturating_sub,
+                    i32::checked_sub,
+                )
+            },
+            "Neg",
+        )
+    }
+
+    // `i32::MIN` has no positive counterpart, so it overflows like `Neg`.
+    fn abs(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        let mode = self.arithmetic_mode;
+        self.try_unary_op(
+            src_register,
+            target_register,
+            |value| {
+                mode.apply(
+                    value,
+                    0,
+                    |a, _| a.wrapping_abs(),
+                    |a, _| a.saturating_abs(),
+                    |a, _| a.checked_abs(),
+                )
+            },
+            "Abs",
+        )
+    }
+
+    // Bit counts work on the `u32` bit pattern, so a zero register has 32
+    // leading and 32 trailing zeros.
+    fn popcount(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.try_unary_op(
+            src_register,
+            target_register,
+            |value| Ok(value.count_ones() as i32),
+            "Popcount",
+        )
+    }
+
+    fn clz(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.try_unary_op(
+            src_register,
+            target_register,
+            |value| Ok(value.leading_zeros() as i32),
+            "Clz",
+        )
+    }
+
+    fn ctz(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.try_unary_op(
+            src_register,
+            target_register,
+            |value| Ok(value.trailing_zeros() as i32),
+            "Ctz",
+        )
+    }
+
+    fn min(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.binary_op(reg_a, reg_b, target_register, i32::min, "Min")
+    }
+
+    fn max(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.binary_op(reg_a, reg_b, target_register, i32::max, "Max")
+    }
+
+    // An integer operation on one register. The target register is left
+    // untouched on error.
+    fn sext_byte(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.try_unary_op(
+            src_register,
+            target_register,
+            |value| Ok(value as i8 as i32),
+            "SextByte",
+        )
+    }
+
+    fn sext_half(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.try_unary_op(
+            src_register,
+            target_register,
+            |value| Ok(value as i16 as i32),
+            "SextHalf",
+        )
+    }
+
+    fn zext_byte(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.try_unary_op(
+            src_register,
+            target_register,
+            |value| Ok(value as u8 as i32),
+            "ZextByte",
+        )
+    }
+
+    fn zext_half(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.try_unary_op(
+            src_register,
+            target_register,
+            |value| Ok(value as u16 as i32),
+            "ZextHalf",
+        )
+    }
+
+    fn trunc(
+        &mut self,
+        src_register: usize,
+        bits: u8,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        if !(1..=32).contains(&bits) {
+            return Err(VmError::InvalidWidth(bits));
+        }
+        let mask = u32::MAX >> (32 - bits);
+        self.try_unary_op(
+            src_register,
+            target_register,
+            |value| Ok((value as u32 & mask) as i32),
+            "Trunc",
+        )
+    }
+
+    fn try_unary_op<F>(
+        &mut self,
+        src_register: usize,
+        target_register: usize,
+        op: F,
+        op_name: &str,
+    ) -> Result<(), VmError>
+    where
+        F: Fn(i32) -> Result<i32, VmError>,
+    {
+        self.check_register(target_register)?;
+        let value = self.read_int(src_register)?;
+        let result = op(value)?;
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Int(result),
+        });
+        vm_log!(
+            trace,
+            "{}: {} -> {} (stored in register {})",
+            op_name,
+            value,
+            result,
+            target_register
+        );
+        Ok(())
+    }
+
+    // Shift counts are taken modulo 32, like the rotation counts.
+    fn shl(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| ((a as u32) << (b as u32 % 32)) as i32,
+            "Shl",
+        )
+    }
+
+    fn shr(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| ((a as u32) >> (b as u32 % 32)) as i32,
+            "Shr",
+        )
+    }
+
+    fn sar(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| a >> (b as u32 % 32),
+            "Sar",
+        )
+    }
+
+    // Sets the flags as `Sub` would, without storing the difference.
+    fn cmp(&mut self, reg_a: usize, reg_b: usize) -> Result<(), VmError> {
+        let a = self.read_int(reg_a)?;
+        let b = self.read_int(reg_b)?;
+        self.flags = Flags::sub(a, b);
+        vm_log!(trace, "Cmp: {} and {} -> {:?}", a, b, self.flags);
+        Ok(())
+    }
+
+    fn cmp_imm(&mut self, reg_a: usize, immediate: i32) -> Result<(), VmError> {
+        let a = self.read_int(reg_a)?;
+        self.flags = Flags::sub(a, immediate);
+        vm_log!(trace, "CmpImm: {} and {} -> {:?}", a, immediate, self.flags);
+        Ok(())
+    }
+
+    fn binary_op<F>(
+        &mut self,
+        reg_a: usize,
+        reg_b: usize,
+        target_register: usize,
+        op: F,
+        op_name: &str,
+    ) -> Result<(), VmError>
+    where
+        F: Fn(i32, i32) -> i32,
+    {
+        self.try_binary_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| Ok(op(a, b)),
+            None,
+            op_name,
+        )
+    }
+
+    // Like `binary_op`, for operations that can fail. The target register and
+    // the flags are left untouched on error, otherwise `flags` sets the flags
+    // from the operands.
+    fn try_binary_op<F>(
+        &mut self,
+        reg_a: usize,
+        reg_b: usize,
+        target_register: usize,
+        op: F,
+        flags: Option<fn(i32, i32) -> Flags>,
+        op_name: &str,
+    ) -> Result<(), VmError>
+    where
+        F: Fn(i32, i32) -> Result<i32, VmError>,
+    {
+        self.check_register(target_register)?;
+        let a = self.read_int(reg_a)?;
+        let b = self.read_int(reg_b)?;
+        let result = op(a, b)?;
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Int(result),
+        });
+        if let Some(flags) = flags {
+            self.flags = flags(a, b);
+        }
+        vm_log!(
+            trace,
+            "{}: {} and {} -> {} (stored in register {})",
+            op_name,
+            a,
+            b,
+            result,
+            target_register
+        );
+        Ok(())
+    }
+
+    fn eq(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        if self.register_mode == RegisterMode::Tagged {
+            return self.tagged_compare_op(
+                reg_a,
+                reg_b,
+                target_register,
+                false,
+                |ordering| ordering == Some(Ordering::Equal),
+                "Eq",
+            );
+        }
+        self.compare_op(reg_a, reg_b, target_register, |a, b| a == b, "Eq")
+    }
+
+    fn neq(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        if self.register_mode == RegisterMode::Tagged {
+            return self.tagged_compare_op(
+                reg_a,
+                reg_b,
+                target_register,
+                false,
+                |ordering| ordering != Some(Ordering::Equal),
+                "Neq",
+            );
+        }
+        self.compare_op(reg_a, reg_b, target_register, |a, b| a != b, "Neq")
+    }
+
+    fn gt(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        if self.register_mode == RegisterMode::Tagged {
+            return self.tagged_compare_op(
+                reg_a,
+                reg_b,
+                target_register,
+                true,
+                |ordering| ordering == Some(Ordering::Greater),
+                "Gt",
+            );
+        }
+        self.compare_op(reg_a, reg_b, target_register, |a, b| a > b, "Gt")
+    }
+
+    fn lt(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        if self.register_mode == RegisterMode::Tagged {
+            return self.tagged_compare_op(
+                reg_a,
+                reg_b,
+                target_register,
+                true,
+                |ordering| ordering == Some(Ordering::Less),
+                "Lt",
+            );
+        }
+        self.compare_op(reg_a, reg_b, target_register, |a, b| a < b, "Lt")
+    }
+
+    fn gte(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        if self.register_mode == RegisterMode::Tagged {
+            return self.tagged_compare_op(
+                reg_a,
+                reg_b,
+                target_register,
+                true,
+                |ordering| matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+                "Gte",
+            );
+        }
+        self.compare_op(reg_a, reg_b, target_register, |a, b| a >= b, "Gte")
+    }
+
+    fn lte(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        if self.register_mode == RegisterMode::Tagged {
+            return self.tagged_compare_op(
+                reg_a,
+                reg_b,
+                target_register,
+                true,
+                |ordering| matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                "Lte",
+            );
+        }
+        self.compare_op(reg_a, reg_b, target_register, |a, b| a <= b, "Lte")
+    }
+
+    fn lt_u(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.compare_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| (a as u32) < (b as u32),
+            "LtU",
+        )
+    }
+
+    fn gt_u(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.compare_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| a as u32 > b as u32,
+            "GtU",
+        )
+    }
+
+    fn lte_u(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.compare_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| a as u32 <= b as u32,
+            "LteU",
+        )
+    }
+
+    fn gte_u(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.compare_op(
+            reg_a,
+            reg_b,
+            target_register,
+            |a, b| a as u32 >= b as u32,
+            "GteU",
+        )
+    }
+
+    fn compare_op<F>(
+        &mut self,
+        reg_a: usize,
+        reg_b: usize,
+        target_register: usize,
+        op: F,
+        op_name: &str,
+    ) -> Result<(), VmError>
+    where
+        F: Fn(i32, i32) -> bool,
+    {
+        self.check_register(target_register)?;
+        let a = self.read_int(reg_a)?;
+        let b = self.read_int(reg_b)?;
+        let result = op(a, b);
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Int(if result { 1 } else { 0 }),
+        });
+        vm_log!(
+            trace,
+            "{}: {} and {} -> {} (stored in register {})",
+            op_name,
+            a,
+            b,
+            if result { 1 } else { 0 },
+            target_register
+        );
+        Ok(())
+    }
+
+    // The operands of an arithmetic or comparison in tagged mode, with any
+    // `Value::Int` left in a register by the host read as the 64-bit integer.
+    fn read_tagged(&self, register_index: usize) -> Result<Value, VmError> {
+        match self.registers.get(register_index) {
+            Some(Value::Int(value)) => Ok(Value::Int64((*value).into())),
+            Some(value) => Ok(*value),
+            None => Err(VmError::InvalidRegister(register_index)),
+        }
+    }
+
+    // Arithmetic in tagged mode, on two integers or two floats.
+    fn tagged_binary_op(
+        &mut self,
+        reg_a: usize,
+        reg_b: usize,
+        target_register: usize,
+        int_op: impl Fn(i64, i64) -> Result<i64, VmError>,
+        float_op: fn(f64, f64) -> f64,
+        op_name: &str,
+    ) -> Result<(), VmError> {
+        self.check_register(target_register)?;
+        let a = self.read_tagged(reg_a)?;
+        let b = self.read_tagged(reg_b)?;
+        let result = match (a, b) {
+            (Value::Int64(a), Value::Int64(b)) => Value::Int64(int_op(a, b)?),
+            (Value::Float(a), Value::Float(b)) => Value::Float(float_op(a, b)),
+            (Value::Int64(_) | Value::Float(_), _) => return Err(VmError::TypeMismatch(reg_b)),
+            _ => return Err(VmError::TypeMismatch(reg_a)),
+        };
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: result,
+        });
+        vm_log!(
+            trace,
+            "{}: {} and {} -> {} (stored in register {})",
+            op_name,
+            a,
+            b,
+            result,
+            target_register
+        );
+        Ok(())
+    }
+
+    // A comparison in tagged mode. Integers and floats are ordered among
+    // themselves; unless `ordered`, any two values compare, by type and value.
+    fn tagged_compare_op(
+        &mut self,
+        reg_a: usize,
+        reg_b: usize,
+        target_register: usize,
+        ordered: bool,
+        op: fn(Option<Ordering>) -> bool,
+        op_name: &str,
+    ) -> Result<(), VmError> {
+        self.check_register(target_register)?;
+        let a = self.read_tagged(reg_a)?;
+        let b = self.read_tagged(reg_b)?;
+        let ordering = match (a, b) {
+            (Value::Int64(a), Value::Int64(b)) => Some(a.cmp(&b)),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(&b),
+            _ if !ordered => (a == b).then_some(Ordering::Equal),
+            (Value::Int64(_) | Value::Float(_), _) => return Err(VmError::TypeMismatch(reg_b)),
+            _ => return Err(VmError::TypeMismatch(reg_a)),
+        };
+        let result = op(ordering);
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Int64(result.into()),
+        });
+        vm_log!(
+            trace,
+            "{}: {} and {} -> {} (stored in register {})",
+            op_name,
+            a,
+            b,
+            result as i32,
+            target_register
+        );
+        Ok(())
+    }
+
+    fn add_imm(
+        &mut self,
+        reg_a: usize,
+        immediate: i32,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        let mode = self.arithmetic_mode;
+        self.binary_imm_op(
+            reg_a,
+            immediate,
+            target_register,
+            |a, b| {
+                mode.apply(
+                    a,
+                    b,
+                    i32::wrapping_add,
+                    i32::saturating_add,
+                    i32::checked_add,
+                )
+            },
+            Some(Flags::add),
+            "AddImm",
+        )
+    }
+
+    fn sub_imm(
+        &mut self,
+        reg_a: usize,
+        immediate: i32,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        let mode = self.arithmetic_mode;
+        self.binary_imm_op(
+            reg_a,
+            immediate,
+            target_register,
+            |a, b| {
+                mode.apply(
+                    a,
+                    b,
+                    i32::wrapping_sub,
+                    i32::saturating_sub,
+                    i32::checked_sub,
+                )
+            },
+            Some(Flags::sub),
+            "SubImm",
+        )
+    }
+
+    fn mul_imm(
+        &mut self,
+        reg_a: usize,
+        immediate: i32,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        let mode = self.arithmetic_mode;
+        self.binary_imm_op(
+            reg_a,
+            immediate,
+            target_register,
+            |a, b| {
+                mode.apply(
+                    a,
+                    b,
+                    i32::wrapping_mul,
+                    i32::saturating_mul,
+                    i32::checked_mul,
+                )
+            },
+            Some(Flags::mul),
+            "MulImm",
+        )
+    }
+
+    fn eq_imm(
+        &mut self,
+        reg_a: usize,
+        immediate: i32,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        self.compare_imm_op(reg_a, immediate, target_register, |a, b| a == b, "EqImm")
+    }
+
+    fn neq_imm(
+        &mut self,
+        reg_a: usize,
+        immediate: i32,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        self.compare_imm_op(reg_a, immediate, target_register, |a, b| a != b, "NeqImm")
+    }
+
+    fn gt_imm(
+        &mut self,
+        reg_a: usize,
+        immediate: i32,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        self.compare_imm_op(reg_a, immediate, target_register, |a, b| a > b, "GtImm")
+    }
+
+    fn lt_imm(
+        &mut self,
+        reg_a: usize,
+        immediate: i32,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        self.compare_imm_op(reg_a, immediate, target_register, |a, b| a < b, "LtImm")
+    }
+
+    fn gte_imm(
+        &mut self,
+        reg_a: usize,
+        immediate: i32,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        self.compare_imm_op(reg_a, immediate, target_register, |a, b| a >= b, "GteImm")
+    }
+
+    fn lte_imm(
+        &mut self,
+        reg_a: usize,
+        immediate: i32,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        self.compare_imm_op(reg_a, immediate, target_register, |a, b| a <= b, "LteImm")
+    }
+
+    fn binary_imm_op<F>(
+        &mut self,
+        reg_a: usize,
+        immediate: i32,
+        target_register: usize,
+        op: F,
+        flags: Option<fn(i32, i32) -> Flags>,
+        op_name: &str,
+    ) -> Result<(), VmError>
+    where
+        F: Fn(i32, i32) -> Result<i32, VmError>,
+    {
+        self.check_register(target_register)?;
+        let a = self.read_int(reg_a)?;
+        let result = op(a, immediate)?;
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Int(result),
+        });
+        if let Some(flags) = flags {
+            self.flags = flags(a, immediate);
+        }
+        vm_log!(
+            trace,
+            "{}: {} and {} -> {} (stored in register {})",
+            op_name,
+            a,
+            immediate,
+            result,
+            target_register
+        );
+        Ok(())
+    }
+
+    fn compare_imm_op<F>(
+        &mut self,
+        reg_a: usize,
+        immediate: i32,
+        target_register: usize,
+        op: F,
+        op_name: &str,
+    ) -> Result<(), VmError>
+    where
+        F: Fn(i32, i32) -> bool,
+    {
+        self.binary_imm_op(
+            reg_a,
+            immediate,
+            target_register,
+            |a, b| Ok(op(a, b) as i32),
+            None,
+            op_name,
+        )
+    }
+
+    fn fadd(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.float_binary_op(reg_a, reg_b, target_register, |a, b| a + b, "FAdd")
+    }
+
+    fn fsub(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.float_binary_op(reg_a, reg_b, target_register, |a, b| a - b, "FSub")
+    }
+
+    fn fmul(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.float_binary_op(reg_a, reg_b, target_register, |a, b| a * b, "FMul")
+    }
+
+    fn fdiv(&mut self, reg_a: usize, reg_b: usize, target_register: usize) -> Result<(), VmError> {
+        self.float_binary_op(reg_a, reg_b, target_register, |a, b| a / b, "FDiv")
+    }
+
+    fn float_binary_op<F>(
+        &mut self,
+        reg_a: usize,
+        reg_b: usize,
+        target_register: usize,
+        op: F,
+        op_name: &str,
+    ) -> Result<(), VmError>
+    where
+        F: Fn(f64, f64) -> f64,
+    {
+        self.check_register(target_register)?;
+        let a = self.read_float(reg_a)?;
+        let b = self.read_float(reg_b)?;
+        let result = op(a, b);
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Float(result),
+        });
+        vm_log!(
+            trace,
+            "{}: {} and {} -> {} (stored in register {})",
+            op_name,
+            a,
+            b,
+            result,
+            target_register
+        );
+        Ok(())
+    }
+
+    fn set_float_register(&mut self, register_index: usize, value: f64) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Float(value),
+        });
+        vm_log!(trace, "Set register {} to {}", register_index, value);
+        Ok(())
+    }
+
+    // NaN is unordered: it is neither less than, equal to nor greater than
+    // anything, itself included, so it gets a result of its own.
+    fn float_compare(
+        &mut self,
+        reg_a: usize,
+        reg_b: usize,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        self.check_register(target_register)?;
+        let a = self.read_float(reg_a)?;
+        let b = self.read_float(reg_b)?;
+        let result = match a.partial_cmp(&b) {
+            Some(Ordering::Less) => -1,
+            Some(Ordering::Equal) => 0,
+            Some(Ordering::Greater) => 1,
+            None => 2,
+        };
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Int(result),
+        });
+        vm_log!(
+            trace,
+            "FCmp: {} and {} -> {} (stored in register {})",
+            a,
+            b,
+            result,
+            target_register
+        );
+        Ok(())
+    }
+
+    fn float_trunc(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.float_unary_op(src_register, target_register, f64::trunc, "FloatTrunc")
+    }
+
+    fn float_round(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.float_unary_op(src_register, target_register, f64::round, "FloatRound")
+    }
+
+    fn float_floor(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.float_unary_op(src_register, target_register, f64::floor, "FloatFloor")
+    }
+
+    fn float_ceil(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.float_unary_op(src_register, target_register, f64::ceil, "FloatCeil")
+    }
+
+    // The result stays a float, use `FloatToInt` to get an integer. NaN and
+    // the infinities are left as they are.
+    fn float_unary_op(
+        &mut self,
+        src_register: usize,
+        target_register: usize,
+        op: fn(f64) -> f64,
+        op_name: &str,
+    ) -> Result<(), VmError> {
+        self.check_register(target_register)?;
+        let value = self.read_float(src_register)?;
+        let result = op(value);
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Float(result),
+        });
+        vm_log!(
+            trace,
+            "{}: {} -> {} (stored in register {})",
+            op_name,
+            value,
+            result,
+            target_register
+        );
+        Ok(())
+    }
+
+    fn read_vector(&self, vector: usize) -> Result<[i32; 4], VmError> {
+        self.vectors
+            .get(vector)
+            .copied()
+            .ok_or(VmError::InvalidVectorRegister(vector))
+    }
+
+    fn check_vector(&self, vector: usize) -> Result<(), VmError> {
+        self.read_vector(vector).map(|_| ())
+    }
+
+    fn check_lane(lane: u8) -> Result<usize, VmError> {
+        match lane {
+            0..=3 => Ok(lane as usize),
+            _ => Err(VmError::InvalidLane(lane)),
+        }
+    }
+
+    fn write_vector(&mut self, vector: usize, lanes: [i32; 4]) {
+        self.apply(Effect::VectorWrite {
+            register: vector,
+            lanes,
+        });
+    }
+
+    // Lanes are little-endian 32-bit words, lane 0 at the lowest address.
+    fn vload(
+        &mut self,
+        address_register: usize,
+        offset_register: usize,
+        vector: usize,
+    ) -> Result<(), VmError> {
+        self.check_vector(vector)?;
+        let absolute = self.indirect_address(address_register, offset_register)?;
+        let bytes = self.read_memory(absolute, 16)?;
+        let lanes = std::array::from_fn(|lane| {
+            i32::from_le_bytes(bytes[4 * lane..4 * lane + 4].try_into().unwrap())
+        });
+        self.write_vector(vector, lanes);
+        vm_log!(
+            debug,
+            "Loaded {:?} from memory address {} into vector register {}",
+            lanes,
+            absolute,
+            vector
+        );
+        Ok(())
+    }
+
+    fn vstore(
+        &mut self,
+        address_register: usize,
+        offset_register: usize,
+        vector: usize,
+    ) -> Result<(), VmError> {
+        let lanes = self.read_vector(vector)?;
+        let absolute = self.indirect_address(address_register, offset_register)?;
+        self.check_writable(absolute, 16)?;
+        self.apply(Effect::MemoryWriteBlock {
+            address: absolute,
+            bytes: lanes.iter().flat_map(|lane| lane.to_le_bytes()).collect(),
+        });
+        vm_log!(
+            debug,
+            "Stored {:?} from vector register {} at memory address {}",
+            lanes,
+            vector,
+            absolute
+        );
+        Ok(())
+    }
+
+    // Lane arithmetic always wraps, like packed integer arithmetic on the host.
+    fn vector_op(
+        &mut self,
+        vector_a: usize,
+        vector_b: usize,
+        target_vector: usize,
+        op: fn(i32, i32) -> i32,
+        op_name: &str,
+    ) -> Result<(), VmError> {
+        self.check_vector(target_vector)?;
+        let a = self.read_vector(vector_a)?;
+        let b = self.read_vector(vector_b)?;
+        let result = std::array::from_fn(|lane| op(a[lane], b[lane]));
+        self.write_vector(target_vector, result);
+        vm_log!(
+            trace,
+            "{}: {:?} and {:?} -> {:?} (stored in vector register {})",
+            op_name,
+            a,
+            b,
+            result,
+            target_vector
+        );
+        Ok(())
+    }
+
+    fn vextract(&mut self, vector: usize, lane: u8, target_register: usize) -> Result<(), VmError> {
+        self.check_register(target_register)?;
+        let lanes = self.read_vector(vector)?;
+        let value = lanes[VM::check_lane(lane)?];
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Int(value),
+        });
+        Ok(())
+    }
+
+    fn vinsert(&mut self, src_register: usize, lane: u8, vector: usize) -> Result<(), VmError> {
+        let value = self.read_int(src_register)?;
+        let mut lanes = self.read_vector(vector)?;
+        lanes[VM::check_lane(lane)?] = value;
+        self.write_vector(vector, lanes);
+        Ok(())
+    }
+
+    fn vsplat(&mut self, src_register: usize, vector: usize) -> Result<(), VmError> {
+        let value = self.read_int(src_register)?;
+        self.check_vector(vector)?;
+        self.write_vector(vector, [value; 4]);
+        Ok(())
+    }
+
+    fn int_to_float(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.check_register(target_register)?;
+        let value = self.read_int(src_register)?;
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Float(value as f64),
+        });
+        vm_log!(
+            trace,
+            "IntToFloat: {} -> {} (stored in register {})",
+            value,
+            value as f64,
+            target_register
+        );
+        Ok(())
+    }
+
+    // Truncates toward zero. Out-of-range values saturate to i32::MIN / i32::MAX
+    // and NaN converts to 0, except in Checked mode where both are an overflow.
+    fn float_to_int(&mut self, src_register: usize, target_register: usize) -> Result<(), VmError> {
+        self.check_register(target_register)?;
+        let value = self.read_float(src_register)?;
+        let in_range = value.trunc() >= i32::MIN as f64 && value.trunc() <= i32::MAX as f64;
+        if self.arithmetic_mode == ArithmeticMode::Checked && !in_range {
+            return Err(VmError::Overflow);
+        }
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Int(value as i32),
+        });
+        vm_log!(
+            trace,
+            "FloatToInt: {} -> {} (stored in register {})",
+            value,
+            value as i32,
+            target_register
+        );
+        Ok(())
+    }
+
+    fn check_register(&self, register_index: usize) -> Result<(), VmError> {
+        if register_index < self.registers.len() {
+            Ok(())
+        } else {
+            Err(VmError::InvalidRegister(register_index))
+        }
+    }
+
+    fn read_int(&self, register_index: usize) -> Result<i32, VmError> {
+        match self.registers.get(register_index) {
+            Some(Value::Int(value)) => Ok(*value),
+            Some(Value::Int64(value)) => i32::try_from(*value).map_err(|_| VmError::Overflow),
+            Some(Value::Float(_) | Value::Ref(_) | Value::Nil) => {
+                Err(VmError::TypeMismatch(register_index))
+            }
+            None => Err(VmError::InvalidRegister(register_index)),
+        }
+    }
+
+    fn read_float(&self, register_index: usize) -> Result<f64, VmError> {
+        match self.registers.get(register_index) {
+            Some(Value::Float(value)) => Ok(*value),
+            Some(_) => Err(VmError::TypeMismatch(register_index)),
+            None => Err(VmError::InvalidRegister(register_index)),
+        }
+    }
+
+    fn allocate_memory(
+        &mut self,
+        size_register: usize,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        let size = self.read_size(size_register)?;
+        self.check_register(target_register)?;
+        self.check_heap(size, 0)?;
+        let address = self.memory.borrow().find_free(size);
+        self.memory.borrow().check_allocation(address, size)?;
+        self.apply(Effect::Allocate { address, size });
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Int(address as i32),
+        });
+        vm_log!(
+            debug,
+            "Allocated {} bytes of memory at address {}",
+            size,
+            address
+        );
+        Ok(())
+    }
+
+    // Fails with `OutOfMemory` when allocating `size` bytes after freeing a
+    // region of `freed` bytes would take the program past its heap limit.
+    fn check_heap(&self, size: usize, freed: usize) -> Result<(), VmError> {
+        let live = self.memory_stats.live_bytes - freed;
+        if size > self.max_heap.saturating_sub(live) {
+            return Err(VmError::OutOfMemory(size));
+        }
+        Ok(())
+    }
+
+    // The size of the region allocated at exactly `address`.
+    fn region_size(&self, address: usize) -> Result<usize, VmError> {
+        let memory = self.memory.borrow();
+        match (memory.region(address), memory.freed_region(address)) {
+            (Some(region), _) => Ok(region.size),
+            (None, Some(_)) => Err(VmError::UseAfterFree(address)),
+            (None, None) => Err(VmError::NoMemoryRegion(address)),
+        }
+    }
+
+    fn free_memory(&mut self, address: usize) -> Result<(), VmError> {
+        if self.memory.borrow().region(address).is_none() {
+            return match self.memory.borrow().freed_region(address) {
+                Some(region) if region.address == address => Err(VmError::DoubleFree(address)),
+                _ => Err(VmError::NoMemoryRegion(address)),
+            };
+        }
+        if self.owned_by_vm(address) {
+            return Err(VmError::ReadOnlyMemory(address));
+        }
+        self.apply(Effect::Free { address });
+        vm_log!(debug, "Freed memory at address {}", address);
+        Ok(())
+    }
+
+    fn retain(&mut self, register_index: usize) -> Result<(), VmError> {
+        let address = self.read_address(register_index)?;
+        self.region_size(address)?;
+        if self.owned_by_vm(address) {
+            return Err(VmError::ReadOnlyMemory(address));
+        }
+        let count = self.memory.borrow().refcount(address).unwrap() + 1;
+        self.apply(Effect::Refcount { address, count });
+        vm_log!(
+            debug,
+            "Retained memory at address {}, {} references",
+            address,
+            count
+        );
+        Ok(())
+    }
+
+    // Dropping the last reference frees the region like `FreeMemory`, with the
+    // same errors for memory that isn't allocated.
+    fn release(&mut self, register_index: usize) -> Result<(), VmError> {
+        let address = self.read_address(register_index)?;
+        let count = self.memory.borrow().refcount(address);
+        let count = match count {
+            Some(count) if count > 1 && !self.owned_by_vm(address) => count - 1,
+            _ => return self.free_memory(address),
+        };
+        self.apply(Effect::Refcount { address, count });
+        vm_log!(
+            debug,
+            "Released memory at address {}, {} references",
+            address,
+            count
+        );
+        Ok(())
+    }
+
+    // Whether the region at `address` is the data section or the memory stack,
+    // which the program didn't allocate and can't free or resize.
+    fn owned_by_vm(&self, address: usize) -> bool {
+        address < self.memory.borrow().read_only_end()
+            || self
+                .memory_stack
+                .as_ref()
+                .is_some_and(|stack| stack.start == address)
+    }
+
+    // The region is freed before the new one is placed where `AllocateMemory`
+    // would put it, so it can reuse its own space, but even a shrinking region
+    // may move into a lower free block. Bytes past the old size are zeroed, and
+    // the region keeps its protection.
+    fn realloc_memory(
+        &mut self,
+        address_register: usize,
+        size_register: usize,
+        target_register: usize,
+    ) -> Result<(), VmError> {
+        let address = self.read_address(address_register)?;
+        let size = self.read_size(size_register)?;
+        self.check_register(target_register)?;
+        let old_size = self.region_size(address)?;
+        if self.owned_by_vm(address) {
+            return Err(VmError::ReadOnlyMemory(address));
+        }
+        self.check_heap(size, self.memory_stats.live_size(address))?;
+        let kept = self
+            .memory
+            .borrow()
+            .bytes(address, old_size.min(size))?
+            .to_vec();
+        let poison = self.memory.borrow().poison(address, kept.len());
+        let protection = self.memory.borrow().region(address).unwrap().protection;
+        let refcount = self.memory.borrow().refcounts.get(&address).copied();
+        let new_address = self.memory.borrow().find_free_replacing(address, size);
+        self.memory.borrow().check_allocation(new_address, size)?;
+        self.apply(Effect::Free { address });
+        self.apply(Effect::Allocate {
+            address: new_address,
+            size,
+        });
+        if !kept.is_empty() {
+            self.apply(Effect::MemoryWriteBlock {
+                address: new_address,
+                bytes: kept,
+            });
+        }
+        if let Some(poison) = poison {
+            self.memory.borrow_mut().repoison(new_address, &poison);
+        }
+        if protection != Protection::default() {
+            self.apply(Effect::Protect {
+                address: new_address,
+                protection,
+            });
+        }
+        if let Some(count) = refcount {
+            self.apply(Effect::Refcount {
+                address: new_address,
+                count,
+            });
+        }
+        self.apply(Effect::RegisterWrite {
+            register: target_register,
+            value: Value::Int(new_address as i32),
+        });
+        vm_log!(
+            debug,
+            "Resized memory at address {} from {} to {} bytes, now at address {}",
+            address,
+            old_size,
+            size,
+            new_address
+        );
+        Ok(())
+    }
+
+    fn protect_memory(&mut self, address_register: usize, flags: u8) -> Result<(), VmError> {
+        let address = self.read_address(address_register)?;
+        let protection = Protection::from_bits(flags).ok_or(VmError::InvalidProtection(flags))?;
+        self.region_size(address)?;
+        if address < self.memory.borrow().read_only_end() && protection.write {
+            return Err(VmError::ReadOnlyMemory(address));
+        }
+        self.apply(Effect::Protect {
+            address,
+            protection,
+        });
+        vm_log!(
+            debug,
+            "Protected memory at address {} as {:?}",
+            address,
+            protection
+        );
+        Ok(())
+    }
+
+    fn size_of(&mut self, address: usize, register_index: usize) -> Result<(), VmError> {
+        let size = self.region_size(address)?;
+        self.check_register(register_index)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(size as i32),
+        });
+        vm_log!(
+            debug,
+            "Size of memory block at address {} is {} (stored in register {})",
+            address,
+            size,
+            register_index
+        );
+        Ok(())
+    }
+
+    fn store_to_memory(
+        &mut self,
+        address: usize,
+        register_index: usize,
+        offset: usize,
+    ) -> Result<(), VmError> {
+        let value = self.read_int(register_index)?;
+        let absolute = address
+            .checked_add(offset)
+            .ok_or(VmError::OutOfBounds(address))?;
+        self.check_writable(absolute, 1)?;
+        self.apply(Effect::MemoryWrite {
+            address: absolute,
+            value: value as u8,
+        });
+        vm_log!(
+            debug,
+            "Stored value {} from register {} at memory address {}",
+            value,
+            register_index,
+            absolute
+        );
+        Ok(())
+    }
+
+    fn check_writable(&mut self, address: usize, len: usize) -> Result<(), VmError> {
+        if let Some(device) = self.devices.find(address, len) {
+            return device.map(|_| ());
+        }
+        self.memory.borrow().check_writable(address, len)
+    }
+
+    // Read `len` bytes for the program, from a device when they lie in its range.
+    fn read_memory(&mut self, address: usize, len: usize) -> Result<Vec<u8>, VmError> {
+        match self.devices.read(address, len) {
+            Some(bytes) => bytes,
+            None => Ok(self.memory.borrow().read(address, len)?.to_vec()),
+        }
+    }
+
+    // The source bytes are read before anything is written, so overlapping
+    // ranges behave like `memmove`.
+    fn mem_copy(
+        &mut self,
+        dst_address: usize,
+        src_address: usize,
+        len: usize,
+    ) -> Result<(), VmError> {
+        if len == 0 {
+            return Ok(());
+        }
+        // Unlike a load, copying bytes that were never written is fine.
+        let bytes = match self.devices.read(src_address, len) {
+            Some(bytes) => bytes?,
+            None => {
+                let memory = self.memory.borrow();
+                memory.check_readable(src_address, len)?;
+                memory.bytes(src_address, len)?.to_vec()
+            }
+        };
+        self.check_writable(dst_address, len)?;
+        let poison = self.memory.borrow().poison(src_address, len);
+        self.apply(Effect::MemoryWriteBlock {
+            address: dst_address,
+            bytes,
+        });
+        if let Some(poison) = poison {
+            self.memory.borrow_mut().repoison(dst_address, &poison);
+        }
+        vm_log!(
+            debug,
+            "Copied {} bytes from memory address {} to memory address {}",
+            len,
+            src_address,
+            dst_address
+        );
+        Ok(())
+    }
+
+    fn mem_copy_register(
+        &mut self,
+        dst_register: usize,
+        src_register: usize,
+        len_register: usize,
+    ) -> Result<(), VmError> {
+        let dst_address = self.read_address(dst_register)?;
+        let src_address = self.read_address(src_register)?;
+        let len = self.read_address(len_register)?;
+        self.mem_copy(dst_address, src_address, len)
+    }
+
+    fn mem_fill(
+        &mut self,
+        address: usize,
+        value_register: usize,
+        len: usize,
+    ) -> Result<(), VmError> {
+        let value = self.read_int(value_register)?;
+        if len == 0 {
+            return Ok(());
+        }
+        self.check_writable(address, len)?;
+        self.apply(Effect::MemoryWriteBlock {
+            address,
+            bytes: vec![value as u8; len],
+        });
+        vm_log!(
+            debug,
+            "Filled {} bytes at memory address {} with value {}",
+            len,
+            address,
+            value as u8
+        );
+        Ok(())
+    }
+
+    fn mem_fill_register(
+        &mut self,
+        address_register: usize,
+        value_register: usize,
+        len_register: usize,
+    ) -> Result<(), VmError> {
+        let address = self.read_address(address_register)?;
+        let len = self.read_address(len_register)?;
+        self.mem_fill(address, value_register, len)
+    }
+
+    // Read the little-endian 32-bit word at the absolute `address`.
+    fn load_word(&mut self, address: usize) -> Result<i32, VmError> {
+        self.read_sized(address, 4, true)
+    }
+
+    // Write `value` as a little-endian 32-bit word at the absolute `address`.
+    fn store_word(&mut self, address: usize, value: i32) -> Result<(), VmError> {
+        self.check_writable(address, 4)?;
+        self.apply(Effect::MemoryWriteBlock {
+            address,
+            bytes: value.to_le_bytes().to_vec(),
+        });
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        address: usize,
+        offset: usize,
+        expected_register: usize,
+        new_register: usize,
+        result_register: usize,
+    ) -> Result<(), VmError> {
+        self.check_register(result_register)?;
+        let expected = self.read_int(expected_register)?;
+        let new_value = self.read_int(new_register)?;
+        let absolute = address
+            .checked_add(offset)
+            .ok_or(VmError::OutOfBounds(address))?;
+        let current = self.load_word(absolute)?;
+        let swapped = current == expected;
+        if swapped {
+            self.store_word(absolute, new_value)?;
+        }
+        self.apply(Effect::RegisterWrite {
+            register: result_register,
+            value: Value::Int(swapped as i32),
+        });
+        vm_log!(
+            debug,
+            "Cas at memory address {}: found {}, expected {} -> {}",
+            absolute,
+            current,
+            expected,
+            if swapped { "swapped" } else { "unchanged" }
+        );
+        Ok(())
+    }
+
+    fn load_from_memory(
+        &mut self,
+        address: usize,
+        offset: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let absolute = address
+            .checked_add(offset)
+            .ok_or(VmError::OutOfBounds(address))?;
+        let value = self.read_memory(absolute, 1)?[0] as i32;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(value),
+        });
+        vm_log!(
+            debug,
+            "Loaded value {} from memory address {} into register {}",
+            value,
+            absolute,
+            register_index
+        );
+        Ok(())
+    }
+
+    // Registers hold addresses as unsigned 32-bit values.
+    fn read_address(&self, register_index: usize) -> Result<usize, VmError> {
+        Ok(self.read_int(register_index)? as u32 as usize)
+    }
+
+    // Allocation sizes are signed, so a negative size is an error rather than
+    // a request for nearly 4 GiB.
+    fn read_size(&self, register_index: usize) -> Result<usize, VmError> {
+        let size = self.read_int(register_index)?;
+        usize::try_from(size).map_err(|_| VmError::InvalidSize(size))
+    }
+
+    fn store_indirect(
+        &mut self,
+        address_register: usize,
+        offset_register: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        let address = self.read_address(address_register)?;
+        let offset = self.read_address(offset_register)?;
+        self.store_to_memory(address, register_index, offset)
+    }
+
+    fn load_indirect(
+        &mut self,
+        address_register: usize,
+        offset_register: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        let address = self.read_address(address_register)?;
+        let offset = self.read_address(offset_register)?;
+        self.load_from_memory(address, offset, register_index)
+    }
+
+    fn indirect_address(
+        &self,
+        address_register: usize,
+        offset_register: usize,
+    ) -> Result<usize, VmError> {
+        let address = self.read_address(address_register)?;
+        let offset = self.read_address(offset_register)?;
+        address
+            .checked_add(offset)
+            .ok_or(VmError::OutOfBounds(address))
+    }
+
+    fn store_sized(
+        &mut self,
+        address_register: usize,
+        offset_register: usize,
+        register_index: usize,
+        width: usize,
+    ) -> Result<(), VmError> {
+        let value = self.read_int(register_index)?;
+        let absolute = self.indirect_address(address_register, offset_register)?;
+        self.check_writable(absolute, width)?;
+        self.apply(Effect::MemoryWriteBlock {
+            address: absolute,
+            bytes: value.to_le_bytes()[..width].to_vec(),
+        });
+        vm_log!(
+            debug,
+            "Stored {}-byte value {} from register {} at memory address {}",
+            width,
+            value,
+            register_index,
+            absolute
+        );
+        Ok(())
+    }
+
+    fn load_sized(
+        &mut self,
+        address_register: usize,
+        offset_register: usize,
+        register_index: usize,
+        width: usize,
+        signed: bool,
+    ) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let absolute = self.indirect_address(address_register, offset_register)?;
+        let value = self.read_sized(absolute, width, signed)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(value),
+        });
+        vm_log!(
+            debug,
+            "Loaded {}-byte value {} from memory address {} into register {}",
+            width,
+            value,
+            absolute,
+            register_index
+        );
+        Ok(())
+    }
+
+    // Read a little-endian value of `width` bytes at the absolute `address`,
+    // sign- or zero-extending it to 32 bits.
+    fn read_sized(&mut self, address: usize, width: usize, signed: bool) -> Result<i32, VmError> {
+        let bytes = self.read_memory(address, width)?;
+        let mut word = [0; 4];
+        word[..width].copy_from_slice(&bytes);
+        let value = u32::from_le_bytes(word);
+        let unused = 32 - 8 * width as u32;
+        Ok(if signed {
+            ((value << unused) as i32) >> unused
+        } else {
+            value as i32
+        })
+    }
+
+    // The offset is relative to the instruction after the jump, which is where
+    // `ip` already points.
+    fn jump(&mut self, ip_offset: isize) -> Result<(), VmError> {
+        self.ip = self
+            .ip
+            .checked_add_signed(ip_offset)
+            .filter(|target| *target < self.program.len())
+            .ok_or(VmError::InvalidJump(
+                (self.ip as isize).saturating_add(ip_offset),
+            ))?;
+        vm_log!(trace, "Jumping to instruction {}", self.ip);
+        Ok(())
+    }
+
+    fn jump_if_zero(&mut self, register_index: usize, ip_offset: isize) -> Result<(), VmError> {
+        if self.read_int(register_index)? == 0 {
+            self.jump(ip_offset)?;
+        }
+        Ok(())
+    }
+
+    fn jump_if_non_zero(&mut self, register_index: usize, ip_offset: isize) -> Result<(), VmError> {
+        if self.read_int(register_index)? != 0 {
+            self.jump(ip_offset)?;
+        }
+        Ok(())
+    }
+
+    // The flag branches, `taken` is their condition on the flags.
+    fn jump_if(&mut self, taken: bool, ip_offset: isize) -> Result<(), VmError> {
+        if taken {
+            self.jump(ip_offset)?;
+        }
+        Ok(())
+    }
+
+    fn assert_eq(&mut self, register_index: usize, expected: i32) -> Result<(), VmError> {
+        let actual = self.read_int(register_index)?;
+        if actual != expected {
+            return Err(VmError::AssertionFailed {
+                ip: self.ip - 1,
+                register: register_index,
+                expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    fn print(&mut self, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        self.apply(Effect::Print {
+            register: register_index,
+            value: self.registers[register_index],
+        });
+        Ok(())
+    }
+
+    // Print the register's bit pattern as a `u32`, so `-1` shows as `4294967295`.
+    fn print_unsigned(&mut self, register_index: usize) -> Result<(), VmError> {
+        let value = self.read_int(register_index)?;
+        self.apply(Effect::PrintUnsigned {
+            register: register_index,
+            value: value as u32,
+        });
+        Ok(())
+    }
+
+    // Write the bytes as they are: no register prefix and no newline.
+    fn print_str(&mut self, address_register: usize, len_register: usize) -> Result<(), VmError> {
+        let address = self.read_address(address_register)?;
+        let len = self.read_address(len_register)?;
+        if len == 0 {
+            return Ok(());
+        }
+        let bytes = self.read_memory(address, len)?;
+        self.apply(Effect::PrintStr { address, bytes });
+        Ok(())
+    }
+
+    fn syscall(&mut self, number: usize) -> Result<(), VmError> {
+        // A dry run has no host functions: it only records that the call happens.
+        if self.dry_run {
+            self.apply(Effect::Syscall { number });
+            return Ok(());
+        }
+        if let Some(event) = self.replayed()? {
+            return self.replay_syscall(number, event);
+        }
+        // Take the handler out while it runs so it can borrow the VM mutably.
+        let Some(mut handler) = self.syscalls.remove(&number) else {
+            return Err(VmError::UnknownSyscall(number));
+        };
+        vm_log!(debug, "Calling syscall {}", number);
+        self.apply(Effect::Syscall { number });
+        // While recording inputs, collect the effects of the handler separately
+        // and hand them on to any outer recording afterwards.
+        let outer_effects = self
+            .input_log
+            .is_some()
+            .then(|| self.effects.replace(Vec::new()));
+        let result = handler(&mut VmContext { vm: self });
+        // Keep a replacement the handler may have registered for itself.
+        self.syscalls.entry(number).or_insert(handler);
+        if let Some(outer_effects) = outer_effects {
+            let effects = std::mem::replace(&mut self.effects, outer_effects).unwrap_or_default();
+            let memory = effects
+                .iter()
+                .filter(|effect| {
+                    matches!(
+                        effect,
+                        Effect::MemoryWrite { .. }
+                            | Effect::MemoryWriteBlock { .. }
+                            | Effect::Allocate { .. }
+                            | Effect::Free { .. }
+                            | Effect::Protect { .. }
+                            | Effect::Refcount { .. }
+                    )
+                })
+                .cloned()
+                .collect();
+            if let Some(outer) = &mut self.effects {
+                outer.extend(effects);
+            }
+            self.record_input(InputEvent::Syscall {
+                number,
+                registers: self.registers.clone(),
+                memory,
+                result: result.clone(),
+            });
+        }
+        result
+    }
+
+    // Redo what the host function did when the log was recorded, without
+    // calling it.
+    fn replay_syscall(&mut self, number: usize, event: InputEvent) -> Result<(), VmError> {
+        let InputEvent::Syscall {
+            number: recorded,
+            registers,
+            memory,
+            result,
+        } = event
+        else {
+            return Err(VmError::ReplayMismatch(self.ip - 1));
+        };
+        if recorded != number || registers.len() != self.registers.len() {
+            return Err(VmError::ReplayMismatch(self.ip - 1));
+        }
+        self.apply(Effect::Syscall { number });
+        for effect in memory.iter().cloned() {
+            self.apply(effect);
+        }
+        for (register, &value) in registers.iter().enumerate() {
+            if self.registers[register] != value {
+                self.apply(Effect::RegisterWrite { register, value });
+            }
+        }
+        self.record_input(InputEvent::Syscall {
+            number,
+            registers,
+            memory,
+            result: result.clone(),
+        });
+        result
+    }
+
+    fn read_int_input(&mut self, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let value = match self.replayed()? {
+            Some(InputEvent::ReadInt(value)) => value,
+            Some(_) => return Err(VmError::ReplayMismatch(self.ip - 1)),
+            None => self.read_int_line(),
+        };
+        self.record_input(InputEvent::ReadInt(value.clone()));
+        let value = value?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(value),
+        });
+        vm_log!(trace, "Read {} into register {}", value, register_index);
+        Ok(())
+    }
+
+    fn read_int_line(&mut self) -> Result<i32, VmError> {
+        let mut line = String::new();
+        let read = self
+            .input
+            .read_line(&mut line)
+            .map_err(|error| VmError::InputError(error.to_string()))?;
+        if read == 0 {
+            return Err(VmError::EndOfInput);
+        }
+        line.trim()
+            .parse::<i32>()
+            .map_err(|_| VmError::InputError(format!("`{}` is not an integer", line.trim())))
+    }
+
+    fn read_byte_input(&mut self, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let value = match self.replayed()? {
+            Some(InputEvent::ReadByte(value)) => value,
+            Some(_) => return Err(VmError::ReplayMismatch(self.ip - 1)),
+            None => self.read_input_byte(),
+        };
+        self.record_input(InputEvent::ReadByte(value.clone()));
+        let value = value?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(value),
+        });
+        vm_log!(
+            trace,
+            "Read byte {} into register {}",
+            value,
+            register_index
+        );
+        Ok(())
+    }
+
+    // The next input byte, or -1 at the end of the input.
+    fn read_input_byte(&mut self) -> Result<i32, VmError> {
+        let buffer = self
+            .input
+            .fill_buf()
+            .map_err(|error| VmError::InputError(error.to_string()))?;
+        let Some(&byte) = buffer.first() else {
+            return Ok(-1);
+        };
+        self.input.consume(1);
+        Ok(byte as i32)
+    }
+
+    fn call(&mut self, target: usize) -> Result<(), VmError> {
+        if target >= self.program.len() {
+            return Err(VmError::InvalidJump(target as isize));
+        }
+        if self.stack.len() >= self.max_call_depth {
+            return Err(VmError::StackOverflow);
+        }
+        // Push the return address to the stack
+        self.stack.push(self.ip);
+        if self.calling_convention == CallingConvention::SaveCalleeSaved {
+            let end = CALLEE_SAVED_REGISTERS.end.min(self.registers.len());
+            let start = CALLEE_SAVED_REGISTERS.start.min(end);
+            self.saved_registers
+                .push(self.registers[start..end].to_vec());
+        }
+        // Jump to the absolute function address
+        self.ip = target;
+        vm_log!(trace, "Calling function at {}", self.ip);
+        Ok(())
+    }
+
+    fn try_begin(&mut self, ip_offset: isize) -> Result<(), VmError> {
+        let target = self
+            .ip
+            .checked_add_signed(ip_offset)
+            .filter(|target| *target < self.program.len())
+            .ok_or(VmError::InvalidJump(
+                (self.ip as isize).saturating_add(ip_offset),
+            ))?;
+        if self.handlers.len() >= self.max_call_depth {
+            return Err(VmError::StackOverflow);
+        }
+        self.handlers.push(Handler {
+            target,
+            calls: self.stack.len(),
+            frames: self.frames.len(),
+            data_stack: self.data_stack.len(),
+            sp: self.sp,
+        });
+        vm_log!(trace, "Installed an exception handler at {}", target);
+        Ok(())
+    }
+
+    fn try_end(&mut self) -> Result<(), VmError> {
+        match self.handlers.last() {
+            Some(handler) if handler.calls == self.stack.len() => {
+                self.handlers.pop();
+                Ok(())
+            }
+            _ => Err(VmError::NoHandler),
+        }
+    }
+
+    // Unwind to the innermost handler: return from the calls made since its
+    // `TryBegin`, close the frames opened since, drop what was pushed since and
+    // continue at the handler with the code in the return register.
+    fn throw(&mut self, register_index: usize) -> Result<(), VmError> {
+        let code = self.read_int(register_index)?;
+        let handler = self
+            .handlers
+            .pop()
+            .ok_or(VmError::UncaughtException(code))?;
+        while self.stack.len() > handler.calls {
+            self.return_from_function()?;
+        }
+        if let Some(&base) = self.frames.get(handler.frames) {
+            self.frames.truncate(handler.frames);
+            self.locals.truncate(base);
+        }
+        self.data_stack.truncate(handler.data_stack);
+        self.sp = handler.sp;
+        self.ip = handler.target;
+        self.apply(Effect::RegisterWrite {
+            register: RETURN_REGISTER,
+            value: Value::Int(code),
+        });
+        vm_log!(trace, "Threw {} to the handler at {}", code, self.ip);
+        Ok(())
+    }
+
+    fn call_register(&mut self, register_index: usize) -> Result<(), VmError> {
+        let target = self.read_address(register_index)?;
+        self.call(target)
+    }
+
+    fn call_table(&mut self, register_index: usize) -> Result<(), VmError> {
+        let index = self.read_int(register_index)?;
+        let target = usize::try_from(index)
+            .ok()
+            .and_then(|index| self.functions.get(index))
+            .copied()
+            .ok_or(VmError::InvalidFunctionIndex(index))?;
+        self.call(target)
+    }
+
+    fn return_from_function(&mut self) -> Result<(), VmError> {
+        // Pop the return address from the stack and continue
+        self.ip = self.stack.pop().ok_or(VmError::StackUnderflow)?;
+        // Handlers the returning call left installed don't outlive it
+        let calls = self.stack.len();
+        while self
+            .handlers
+            .last()
+            .is_some_and(|handler| handler.calls > calls)
+        {
+            self.handlers.pop();
+        }
+        if self.calling_convention == CallingConvention::SaveCalleeSaved {
+            let saved = self.saved_registers.pop().unwrap_or_default();
+            for (register, value) in CALLEE_SAVED_REGISTERS.zip(saved) {
+                self.apply(Effect::RegisterWrite { register, value });
+            }
+        }
+        Ok(())
+    }
+
+    // Every observable side effect of an instruction goes through here, so it can
+    // be recorded and, in dry-run mode, kept from reaching the outside world.
+    fn apply(&mut self, effect: Effect) {
+        self.record_memory(&effect);
+        match &effect {
+            Effect::RegisterWrite { register, value } => {
+                self.registers[*register] = match (self.register_mode, *value) {
+                    (RegisterMode::Tagged, Value::Int(value)) => Value::Int64(value.into()),
+                    (_, value) => value,
+                };
+            }
+            Effect::VectorWrite { register, lanes } => {
+                self.vectors[*register] = *lanes;
+            }
+            Effect::MemoryWrite { address, value } => {
+                if !self.devices.write(*address, &[*value]) {
+                    self.memory.borrow_mut().write(*address, &[*value]);
+                }
+            }
+            Effect::MemoryWriteBlock { address, bytes } => {
+                if !self.devices.write(*address, bytes) {
+                    self.memory.borrow_mut().write(*address, bytes);
+                }
+            }
+            Effect::Print { register, value } => {
+                if !self.dry_run {
+                    self.write_output(format_args!("Register {}: {}", register, value));
+                }
+            }
+            Effect::PrintUnsigned { register, value } => {
+                if !self.dry_run {
+                    self.write_output(format_args!("Register {}: {}", register, value));
+                }
+            }
+            Effect::PrintStr { bytes, .. } | Effect::PrintString { bytes, .. } => {
+                if !self.dry_run {
+                    if let Err(error) = self.output.write_all(bytes) {
+                        vm_log!(error, "Failed to write program output: {}", error);
+                    }
+                }
+            }
+            Effect::Allocate { address, size } => {
+                self.memory.borrow_mut().allocate_at(*address, *size);
+                // `ip` already points past the allocating instruction.
+                self.memory_stats
+                    .allocated(*address, *size, self.ip.saturating_sub(1));
+            }
+            Effect::Free { address } => {
+                self.memory.borrow_mut().free(*address);
+                self.memory_stats.freed(*address);
+            }
+            Effect::Protect {
+                address,
+                protection,
+            } => {
+                self.memory
+                    .borrow_mut()
+                    .set_protection(*address, *protection);
+            }
+            Effect::Refcount { address, count } => {
+                self.memory.borrow_mut().set_refcount(*address, *count);
+            }
+            Effect::Syscall { .. } => {
+                // The handler itself is run by `syscall`, it can't be described up front.
+            }
+        }
+        if let Some(trace) = &mut self.json_trace {
+            trace.record(&effect);
+        }
+        if let Some(effects) = &mut self.effects {
+            effects.push(effect);
+        }
+    }
+
+    fn write_output(&mut self, line: fmt::Arguments<'_>) {
+        if let Err(error) = writeln!(self.output, "{}", line) {
+            vm_log!(error, "Failed to write program output: {}", error);
+        }
+    }
+
+    /// Start recording the side effects of subsequent instructions.
+    pub fn record_effects(&mut self) {
+        self.effects = Some(Vec::new());
+    }
+
+    /// Return the effects recorded so far and keep recording.
+    pub fn take_effects(&mut self) -> Vec<Effect> {
+        self.effects
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    // Execute one instruction like `step`, also returning the effects it had.
+    // A recording started with `record_effects` still sees them.
+    pub(crate) fn step_with_effects(&mut self) -> (Result<VmState, VmError>, Vec<Effect>) {
+        let outer_effects = self.effects.replace(Vec::new());
+        let result = self.step();
+        let effects = std::mem::replace(&mut self.effects, outer_effects).unwrap_or_default();
+        if let Some(outer) = &mut self.effects {
+            outer.extend(effects.iter().cloned());
+        }
+        (result, effects)
+    }
+
+    // Move past the instruction at the ip without executing it, for a debugger
+    // that takes over at a `Brk`.
+    pub(crate) fn skip_instruction(&mut self) {
+        self.ip += 1;
+    }
+
+    /// Start recording the nondeterministic inputs of subsequent instructions:
+    /// what `ReadInt` and `ReadByte` read and what host functions did.
+    pub fn record_inputs(&mut self) {
+        self.input_log = Some(Vec::new());
+    }
+
+    /// Return the inputs recorded so far and keep recording.
+    pub fn take_input_log(&mut self) -> InputLog {
+        InputLog {
+            events: self
+                .input_log
+                .as_mut()
+                .map(std::mem::take)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Feed a recorded log back instead of reading the input and calling host
+    /// functions, so the run it was recorded from repeats exactly. A read or
+    /// syscall that doesn't match the next event, or comes after the last one,
+    /// fails with `VmError::ReplayMismatch`.
+    pub fn replay_inputs(&mut self, log: InputLog) {
+        self.replay = Some(log.events.into());
+    }
+
+    // The next replayed event, `None` when not replaying.
+    fn replayed(&mut self) -> Result<Option<InputEvent>, VmError> {
+        match &mut self.replay {
+            None => Ok(None),
+            Some(events) => events
+                .pop_front()
+                .map(Some)
+                .ok_or(VmError::ReplayMismatch(self.ip - 1)),
+        }
+    }
+
+    fn record_input(&mut self, event: InputEvent) {
+        if let Some(log) = &mut self.input_log {
+            log.push(event);
+        }
+    }
+
+    // The data stack is VM state like the call stack, not an effect: only the
+    // register written by Pop is.
+    fn push(&mut self, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        self.push_value(self.registers[register_index])
+    }
+
+    fn push_value(&mut self, value: Value) -> Result<(), VmError> {
+        if self.data_stack.len() >= self.max_data_stack {
+            return Err(VmError::DataStackOverflow);
+        }
+        self.data_stack.push(value);
+        vm_log!(trace, "Pushed {} onto the data stack", value);
+        Ok(())
+    }
+
+    fn pop(&mut self, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let value = self.data_stack.pop().ok_or(VmError::DataStackUnderflow)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value,
+        });
+        vm_log!(trace, "Popped {} into register {}", value, register_index);
+        Ok(())
+    }
+
+    fn dup(&mut self) -> Result<(), VmError> {
+        let top = *self.data_stack.last().ok_or(VmError::DataStackUnderflow)?;
+        self.push_value(top)
+    }
+
+    fn swap_top(&mut self) -> Result<(), VmError> {
+        let len = self.data_stack.len();
+        if len < 2 {
+            return Err(VmError::DataStackUnderflow);
+        }
+        self.data_stack.swap(len - 1, len - 2);
+        Ok(())
+    }
+
+    // Frames are independent of calls: a function usually opens one right after
+    // it is called and closes it before it returns.
+    fn enter(&mut self, local_count: usize) -> Result<(), VmError> {
+        if self.frames.len() >= self.max_call_depth
+            || local_count > self.max_locals - self.locals.len()
+        {
+            return Err(VmError::StackOverflow);
+        }
+        self.frames.push(self.locals.len());
+        self.locals
+            .resize(self.locals.len() + local_count, Value::default());
+        vm_log!(trace, "Entered a frame with {} locals", local_count);
+        Ok(())
+    }
+
+    fn leave(&mut self) -> Result<(), VmError> {
+        let base = self.frames.pop().ok_or(VmError::NoFrame)?;
+        self.locals.truncate(base);
+        Ok(())
+    }
+
+    // The index of `slot` of the innermost frame in `locals`.
+    fn local_index(&self, slot: usize) -> Result<usize, VmError> {
+        let base = *self.frames.last().ok_or(VmError::NoFrame)?;
+        if slot >= self.locals.len() - base {
+            return Err(VmError::InvalidLocal(slot));
+        }
+        Ok(base + slot)
+    }
+
+    fn load_local(&mut self, slot: usize, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let value = self.locals[self.local_index(slot)?];
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value,
+        });
+        vm_log!(
+            trace,
+            "Loaded local {} ({}) into register {}",
+            slot,
+            value,
+            register_index
+        );
+        Ok(())
+    }
+
+    fn store_local(&mut self, register_index: usize, slot: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let index = self.local_index(slot)?;
+        self.locals[index] = self.registers[register_index];
+        vm_log!(
+            trace,
+            "Stored register {} in local {}",
+            register_index,
+            slot
+        );
+        Ok(())
+    }
+
+    fn load_global(&mut self, global: usize, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let value = *self
+            .globals
+            .get(global)
+            .ok_or(VmError::InvalidGlobal(global))?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value,
+        });
+        vm_log!(
+            trace,
+            "Loaded global {} ({}) into register {}",
+            global,
+            value,
+            register_index
+        );
+        Ok(())
+    }
+
+    fn store_global(&mut self, register_index: usize, global: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let slot = self
+            .globals
+            .get_mut(global)
+            .ok_or(VmError::InvalidGlobal(global))?;
+        *slot = self.registers[register_index];
+        vm_log!(
+            trace,
+            "Stored register {} in global {}",
+            register_index,
+            global
+        );
+        Ok(())
+    }
+
+    fn read_reference(&self, register_index: usize) -> Result<u32, VmError> {
+        match self.registers.get(register_index) {
+            Some(Value::Ref(handle)) => Ok(*handle),
+            Some(_) => Err(VmError::TypeMismatch(register_index)),
+            None => Err(VmError::InvalidRegister(register_index)),
+        }
+    }
+
+    // A reference to an object or array, which strings and maps aren't.
+    fn read_object(&self, register_index: usize) -> Result<u32, VmError> {
+        let handle = self.read_reference(register_index)?;
+        if self.objects.is_live(handle) && self.objects.get(handle).is_none() {
+            return Err(VmError::TypeMismatch(register_index));
+        }
+        Ok(handle)
+    }
+
+    fn read_string(&self, register_index: usize) -> Result<&[u8], VmError> {
+        let handle = self.read_reference(register_index)?;
+        if self.objects.is_live(handle) && self.objects.string(handle).is_none() {
+            return Err(VmError::TypeMismatch(register_index));
+        }
+        self.objects
+            .string(handle)
+            .ok_or(VmError::InvalidReference(handle as usize))
+    }
+
+    fn read_map(&self, register_index: usize) -> Result<u32, VmError> {
+        let handle = self.read_reference(register_index)?;
+        if self.objects.is_live(handle) && self.objects.map(handle).is_none() {
+            return Err(VmError::TypeMismatch(register_index));
+        }
+        Ok(handle)
+    }
+
+    // An integer, or the contents of a string.
+    fn read_key(&self, register_index: usize) -> Result<MapKey, VmError> {
+        match self.registers.get(register_index) {
+            Some(Value::Int(_) | Value::Int64(_)) => {
+                Ok(MapKey::Int(self.read_int(register_index)?))
+            }
+            Some(Value::Ref(_)) => Ok(MapKey::String(self.read_string(register_index)?.to_vec())),
+            Some(Value::Float(_) | Value::Nil) => Err(VmError::TypeMismatch(register_index)),
+            None => Err(VmError::InvalidRegister(register_index)),
+        }
+    }
+
+    // Make room for a new object of `size` slots. One that doesn't fit next to
+    // the live ones first collects the unreachable ones. The target register
+    // still holds its old value then, so an object only it references survives.
+    fn reserve_object(&mut self, size: usize) -> Result<(), VmError> {
+        if size > self.max_object_slots - self.objects.used_slots() {
+            self.collect_garbage();
+        }
+        if size > self.max_object_slots - self.objects.used_slots() {
+            return Err(VmError::OutOfMemory(size));
+        }
+        Ok(())
+    }
+
+    fn new_object(&mut self, size: usize, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        self.reserve_object(size)?;
+        self.record_objects();
+        let handle = self.objects.allocate(size)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Ref(handle),
+        });
+        vm_log!(debug, "Created object {} with {} slots", handle, size);
+        Ok(())
+    }
+
+    fn new_array(&mut self, length_register: usize, register_index: usize) -> Result<(), VmError> {
+        let length = self.read_address(length_register)?;
+        self.new_object(length, register_index)
+    }
+
+    // Negative indices wrap around like addresses and are out of bounds.
+    fn array_get(
+        &mut self,
+        array_register: usize,
+        index_register: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        let index = self.read_address(index_register)?;
+        self.load_slot(array_register, index, register_index)
+    }
+
+    fn array_set(
+        &mut self,
+        array_register: usize,
+        index_register: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        let index = self.read_address(index_register)?;
+        self.store_slot(register_index, array_register, index)
+    }
+
+    fn array_len(&mut self, array_register: usize, register_index: usize) -> Result<(), VmError> {
+        let handle = self.read_object(array_register)?;
+        self.check_register(register_index)?;
+        let length = self
+            .objects
+            .get(handle)
+            .ok_or(VmError::InvalidReference(handle as usize))?
+            .len();
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(length as i32),
+        });
+        Ok(())
+    }
+
+    // Put a new string into the target register.
+    fn store_string(&mut self, bytes: Vec<u8>, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        self.reserve_object(string_size(bytes.len()))?;
+        let len = bytes.len();
+        self.record_objects();
+        let handle = self.objects.allocate_string(bytes)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Ref(handle),
+        });
+        vm_log!(debug, "Created string {} of {} bytes", handle, len);
+        Ok(())
+    }
+
+    fn new_string(
+        &mut self,
+        address_register: usize,
+        len_register: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        let address = self.read_address(address_register)?;
+        let len = self.read_address(len_register)?;
+        let bytes = if len == 0 {
+            Vec::new()
+        } else {
+            self.read_memory(address, len)?
+        };
+        self.store_string(bytes, register_index)
+    }
+
+    fn str_concat(
+        &mut self,
+        first_register: usize,
+        second_register: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        let mut bytes = self.read_string(first_register)?.to_vec();
+        bytes.extend_from_slice(self.read_string(second_register)?);
+        self.store_string(bytes, register_index)
+    }
+
+    fn str_len(&mut self, string_register: usize, register_index: usize) -> Result<(), VmError> {
+        let len = self.read_string(string_register)?.len();
+        self.check_register(register_index)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(len as i32),
+        });
+        Ok(())
+    }
+
+    fn str_byte(
+        &mut self,
+        string_register: usize,
+        index_register: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        let index = self.read_address(index_register)?;
+        let byte = *self
+            .read_string(string_register)?
+            .get(index)
+            .ok_or(VmError::IndexOutOfBounds(index))?;
+        self.check_register(register_index)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(i32::from(byte)),
+        });
+        Ok(())
+    }
+
+    fn str_cmp(
+        &mut self,
+        first_register: usize,
+        second_register: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        let ordering = self
+            .read_string(first_register)?
+            .cmp(self.read_string(second_register)?);
+        self.check_register(register_index)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(ordering as i32),
+        });
+        Ok(())
+    }
+
+    fn print_string(&mut self, string_register: usize) -> Result<(), VmError> {
+        let bytes = self.read_string(string_register)?.to_vec();
+        self.apply(Effect::PrintString {
+            register: string_register,
+            bytes,
+        });
+        Ok(())
+    }
+
+    fn map_new(&mut self, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        self.record_objects();
+        let handle = self.objects.allocate_map()?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Ref(handle),
+        });
+        vm_log!(debug, "Created map {}", handle);
+        Ok(())
+    }
+
+    fn map_get(
+        &mut self,
+        map_register: usize,
+        key_register: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        let handle = self.read_map(map_register)?;
+        let key = self.read_key(key_register)?;
+        self.check_register(register_index)?;
+        let value = self
+            .objects
+            .map_get(handle, &key)?
+            .ok_or_else(|| VmError::MissingKey(key.to_string()))?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value,
+        });
+        Ok(())
+    }
+
+    // A new key makes the map grow, which can collect garbage like a new
+    // object. The map is in a register, so it survives.
+    fn map_set(
+        &mut self,
+        map_register: usize,
+        key_register: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        let handle = self.read_map(map_register)?;
+        let key = self.read_key(key_register)?;
+        self.check_register(register_index)?;
+        if self.objects.map_get(handle, &key)?.is_none() {
+            self.reserve_object(key.entry_size())?;
+        }
+        self.record_objects();
+        self.objects
+            .map_set(handle, key, self.registers[register_index])
+    }
+
+    // Removing a key the map doesn't hold does nothing.
+    fn map_del(&mut self, map_register: usize, key_register: usize) -> Result<(), VmError> {
+        let handle = self.read_map(map_register)?;
+        let key = self.read_key(key_register)?;
+        self.record_objects();
+        self.objects.map_remove(handle, &key)
+    }
+
+    fn map_has(
+        &mut self,
+        map_register: usize,
+        key_register: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        let handle = self.read_map(map_register)?;
+        let key = self.read_key(key_register)?;
+        self.check_register(register_index)?;
+        let found = self.objects.map_get(handle, &key)?.is_some();
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(i32::from(found)),
+        });
+        Ok(())
+    }
+
+    // The type tag of a register's value, telling the kinds of objects apart.
+    fn type_of(&mut self, source: usize, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let tag = match self.registers.get(source) {
+            None => return Err(VmError::InvalidRegister(source)),
+            Some(Value::Nil) => 0,
+            Some(Value::Int(_) | Value::Int64(_)) => 1,
+            Some(Value::Float(_)) => 2,
+            Some(&Value::Ref(handle)) if self.objects.get(handle).is_some() => 3,
+            Some(&Value::Ref(handle)) if self.objects.string(handle).is_some() => 4,
+            Some(&Value::Ref(handle)) if self.objects.map(handle).is_some() => 5,
+            Some(&Value::Ref(handle)) => return Err(VmError::InvalidReference(handle as usize)),
+        };
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(tag),
+        });
+        vm_log!(trace, "TypeOf: register {} has type {}", source, tag);
+        Ok(())
+    }
+
+    fn load_slot(
+        &mut self,
+        object_register: usize,
+        slot: usize,
+        register_index: usize,
+    ) -> Result<(), VmError> {
+        let handle = self.read_object(object_register)?;
+        self.check_register(register_index)?;
+        let value = self.objects.slot(handle, slot)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value,
+        });
+        Ok(())
+    }
+
+    fn store_slot(
+        &mut self,
+        register_index: usize,
+        object_register: usize,
+        slot: usize,
+    ) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let handle = self.read_object(object_register)?;
+        self.record_objects();
+        self.objects
+            .set_slot(handle, slot, self.registers[register_index])
+    }
+
+    // The address of the word `offset` bytes above SP, which must lie in the
+    // memory stack.
+    fn sp_address(&self, offset: usize) -> Result<usize, VmError> {
+        let stack = self.memory_stack.as_ref().ok_or(VmError::NoMemoryStack)?;
+        let address = self
+            .sp
+            .checked_add(offset)
+            .ok_or(VmError::OutOfBounds(self.sp))?;
+        if address.saturating_add(4) > stack.end {
+            return Err(VmError::OutOfBounds(address));
+        }
+        Ok(address)
+    }
+
+    fn push_sp(&mut self, register_index: usize) -> Result<(), VmError> {
+        let value = self.read_int(register_index)?;
+        let stack = self.memory_stack.as_ref().ok_or(VmError::NoMemoryStack)?;
+        if self.sp < stack.start + 4 {
+            return Err(VmError::StackOverflow);
+        }
+        self.store_word(self.sp - 4, value)?;
+        self.sp -= 4;
+        vm_log!(trace, "Pushed {} onto the memory stack", value);
+        Ok(())
+    }
+
+    fn pop_sp(&mut self, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let stack = self.memory_stack.as_ref().ok_or(VmError::NoMemoryStack)?;
+        if self.sp + 4 > stack.end {
+            return Err(VmError::StackUnderflow);
+        }
+        let value = self.load_word(self.sp)?;
+        self.sp += 4;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(value),
+        });
+        vm_log!(trace, "Popped {} into register {}", value, register_index);
+        Ok(())
+    }
+
+    fn load_sp_offset(&mut self, offset: usize, register_index: usize) -> Result<(), VmError> {
+        self.check_register(register_index)?;
+        let address = self.sp_address(offset)?;
+        let value = self.load_word(address)?;
+        self.apply(Effect::RegisterWrite {
+            register: register_index,
+            value: Value::Int(value),
+        });
+        Ok(())
+    }
+
+    fn store_sp_offset(&mut self, register_index: usize, offset: usize) -> Result<(), VmError> {
+        let value = self.read_int(register_index)?;
+        let address = self.sp_address(offset)?;
+        self.store_word(address, value)
+    }
+
+    /// Run the program from the current state on a scratch copy of the machine and
+    /// report the side effects it would have. Neither this VM nor stdout is touched.
+    pub fn dry_run(&self) -> Vec<Effect> {
+        let mut shadow = VM {
+            ip: self.ip,
+            program: self.program.clone(),
+            registers: self.registers.clone(),
+            vectors: self.vectors.clone(),
+            memory: Rc::new(RefCell::new(self.memory.borrow().clone())),
+            stack: self.stack.clone(),
+            functions: self.functions.clone(),
+            symbols: self.symbols.clone(),
+            saved_registers: self.saved_registers.clone(),
+            data_stack: self.data_stack.clone(),
+            frames: self.frames.clone(),
+            handlers: self.handlers.clone(),
+            locals: self.locals.clone(),
+            globals: self.globals.clone(),
+            objects: self.objects.clone(),
+            memory_stack: self.memory_stack.clone(),
+            sp: self.sp,
+            effects: Some(Vec::new()),
+            undo: None,
+            dry_run: true,
+            time_limit: self.time_limit,
+            output: Box::new(io::sink()),
+            input: Box::new(io::empty()),
+            input_log: None,
+            replay: None,
+            syscalls: HashMap::new(),
+            strict: self.strict,
+            tracer: None,
+            json_trace: None,
+            profiler: None,
+            memory_stats: self.memory_stats.clone(),
+            leak_report: false,
+            state: self.state,
+            trap: self.trap.clone(),
+            flags: self.flags,
+            fuel: self.fuel,
+            arithmetic_mode: self.arithmetic_mode,
+            register_mode: self.register_mode,
+            max_call_depth: self.max_call_depth,
+            max_data_stack: self.max_data_stack,
+            max_locals: self.max_locals,
+            max_memory_stack: self.max_memory_stack,
+            max_heap: self.max_heap,
+            max_object_slots: self.max_object_slots,
+            calling_convention: self.calling_convention,
+            devices: DeviceMap::default(), // Reading a device could change it
+        };
+        // A program that stops early still reports the effects it had so far.
+        let _ = shadow.run();
+        shadow.take_effects()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*; // Bring the entire VM module into the test scope
+
+    #[test]
+    fn test_set_register() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 42), // Set reg0 to 42
+            Instruction::SetReg(1, 100), // Set reg1 to 100
+        ]);
+
+        vm.run().unwrap();
+
+        // Assert that the registers were set correctly
+        assert_eq!(vm.registers[0], Value::Int(42));
+        assert_eq!(vm.registers[1], Value::Int(100));
+    }
+
+    #[test]
+    fn test_addition() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 42),        // Set reg0 to 42
+            Instruction::SetReg(1, 58),        // Set reg1 to 58
+            Instruction::Add(0, 1, 2),         // Add reg0 and reg1, store in reg2
+        ]);
+
+        vm.run().unwrap();
+
+        // Assert that the addition was performed correctly
+        assert_eq!(vm.registers[2], Value::Int(100));
+    }
+
+    #[test]
+    fn test_subtraction() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 42),        // Set reg0 to 42
+            Instruction::SetReg(1, 58),        // Set reg1 to 58
+            Instruction::Sub(1, 0, 2),         // Subtract reg0 from reg1, store in reg2
+        ]);
+
+        vm.run().unwrap();
+
+        // Assert that the subtraction was performed correctly
+        assert_eq!(vm.registers[2], Value::Int(16)); // 58 - 42 = 16
+    }
+
+    #[test]
+    fn test_allocate_and_free_memory() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 100),        // Set reg0 to 100
+            Instruction::SetReg(1, 200),        // Set reg1 to 200
+            Instruction::AllocateMemory(0, 2),  // Allocate 100 bytes, address into reg2
+            Instruction::AllocateMemory(1, 3),  // Allocate another 200 bytes, address into reg3
+            Instruction::FreeMemory(0),         // Free memory at address 0
+        ]);
+
+        vm.run().unwrap();
+
+        // Assert that the addresses landed in the target registers
+        assert_eq!(vm.registers[2], Value::Int(0));
+        assert_eq!(vm.registers[3], Value::Int(100));
+
+        // Assert that the memory was allocated correctly and that the memory at address 0 was freed
+        assert!(vm.memory.borrow().regions.contains_key(&100)); // Memory at address 100 (next available address)
+        assert!(!vm.memory.borrow().regions.contains_key(&0));  // Memory at address 0 should have been freed
+    }
+
+    #[test]
+    fn test_zero_size_allocation() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 0),
+            Instruction::SetReg(1, 10),
+            Instruction::AllocateMemory(0, 2), // An empty region still gets an address of its own
+            Instruction::AllocateMemory(1, 3), // 1..11
+            Instruction::FreeMemory(0),
+        ]);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], Value::Int(0));
+        assert_eq!(vm.registers[3], Value::Int(1));
+        let memory = vm.memory.borrow();
+        let regions: Vec<_> = memory.regions().map(|region| (region.address, region.size)).collect();
+        assert_eq!(regions, vec![(1, 10)]); // Freeing the empty region leaves the other alone
+        assert_eq!(memory.free_blocks().count(), 1);
+
+        // The counters agree with the regions
+        let stats = vm.memory_stats();
+        assert_eq!((stats.allocations, stats.frees), (2, 1));
+        assert_eq!(stats.live_allocations(), regions.len());
+        assert_eq!(stats.live_bytes, 10);
+        let leaks: Vec<_> = stats.leaks().map(|leak| (leak.region.address, leak.region.size)).collect();
+        assert_eq!(leaks, regions);
+    }
+
+    #[test]
+    fn test_allocated_address_is_usable() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 16),
+            Instruction::AllocateMemory(0, 1),   // 0..16
+            Instruction::AllocateMemory(0, 2),   // 16..32
+            Instruction::FreeMemory(0),
+            Instruction::SetReg(0, 8),
+            Instruction::AllocateMemory(0, 1),   // Reuses the freed block
+            Instruction::SetReg(3, 2),
+            Instruction::SetReg(4, 0x5A),
+            Instruction::StoreIndirect(2, 3, 4), // Through the returned addresses
+            Instruction::LoadIndirect(2, 3, 5),
+            Instruction::AllocateMemory(8, 0),   // No such size register
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::InvalidRegister(8)));
+        assert_eq!(vm.registers[1], Value::Int(0));
+        assert_eq!(vm.registers[2], Value::Int(16));
+        assert_eq!(vm.registers[5], Value::Int(0x5A));
+        assert_eq!(region_data(&vm, 16)[2], 0x5A);
+    }
+
+    #[test]
+    fn test_memory_store_and_load() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 100),         // Set reg0 to 100
+            Instruction::AllocateMemory(0, 2),   // Allocate 100 bytes
+            Instruction::SetReg(0, 42),          // Set reg0 to 42
+            Instruction::StoreToMemory(0, 0, 0), // Store reg0 value into memory at address 0
+            Instruction::SetReg(1, 0),           // Set reg1 to 0 (for testing load)
+            Instruction::LoadFromMemory(0, 0, 1), // Load memory at address 0 into reg1
+        ]);
+
+        vm.run().unwrap();
+
+        // Assert that the value was stored and loaded correctly
+        assert_eq!(vm.registers[1], Value::Int(42)); // reg1 should contain the value 42 loaded from memory
+    }
+
+    #[test]
+    fn test_memory_load_with_offset() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 8),
+            Instruction::AllocateMemory(0, 5),
+            Instruction::SetReg(0, 7),
+            Instruction::SetReg(1, 9),
+            Instruction::StoreToMemory(0, 0, 3),
+            Instruction::StoreToMemory(2, 1, 5),
+            Instruction::LoadFromMemory(0, 3, 2),  // The byte stored at 0 + 3
+            Instruction::LoadFromMemory(3, 4, 3),  // The byte stored at 2 + 5
+            Instruction::LoadFromMemory(0, 0, 4),  // Never written
+        ]);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], Value::Int(7));
+        assert_eq!(vm.registers[3], Value::Int(9));
+        assert_eq!(vm.registers[4], Value::Int(0));
+
+        // The offset is bounds-checked like a store's
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 8),
+            Instruction::AllocateMemory(0, 0),
+            Instruction::LoadFromMemory(4, 4, 0),
+        ]);
+        assert_eq!(vm.run(), Err(VmError::NoMemoryRegion(8)));
+
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 8),
+            Instruction::AllocateMemory(0, 0),
+            Instruction::LoadFromMemory(usize::MAX, 1, 0),
+        ]);
+        assert_eq!(vm.run(), Err(VmError::OutOfBounds(usize::MAX)));
+    }
+
+    #[test]
+    fn test_jump_if_zero() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 0),           // Set reg0 to 0
+            Instruction::JumpIfZero(0, 1),       // Skip the next instruction if reg0 is 0
+            Instruction::SetReg(1, 100),         // This will be skipped due to the jump
+            Instruction::SetReg(2, 200),         // This will be executed after the jump
+        ]);
+
+        vm.run().unwrap();
+
+        // Assert that reg2 was set (since reg0 was 0, we jumped over the previous instructions)
+        assert_eq!(vm.registers[1], Value::Int(0));
+        assert_eq!(vm.registers[2], Value::Int(200));
+    }
+
+    #[test]
+    fn test_halt_execution() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 42), // Set reg0 to 42
+            Instruction::Halt,           // Halt the program
+            Instruction::SetReg(1, 100), // This should not be executed
+        ]);
+
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+
+        // Assert that the program halts and the second instruction does not execute
+        assert_eq!(vm.registers[0], Value::Int(42));
+        assert_eq!(vm.registers[1], Value::Int(0)); // reg1 should still be 0 since the second instruction was never executed
+    }
+
+    #[test]
+    fn test_function_call_and_return() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 42),    // Set reg0 to 42
+            Instruction::Call(4),           // Call function at address 4
+            Instruction::Print(0),          // Print reg0 after return (should be 99)
+            Instruction::Halt,
+            // Function body starts here (address 4)
+            Instruction::SetReg(0, 99),     // Set reg0 to 99 inside function
+            Instruction::Return,            // Return from function
+        ]);
+
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+
+        // Assert that the function ran and returned to the instruction after the call
+        assert_eq!(vm.registers[0], Value::Int(99));
+        assert_eq!(vm.ip, 4);
+        assert!(vm.call_stack().is_empty());
+    }
+
+    #[test]
+    fn test_float_arithmetic() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 3),
+            Instruction::SetReg(1, 2),
+            Instruction::IntToFloat(0, 0), // reg0 = 3.0
+            Instruction::IntToFloat(1, 1), // reg1 = 2.0
+            Instruction::FAdd(0, 1, 2),    // reg2 = 5.0
+            Instruction::FSub(0, 1, 3),    // reg3 = 1.0
+            Instruction::FMul(0, 1, 4),    // reg4 = 6.0
+            Instruction::FDiv(0, 1, 5),    // reg5 = 1.5
+        ]);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], Value::Float(5.0));
+        assert_eq!(vm.registers[3], Value::Float(1.0));
+        assert_eq!(vm.registers[4], Value::Float(6.0));
+        assert_eq!(vm.registers[5], Value::Float(1.5));
+    }
+
+    #[test]
+    fn test_int_float_conversion() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 7),
+            Instruction::SetReg(1, 2),
+            Instruction::IntToFloat(0, 2), // reg2 = 7.0
+            Instruction::IntToFloat(1, 3), // reg3 = 2.0
+            Instruction::FDiv(2, 3, 4),    // reg4 = 3.5
+            Instruction::FloatToInt(4, 5), // reg5 = 3 (truncated)
+            Instruction::SetReg(6, -7),
+            Instruction::IntToFloat(6, 6), // reg6 = -7.0
+            Instruction::FDiv(6, 3, 6),    // reg6 = -3.5
+            Instruction::FloatToInt(6, 7), // reg7 = -3 (truncated toward zero)
+        ]);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], Value::Float(7.0));
+        assert_eq!(vm.registers[4], Value::Float(3.5));
+        assert_eq!(vm.registers[5], Value::Int(3));
+        assert_eq!(vm.registers[7], Value::Int(-3));
+    }
+
+    #[test]
+    fn test_float_compare() {
+        let mut vm = VM::new(vec![
+            Instruction::SetFReg(0, 0.1),
+            Instruction::SetFReg(1, 0.2),
+            Instruction::FAdd(0, 1, 2), // reg2 = 0.30000000000000004 in f64
+            Instruction::FCmp(0, 1, 3), // Less
+            Instruction::FCmp(1, 1, 4), // Equal
+            Instruction::FCmp(2, 0, 5), // Greater
+            Instruction::FSub(0, 0, 6),
+            Instruction::FDiv(6, 6, 6), // 0 / 0 is NaN
+            Instruction::FCmp(6, 6, 7), // NaN is unordered, even with itself
+        ]);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], Value::Float(0.1 + 0.2));
+        assert_eq!(vm.registers[3], Value::Int(-1));
+        assert_eq!(vm.registers[4], Value::Int(0));
+        assert_eq!(vm.registers[5], Value::Int(1));
+        assert_eq!(vm.registers[7], Value::Int(2));
+
+        let mut vm = VM::new(vec![Instruction::SetFReg(0, f64::NAN), Instruction::FCmp(0, 1, 2)]);
+        assert_eq!(vm.run(), Err(VmError::TypeMismatch(1))); // reg1 holds an integer
+    }
+
+    #[test]
+    fn test_integer_op_rejects_float() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 1),
+            Instruction::IntToFloat(0, 1), // reg1 = 1.0
+            Instruction::SetReg(2, 5),
+            Instruction::Add(0, 1, 2), // Type mismatch, reg2 is left untouched
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::TypeMismatch(1)));
+
+        assert_eq!(vm.registers[2], Value::Int(5));
+    }
+
+    #[test]
+    fn test_compact_immediates() {
+        let mut vm = VM::new(vec![
+            Instruction::SetRegShifted(0, 0xFF, 8), // reg0 = 0xFF00
+            Instruction::SetRegByte(1, 0xFF),       // reg1 = -1 (sign-extended)
+            Instruction::SetRegByte(2, 0x7F),       // reg2 = 127
+            Instruction::SetRegShifted(3, 0x80, 24), // reg3 = i32::MIN
+            Instruction::SetRegShifted(4, 0xFF, 25), // Bits shifted out, reg4 is left untouched
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::InvalidShift(25)));
+
+        assert_eq!(vm.registers[0], Value::Int(0xFF00));
+        assert_eq!(vm.registers[1], Value::Int(-1));
+        assert_eq!(vm.registers[2], Value::Int(127));
+        assert_eq!(vm.registers[3], Value::Int(i32::MIN));
+        assert_eq!(vm.registers[4], Value::Int(0));
+    }
+
+    #[test]
+    fn test_load_immediate_round_trip() {
+        let values = [
+            0, 1, -1, 127, -128, 128, 0xFF00, -256, 0x7F00_0000, i32::MIN, i32::MAX, 123_456,
+        ];
+        for value in values {
+            let mut vm = VM::new(vec![Instruction::load_immediate(0, value)]);
+            vm.run().unwrap();
+            assert_eq!(vm.registers[0], Value::Int(value), "value {}", value);
+        }
+
+        assert!(matches!(
+            Instruction::load_immediate(0, -128),
+            Instruction::SetRegByte(0, 0x80)
+        ));
+        assert!(matches!(
+            Instruction::load_immediate(0, 0xFF00),
+            Instruction::SetRegShifted(0, 0xFF, 8)
+        ));
+        assert!(matches!(
+            Instruction::load_immediate(0, 128),
+            Instruction::SetRegShifted(0, 1, 7)
+        ));
+        assert!(matches!(
+            Instruction::load_immediate(0, -256),
+            Instruction::SetReg(0, -256)
+        ));
+    }
+
+    #[test]
+    fn test_memory_absolute_addressing() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 100),
+            Instruction::AllocateMemory(7, 6),    // Region at 0..100
+            Instruction::AllocateMemory(7, 6),    // Region at 100..200
+            Instruction::SetReg(0, 7),
+            Instruction::StoreToMemory(150, 0, 0), // Middle of the second region
+            Instruction::SetReg(0, 9),
+            Instruction::StoreToMemory(100, 0, 60), // Base plus offset, same region
+            Instruction::LoadFromMemory(150, 0, 1),
+            Instruction::LoadFromMemory(160, 0, 2),
+            Instruction::LoadFromMemory(200, 0, 3), // Not inside any region
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::NoMemoryRegion(200)));
+
+        assert_eq!(region_data(&vm, 100)[50], 7);
+        assert_eq!(region_data(&vm, 100)[60], 9);
+        assert_eq!(vm.registers[1], Value::Int(7));
+        assert_eq!(vm.registers[2], Value::Int(9));
+        assert_eq!(vm.registers[3], Value::Int(0));
+        assert_eq!(vm.check_writable(199, 1), Ok(()));
+        assert_eq!(vm.check_writable(200, 1), Err(VmError::NoMemoryRegion(200)));
+    }
+
+    #[test]
+    fn test_memory_spans_adjacent_regions() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 2),
+            Instruction::AllocateMemory(7, 6), // 0..2
+            Instruction::AllocateMemory(7, 6), // 2..4
+            Instruction::SetReg(7, 4),
+            Instruction::AllocateMemory(7, 6), // 4..8
+            Instruction::SetReg(0, 0),
+            Instruction::SetReg(1, 1),
+            Instruction::SetReg(2, -1),
+            Instruction::Store32(0, 1, 2), // Bytes 1..5 cross three regions
+            Instruction::FreeMemory(2),
+            Instruction::Load32(0, 1, 3),  // Byte 2 is no longer allocated
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::OutOfBounds(1)));
+
+        let memory = vm.memory.borrow();
+        assert_eq!(memory.bytes(0, 2), Ok(&[0, 0xFF][..]));
+        assert_eq!(memory.bytes(4, 2), Ok(&[0xFF, 0][..]));
+        assert_eq!(memory.bytes(2, 1), Err(VmError::NoMemoryRegion(2)));
+        assert_eq!(memory.bytes(6, 4), Err(VmError::OutOfBounds(6)));
+        assert_eq!(memory.regions().map(|region| region.address).collect::<Vec<_>>(), vec![0, 4]);
+    }
+
+    #[test]
+    fn test_size_of() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 64),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SizeOf(0, 0),  // reg0 = 64
+            Instruction::SetReg(1, -1),
+            Instruction::SizeOf(64, 1), // No region at address 64, reg1 is left untouched
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::NoMemoryRegion(64)));
+
+        assert_eq!(vm.registers[0], Value::Int(64));
+        assert_eq!(vm.registers[1], Value::Int(-1));
+    }
+
+    #[test]
+    fn test_mem_copy_between_regions() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 8),
+            Instruction::AllocateMemory(7, 6), // Source region at 0
+            Instruction::AllocateMemory(7, 6), // Destination region at 8
+            Instruction::SetReg(0, 1),
+            Instruction::StoreToMemory(0, 0, 0),
+            Instruction::SetReg(0, 2),
+            Instruction::StoreToMemory(0, 0, 1),
+            Instruction::SetReg(0, 3),
+            Instruction::StoreToMemory(0, 0, 2),
+            Instruction::MemCopy(8, 0, 3),  // Copy 3 bytes into the second region
+            Instruction::MemCopy(8, 0, 9),  // Runs past the end of the second region, ignored
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::OutOfBounds(8)));
+
+        assert_eq!(region_data(&vm, 8), vec![1, 2, 3, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_mem_copy_overlapping() {
+        let mut vm = VM::new(vec![Instruction::SetReg(7, 8), Instruction::AllocateMemory(7, 6)]);
+        vm.run().unwrap();
+        vm.memory.borrow_mut().write(0, &[1, 2, 3, 4, 5, 0, 0, 0]);
+
+        vm.program = vec![
+            Instruction::MemCopy(2, 0, 5), // Forward overlap
+        ];
+        vm.ip = 0;
+        vm.run().unwrap();
+        assert_eq!(region_data(&vm, 0), vec![1, 2, 1, 2, 3, 4, 5, 0]);
+
+        vm.program = vec![
+            Instruction::MemCopy(0, 2, 5), // Backward overlap
+        ];
+        vm.ip = 0;
+        vm.run().unwrap();
+        assert_eq!(region_data(&vm, 0), vec![1, 2, 3, 4, 5, 4, 5, 0]);
+    }
+
+    #[test]
+    fn test_realloc_memory() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 4),
+            Instruction::AllocateMemory(7, 6), // 0..4
+            Instruction::AllocateMemory(7, 6), // 4..8
+            Instruction::SetReg(0, 0),
+            Instruction::SetReg(1, 0x0403_0201),
+            Instruction::Store32(0, 0, 1),
+            Instruction::SetReg(2, 6),
+            Instruction::ReallocMemory(0, 2, 3), // Doesn't fit at 0, moves to 8
+            Instruction::SetReg(2, 2),
+            Instruction::ReallocMemory(3, 2, 4), // Shrinks into the block freed at 0
+            Instruction::ReallocMemory(4, 2, 5), // Same size, stays
+            Instruction::FreeMemory(4),
+            Instruction::SetReg(6, 4),
+            Instruction::ReallocMemory(6, 2, 6), // Nothing at 4 any more
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::NoMemoryRegion(4)));
+        assert_eq!(vm.registers[3..6], [8, 0, 0].map(Value::Int));
+        assert_eq!(vm.memory.borrow().regions().count(), 1);
+        assert_eq!(region_data(&vm, 0), vec![1, 2]);
+
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 2),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SetReg(0, 0),
+            Instruction::SetReg(1, 0x0201),
+            Instruction::Store16(0, 0, 1),
+            Instruction::SetReg(1, 4),
+            Instruction::ReallocMemory(0, 1, 2), // The last region grows in place
+        ]);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], Value::Int(0));
+        assert_eq!(region_data(&vm, 0), vec![1, 2, 0, 0]);
+    }
+
+    // Records what the program writes, and reads back each byte's offset.
+    struct TestDevice {
+        writes: Rc<RefCell<Vec<(usize, u8)>>>,
+    }
+
+    impl MmioDevice for TestDevice {
+        fn read(&mut self, offset: usize) -> u8 {
+            0x10 + offset as u8
+        }
+
+        fn write(&mut self, offset: usize, value: u8) {
+            self.writes.borrow_mut().push((offset, value));
+        }
+    }
+
+    #[test]
+    fn test_memory_mapped_device() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let program = |last: Instruction| {
+            let mut vm = VM::new(vec![
+                Instruction::SetReg(0, 8),
+                Instruction::AllocateMemory(0, 1), // Below the device
+                Instruction::SetReg(2, 0x100),
+                Instruction::SetReg(3, 0x41),
+                Instruction::SetReg(4, 0),
+                last,
+            ]);
+            let device = TestDevice {
+                writes: writes.clone(),
+            };
+            assert!(vm.map_device(0x100..0x104, device));
+            vm
+        };
+
+        let mut vm = program(Instruction::Store32(2, 4, 3));
+        vm.program.extend([
+            Instruction::StoreToMemory(0x103, 3, 0),
+            Instruction::LoadFromMemory(0x100, 2, 5),
+            Instruction::Load32(2, 4, 6),
+            Instruction::MemCopy(0, 0x101, 2),
+        ]);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], Value::Int(0));
+        assert_eq!(vm.registers[5], Value::Int(0x12));
+        assert_eq!(vm.registers[6], Value::Int(0x13121110));
+        assert_eq!(region_data(&vm, 0), vec![0x11, 0x12, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(*writes.borrow(), [(0, 0x41), (1, 0), (2, 0), (3, 0), (3, 0x41)]);
+        assert_eq!(vm.memory.borrow().reserved().collect::<Vec<_>>(), [(0x100, 4)]);
+
+        // Accesses must lie within the device, and devices can't overlap memory
+        let mut vm = program(Instruction::MemCopy(0, 0x102, 4));
+        assert_eq!(vm.run(), Err(VmError::OutOfBounds(0x102)));
+        let mut vm = program(Instruction::LoadFromMemory(0xFF, 0, 6));
+        assert_eq!(vm.run(), Err(VmError::NoMemoryRegion(0xFF)));
+        let mut vm = program(Instruction::Store16(2, 0, 3)); // 0x108..0x10A
+        assert_eq!(vm.run(), Err(VmError::NoMemoryRegion(0x108)));
+        let device = || TestDevice {
+            writes: writes.clone(),
+        };
+        assert!(!vm.map_device(0x102..0x110, device()));
+        assert!(!vm.map_device(4..6, device()));
+        assert!(!vm.map_device(0x200..0x200, device()));
+        assert!(vm.map_device(0x104..0x108, device()));
+    }
+
+    #[test]
+    fn test_allocation_size() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, -5),
+            Instruction::AllocateMemory(0, 1),
+        ]);
+        assert_eq!(vm.run(), Err(VmError::InvalidSize(-5)));
+        assert_eq!(vm.memory_stats().allocations, 0);
+
+        // Regions must end within the 32-bit address space, here with an
+        // 8-byte region at 0 and the allocator 8 bytes short of the end
+        let program = |last: Instruction| {
+            let vm = VM::new(vec![Instruction::SetReg(0, 16), last]);
+            vm.memory.borrow_mut().allocate_at(0, 8);
+            vm.memory.borrow_mut().next_free_address = 0xFFFF_FFF8;
+            vm
+        };
+        for last in [
+            Instruction::AllocateMemory(0, 2),
+            Instruction::ReallocMemory(1, 0, 2),
+        ] {
+            let mut vm = program(last.clone());
+            assert_eq!(vm.run(), Err(VmError::OutOfMemory(16)), "{:?}", last);
+            assert_eq!(vm.registers[2], Value::Int(0));
+            assert_eq!(vm.memory.borrow().regions().count(), 1);
+        }
+
+        let mut vm = program(Instruction::SetReg(0, -1));
+        vm.program.push(Instruction::ReallocMemory(1, 0, 2));
+        assert_eq!(vm.run(), Err(VmError::InvalidSize(-1)));
+    }
+
+    #[test]
+    fn test_paged_memory() {
+        let config = PagingConfig {
+            page_size: 16,
+            memory_size: 128,
+            stack_size: 32,
+            guard_pages: 1,
+        };
+        let program = |last: Vec<Instruction>| {
+            let mut instructions = vec![
+                Instruction::SetReg(0, 20),
+                Instruction::AllocateMemory(0, 1), // 16..36, mapping 16..48
+                Instruction::SetReg(2, 0x55),
+            ];
+            instructions.extend(last);
+            let mut vm = VM::from_program(Program {
+                instructions,
+                data: b"hi".to_vec(),
+                ..Program::default()
+            });
+            assert!(vm.enable_paging(config));
+            vm
+        };
+
+        let mut vm = program(vec![
+            Instruction::StoreToMemory(40, 2, 0), // Unallocated, but in a mapped page
+            Instruction::StoreToMemory(100, 2, 27), // The top of the stack
+        ]);
+        assert!(!vm.enable_paging(config));
+        let segment = |kind, base, size, protection| Segment {
+            kind,
+            base,
+            size,
+            protection,
+        };
+        assert_eq!(
+            vm.segments(),
+            [
+                segment(SegmentKind::Code, 0, 5, Protection::READ_ONLY),
+                segment(SegmentKind::Data, 0, 2, Protection::READ_ONLY),
+                segment(SegmentKind::Heap, 16, 64, Protection::READ_WRITE),
+                segment(SegmentKind::Stack, 96, 32, Protection::READ_WRITE),
+            ]
+        );
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], Value::Int(16));
+        let memory = vm.memory.borrow();
+        assert_eq!(memory.mapped_pages(), [0, 16, 32, 96, 112]);
+        assert_eq!(memory.bytes(40, 1), Ok(&[0x55][..]));
+        assert_eq!(memory.bytes(127, 1), Ok(&[0x55][..]));
+        assert_eq!(memory.bytes(2, 4), Ok(&[0; 4][..])); // The rest of the data page
+        assert_eq!(memory.bytes(44, 8), Err(VmError::PageFault(48)));
+        drop(memory);
+
+        for (last, error) in [
+            (Instruction::StoreToMemory(48, 2, 0), VmError::PageFault(48)),
+            (Instruction::LoadFromMemory(80, 15, 3), VmError::PageFault(95)), // Guard page
+            (Instruction::AllocateMemory(3, 4), VmError::OutOfMemory(0x55)),
+            (Instruction::ReallocMemory(1, 3, 4), VmError::OutOfMemory(0x55)),
+            (Instruction::StoreToMemory(0, 2, 0), VmError::ReadOnlyMemory(0)),
+        ] {
+            let mut vm = program(vec![Instruction::SetReg(3, 0x55), last.clone()]);
+            assert_eq!(vm.run(), Err(error), "{:?}", last);
+        }
+
+        // The heap grows up to the guard page, and freeing unmaps its pages
+        let mut vm = program(vec![
+            Instruction::SetReg(3, 44),
+            Instruction::ReallocMemory(1, 3, 4), // 16..60, in place
+            Instruction::AllocateMemory(3, 5),   // Too big for 60..80
+        ]);
+        assert_eq!(vm.run(), Err(VmError::OutOfMemory(44)));
+        assert_eq!(vm.registers[4], Value::Int(16));
+        vm.memory.borrow_mut().free(16);
+        assert_eq!(vm.memory.borrow().mapped_pages(), [0, 96, 112]);
+        assert_eq!(vm.memory.borrow().bytes(16, 1), Err(VmError::PageFault(16)));
+    }
+
+    #[test]
+    fn test_garbage_collection() {
+        let program = |last: Vec<Instruction>| {
+            let mut program = vec![
+                Instruction::NewObject(2, 0), // A pair
+                Instruction::NewObject(1, 1),
+                Instruction::SetReg(2, 7),
+                Instruction::StoreSlot(2, 1, 0), // r1.0 = 7
+                Instruction::StoreSlot(1, 0, 1), // r0.1 = r1
+                Instruction::ClearReg(1),        // Only the pair reaches it now
+                Instruction::NewObject(3, 3),
+                Instruction::ClearReg(3),     // Garbage
+                Instruction::NewObject(3, 3), // Doesn't fit without collecting
+                Instruction::LoadSlot(0, 1, 4),
+                Instruction::LoadSlot(4, 0, 5),
+            ];
+            program.extend(last);
+            VM::with_config(
+                program,
+                VmConfig {
+                    max_object_slots: 8,
+                    ..VmConfig::default()
+                },
+            )
+        };
+
+        let mut vm = program(Vec::new());
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(vm.registers[3], Value::Ref(2)); // The collected handle, reused
+        assert_eq!(vm.registers[4], Value::Ref(1));
+        assert_eq!(vm.registers[5], Value::Int(7));
+        let objects = vm.objects();
+        assert_eq!((objects.len(), objects.used_slots()), (3, 6));
+        assert_eq!((objects.collections, objects.collected), (1, 1));
+        assert_eq!(objects.get(0), Some(&[Value::Int(0), Value::Ref(1)][..]));
+
+        // Snapshots keep the objects
+        let mut restored = program(Vec::new());
+        restored.restore(&vm.snapshot());
+        assert_eq!(restored.objects(), vm.objects());
+
+        // The data stack is a root, and only references reach objects
+        vm.program.extend([Instruction::Push(4), Instruction::ClearReg(4)]);
+        vm.run().unwrap();
+        assert_eq!(vm.collect_garbage(), 0);
+        vm.program.extend([Instruction::Pop(6), Instruction::ClearReg(0)]);
+        vm.run().unwrap();
+        assert_eq!(vm.collect_garbage(), 1); // The pair
+        assert_eq!(vm.objects().get(1), Some(&[Value::Int(7)][..]));
+
+        for (last, error) in [
+            (Instruction::NewObject(3, 6), VmError::OutOfMemory(3)),
+            (Instruction::LoadSlot(2, 0, 6), VmError::TypeMismatch(2)),
+            (Instruction::StoreSlot(2, 0, 2), VmError::IndexOutOfBounds(2)),
+            (Instruction::AddImm(0, 1, 6), VmError::TypeMismatch(0)),
+            (Instruction::Store32(0, 2, 2), VmError::TypeMismatch(0)),
+        ] {
+            let mut vm = program(vec![last.clone()]);
+            assert_eq!(vm.run(), Err(error), "{:?}", last);
+        }
+        let mut vm = program(vec![Instruction::LoadSlot(6, 0, 6)]);
+        vm.registers[6] = Value::Ref(9);
+        assert_eq!(vm.run(), Err(VmError::InvalidReference(9)));
+    }
+
+    #[test]
+    fn test_arrays() {
+        let program = |last: Vec<Instruction>| {
+            let mut program = vec![
+                Instruction::SetReg(0, 3),
+                Instruction::NewArray(0, 1),
+                Instruction::SetReg(2, 2),
+                Instruction::SetFReg(3, 2.5),
+                Instruction::ArraySet(1, 2, 3), // a[2] = 2.5
+                Instruction::NewArray(2, 4),
+                Instruction::ClearReg(5),
+                Instruction::ArraySet(1, 5, 4), // a[0] = a nested array
+                Instruction::ArrayLen(1, 6),
+                Instruction::ArrayGet(1, 2, 7),
+            ];
+            program.extend(last);
+            VM::new(program)
+        };
+
+        let mut vm = program(Vec::new());
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(vm.registers[6], Value::Int(3));
+        assert_eq!(vm.registers[7], Value::Float(2.5));
+        assert_eq!(
+            vm.objects().get(0),
+            Some(&[Value::Ref(1), Value::Int(0), Value::Float(2.5)][..])
+        );
+        // Arrays are objects, collected once unreachable
+        vm.registers[1] = Value::Int(0);
+        assert_eq!(vm.collect_garbage(), 1);
+        assert_eq!(vm.objects().len(), 1); // r4 still holds the nested one
+
+        for (last, error) in [
+            (Instruction::ArrayGet(1, 0, 7), VmError::IndexOutOfBounds(3)),
+            (Instruction::ArraySet(1, 6, 2), VmError::IndexOutOfBounds(3)),
+            (Instruction::ArrayGet(0, 2, 7), VmError::TypeMismatch(0)),
+            (Instruction::ArrayLen(6, 7), VmError::TypeMismatch(6)),
+            (Instruction::ArrayGet(1, 3, 7), VmError::TypeMismatch(3)),
+        ] {
+            let mut vm = program(vec![last.clone()]);
+            assert_eq!(vm.run(), Err(error), "{:?}", last);
+        }
+        let mut vm = program(vec![Instruction::SetReg(2, -1), Instruction::ArrayGet(1, 2, 7)]);
+        assert_eq!(vm.run(), Err(VmError::IndexOutOfBounds(u32::MAX as usize)));
+        let mut vm = program(vec![Instruction::SetReg(2, 70000), Instruction::NewArray(2, 7)]);
+        assert_eq!(vm.run(), Err(VmError::OutOfMemory(70000)));
+    }
+
+    #[test]
+    fn test_strings() {
+        let program = |last: Vec<Instruction>| {
+            let mut instructions = vec![
+                Instruction::SetReg(0, 0),
+                Instruction::SetReg(1, 7),
+                Instruction::NewString(0, 1, 2), // "Hello, "
+                Instruction::SetReg(0, 7),
+                Instruction::SetReg(1, 5),
+                Instruction::NewString(0, 1, 3), // "world"
+                Instruction::StrConcat(2, 3, 4),
+                Instruction::StrLen(4, 5),
+                Instruction::SetReg(0, 7),
+                Instruction::StrByte(4, 0, 6),
+                Instruction::StrCmp(2, 3, 7),
+                Instruction::PrintString(4),
+            ];
+            instructions.extend(last);
+            VM::from_program(Program {
+                instructions,
+                data: b"Hello, world".to_vec(),
+                ..Program::default()
+            })
+        };
+
+        let output = SharedBuffer::default();
+        let mut vm = program(Vec::new());
+        vm.set_output(output.clone());
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(output.contents(), "Hello, world");
+        assert_eq!(vm.objects().string(2), Some(&b"Hello, world"[..]));
+        assert_eq!(vm.registers[5], Value::Int(12));
+        assert_eq!(vm.registers[6], Value::Int(b'w' as i32));
+        assert_eq!(vm.registers[7], Value::Int(-1)); // "Hello, " < "world"
+        assert_eq!(vm.objects().used_slots(), 1 + 1 + 2);
+
+        // Strings are collected like other objects
+        vm.registers[4] = Value::Int(0);
+        assert_eq!(vm.collect_garbage(), 1);
+        assert_eq!(vm.objects().string(2), None);
+
+        for (last, error) in [
+            (Instruction::StrByte(4, 5, 6), VmError::IndexOutOfBounds(12)),
+            (Instruction::StrLen(5, 6), VmError::TypeMismatch(5)),
+            (Instruction::LoadSlot(4, 0, 6), VmError::TypeMismatch(4)),
+            (Instruction::ArrayLen(4, 6), VmError::TypeMismatch(4)),
+            (Instruction::NewString(5, 5, 6), VmError::NoMemoryRegion(12)),
+        ] {
+            let mut vm = program(vec![last.clone()]);
+            vm.set_output(io::sink());
+            assert_eq!(vm.run(), Err(error), "{:?}", last);
+        }
+        let mut vm = program(vec![Instruction::NewObject(1, 1), Instruction::StrCmp(2, 1, 6)]);
+        vm.set_output(io::sink());
+        assert_eq!(vm.run(), Err(VmError::TypeMismatch(1)));
+    }
+
+    #[test]
+    fn test_maps() {
+        let program = |last: Vec<Instruction>| {
+            let mut instructions = vec![
+                Instruction::MapNew(0),
+                Instruction::SetReg(1, 42),
+                Instruction::SetFReg(2, 0.5),
+                Instruction::MapSet(0, 1, 2), // m[42] = 0.5
+                Instruction::SetReg(3, 0),
+                Instruction::SetReg(4, 3),
+                Instruction::NewString(3, 4, 5), // "key"
+                Instruction::NewObject(1, 6),
+                Instruction::MapSet(0, 5, 6), // m["key"] = an object
+                Instruction::NewString(3, 4, 6), // Another "key"
+                Instruction::MapGet(0, 6, 7),
+                Instruction::MapHas(0, 1, 4),
+                Instruction::MapDel(0, 1),
+                Instruction::MapHas(0, 1, 3),
+                Instruction::MapDel(0, 1), // Already gone
+            ];
+            instructions.extend(last);
+            VM::from_program(Program {
+                instructions,
+                data: b"key".to_vec(),
+                ..Program::default()
+            })
+        };
+
+        let mut vm = program(Vec::new());
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(vm.registers[7], Value::Ref(2)); // Strings with equal contents are one key
+        assert_eq!(vm.registers[4], Value::Int(1));
+        assert_eq!(vm.registers[3], Value::Int(0));
+        assert_eq!(
+            vm.objects().map(0),
+            Some(&[(MapKey::String(b"key".to_vec()), Value::Ref(2))][..])
+        );
+        assert_eq!(vm.objects().used_slots(), 2 + 1 + 1 + 1); // Entry, object, two strings
+
+        // Values a map holds are reachable, keys are copies
+        vm.registers[5] = Value::Int(0);
+        vm.registers[6] = Value::Int(0);
+        vm.registers[7] = Value::Int(0);
+        assert_eq!(vm.collect_garbage(), 2);
+        assert!(vm.objects().get(2).is_some());
+
+        for (last, error) in [
+            (Instruction::MapGet(0, 1, 7), VmError::MissingKey("42".to_string())),
+            (Instruction::MapGet(0, 2, 7), VmError::TypeMismatch(2)),
+            (Instruction::MapGet(5, 1, 7), VmError::TypeMismatch(5)),
+            (Instruction::MapSet(6, 1, 7), VmError::TypeMismatch(6)),
+            (Instruction::LoadSlot(0, 0, 7), VmError::TypeMismatch(0)),
+        ] {
+            let mut vm = program(vec![last.clone()]);
+            assert_eq!(vm.run(), Err(error), "{:?}", last);
+        }
+        let mut vm = program(vec![Instruction::MapDel(0, 5), Instruction::MapGet(0, 5, 7)]);
+        assert_eq!(vm.run(), Err(VmError::MissingKey("\"key\"".to_string())));
+    }
+
+    #[test]
+    fn test_tagged_registers() {
+        let program = |mode: RegisterMode, last: Vec<Instruction>| {
+            let mut instructions = vec![
+                Instruction::SetReg(0, i32::MAX),
+                Instruction::SetReg(1, 2),
+                Instruction::Mul(0, 1, 2), // Doesn't wrap in tagged mode
+                Instruction::SetFReg(3, 1.5),
+                Instruction::Add(3, 3, 4),
+                Instruction::Lt(1, 2, 0),
+                Instruction::TypeOf(5, 6), // Never written
+                Instruction::MapNew(7),
+                Instruction::TypeOf(7, 7),
+            ];
+            instructions.extend(last);
+            let mut vm = VM::new(instructions);
+            vm.set_register_mode(mode);
+            vm
+        };
+
+        let mut vm = program(RegisterMode::Tagged, Vec::new());
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(vm.registers[..], [
+            Value::Int64(1),
+            Value::Int64(2),
+            Value::Int64(2 * i64::from(i32::MAX)),
+            Value::Float(1.5),
+            Value::Float(3.0),
+            Value::Nil,
+            Value::Int64(0),
+            Value::Int64(5),
+        ]);
+
+        // Raw mode wraps, and floats take the float instructions
+        let mut vm = program(RegisterMode::Raw, Vec::new());
+        assert_eq!(vm.run(), Err(VmError::TypeMismatch(3)));
+        assert_eq!(vm.registers[2], Value::Int(-2));
+
+        for (last, error) in [
+            (Instruction::Add(1, 3, 7), VmError::TypeMismatch(3)),
+            (Instruction::Sub(5, 1, 7), VmError::TypeMismatch(5)),
+            (Instruction::Gte(3, 5, 7), VmError::TypeMismatch(5)),
+            (Instruction::Div(1, 6, 7), VmError::DivisionByZero),
+            (Instruction::JumpIfZero(2, 0), VmError::Overflow), // Doesn't fit in an i32
+        ] {
+            let mut vm = program(RegisterMode::Tagged, vec![last.clone()]);
+            assert_eq!(vm.run(), Err(error), "{:?}", last);
+        }
+
+        // Values of different types are unequal, and clearing makes nil again
+        let mut vm = program(RegisterMode::Tagged, vec![
+            Instruction::Eq(5, 5, 0),
+            Instruction::Neq(3, 1, 1),
+            Instruction::ClearReg(3),
+        ]);
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(vm.registers[..4], [Value::Int64(1), Value::Int64(1), Value::Int64(2 * i64::from(i32::MAX)), Value::Nil]);
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let program = |last: Vec<Instruction>| {
+            let mut program = vec![
+                Instruction::SetReg(7, 10),
+                Instruction::AllocateMemory(7, 6), // 0..10
+            ];
+            program.extend(last);
+            VM::with_config(
+                program,
+                VmConfig {
+                    max_heap: 16,
+                    ..VmConfig::default()
+                },
+            )
+        };
+
+        let mut vm = program(vec![Instruction::AllocateMemory(7, 5)]);
+        assert_eq!(vm.run(), Err(VmError::OutOfMemory(10)));
+        assert_eq!(vm.memory_stats().live_bytes, 10);
+
+        // Freed memory counts no more, and resizing only counts the new size
+        let mut vm = program(vec![
+            Instruction::FreeMemory(0),
+            Instruction::AllocateMemory(7, 5),
+            Instruction::SetReg(7, 16),
+            Instruction::ReallocMemory(5, 7, 5),
+            Instruction::SetReg(7, 17),
+            Instruction::ReallocMemory(5, 7, 5),
+        ]);
+        assert_eq!(vm.run(), Err(VmError::OutOfMemory(17)));
+        assert_eq!(vm.memory_stats().live_bytes, 16);
+
+        // Another VM's regions in a shared store don't count
+        let memory = Rc::new(RefCell::new(MemoryStore::new()));
+        memory.borrow_mut().allocate_at(0, 100);
+        let mut vm = VM::new_shared(program(Vec::new()).program, memory);
+        vm.set_max_heap(10);
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(vm.registers[6], Value::Int(100));
+    }
+
+    #[test]
+    fn test_memory_protection() {
+        let program = |last: Instruction| {
+            VM::new(vec![
+                Instruction::SetReg(0, 8),
+                Instruction::AllocateMemory(0, 1), // 0..8
+                Instruction::AllocateMemory(0, 2), // 8..16
+                Instruction::SetReg(3, 0x55),
+                Instruction::StoreToMemory(1, 3, 0),
+                Instruction::ProtectMemory(1, 0b01), // Read-only
+                Instruction::ProtectMemory(2, 0b00), // No access
+                last,
+            ])
+        };
+
+        let mut vm = program(Instruction::LoadFromMemory(1, 0, 4));
+        vm.run().unwrap();
+        assert_eq!(vm.registers[4], Value::Int(0x55));
+        let regions: Vec<_> = vm.memory.borrow().regions().map(|region| region.protection).collect();
+        assert_eq!(regions, [Protection::READ_ONLY, Protection::from_bits(0).unwrap()]);
+
+        for (last, error) in [
+            (Instruction::StoreToMemory(1, 3, 0), VmError::ReadOnlyMemory(1)),
+            (Instruction::MemFill(6, 3, 2), VmError::ReadOnlyMemory(6)),
+            (Instruction::LoadFromMemory(8, 0, 4), VmError::UnreadableMemory(8)),
+            (Instruction::Load16U(1, 0, 4), VmError::UnreadableMemory(8)), // At r1 + r0
+            (Instruction::MemCopy(0, 8, 1), VmError::UnreadableMemory(8)),
+            (Instruction::ProtectMemory(1, 0b100), VmError::InvalidProtection(0b100)),
+            (Instruction::ProtectMemory(3, 0b11), VmError::NoMemoryRegion(0x55)),
+        ] {
+            assert_eq!(program(last.clone()).run(), Err(error), "{:?}", last);
+        }
+
+        // Protection can be lifted again, survives a resize and ends with a free
+        let mut vm = program(Instruction::ProtectMemory(2, 0b11));
+        vm.program.extend([
+            Instruction::StoreToMemory(8, 3, 0),
+            Instruction::SetReg(4, 16),
+            Instruction::ReallocMemory(1, 4, 5), // Moves to 16..32
+            Instruction::FreeMemory(8),
+            Instruction::AllocateMemory(0, 6), // Reuses freed memory, read-write
+        ]);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[5], Value::Int(16));
+        let memory = vm.memory.borrow();
+        assert_eq!(memory.region(16).map(|region| region.protection), Some(Protection::READ_ONLY));
+        assert_eq!(memory.bytes(17, 1), Ok(&[0x55][..]));
+        let Value::Int(reused) = vm.registers[6] else {
+            panic!("expected an address in r6");
+        };
+        assert_eq!(memory.region(reused as usize).map(|region| region.protection), Some(Protection::READ_WRITE));
+        drop(memory);
+
+        // The data section stays read-only
+        let mut vm = VM::from_program(Program {
+            instructions: vec![Instruction::ProtectMemory(0, 0b11)],
+            data: b"const".to_vec(),
+            ..Program::default()
+        });
+        assert_eq!(vm.run(), Err(VmError::ReadOnlyMemory(0)));
+        assert_eq!(vm.memory.borrow().region(0).unwrap().protection, Protection::READ_ONLY);
+    }
+
+    #[test]
+    fn test_mem_copy_register() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 8),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SetReg(0, 0x0403_0201),
+            Instruction::SetReg(1, 0),
+            Instruction::Store32(1, 1, 0),
+            Instruction::SetReg(2, 3),
+            Instruction::SetReg(3, 4),
+            Instruction::MemCopyReg(2, 1, 3), // Overlapping, like `memmove`
+            Instruction::SetReg(3, 0),
+            Instruction::MemCopyReg(2, 1, 3), // Nothing to copy
+            Instruction::SetReg(3, -1),
+            Instruction::MemCopyReg(2, 1, 3), // 0xFFFF_FFFF bytes
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::OutOfBounds(0)));
+        assert_eq!(region_data(&vm, 0), vec![1, 2, 3, 1, 2, 3, 4, 0]);
+    }
+
+    #[test]
+    fn test_mem_fill_register() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 8),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SetReg(0, 6),
+            Instruction::SetReg(1, 0x1FF), // Only the low byte is written
+            Instruction::SetReg(2, 4),
+            Instruction::MemFillReg(0, 1, 2), // Bytes 6..10 span both regions
+            Instruction::SetReg(2, 11),
+            Instruction::MemFillReg(0, 2, 2), // Runs past the second region, ignored
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::OutOfBounds(6)));
+        assert_eq!(region_data(&vm, 0), vec![0, 0, 0, 0, 0, 0, 0xFF, 0xFF]);
+        assert_eq!(region_data(&vm, 8), vec![0xFF, 0xFF, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_mem_fill() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 16),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SetReg(0, 0xAB),
+            Instruction::MemFill(2, 0, 10), // Fill bytes 2..12
+            Instruction::SetReg(0, 0xCD),
+            Instruction::MemFill(10, 0, 7), // Runs past the end of the region, ignored
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::OutOfBounds(10)));
+
+        let data = region_data(&vm, 0);
+        assert_eq!(&data[..2], &[0, 0]);
+        assert!(data[2..12].iter().all(|byte| *byte == 0xAB));
+        assert_eq!(&data[12..], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_time_limit() {
+        // An endless loop with a limit that is exceeded at the first check
+        let mut vm = VM::new(vec![
+            Instruction::AddImm(0, 1, 0),
+            Instruction::Jump(-2),
+        ]);
+        vm.set_time_limit(Some(Duration::ZERO));
+
+        let started = Instant::now();
+        assert_eq!(vm.run(), Err(VmError::TimedOut));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(vm.registers[0], Value::Int(TIME_CHECK_INTERVAL as i32 / 2));
+    }
+
+    // A `Write` sink whose contents can still be read after handing it to the VM.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_json_trace() {
+        let trace = SharedBuffer::default();
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 4),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SetReg(1, 0x0102),
+            Instruction::Store16(0, 0, 1),
+            Instruction::IntToFloat(1, 2),
+            Instruction::Print(7),
+            Instruction::Print(8),
+        ]);
+        vm.set_output(io::sink());
+        vm.set_json_trace(Some(Box::new(trace.clone())));
+
+        assert_eq!(vm.run(), Err(VmError::InvalidRegister(8)));
+        let lines: Vec<_> = trace.contents().lines().map(str::to_string).collect();
+        assert_eq!(lines, [
+            r#"{"ip":0,"memory":[],"opcode":"setw","operands":["r7","4"],"registers":[{"register":7,"value":4}]}"#,
+            r#"{"ip":1,"memory":[],"opcode":"alloc","operands":["r7","r6"],"registers":[{"register":6,"value":0}]}"#,
+            r#"{"ip":2,"memory":[],"opcode":"setw","operands":["r1","258"],"registers":[{"register":1,"value":258}]}"#,
+            r#"{"ip":3,"memory":[{"address":0,"bytes":[2,1]}],"opcode":"st16","operands":["r0","r0","r1"],"registers":[]}"#,
+            r#"{"ip":4,"memory":[],"opcode":"itof","operands":["r1","r2"],"registers":[{"register":2,"value":258.0}]}"#,
+            r#"{"ip":5,"memory":[],"opcode":"print","operands":["r7"],"registers":[]}"#,
+            r#"{"error":"invalid register index 8","ip":6,"memory":[],"opcode":"print","operands":["r8"],"registers":[]}"#,
+        ]);
+    }
+
+    #[test]
+    fn test_profiling() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 3),
+            Instruction::SubImm(0, 1, 0), // 1: runs three times
+            Instruction::JumpIfNonZero(0, -2),
+            Instruction::Halt,
+            Instruction::Halt, // Never reached
+        ]);
+        assert!(vm.profile().is_none());
+        vm.set_profiling(true);
+        vm.run().unwrap();
+
+        let profile = vm.profile().unwrap();
+        let counts: Vec<_> = profile
+            .by_address()
+            .into_iter()
+            .map(|(address, opcode, stats)| (address, opcode, stats.count))
+            .collect();
+        assert_eq!(counts.len(), 4);
+        assert!(counts.contains(&(1, "subi", 3)));
+        assert!(counts.contains(&(2, "jmpnz", 3)));
+        assert!(counts.contains(&(3, "halt", 1)));
+        let opcodes: u64 = profile.by_opcode().iter().map(|(_, stats)| stats.count).sum();
+        assert_eq!(opcodes, 8);
+        assert!(profile.to_string().contains("0001 subi"));
+
+        vm.set_profiling(false);
+        assert!(vm.profile().is_none());
+    }
+
+    #[test]
+    fn test_memory_stats_and_leak_report() {
+        let output = SharedBuffer::default();
+        let mut vm = VM::with_output(
+            vec![
+                Instruction::SetReg(0, 16),
+                Instruction::AllocateMemory(0, 1), // 0..16, freed
+                Instruction::AllocateMemory(0, 2), // 16..32, leaked
+                Instruction::SetReg(0, 8),
+                Instruction::FreeMemory(0),
+                Instruction::AllocateMemory(0, 3), // 0..8, resized to 4 and leaked
+                Instruction::SetReg(0, 4),
+                Instruction::ReallocMemory(3, 0, 3),
+                Instruction::Halt,
+            ],
+            output.clone(),
+        );
+        vm.set_leak_report(true);
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+
+        let stats = vm.memory_stats();
+        assert_eq!((stats.allocations, stats.frees), (4, 2));
+        assert_eq!((stats.live_bytes, stats.peak_bytes), (20, 32));
+        assert_eq!(stats.live_allocations(), 2);
+        let leaks: Vec<_> = stats.leaks().map(|leak| (leak.region.address, leak.ip)).collect();
+        assert_eq!(leaks, vec![(0, 7), (16, 2)]);
+        assert_eq!(
+            output.contents(),
+            concat!(
+                "Allocations: 4, frees: 2, peak usage: 32 bytes\n",
+                "Leaked 20 bytes in 2 regions:\n",
+                "  0x0000: 4 bytes allocated at 0007\n",
+                "  0x0010: 16 bytes allocated at 0002\n",
+            )
+        );
+
+        let output = SharedBuffer::default();
+        let mut vm = VM::with_output(
+            vec![
+                Instruction::SetReg(0, 8),
+                Instruction::AllocateMemory(0, 1),
+                Instruction::FreeMemory(0),
+            ],
+            output.clone(),
+        );
+        vm.set_leak_report(true);
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(output.contents(), "Allocations: 1, frees: 1, peak usage: 8 bytes\nNo leaks\n");
+    }
+
+    #[test]
+    fn test_reference_counting() {
+        let output = SharedBuffer::default();
+        let mut vm = VM::with_output(
+            vec![
+                Instruction::SetReg(7, 8),
+                Instruction::AllocateMemory(7, 0), // 0..8
+                Instruction::AllocateMemory(7, 1), // 8..16
+                Instruction::Retain(0),
+                Instruction::Retain(0),
+                Instruction::Release(0),
+                Instruction::SetReg(6, 0),
+                Instruction::Retain(1),
+                Instruction::Store32(0, 6, 1), // The regions refer to each other
+                Instruction::Store32(1, 6, 0),
+                Instruction::Release(1),
+                Instruction::Release(0),
+                Instruction::Halt,
+            ],
+            output.clone(),
+        );
+        vm.set_leak_report(true);
+        for _ in 0..6 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.memory.borrow().refcount(0), Some(2));
+        assert_eq!(vm.memory.borrow().refcount(8), Some(1));
+        assert_eq!(vm.memory.borrow().refcount(4), None);
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.memory.borrow().refcount(0), Some(1));
+        assert_eq!(vm.memory.borrow().reference_cycles(), [vec![0, 8]]);
+        assert!(output
+            .contents()
+            .ends_with("Reference cycle, never freed: 0x0000, 0x0008\n"));
+
+        // The last reference frees the region, which keeps its count when resized
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 8),
+            Instruction::AllocateMemory(7, 0),
+            Instruction::Retain(0),
+            Instruction::SetReg(7, 64),
+            Instruction::ReallocMemory(0, 7, 0),
+            Instruction::Release(0),
+            Instruction::Release(0),
+            Instruction::Release(0),
+        ]);
+        for _ in 0..5 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.memory.borrow().refcount(0), Some(2));
+        assert_eq!(vm.run(), Err(VmError::NoMemoryRegion(0)));
+        assert_eq!(vm.memory_stats().frees, 2);
+        assert!(vm.memory.borrow().regions().next().is_none());
+        assert!(vm.memory.borrow().refcounts.is_empty());
+
+        let mut vm = VM::new(vec![Instruction::SetReg(0, 4), Instruction::Retain(0)]);
+        assert_eq!(vm.run(), Err(VmError::NoMemoryRegion(4)));
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let program = Program::recursive_fibonacci(12);
+        let mut vm = VM::from_program(program.clone());
+        vm.registers[7] = Value::Float(f64::NAN);
+        vm.memory().borrow_mut().allocate_at(0, 4);
+        for _ in 0..100 {
+            vm.step().unwrap();
+        }
+        assert!(!vm.call_stack().is_empty());
+        let snapshot = vm.snapshot();
+
+        let path = std::env::temp_dir().join(format!("basic_rust_vm_{}.json", std::process::id()));
+        snapshot.save(&path).unwrap();
+        let loaded = VmSnapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.ip(), snapshot.ip());
+        assert!(matches!(loaded.registers()[7], Value::Float(value) if value.is_nan()));
+
+        // A fresh VM picks up where the first one stopped
+        let mut resumed = VM::from_program(program);
+        resumed.restore(&loaded);
+        assert_eq!(resumed.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(resumed.registers()[..7], vm.registers()[..7]);
+        assert_eq!(resumed.registers[0], Value::Int(144));
+        assert_eq!(resumed.memory().borrow().region(0).map(|region| region.size), Some(4));
+
+        // Restoring rewinds a finished VM
+        vm.restore(&snapshot);
+        assert_eq!(vm.state(), VmState::Running);
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.registers[0], Value::Int(144));
+    }
+
+    #[test]
+    fn test_print_unsigned() {
+        let output = SharedBuffer::default();
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, -1),
+            Instruction::Print(0),  // Signed
+            Instruction::PrintU(0), // Unsigned
+        ]);
+        vm.set_output(output.clone());
+
+        vm.run().unwrap();
+
+        assert_eq!(output.contents(), "Register 0: -1\nRegister 0: 4294967295\n");
+    }
+
+    #[test]
+    fn test_with_output() {
+        let output = SharedBuffer::default();
+        let mut vm = VM::with_output(
+            vec![
+                Instruction::SetReg(3, 7),
+                Instruction::IntToFloat(3, 4),
+                Instruction::Print(3),
+                Instruction::Print(4),
+            ],
+            output.clone(),
+        );
+
+        vm.run().unwrap();
+
+        assert_eq!(output.contents(), "Register 3: 7\nRegister 4: 7\n");
+    }
+
+    #[test]
+    fn test_print_str_from_data_section() {
+        let output = SharedBuffer::default();
+        let mut vm = VM::from_program(Program {
+            instructions: vec![
+                Instruction::SetReg(0, 0), // Address of the string
+                Instruction::SetReg(1, 6), // Its length
+                Instruction::PrintStr(0, 1),
+                Instruction::SetReg(7, 4),
+                Instruction::AllocateMemory(7, 6), // Placed after the data section
+                Instruction::SetReg(2, 6),
+                Instruction::Store8(2, 0, 1),
+            ],
+            data: b"hello\n".to_vec(),
+            ..Program::default()
+        });
+        vm.set_output(output.clone());
+
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(output.contents(), "hello\n");
+        assert_eq!(region_data(&vm, 6), vec![6, 0, 0, 0]);
+
+        // The data section can be read but not written or freed
+        for instruction in [
+            Instruction::StoreToMemory(0, 0, 0),
+            Instruction::MemFill(0, 0, 2),
+            Instruction::FreeMemory(0),
+        ] {
+            let mut vm = VM::from_program(Program {
+                instructions: vec![instruction],
+                data: b"hello\n".to_vec(),
+                ..Program::default()
+            });
+            assert_eq!(vm.run(), Err(VmError::ReadOnlyMemory(0)));
+        }
+        let mut vm = VM::from_program(Program {
+            instructions: vec![
+                Instruction::SetReg(0, 4),
+                Instruction::SetReg(1, 3), // One byte past the end of the data
+                Instruction::PrintStr(0, 1),
+            ],
+            data: b"hello\n".to_vec(),
+            ..Program::default()
+        });
+        assert_eq!(vm.run(), Err(VmError::OutOfBounds(4)));
+    }
+
+    #[test]
+    fn test_shared_memory() {
+        let memory = Rc::new(RefCell::new(MemoryStore::new()));
+        let mut writer = VM::new_shared(
+            vec![
+                Instruction::SetReg(7, 16),
+                Instruction::AllocateMemory(7, 6),
+                Instruction::SetReg(0, 99),
+                Instruction::StoreToMemory(0, 0, 4),
+            ],
+            Rc::clone(&memory),
+        );
+        let mut reader = VM::new_shared(
+            vec![
+                Instruction::LoadFromMemory(4, 0, 1),
+                Instruction::SetReg(7, 8),
+                Instruction::AllocateMemory(7, 6), // Allocated after the writer's block
+            ],
+            Rc::clone(&memory),
+        );
+
+        writer.run().unwrap();
+        reader.run().unwrap();
+
+        assert_eq!(reader.registers[1], Value::Int(99));
+        assert!(memory.borrow().region(16).is_some());
+    }
+
+    #[test]
+    fn test_syscall() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 21),
+            Instruction::Syscall(1),   // Doubles reg0
+            Instruction::Syscall(7),   // Not registered
+            Instruction::SetReg(1, 1), // Not executed
+        ]);
+        vm.register_syscall(
+            1,
+            Box::new(|vm: &mut VM| {
+                if let Value::Int(value) = vm.registers[0] {
+                    vm.registers[0] = Value::Int(value * 2);
+                }
+            }),
+        );
+
+        assert_eq!(vm.run(), Err(VmError::UnknownSyscall(7)));
+        assert_eq!(vm.registers[0], Value::Int(42));
+        assert_eq!(vm.registers[1], Value::Int(0));
+    }
+
+    #[test]
+    fn test_record_and_replay_inputs() {
+        let program = vec![
+            Instruction::SetReg(7, 4),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::ReadInt(1),
+            Instruction::ReadByte(2),
+            Instruction::Syscall(3), // Returns a value the host picks, writes memory
+            Instruction::Add(0, 1, 3),
+            Instruction::ReadInt(4), // End of input
+        ];
+        let mut vm = VM::new(program.clone());
+        vm.set_input(io::Cursor::new("12\nx"));
+        let mut calls = 0;
+        vm.register_host_fn(3, move |context| {
+            calls += 1;
+            context.set_return(Value::Int(100 * calls));
+            context.write_memory(0, &[7, 7])
+        });
+        vm.record_inputs();
+        assert_eq!(vm.run(), Err(VmError::EndOfInput));
+        let log = vm.take_input_log();
+        assert_eq!(log.events.len(), 4);
+
+        // The replay needs neither the input nor the host function
+        let path = std::env::temp_dir().join(format!("basic_rust_vm_inputs_{}.json", std::process::id()));
+        log.save(&path).unwrap();
+        let mut replay = VM::new(program.clone());
+        replay.replay_inputs(InputLog::load(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replay.run(), Err(VmError::EndOfInput));
+        assert_eq!(replay.registers(), vm.registers());
+        assert_eq!(replay.registers[3], Value::Int(112));
+        assert_eq!(replay.registers[2], Value::Int(b'x' as i32));
+        assert_eq!(region_data(&replay, 0), vec![7, 7, 0, 0]);
+
+        // A program that reads differently from the recorded one diverges
+        let mut replay = VM::new(vec![Instruction::ReadByte(1)]);
+        replay.replay_inputs(log.clone());
+        assert_eq!(replay.run(), Err(VmError::ReplayMismatch(0)));
+        let mut replay = VM::new(vec![Instruction::Syscall(3)]);
+        replay.replay_inputs(InputLog::default());
+        assert_eq!(replay.run(), Err(VmError::ReplayMismatch(0)));
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 16),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SetReg(0, 0),   // Expected value
+            Instruction::SetReg(1, 500), // New value
+            Instruction::Cas(0, 4, 0, 1, 2), // Word at 4 is 0: swap, reg2 = 1
+            Instruction::Cas(0, 4, 0, 1, 3), // Word at 4 is now 500: no swap, reg3 = 0
+            Instruction::SetReg(4, 500),
+            Instruction::SetReg(5, -7),
+            Instruction::Cas(0, 4, 4, 5, 6), // Matches 500: swap to -7, reg6 = 1
+        ]);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], Value::Int(1));
+        assert_eq!(vm.registers[3], Value::Int(0));
+        assert_eq!(vm.registers[6], Value::Int(1));
+        assert_eq!(vm.load_word(4), Ok(-7));
+    }
+
+    #[test]
+    fn test_compare_and_swap_mismatch() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 8),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SetReg(0, 42),
+            Instruction::StoreToMemory(0, 0, 0), // Word at 0 is 42
+            Instruction::SetReg(1, 7),
+            Instruction::SetReg(2, 9),
+            Instruction::Cas(0, 0, 1, 2, 3), // Expected 7, found 42: reg3 = 0
+            Instruction::SetReg(4, 1),
+            Instruction::Cas(0, 6, 1, 2, 4), // Word would run past the region: reg4 untouched
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::OutOfBounds(6)));
+
+        assert_eq!(vm.registers[3], Value::Int(0));
+        assert_eq!(vm.registers[4], Value::Int(1));
+        assert_eq!(vm.load_word(0), Ok(42));
+    }
+
+    #[test]
+    fn test_clear_registers() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 1),
+            Instruction::SetReg(3, 2),
+            Instruction::SetReg(7, 3),
+            Instruction::IntToFloat(3, 5),
+            Instruction::ClearRegs,
+            Instruction::SetReg(1, 10),
+            Instruction::SetReg(2, 20),
+            Instruction::ClearReg(1),
+        ]);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[1], Value::Int(0));
+        assert_eq!(vm.registers[2], Value::Int(20));
+        for register_index in [0, 3, 4, 5, 6, 7] {
+            assert_eq!(vm.registers[register_index], Value::Int(0));
+        }
+    }
+
+    #[test]
+    fn test_nop_and_mov() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 42),
+            Instruction::Nop,
+            Instruction::Mov(0, 1),
+            Instruction::SetFReg(2, 1.5),
+            Instruction::Mov(2, 3), // Floats are copied as they are
+            Instruction::Mov(0, 8),
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::InvalidRegister(8)));
+        assert_eq!(vm.registers[1], Value::Int(42));
+        assert_eq!(vm.registers[3], Value::Float(1.5));
+    }
+
+    #[test]
+    fn test_lenient_mode_continues_after_error() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 1),
+            Instruction::SetReg(42, 1), // Invalid register, skipped
+            Instruction::SetReg(1, 2),
+        ]);
+        vm.set_strict(false);
+
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(vm.registers[0], Value::Int(1));
+        assert_eq!(vm.registers[1], Value::Int(2));
+    }
+
+    #[test]
+    fn test_strict_mode_stops_on_error() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 1),
+            Instruction::SetReg(42, 1), // Invalid register, stops the VM
+            Instruction::SetReg(1, 2),
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::InvalidRegister(42)));
+        assert_eq!(vm.ip, 2);
+        assert_eq!(vm.registers[0], Value::Int(1));
+        assert_eq!(vm.registers[1], Value::Int(0));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 0x12345678),
+            Instruction::SetReg(1, 8),
+            Instruction::Rol(0, 1, 2), // reg2 = 0x34567812
+            Instruction::Ror(2, 1, 3), // reg3 = 0x12345678
+            Instruction::SetReg(4, 40),
+            Instruction::Rol(0, 4, 5), // 40 mod 32 = 8, reg5 = 0x34567812
+            Instruction::SetReg(6, 1),
+            Instruction::SetReg(7, i32::MIN),
+            Instruction::Rol(7, 6, 7), // The top bit wraps around, reg7 = 1
+            Instruction::SetReg(1, 32),
+            Instruction::Ror(0, 1, 4), // A full turn, reg4 = 0x12345678
+            Instruction::SetReg(1, -1),
+            Instruction::Ror(0, 1, 6), // -1 is 31 mod 32, the same as Rol by 1
+        ]);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], Value::Int(0x34567812));
+        assert_eq!(vm.registers[3], Value::Int(0x12345678));
+        assert_eq!(vm.registers[4], Value::Int(0x12345678));
+        assert_eq!(vm.registers[5], Value::Int(0x34567812));
+        assert_eq!(vm.registers[6], Value::Int(0x2468ACF0));
+        assert_eq!(vm.registers[7], Value::Int(1));
+    }
+
+    #[test]
+    fn test_tracer() {
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 0),
+            Instruction::JumpIfZero(0, 2), // Taken, skips 2 and 3
+            Instruction::SetReg(1, 1),
+            Instruction::Print(1),
+            Instruction::SetReg(0, 5),
+            Instruction::JumpIfZero(0, 1), // Not taken
+            Instruction::Halt,
+            Instruction::SetReg(2, 2), // Never reached
+        ]);
+        vm.set_output(io::sink());
+        let recorded = Rc::clone(&trace);
+        vm.set_tracer(Some(Box::new(move |ip, instruction, registers| {
+            recorded
+                .borrow_mut()
+                .push((ip, instruction.clone(), registers[0]));
+        })));
+
+        vm.run().unwrap();
+
+        let ips: Vec<usize> = trace.borrow().iter().map(|(ip, _, _)| *ip).collect();
+        assert_eq!(ips, vec![0, 1, 4, 5, 6]);
+        // The registers are observed before the instruction executes
+        assert_eq!(
+            trace.borrow()[3],
+            (5, Instruction::JumpIfZero(0, 1), Value::Int(5))
+        );
+        assert_eq!(trace.borrow()[2].2, Value::Int(0));
+    }
+
+    #[test]
+    fn test_brk() {
+        let program = vec![
+            Instruction::SetReg(0, 1),
+            Instruction::Brk(7),
+            Instruction::SetReg(0, 2),
+        ];
+
+        let mut vm = VM::new(program.clone());
+        assert_eq!(vm.run(), Err(VmError::Break(7)));
+        assert_eq!(vm.trap().unwrap().to_string(), "instruction 1: break instruction with code 7");
+        assert_eq!(vm.registers[0], Value::Int(1));
+
+        // Lenient mode stops too
+        let mut vm = VM::new(program);
+        vm.set_strict(false);
+        assert_eq!(vm.run(), Err(VmError::Break(7)));
+        assert_eq!(vm.state(), VmState::Trapped);
+    }
+
+    #[test]
+    fn test_assert_eq() {
+        let program = vec![
+            Instruction::SetReg(0, 2),
+            Instruction::SetReg(1, 3),
+            Instruction::Add(0, 1, 2),
+            Instruction::AssertEq(2, 5), // Passes
+            Instruction::AssertEq(2, 6), // Fails
+            Instruction::SetReg(3, 1),
+        ];
+
+        let mut vm = VM::new(program.clone());
+        assert_eq!(
+            vm.run(),
+            Err(VmError::AssertionFailed {
+                ip: 4,
+                register: 2,
+                expected: 6,
+                actual: 5
+            })
+        );
+        assert_eq!(vm.registers[3], Value::Int(0));
+
+        // Lenient mode only logs the failure
+        let mut vm = VM::new(program);
+        vm.set_strict(false);
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(vm.registers[3], Value::Int(1));
+    }
+
+    #[test]
+    fn test_return_without_call() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 1),
+            Instruction::Return, // Nothing to return to
+            Instruction::SetReg(1, 2),
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::StackUnderflow));
+        assert_eq!(vm.ip, 2);
+        assert_eq!(vm.registers[1], Value::Int(0));
+    }
+
+    #[test]
+    fn test_call_targets_are_absolute() {
+        let mut vm = VM::new(vec![
+            Instruction::Call(4), // Both calls reach the same function
+            Instruction::Call(4),
+            Instruction::Halt,
+            Instruction::SetReg(0, 7), // Never reached
+            Instruction::AddImm(1, 1, 1),
+            Instruction::Return,
+        ]);
+
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.registers[0], Value::Int(0));
+        assert_eq!(vm.registers[1], Value::Int(2));
+
+        let mut vm = VM::new(vec![Instruction::Call(1)]);
+        assert_eq!(vm.run(), Err(VmError::InvalidJump(1)));
+    }
+
+    #[test]
+    fn test_indirect_calls() {
+        let mut vm = VM::from_program(Program {
+            instructions: vec![
+                Instruction::SetReg(4, 8), // Callback address
+                Instruction::CallReg(4),
+                Instruction::SetReg(5, 1),
+                Instruction::CallTable(5), // functions[1], the same callback
+                Instruction::SetReg(5, 0),
+                Instruction::CallTable(5), // functions[0]
+                Instruction::Halt,
+                Instruction::AddImm(1, 10, 1), // 7
+                Instruction::AddImm(1, 1, 1),  // 8
+                Instruction::Return,
+            ],
+            functions: vec![7, 8],
+            ..Program::default()
+        });
+
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.registers[1], Value::Int(13));
+        assert!(vm.call_stack().is_empty());
+
+        let mut vm = VM::new(vec![Instruction::SetReg(0, 2), Instruction::CallTable(0)]);
+        vm.set_function_table(vec![0, 1]);
+        assert_eq!(vm.run(), Err(VmError::InvalidFunctionIndex(2)));
+        let mut vm = VM::new(vec![Instruction::SetReg(0, -1), Instruction::CallTable(0)]);
+        assert_eq!(vm.run(), Err(VmError::InvalidFunctionIndex(-1)));
+        let mut vm = VM::new(vec![Instruction::SetReg(0, 9), Instruction::CallReg(0)]);
+        assert_eq!(vm.run(), Err(VmError::InvalidJump(9)));
+    }
+
+    #[test]
+    fn test_jump_table() {
+        let program = |index| {
+            vec![
+                Instruction::SetReg(0, index),
+                Instruction::JumpTable(0, vec![2, 0, 4], 6),
+                Instruction::SetReg(1, 10), // 2: case 1
+                Instruction::Halt,
+                Instruction::SetReg(1, 20), // 4: case 0
+                Instruction::Halt,
+                Instruction::SetReg(1, 30), // 6: case 2
+                Instruction::Halt,
+                Instruction::SetReg(1, -1), // 8: default
+            ]
+        };
+
+        for (index, expected) in [(0, 20), (1, 10), (2, 30), (3, -1), (-1, -1), (i32::MAX, -1)] {
+            let mut vm = VM::new(program(index));
+            vm.run().unwrap();
+            assert_eq!(vm.registers[1], Value::Int(expected), "index {}", index);
+        }
+
+        let mut vm = VM::new(vec![Instruction::JumpTable(0, vec![5], 0)]);
+        assert_eq!(vm.run(), Err(VmError::InvalidJump(6)));
+        let mut vm = VM::new(vec![Instruction::JumpTable(0, vec![], -3)]);
+        assert_eq!(vm.run(), Err(VmError::InvalidJump(-2)));
+    }
+
+    #[test]
+    fn test_with_config() {
+        let program = vec![
+            Instruction::SetReg(12, 5),
+            Instruction::AddImm(12, 1, 15),
+            Instruction::ClearRegs,
+        ];
+        let config = VmConfig {
+            register_count: 16,
+            max_call_depth: 2,
+            ..VmConfig::default()
+        };
+
+        let mut vm = VM::with_config(program.clone(), config);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.registers().len(), 16);
+        assert_eq!(vm.registers()[15], Value::Int(6));
+        vm.run().unwrap();
+        assert!(vm.registers().iter().all(|value| *value == Value::Int(0)));
+
+        let mut vm = VM::new(program);
+        assert_eq!(vm.run(), Err(VmError::InvalidRegister(12)));
+
+        let mut vm = VM::with_config(vec![Instruction::Call(0)], config);
+        assert_eq!(vm.run(), Err(VmError::StackOverflow));
+        assert_eq!(vm.call_stack().len(), 2);
+
+        // Host functions and throws return in r0, which every VM keeps
+        let config = VmConfig {
+            register_count: 0,
+            ..VmConfig::default()
+        };
+        let program = vec![
+            Instruction::Syscall(3),
+            Instruction::TryBegin(1),
+            Instruction::Throw(0),
+            Instruction::Halt,
+        ];
+        let mut vm = VM::with_config(program, config);
+        vm.register_host_fn(3, |context| {
+            context.set_return(Value::Int(7));
+            Ok(())
+        });
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.registers(), [Value::Int(7)]);
+    }
+
+    #[test]
+    fn test_recursion_saves_registers_on_data_stack() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 5),
+            Instruction::Call(3), // r1 = factorial(r0)
+            Instruction::Halt,
+            Instruction::JumpIfNonZero(0, 2), // 3: factorial
+            Instruction::SetReg(1, 1),
+            Instruction::Return,
+            Instruction::Push(0), // 6: save n across the recursive call
+            Instruction::SubImm(0, 1, 0),
+            Instruction::Call(3),
+            Instruction::Pop(0),
+            Instruction::Mul(0, 1, 1),
+            Instruction::Return,
+        ]);
+
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.registers[0], Value::Int(5));
+        assert_eq!(vm.registers[1], Value::Int(120));
+        assert!(vm.data_stack().is_empty());
+    }
+
+    #[test]
+    fn test_recursion_with_local_slots() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 10),
+            Instruction::Call(3), // r1 = fib(r0)
+            Instruction::Halt,
+            Instruction::Enter(2),       // 3: fib, local 0 is n, local 1 is fib(n - 1)
+            Instruction::StoreLocal(0, 0),
+            Instruction::LtImm(0, 2, 2), // n < 2?
+            Instruction::JumpIfZero(2, 3),
+            Instruction::AddImm(0, 0, 1), // fib(n) = n
+            Instruction::Leave,
+            Instruction::Return,
+            Instruction::SubImm(0, 1, 0), // 10
+            Instruction::Call(3),
+            Instruction::StoreLocal(1, 1),
+            Instruction::LoadLocal(0, 0),
+            Instruction::SubImm(0, 2, 0),
+            Instruction::Call(3),
+            Instruction::LoadLocal(1, 2),
+            Instruction::Add(1, 2, 1),
+            Instruction::LoadLocal(0, 0),
+            Instruction::Leave,
+            Instruction::Return,
+        ]);
+
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.registers[0], Value::Int(10));
+        assert_eq!(vm.registers[1], Value::Int(55));
+        assert!(vm.locals().is_empty());
+    }
+
+    #[test]
+    fn test_frame_errors() {
+        let mut vm = VM::new(vec![
+            Instruction::Enter(2),
+            Instruction::SetReg(0, 7),
+            Instruction::StoreLocal(0, 1),
+            Instruction::Enter(1),
+        ]);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.locals(), &[Value::Int(0), Value::Int(7)]);
+        vm.step().unwrap();
+        assert_eq!(vm.locals(), &[Value::Int(0)]); // Only the innermost frame
+
+        for (program, error) in [
+            (vec![Instruction::LoadLocal(0, 0)], VmError::NoFrame),
+            (vec![Instruction::Leave], VmError::NoFrame),
+            (vec![Instruction::Enter(1), Instruction::StoreLocal(0, 1)], VmError::InvalidLocal(1)),
+            (vec![Instruction::Enter(usize::MAX)], VmError::StackOverflow),
+        ] {
+            let mut vm = VM::new(program);
+            assert_eq!(vm.run(), Err(error));
+        }
+    }
+
+    #[test]
+    fn test_global_variables() {
+        let mut vm = VM::from_program(Program {
+            instructions: vec![
+                Instruction::LoadGlobal(0, 0),
+                Instruction::AddImm(0, 1, 0),
+                Instruction::StoreGlobal(0, 0),
+                Instruction::LoadGlobal(1, 1),
+                Instruction::StoreGlobal(1, 2),
+            ],
+            globals: vec![Value::Int(41), Value::Float(0.5), Value::Int(0)],
+            ..Program::default()
+        });
+
+        vm.run().unwrap();
+        assert_eq!(vm.globals(), [Value::Int(42), Value::Float(0.5), Value::Float(0.5)]);
+
+        // Globals are part of snapshots
+        let snapshot = vm.snapshot();
+        vm.globals[0] = Value::Int(0);
+        vm.restore(&snapshot);
+        assert_eq!(vm.globals()[0], Value::Int(42));
+
+        for instruction in [Instruction::LoadGlobal(3, 0), Instruction::StoreGlobal(0, 3)] {
+            let mut vm = VM::new(vec![instruction]);
+            assert_eq!(vm.run(), Err(VmError::InvalidGlobal(3)));
+        }
+    }
+
+    #[test]
+    fn test_memory_stack() {
+        // f(a, b, c, d, e) = a + b + e, with the arguments passed on the memory stack
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 1),
+            Instruction::SetReg(1, 20),
+            Instruction::PushSp(0), // e
+            Instruction::PushSp(1),
+            Instruction::PushSp(0),
+            Instruction::PushSp(1), // b
+            Instruction::PushSp(0), // a, on top
+            Instruction::Call(10),
+            Instruction::PopSp(7), // The result, in place of a
+            Instruction::Halt,
+            Instruction::SetReg(2, 0), // 10: f
+            Instruction::LoadSpOffset(0, 3),
+            Instruction::Add(2, 3, 2),
+            Instruction::LoadSpOffset(4, 3),
+            Instruction::Add(2, 3, 2),
+            Instruction::LoadSpOffset(16, 3),
+            Instruction::Add(2, 3, 2),
+            Instruction::StoreSpOffset(2, 0),
+            Instruction::Return,
+        ]);
+        assert!(vm.enable_memory_stack(64));
+        assert!(!vm.enable_memory_stack(64));
+        assert_eq!(vm.memory_stack(), Some(0..64));
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.registers[7], Value::Int(22));
+        assert_eq!(vm.sp(), 64 - 16);
+        assert_eq!(vm.load_word(vm.sp()), Ok(20));
+        assert_eq!(vm.memory.borrow().next_free_address(), 64);
+        assert_eq!(vm.memory_stats().allocations, 0); // Not the program's allocation
+
+        let mut vm = VM::new(vec![
+            Instruction::PushSp(0),
+            Instruction::PushSp(0),
+            Instruction::PushSp(0),
+        ]);
+        assert!(vm.enable_memory_stack(8));
+        assert_eq!(vm.run(), Err(VmError::StackOverflow));
+        assert_eq!(vm.sp(), 0);
+        assert_eq!(VM::new(vec![Instruction::PopSp(0)]).run(), Err(VmError::NoMemoryStack));
+        for (instruction, error) in [
+            (Instruction::PopSp(0), VmError::StackUnderflow),
+            (Instruction::LoadSpOffset(0, 0), VmError::OutOfBounds(8)), // Nothing pushed yet
+            (Instruction::StoreSpOffset(1, 6), VmError::OutOfBounds(14)),
+            (Instruction::FreeMemory(0), VmError::ReadOnlyMemory(0)),
+        ] {
+            let mut vm = VM::new(vec![instruction.clone()]);
+            vm.enable_memory_stack(8);
+            assert_eq!(vm.run(), Err(error), "{:?}", instruction);
+        }
+
+        // With paging, the memory stack is the top of the stack segment
+        let mut vm = VM::new(vec![Instruction::SetReg(0, 5), Instruction::PushSp(0)]);
+        assert!(vm.enable_paging(PagingConfig::default()));
+        assert!(!vm.enable_memory_stack(8 * 1024));
+        assert!(vm.enable_memory_stack(1024));
+        assert_eq!(vm.memory_stack(), Some(63 * 1024..64 * 1024));
+        vm.run().unwrap();
+        assert_eq!(vm.memory.borrow().bytes(64 * 1024 - 4, 4), Ok(&[5, 0, 0, 0][..]));
+    }
+
+    #[test]
+    fn test_data_stack_operations() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 1),
+            Instruction::SetFReg(1, 2.5),
+            Instruction::Push(0),
+            Instruction::Push(1),
+            Instruction::Dup,
+            Instruction::SwapTop, // Swapping equal values changes nothing visible
+            Instruction::Pop(2),
+            Instruction::SwapTop,
+            Instruction::Pop(3),
+        ]);
+
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], Value::Float(2.5));
+        assert_eq!(vm.registers[3], Value::Int(1));
+        assert_eq!(vm.data_stack(), &[Value::Float(2.5)]);
+
+        for program in [
+            vec![Instruction::Pop(0)],
+            vec![Instruction::Dup],
+            vec![Instruction::Push(0), Instruction::SwapTop],
+        ] {
+            let mut vm = VM::new(program);
+            assert_eq!(vm.run(), Err(VmError::DataStackUnderflow));
+        }
+
+        let config = VmConfig {
+            max_data_stack: 2,
+            ..VmConfig::default()
+        };
+        let mut vm = VM::with_config(vec![Instruction::Push(0), Instruction::Dup, Instruction::Dup], config);
+        assert_eq!(vm.run(), Err(VmError::DataStackOverflow));
+        assert_eq!(vm.data_stack().len(), 2);
+    }
+
+    #[test]
+    fn test_call_saves_callee_saved_registers() {
+        let program = vec![
+            Instruction::SetReg(0, 2), // Argument
+            Instruction::SetReg(4, 100),
+            Instruction::Call(4),
+            Instruction::Halt,
+            Instruction::MulImm(0, 3, 4), // 4: uses r4 as scratch
+            Instruction::AddImm(4, 1, 0), // Returns 3 * r0 + 1
+            Instruction::Return,
+        ];
+        let config = VmConfig {
+            calling_convention: CallingConvention::SaveCalleeSaved,
+            ..VmConfig::default()
+        };
+
+        let mut vm = VM::with_config(program.clone(), config);
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.registers[0], Value::Int(7));
+        assert_eq!(vm.registers[4], Value::Int(100)); // Restored by Return
+
+        let mut vm = VM::new(program);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[4], Value::Int(6)); // Not saved by default
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let mut vm = VM::with_config(
+            vec![
+                Instruction::SetReg(4, 7),
+                Instruction::Enter(2),     // Opened before the handler, so kept
+                Instruction::TryBegin(5),  // Handler at 8
+                Instruction::Push(4),      // Dropped by the unwinding
+                Instruction::Call(10),
+                Instruction::TryEnd,       // Skipped
+                Instruction::Halt,
+                Instruction::Halt,
+                Instruction::Mov(0, 5),    // The thrown code
+                Instruction::Halt,
+                Instruction::Enter(1),     // 10: Closed by the unwinding
+                Instruction::SetReg(4, 9), // Restored by the unwinding
+                Instruction::SetReg(1, 42),
+                Instruction::Throw(1),
+            ],
+            VmConfig {
+                calling_convention: CallingConvention::SaveCalleeSaved,
+                ..VmConfig::default()
+            },
+        );
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.ip, 10);
+        assert_eq!(vm.registers[5], Value::Int(42));
+        assert_eq!(vm.registers[4], Value::Int(7));
+        assert_eq!((vm.stack.len(), vm.frames.len(), vm.locals.len()), (0, 1, 2));
+        assert!(vm.data_stack.is_empty() && vm.handlers.is_empty());
+
+        for (program, error) in [
+            (vec![Instruction::Throw(3)], VmError::UncaughtException(0)),
+            (vec![Instruction::TryEnd], VmError::NoHandler),
+            // A handler only covers the rest of the call that installed it
+            (
+                vec![
+                    Instruction::TryBegin(1),
+                    Instruction::Call(3),
+                    Instruction::Halt,
+                    Instruction::TryEnd,
+                ],
+                VmError::NoHandler,
+            ),
+            (
+                vec![
+                    Instruction::Call(2),
+                    Instruction::Throw(0),
+                    Instruction::TryBegin(-3),
+                    Instruction::Return,
+                ],
+                VmError::UncaughtException(0),
+            ),
+            (
+                vec![Instruction::TryBegin(1), Instruction::TryEnd, Instruction::Throw(0)],
+                VmError::UncaughtException(0),
+            ),
+            (vec![Instruction::TryBegin(5)], VmError::InvalidJump(6)),
+        ] {
+            let mut vm = VM::new(program.clone());
+            assert_eq!(vm.run(), Err(error), "{:?}", program);
+        }
+        let mut vm = VM::new(vec![Instruction::SetReg(0, -1), Instruction::Throw(0)]);
+        let _ = vm.run();
+        assert_eq!(vm.trap().unwrap().to_string(), "instruction 1: uncaught exception -1");
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut vm = VM::from_program(Program {
+            instructions: vec![
+                Instruction::Call(2),         // 0: main
+                Instruction::Halt,
+                Instruction::Call(4),         // 2: outer
+                Instruction::Return,
+                Instruction::Div(0, 0, 1),    // 4: inner
+                Instruction::Return,
+            ],
+            symbols: BTreeMap::from([(0, "main".to_string()), (4, "inner".to_string())]),
+            ..Program::default()
+        });
+        assert_eq!(vm.function_at(3), Some("main")); // The nearest symbol before it
+        assert_eq!(vm.run(), Err(VmError::DivisionByZero));
+        let trap = vm.trap().unwrap();
+        assert_eq!(trap.backtrace[1], Frame {
+            address: 2,
+            function: Some("main".to_string()),
+        });
+        assert_eq!(
+            trap.to_string(),
+            "instruction 4 in inner: division by zero\n  called from 0002 in main\n  called from 0000 in main"
+        );
+
+        vm.set_symbols(BTreeMap::new());
+        assert_eq!(vm.function_at(3), None);
+    }
+
+    #[test]
+    fn test_call_depth_limit() {
+        // A function that calls itself forever
+        let program = vec![Instruction::AddImm(0, 1, 0), Instruction::Call(0)];
+
+        let mut vm = VM::new(program.clone());
+        assert_eq!(vm.run(), Err(VmError::StackOverflow));
+        assert_eq!(vm.call_stack().len(), DEFAULT_MAX_CALL_DEPTH);
+
+        let mut vm = VM::new(program.clone());
+        vm.set_max_call_depth(3);
+        assert_eq!(vm.run(), Err(VmError::StackOverflow));
+        assert_eq!(vm.call_stack(), &[2, 2, 2]);
+        assert_eq!(vm.registers[0], Value::Int(4));
+        let trap = vm.trap().unwrap();
+        let addresses: Vec<usize> = trap.backtrace.iter().map(|frame| frame.address).collect();
+        assert_eq!(addresses, [1, 1, 1, 1]);
+        assert_eq!(
+            trap.to_string(),
+            "instruction 1: stack overflow\n  called from 0001\n  called from 0001\n  called from 0001"
+        );
+
+        // The dump lists the innermost frames and counts the rest
+        let mut vm = VM::new(program);
+        vm.set_max_call_depth(20);
+        vm.run().unwrap_err();
+        let dump = vm.trap().unwrap().to_string();
+        assert_eq!(dump.matches("called from").count(), 16);
+        assert!(dump.ends_with("\n  ... 4 more frames"));
+
+        // Other traps carry a backtrace as well
+        let mut vm = VM::new(vec![Instruction::Call(2), Instruction::Halt, Instruction::Jump(9)]);
+        vm.run().unwrap_err();
+        let addresses: Vec<usize> = vm.trap().unwrap().backtrace.iter().map(|frame| frame.address).collect();
+        assert_eq!(addresses, [2, 0]);
+
+        // The memory stack's limit
+        let mut vm = VM::new(vec![Instruction::Halt]);
+        vm.set_max_memory_stack(64);
+        assert!(!vm.enable_memory_stack(128));
+        assert!(vm.enable_memory_stack(64));
+    }
+
+    #[test]
+    fn test_step() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 1),
+            Instruction::SetReg(1, 2),
+            Instruction::Halt,
+            Instruction::SetReg(2, 3),
+        ]);
+
+        assert_eq!(vm.state(), VmState::Running);
+        assert_eq!(vm.step(), Ok(VmState::Running));
+        assert_eq!(vm.registers[0], Value::Int(1));
+        assert_eq!(vm.registers[1], Value::Int(0));
+        assert_eq!(vm.step(), Ok(VmState::Running));
+        assert_eq!(vm.step(), Ok(VmState::Halted));
+        assert_eq!(vm.step(), Ok(VmState::Halted)); // Stays halted
+        assert_eq!(vm.ip, 3);
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.registers[2], Value::Int(0));
+
+        let mut vm = VM::new(vec![Instruction::SetReg(0, 1)]);
+        assert_eq!(vm.step(), Ok(VmState::EndOfProgram));
+        vm.extend_program([Instruction::AddImm(0, 1, 0)]); // Continues past the old end
+        assert_eq!(vm.state(), VmState::Running);
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(vm.registers[0], Value::Int(2));
+    }
+
+    #[test]
+    fn test_step_trapped() {
+        let mut vm = VM::new(vec![
+            Instruction::Print(9), // Invalid register
+            Instruction::SetReg(0, 1),
+        ]);
+
+        assert_eq!(vm.step(), Err(VmError::InvalidRegister(9)));
+        assert_eq!(vm.state(), VmState::Trapped);
+        assert_eq!(vm.trap().unwrap().to_string(), "instruction 0: invalid register index 9");
+        assert_eq!(vm.step(), Ok(VmState::Trapped));
+        assert_eq!(vm.run(), Err(VmError::InvalidRegister(9)));
+        assert_eq!(vm.registers[0], Value::Int(0));
+    }
+
+    #[test]
+    fn test_fuel() {
+        let program = vec![
+            Instruction::SetReg(0, 6), // 1 unit
+            Instruction::SetReg(1, 7), // 1 unit
+            Instruction::Mul(0, 1, 2), // 2 units
+            Instruction::Print(2),     // 1 unit
+        ];
+
+        let mut vm = VM::with_fuel(program.clone(), 3);
+        assert_eq!(vm.run(), Ok(ExitStatus::OutOfFuel));
+        assert_eq!(vm.ip, 2); // Mul is not started
+        assert_eq!(vm.fuel(), Some(1));
+        assert_eq!(vm.state(), VmState::OutOfFuel);
+        assert_eq!(vm.step(), Ok(VmState::OutOfFuel));
+
+        vm.refuel(1);
+        assert_eq!(vm.step(), Ok(VmState::OutOfFuel)); // Mul ran, nothing left for Print
+        assert_eq!(vm.registers[2], Value::Int(42));
+        vm.refuel(10);
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(vm.fuel(), Some(9));
+
+        // Unmetered VMs ignore refuelling
+        let mut vm = VM::new(program);
+        vm.refuel(5);
+        assert_eq!(vm.fuel(), None);
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 0b1100),
+            Instruction::SetReg(1, 0b1010),
+            Instruction::And(0, 1, 2),
+            Instruction::Or(0, 1, 3),
+            Instruction::Xor(0, 1, 4),
+            Instruction::Not(0, 5),
+        ]);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], Value::Int(0b1000));
+        assert_eq!(vm.registers[3], Value::Int(0b1110));
+        assert_eq!(vm.registers[4], Value::Int(0b0110));
+        assert_eq!(vm.registers[5], Value::Int(!0b1100));
+    }
+
+    #[test]
+    fn test_shifts() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, -16),
+            Instruction::SetReg(1, 2),
+            Instruction::Shl(0, 1, 2),   // -64
+            Instruction::Shr(0, 1, 3),   // Zero fill: 0x3FFFFFFC
+            Instruction::Sar(0, 1, 4),   // Sign fill: -4
+            Instruction::SetReg(1, 33),
+            Instruction::Sar(0, 1, 5),   // Count taken modulo 32: -8
+            Instruction::SetReg(6, 1),
+            Instruction::SetReg(1, 31),
+            Instruction::Shl(6, 1, 6),   // i32::MIN
+            Instruction::Sar(6, 1, 7),   // -1
+        ]);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], Value::Int(-64));
+        assert_eq!(vm.registers[3], Value::Int(0x3FFFFFFC));
+        assert_eq!(vm.registers[4], Value::Int(-4));
+        assert_eq!(vm.registers[5], Value::Int(-8));
+        assert_eq!(vm.registers[6], Value::Int(i32::MIN));
+        assert_eq!(vm.registers[7], Value::Int(-1));
+    }
+
+    #[test]
+    fn test_bit_counts() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 0b1011_0000),
+            Instruction::Popcount(0, 1),
+            Instruction::Clz(0, 2),
+            Instruction::Ctz(0, 3),
+            Instruction::SetReg(4, -1),
+            Instruction::Popcount(4, 4),
+            Instruction::Clz(5, 6), // Zero has no set bits
+            Instruction::Ctz(5, 7),
+        ]);
+
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1..], [3, 24, 4, 32, 0, 32, 32].map(Value::Int));
+    }
+
+    #[test]
+    fn test_flags() {
+        let flags = |zero, negative, carry, overflow| Flags {
+            zero,
+            negative,
+            carry,
+            overflow,
+        };
+        let cases = [
+            (Instruction::Add(0, 1, 2), 1, -1, flags(true, false, true, false)),
+            (Instruction::Add(0, 1, 2), i32::MAX, 1, flags(false, true, false, true)),
+            (Instruction::Sub(0, 1, 2), 1, 2, flags(false, true, true, false)),
+            (Instruction::Sub(0, 1, 2), i32::MIN, 1, flags(false, false, false, true)),
+            (Instruction::Mul(0, 1, 2), 0x10000, 0x10000, flags(true, false, true, true)),
+            (Instruction::AddImm(0, 5, 2), -5, 0, flags(true, false, true, false)),
+            (Instruction::SubImm(0, 5, 2), 5, 0, flags(true, false, false, false)),
+            (Instruction::Cmp(0, 1), 3, 3, flags(true, false, false, false)),
+            (Instruction::Cmp(0, 1), -1, 1, flags(false, true, false, false)),
+            (Instruction::CmpImm(0, -1), 1, 0, flags(false, false, true, false)),
+        ];
+        for (instruction, a, b, expected) in cases {
+            let mut vm = VM::new(vec![
+                Instruction::SetReg(0, a),
+                Instruction::SetReg(1, b),
+                instruction.clone(),
+            ]);
+            vm.run().unwrap();
+            assert_eq!(vm.flags(), expected, "{:?} of {} and {}", instruction, a, b);
+        }
+
+        // Other instructions leave the flags alone, and a Checked overflow
+        // traps before setting them
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, i32::MAX),
+            Instruction::Cmp(0, 0),
+            Instruction::Div(0, 0, 1),
+            Instruction::Add(0, 0, 1),
+        ]);
+        vm.set_arithmetic_mode(ArithmeticMode::Checked);
+        assert_eq!(vm.run(), Err(VmError::Overflow));
+        assert_eq!(vm.flags(), flags(true, false, false, false));
+        assert_eq!(vm.flags().to_string(), "Z---");
+    }
+
+    #[test]
+    fn test_vector_registers() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 32),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SetReg(0, 0), // Base address
+            Instruction::SetReg(1, 4),
+            Instruction::SetReg(2, -3),
+            Instruction::Store32(0, 1, 2), // Lane 1 of the vector at 0 is -3
+            Instruction::VLoad(0, 0, 0),
+            Instruction::SetReg(3, 10),
+            Instruction::VInsert(3, 2, 0), // v0 = [0, -3, 10, 0]
+            Instruction::SetReg(4, 2),
+            Instruction::VSplat(4, 1), // v1 = [2, 2, 2, 2]
+            Instruction::VMul4x32(0, 1, 2),
+            Instruction::VAdd4x32(2, 1, 2), // v2 = v0 * 2 + 2
+            Instruction::SetReg(5, 16),
+            Instruction::VStore(0, 5, 2),
+            Instruction::Load32(0, 5, 6),
+            Instruction::VExtract(2, 1, 7),
+        ]);
+
+        vm.run().unwrap();
+        assert_eq!(vm.vectors()[0], [0, -3, 10, 0]);
+        assert_eq!(vm.vectors()[2], [2, -4, 22, 2]);
+        assert_eq!(vm.registers[6..8], [Value::Int(2), Value::Int(-4)]);
+        assert_eq!(vm.snapshot().vectors, vm.vectors());
+
+        let errors = [
+            (Instruction::VExtract(0, 4, 1), VmError::InvalidLane(4)),
+            (Instruction::VSplat(0, 8), VmError::InvalidVectorRegister(8)),
+            (Instruction::VLoad(0, 0, 1), VmError::NoMemoryRegion(0)),
+        ];
+        for (instruction, error) in errors {
+            let mut vm = VM::new(vec![instruction]);
+            assert_eq!(vm.run(), Err(error));
+        }
+    }
+
+    #[test]
+    fn test_float_rounding() {
+        let program = vec![
+            Instruction::SetFReg(0, -2.5),
+            Instruction::FloatTrunc(0, 1),
+            Instruction::FloatRound(0, 2),
+            Instruction::FloatFloor(0, 3),
+            Instruction::FloatCeil(0, 4),
+            Instruction::SetFReg(5, f64::NAN),
+            Instruction::FloatToInt(5, 6),
+        ];
+        let mut vm = VM::new(program.clone());
+
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1..5], [-2.0, -3.0, -3.0, -2.0].map(Value::Float));
+        assert_eq!(vm.registers[6], Value::Int(0));
+
+        let mut vm = VM::new(program);
+        vm.set_arithmetic_mode(ArithmeticMode::Checked);
+        assert_eq!(vm.run(), Err(VmError::Overflow)); // NaN has no integer value
+        assert_eq!(vm.trap().unwrap().ip, 6);
+
+        let mut vm = VM::new(vec![
+            Instruction::SetFReg(0, 2147483647.9), // Truncates into range
+            Instruction::FloatToInt(0, 1),
+            Instruction::SetFReg(0, 2147483648.0),
+            Instruction::FloatToInt(0, 1),
+        ]);
+        vm.set_arithmetic_mode(ArithmeticMode::Checked);
+        assert_eq!(vm.run(), Err(VmError::Overflow));
+        assert_eq!(vm.registers[1], Value::Int(i32::MAX));
+    }
+
+    #[test]
+    fn test_extend_and_truncate() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 0x1234_80F0),
+            Instruction::SextByte(0, 1),
+            Instruction::SextHalf(0, 2),
+            Instruction::ZextByte(0, 3),
+            Instruction::ZextHalf(0, 4),
+            Instruction::Trunc(0, 12, 5),
+            Instruction::Trunc(0, 32, 6),
+            Instruction::Trunc(0, 33, 7),
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::InvalidWidth(33)));
+        assert_eq!(
+            vm.registers[1..7],
+            [-0x10, -0x7F10, 0xF0, 0x80F0, 0x0F0, 0x1234_80F0].map(Value::Int)
+        );
+        assert_eq!(vm.registers[7], Value::Int(0)); // Untouched
+    }
+
+    #[test]
+    fn test_unsigned_arithmetic() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, -2), // 0xFFFF_FFFE
+            Instruction::SetReg(1, 16),
+            Instruction::DivU(0, 1, 2),
+            Instruction::ModU(0, 1, 3),
+            Instruction::LtU(1, 0, 4), // 16 < 0xFFFF_FFFE
+            Instruction::GtU(1, 0, 5),
+            Instruction::LteU(0, 0, 6),
+            Instruction::GteU(1, 0, 7),
+        ]);
+
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2..], [0x0FFF_FFFF, 14, 1, 0, 1, 0].map(Value::Int));
+
+        let mut vm = VM::new(vec![Instruction::ModU(0, 1, 2)]);
+        assert_eq!(vm.run(), Err(VmError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_multi_precision() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, -1), // r1:r0 = 0x1_FFFF_FFFF
+            Instruction::SetReg(1, 1),
+            Instruction::SetReg(2, 1), // r3:r2 = 1
+            Instruction::SetReg(3, 0),
+            Instruction::Add(0, 2, 4), // r5:r4 = 0x2_0000_0000
+            Instruction::Adc(1, 3, 5),
+            Instruction::Sub(4, 2, 6), // r7:r6 = 0x1_FFFF_FFFF
+            Instruction::Sbb(5, 3, 7),
+            Instruction::MulWide(0, 0, 8, 9), // 0xFFFF_FFFF squared
+        ]);
+        vm.set_arithmetic_mode(ArithmeticMode::Checked); // Adc and Sbb wrap regardless
+        vm.registers.resize(10, Value::Int(0));
+
+        vm.run().unwrap();
+        assert_eq!(vm.registers[4..], [0, 2, -1, 1, -2, 1].map(Value::Int));
+        assert_eq!(vm.flags().to_string(), "----"); // Sbb borrowed nothing
+
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, i32::MAX),
+            Instruction::SetReg(1, -1),
+            Instruction::Cmp(1, 0), // No borrow, carry clear
+            Instruction::Adc(0, 2, 3),
+            Instruction::Cmp(2, 1), // Borrows, carry set
+            Instruction::Adc(0, 2, 4), // i32::MAX + 0 + 1 overflows
+        ]);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[3..5], [Value::Int(i32::MAX), Value::Int(i32::MIN)]);
+        assert_eq!(vm.flags().to_string(), "-N-V");
+
+        let mut vm = VM::new(vec![Instruction::MulWide(0, 1, 2, 8)]);
+        assert_eq!(vm.run(), Err(VmError::InvalidRegister(8)));
+    }
+
+    #[test]
+    fn test_flag_branches() {
+        // Each branch runs after `cmp r0, r1` and stores 1 in r2 when taken
+        let cases = [
+            (Instruction::Jz(1), 4, 4, true),
+            (Instruction::Jz(1), 4, 5, false),
+            (Instruction::Jnz(1), 4, 5, true),
+            (Instruction::Jc(1), 1, 2, true), // Unsigned 1 < 2 borrows
+            (Instruction::Jc(1), -1, 2, false),
+            (Instruction::Jo(1), i32::MIN, 1, true),
+            (Instruction::Jlt(1), -3, 2, true),
+            (Instruction::Jlt(1), i32::MIN, 1, true), // Overflows, still less
+            (Instruction::Jlt(1), 2, 2, false),
+            (Instruction::Jge(1), i32::MAX, -1, true),
+            (Instruction::Jge(1), 1, 2, false),
+        ];
+        for (branch, a, b, taken) in cases {
+            let mut vm = VM::new(vec![
+                Instruction::SetReg(0, a),
+                Instruction::SetReg(1, b),
+                Instruction::Cmp(0, 1),
+                branch.clone(),
+                Instruction::Halt,
+                Instruction::SetReg(2, 1),
+            ]);
+            vm.run().unwrap();
+            let message = format!("{:?} after comparing {} and {}", branch, a, b);
+            assert_eq!(vm.registers[2], Value::Int(taken as i32), "{}", message);
+        }
+
+        let mut vm = VM::new(vec![Instruction::Jz(-2)]);
+        vm.run().unwrap(); // The flags start clear, so not taken
+        let mut vm = VM::new(vec![Instruction::Jnz(-2)]);
+        assert_eq!(vm.run(), Err(VmError::InvalidJump(-1)));
+    }
+
+    #[test]
+    fn test_immediate_arithmetic() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 10),
+            Instruction::AddImm(0, 5, 1),   // 15
+            Instruction::SubImm(1, 20, 2),  // -5
+            Instruction::MulImm(2, -3, 3),  // 15
+            Instruction::EqImm(3, 15, 4),   // 1
+            Instruction::NeqImm(3, 15, 5),  // 0
+            Instruction::GtImm(2, -6, 6),   // 1
+            Instruction::LteImm(2, -6, 7),  // 0
+        ]);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[1], Value::Int(15));
+        assert_eq!(vm.registers[2], Value::Int(-5));
+        assert_eq!(vm.registers[3], Value::Int(15));
+        assert_eq!(vm.registers[4], Value::Int(1));
+        assert_eq!(vm.registers[5], Value::Int(0));
+        assert_eq!(vm.registers[6], Value::Int(1));
+        assert_eq!(vm.registers[7], Value::Int(0));
+
+        let mut vm = VM::new(vec![Instruction::LtImm(0, 1, 8)]);
+        assert_eq!(vm.run(), Err(VmError::InvalidRegister(8)));
+    }
+
+    #[test]
+    fn test_indirect_addressing() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 4),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SetReg(0, 4), // Base address of the second region
+            Instruction::SetReg(1, 0), // Index
+            Instruction::SetReg(2, 7),
+            Instruction::StoreIndirect(0, 1, 2),
+            Instruction::AddImm(1, 3, 1),
+            Instruction::SetReg(2, 9),
+            Instruction::StoreIndirect(0, 1, 2),
+            Instruction::LoadIndirect(0, 1, 3),
+            Instruction::SetReg(1, 0),
+            Instruction::LoadIndirect(0, 1, 4),
+            Instruction::SetReg(1, 4),
+            Instruction::LoadIndirect(0, 1, 5), // Past the end of the last region
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::NoMemoryRegion(8)));
+
+        assert_eq!(region_data(&vm, 4), vec![7, 0, 0, 9]);
+        assert_eq!(vm.registers[3], Value::Int(9));
+        assert_eq!(vm.registers[4], Value::Int(7));
+
+        // Negative register values are large unsigned addresses
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, -1),
+            Instruction::LoadIndirect(0, 1, 2),
+        ]);
+        assert_eq!(vm.run(), Err(VmError::NoMemoryRegion(u32::MAX as usize)));
+    }
+
+    #[test]
+    fn test_sized_memory_access() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 8),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SetReg(0, 0),       // Base address
+            Instruction::SetReg(1, 0),       // Offset
+            Instruction::SetReg(2, -2),      // 0xFFFFFFFE
+            Instruction::Store32(0, 1, 2),
+            Instruction::Load32(0, 1, 3),    // -2
+            Instruction::Load16S(0, 1, 4),   // -2
+            Instruction::Load16U(0, 1, 5),   // 0xFFFE
+            Instruction::Load8S(0, 1, 6),    // -2
+            Instruction::Load8U(0, 1, 7),    // 0xFE
+            Instruction::SetReg(1, 4),
+            Instruction::SetReg(2, 0x1234_5678),
+            Instruction::Store16(0, 1, 2),   // Only 78 56 is written
+            Instruction::AddImm(1, 2, 1),
+            Instruction::Store8(0, 1, 2),    // Only 78 is written
+            Instruction::AddImm(1, 1, 1),
+            Instruction::Store16(0, 1, 2),   // Would run past the region
+        ]);
+
+        assert_eq!(vm.run(), Err(VmError::OutOfBounds(7)));
+
+        assert_eq!(
+            region_data(&vm, 0),
+            vec![0xFE, 0xFF, 0xFF, 0xFF, 0x78, 0x56, 0x78, 0x00]
+        );
+        assert_eq!(vm.registers[3], Value::Int(-2));
+        assert_eq!(vm.registers[4], Value::Int(-2));
+        assert_eq!(vm.registers[5], Value::Int(0xFFFE));
+        assert_eq!(vm.registers[6], Value::Int(-2));
+        assert_eq!(vm.registers[7], Value::Int(0xFE));
+    }
+
+    fn region_data(vm: &VM, address: usize) -> Vec<u8> {
+        let memory = vm.memory.borrow();
+        let size = memory.region(address).unwrap().size;
+        memory.bytes(address, size).unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_freed_memory_is_reused() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 8),
+            Instruction::AllocateMemory(7, 6),  // 0..8
+            Instruction::AllocateMemory(7, 6),  // 8..16
+            Instruction::AllocateMemory(7, 6),  // 16..24
+            Instruction::AllocateMemory(7, 6),  // 24..32
+            Instruction::SetReg(0, 0x55),
+            Instruction::MemFill(8, 0, 16),
+            Instruction::FreeMemory(8),
+            Instruction::FreeMemory(16),     // Coalesces into 8..24
+            Instruction::SetReg(7, 12),
+            Instruction::AllocateMemory(7, 6), // Fits the merged block
+            Instruction::SetReg(7, 4),
+            Instruction::AllocateMemory(7, 6),  // Takes the rest of it
+            Instruction::SetReg(7, 1),
+            Instruction::AllocateMemory(7, 6),  // Nothing free left, goes to the end
+        ]);
+
+        vm.run().unwrap();
+
+        let memory = vm.memory.borrow();
+        let regions: Vec<_> = memory.regions().map(|region| (region.address, region.size)).collect();
+        assert_eq!(regions, vec![(0, 8), (8, 12), (20, 4), (24, 8), (32, 1)]);
+        assert_eq!(memory.bytes(8, 16), Ok(&[0; 16][..])); // Reused memory starts zeroed
+        assert_eq!(memory.free_blocks().count(), 0);
+        drop(memory);
+
+        // Freeing the highest regions gives the address space back
+        vm.program = vec![Instruction::FreeMemory(24), Instruction::FreeMemory(32)];
+        vm.ip = 0;
+        vm.run().unwrap();
+        assert_eq!(vm.memory.borrow().next_free_address(), 24);
+        assert_eq!(vm.memory.borrow().free_blocks().count(), 0);
+    }
+
+    #[test]
+    fn test_quarantined_memory() {
+        let program = |last: Instruction| {
+            let mut vm = VM::new(vec![
+                Instruction::SetReg(0, 8),
+                Instruction::AllocateMemory(0, 1), // 0..8
+                Instruction::AllocateMemory(0, 2), // 8..16
+                Instruction::FreeMemory(0),
+                Instruction::AllocateMemory(0, 3), // Not placed in the freed block
+                Instruction::SetReg(4, 6),
+                last,
+            ]);
+            vm.set_memory_safety(MemorySafety::Quarantine);
+            vm
+        };
+
+        let mut vm = program(Instruction::Nop);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[3], Value::Int(16));
+        assert_eq!(vm.memory.borrow().free_blocks().count(), 0);
+        let freed = vm.memory.borrow().freed_region(5);
+        assert_eq!(freed.map(|region| (region.address, region.size)), Some((0, 8)));
+
+        assert_eq!(program(Instruction::StoreIndirect(1, 4, 0)).run(), Err(VmError::UseAfterFree(6)));
+        assert_eq!(program(Instruction::LoadIndirect(1, 4, 0)).run(), Err(VmError::UseAfterFree(6)));
+        assert_eq!(program(Instruction::FreeMemory(0)).run(), Err(VmError::DoubleFree(0)));
+        assert_eq!(program(Instruction::FreeMemory(4)).run(), Err(VmError::NoMemoryRegion(4)));
+        assert_eq!(program(Instruction::ReallocMemory(1, 0, 5)).run(), Err(VmError::UseAfterFree(0)));
+        assert_eq!(program(Instruction::SizeOf(0, 5)).run(), Err(VmError::UseAfterFree(0)));
+
+        // Without quarantine the freed block is reused and the access goes through
+        let mut vm = program(Instruction::StoreIndirect(1, 4, 0));
+        vm.set_memory_safety(MemorySafety::Off);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[3], Value::Int(0));
+    }
+
+    #[test]
+    fn test_poisoned_memory() {
+        let program = |last: Instruction| {
+            let mut vm = VM::new(vec![
+                Instruction::SetReg(0, 8),
+                Instruction::AllocateMemory(0, 1), // 0..8
+                Instruction::SetReg(2, 2),
+                Instruction::StoreIndirect(1, 2, 0), // Byte 2 is initialized
+                Instruction::AllocateMemory(0, 3),   // 8..16
+                Instruction::MemCopy(9, 0, 4),       // Bytes 0..4 to 9..13, only 11 initialized
+                last,
+            ]);
+            vm.set_memory_safety(MemorySafety::Strict);
+            vm
+        };
+
+        let mut vm = program(Instruction::LoadFromMemory(0, 2, 4));
+        vm.run().unwrap();
+        assert_eq!(vm.registers[4], Value::Int(8));
+        assert_eq!(region_data(&vm, 0), [0xCD, 0xCD, 8, 0xCD, 0xCD, 0xCD, 0xCD, 0xCD]);
+        let mut vm = program(Instruction::LoadFromMemory(8, 3, 4));
+        vm.run().unwrap();
+        assert_eq!(vm.registers[4], Value::Int(8));
+
+        let trap = |last: Instruction| {
+            let mut vm = program(last);
+            vm.run().unwrap_err();
+            vm.trap().cloned().unwrap()
+        };
+        let uninitialized = |address, offset| Trap {
+            ip: 6,
+            error: VmError::UninitializedRead { address, offset },
+            backtrace: vec![Frame {
+                address: 6,
+                function: None,
+            }],
+        };
+        assert_eq!(trap(Instruction::LoadFromMemory(0, 3, 4)), uninitialized(0, 3));
+        assert_eq!(trap(Instruction::Load32(1, 2, 4)), uninitialized(0, 3));
+        assert_eq!(trap(Instruction::LoadFromMemory(8, 2, 4)), uninitialized(8, 2)); // Copied poison
+        assert_eq!(trap(Instruction::Load16U(3, 1, 4)), uninitialized(8, 0));
+        assert_eq!(trap(Instruction::Cas(12, 0, 0, 0, 4)), uninitialized(8, 4));
+
+        // Resizing keeps the poison of the bytes it moves
+        let mut vm = program(Instruction::ReallocMemory(1, 0, 5)); // Moves 0..8 to 16..24
+        vm.program.push(Instruction::LoadFromMemory(16, 2, 4));
+        vm.program.push(Instruction::LoadFromMemory(16, 3, 4));
+        assert_eq!(vm.run(), Err(VmError::UninitializedRead { address: 16, offset: 3 }));
+        assert_eq!(vm.registers[5], Value::Int(16));
+        assert_eq!(vm.registers[4], Value::Int(8));
+    }
+
+    #[test]
+    fn test_arithmetic_modes() {
+        let program = vec![
+            Instruction::SetReg(0, i32::MAX),
+            Instruction::SetReg(1, i32::MIN),
+            Instruction::SetReg(2, 2),
+            Instruction::Mul(1, 2, 5),
+            Instruction::SubImm(1, 1, 4),
+            Instruction::Add(0, 2, 3),
+        ];
+
+        let mut vm = VM::new(program.clone());
+        vm.run().unwrap();
+        assert_eq!(vm.registers[3], Value::Int(i32::MIN + 1));
+        assert_eq!(vm.registers[4], Value::Int(i32::MAX));
+        assert_eq!(vm.registers[5], Value::Int(0));
+
+        let mut vm = VM::new(program.clone());
+        vm.set_arithmetic_mode(ArithmeticMode::Saturating);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[3], Value::Int(i32::MAX));
+        assert_eq!(vm.registers[4], Value::Int(i32::MIN));
+        assert_eq!(vm.registers[5], Value::Int(i32::MIN));
+
+        let mut vm = VM::new(program);
+        vm.set_arithmetic_mode(ArithmeticMode::Checked);
+        assert_eq!(vm.run(), Err(VmError::Overflow));
+        assert_eq!(vm.registers[5], Value::Int(0)); // Left untouched
+        assert_eq!(vm.registers[4], Value::Int(0)); // Never reached
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 7),
+            Instruction::SetReg(2, -1),
+            Instruction::Div(0, 1, 3),
+        ]);
+        assert_eq!(vm.run(), Err(VmError::DivisionByZero));
+        assert_eq!(vm.registers[3], Value::Int(0));
+
+        let mut vm = VM::new(vec![Instruction::Mod(0, 1, 3)]);
+        assert_eq!(vm.run(), Err(VmError::DivisionByZero));
+
+        // In lenient mode the division is skipped and execution carries on
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(3, 5),
+            Instruction::Div(3, 0, 3),
+            Instruction::AddImm(3, 1, 3),
+        ]);
+        vm.set_strict(false);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[3], Value::Int(6));
+    }
+
+    #[test]
+    fn test_neg_abs_min_max() {
+        let program = vec![
+            Instruction::SetReg(0, -7),
+            Instruction::SetReg(1, 3),
+            Instruction::Neg(0, 2),
+            Instruction::Abs(0, 3),
+            Instruction::Min(0, 1, 4),
+            Instruction::Max(0, 1, 5),
+            Instruction::SetReg(6, i32::MIN),
+            Instruction::Abs(6, 7),
+            Instruction::Neg(6, 6),
+        ];
+
+        let mut vm = VM::new(program.clone());
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2..6], [Value::Int(7), Value::Int(7), Value::Int(-7), Value::Int(3)]);
+        assert_eq!(vm.registers[6], Value::Int(i32::MIN)); // Wraps
+        assert_eq!(vm.registers[7], Value::Int(i32::MIN));
+
+        let mut vm = VM::new(program.clone());
+        vm.set_arithmetic_mode(ArithmeticMode::Saturating);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[6], Value::Int(i32::MAX));
+        assert_eq!(vm.registers[7], Value::Int(i32::MAX));
+
+        let mut vm = VM::new(program);
+        vm.set_arithmetic_mode(ArithmeticMode::Checked);
+        assert_eq!(vm.run(), Err(VmError::Overflow));
+        assert_eq!(vm.registers[7], Value::Int(0)); // Left untouched
+    }
+
+    #[test]
+    fn test_division_overflow() {
+        let program = vec![
+            Instruction::SetReg(0, i32::MIN),
+            Instruction::SetReg(1, -1),
+            Instruction::Mod(0, 1, 3),
+            Instruction::Div(0, 1, 2),
+        ];
+
+        let mut vm = VM::new(program.clone());
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], Value::Int(i32::MIN));
+        assert_eq!(vm.registers[3], Value::Int(0));
+
+        let mut vm = VM::new(program.clone());
+        vm.set_arithmetic_mode(ArithmeticMode::Saturating);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], Value::Int(i32::MAX));
+
+        let mut vm = VM::new(program);
+        vm.set_arithmetic_mode(ArithmeticMode::Checked);
+        assert_eq!(vm.run(), Err(VmError::Overflow));
+        assert_eq!(vm.registers[3], Value::Int(0));
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(7, 4),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SetReg(0, 3),
+            Instruction::SetReg(1, 4),
+            Instruction::Syscall(1), // r0 = hypot(r0, r1)
+            Instruction::SetReg(1, 0),
+            Instruction::Syscall(2), // Writes "hi" to the address in r1
+            Instruction::Syscall(3), // Fails
+            Instruction::SetReg(2, 1), // Not executed
+        ]);
+        vm.register_host_fn(1, |context| {
+            let (a, b) = (context.arg(0)?, context.arg(1)?);
+            context.set_return(Value::Int(((a * a + b * b) as f32).sqrt() as i32));
+            Ok(())
+        });
+        vm.register_host_fn(2, |context| {
+            let address = context.arg(1)? as usize;
+            context.write_memory(address, b"hi")
+        });
+        vm.register_host_fn(3, |context| context.read_memory(2, 4).map(|_| ()));
+
+        assert_eq!(vm.run(), Err(VmError::OutOfBounds(2)));
+        assert_eq!(vm.registers[0], Value::Int(5));
+        assert_eq!(vm.memory.borrow().bytes(0, 2), Ok(&b"hi"[..]));
+        assert_eq!(vm.registers[2], Value::Int(0));
+    }
+
+    #[test]
+    fn test_read_input() {
+        let mut vm = VM::new(vec![
+            Instruction::ReadInt(0),
+            Instruction::ReadInt(1),
+            Instruction::ReadByte(2),
+            Instruction::ReadByte(3),
+            Instruction::ReadByte(4), // End of input
+            Instruction::ReadInt(5),
+        ]);
+        vm.set_input(" 42\n-7\nok".as_bytes());
+
+        assert_eq!(vm.run(), Err(VmError::EndOfInput));
+        assert_eq!(vm.registers[0], Value::Int(42));
+        assert_eq!(vm.registers[1], Value::Int(-7));
+        assert_eq!(vm.registers[2], Value::Int(b'o' as i32));
+        assert_eq!(vm.registers[3], Value::Int(b'k' as i32));
+        assert_eq!(vm.registers[4], Value::Int(-1));
+
+        let mut vm = VM::new(vec![Instruction::ReadInt(0)]);
+        vm.set_input("forty\n".as_bytes());
+        assert_eq!(
+            vm.run(),
+            Err(VmError::InputError("`forty` is not an integer".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_countdown_loop() {
+        let mut vm = VM::new(vec![
+            Instruction::SetReg(0, 5),     // Counter
+            Instruction::SetReg(1, 0),     // Sum
+            Instruction::Add(1, 0, 1),     // loop: sum += counter
+            Instruction::SubImm(0, 1, 0),  // counter -= 1
+            Instruction::JumpIfNonZero(0, -3),
+            Instruction::Halt,
+        ]);
+
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.registers[0], Value::Int(0));
+        assert_eq!(vm.registers[1], Value::Int(15));
+    }
+
+    #[test]
+    fn test_invalid_backward_jump() {
+        let mut vm = VM::new(vec![Instruction::SetReg(0, 1), Instruction::Jump(-3)]);
+        assert_eq!(vm.run(), Err(VmError::InvalidJump(-1)));
+
+        let mut vm = VM::new(vec![Instruction::Jump(isize::MIN)]);
+        assert_eq!(vm.run(), Err(VmError::InvalidJump(isize::MIN + 1)));
+    }
+
+    fn example_program() -> Vec<Instruction> {
+        vec![
+            Instruction::SetReg(7, 100),
+            Instruction::AllocateMemory(7, 6),
+            Instruction::SetReg(0, 42),
+            Instruction::StoreToMemory(0, 0, 0),
+            Instruction::LoadFromMemory(0, 0, 1),
+            Instruction::Print(1),
+            Instruction::FreeMemory(0),
+            Instruction::SetReg(0, 2),
+            Instruction::SetReg(1, 3),
+            Instruction::Call(15),
+            Instruction::Print(0),
+            Instruction::JumpIfZero(4, 1),
+            Instruction::Print(4),
+            Instruction::Print(2),
+            Instruction::Halt,
+            Instruction::SetReg(2, 11),
+            Instruction::SetReg(3, 22),
+            Instruction::Add(2, 3, 4),
+            Instruction::Print(4),
+            Instruction::Return,
+        ]
+    }
+
+    #[test]
+    fn test_dry_run_matches_real_run() {
+        let vm = VM::new(example_program());
+        let dry_effects = vm.dry_run();
+
+        // The dry run must leave the VM untouched
+        assert_eq!(vm.ip, 0);
+        assert!(vm.memory.borrow().regions.is_empty());
+        assert!(vm.registers.iter().all(|value| *value == Value::Int(0)));
+
+        let mut vm = VM::new(example_program());
+        vm.record_effects();
+        vm.run().unwrap();
+        let real_effects = vm.take_effects();
+
+        assert_eq!(dry_effects, real_effects);
+        assert_eq!(dry_effects[1], Effect::Allocate { address: 0, size: 100 });
+        assert_eq!(dry_effects[2], Effect::RegisterWrite { register: 6, value: Value::Int(0) });
+        assert!(dry_effects.contains(&Effect::MemoryWrite {
+            address: 0,
+            value: 42
+        }));
+        assert!(dry_effects.contains(&Effect::Print {
+            register: 4,
+            value: Value::Int(33)
+        }));
+        assert_eq!(
+            dry_effects.last(),
+            Some(&Effect::Print {
+                register: 2,
+                value: Value::Int(11)
+            })
+        );
+    }
+
+    #[cfg(feature = "log")]
+    mod trace {
+        use super::*;
+        use log::{Level, Log, Metadata, Record};
+        use std::cell::RefCell;
+
+        // Records are captured per thread so tests running in parallel don't see
+        // each other's trace.
+        thread_local! {
+            static RECORDS: RefCell<Vec<(Level, String)>> = const { RefCell::new(Vec::new()) };
+        }
+
+        struct CapturingLogger;
+
+        impl Log for CapturingLogger {
+            fn enabled(&self, _metadata: &Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record) {
+                RECORDS.with(|records| {
+                    records
+                        .borrow_mut()
+                        .push((record.level(), record.args().to_string()))
+                });
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CapturingLogger = CapturingLogger;
+
+        fn capture<F: FnOnce()>(f: F) -> Vec<(Level, String)> {
+            // Only the first call can install the logger; later calls reuse it.
+            let _ = log::set_logger(&LOGGER);
+            log::set_max_level(log::LevelFilter::Trace);
+            RECORDS.with(|records| records.borrow_mut().clear());
+            f();
+            RECORDS.with(|records| records.borrow_mut().drain(..).collect())
+        }
+
+        #[test]
+        fn test_trace_records() {
+            let records = capture(|| {
+                let mut vm = VM::new(vec![
+                    Instruction::SetReg(0, 42),
+                    Instruction::SetReg(7, 16),
+                    Instruction::AllocateMemory(7, 6),
+                    Instruction::SetReg(9, 1), // Invalid register
+                ]);
+                assert_eq!(vm.run(), Err(VmError::InvalidRegister(9)));
+            });
+
+            assert!(records.contains(&(Level::Trace, "0000: SetReg(0, 42)".to_string())));
+            assert!(records.contains(&(Level::Trace, "Set register 0 to value 42".to_string())));
+            assert!(records.contains(&(
+                Level::Debug,
+                "Allocated 16 bytes of memory at address 0".to_string()
+            )));
+            assert!(records.contains(&(
+                Level::Error,
+                "Trapped at instruction 3: invalid register index 9".to_string()
+            )));
+
+            let records = capture(|| {
+                let mut vm = VM::new(vec![Instruction::Print(9)]);
+                vm.set_strict(false);
+                assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+            });
+            assert!(records.contains(&(
+                Level::Warn,
+                "Skipped instruction 0: invalid register index 9".to_string()
+            )));
+        }
+    }
+}